@@ -0,0 +1,201 @@
+extern crate clap;
+extern crate env_logger;
+extern crate hdrsample;
+
+extern crate coio;
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use clap::{Arg, App};
+use hdrsample::Histogram;
+
+use coio::Scheduler;
+use coio::net::tcp::TcpStream;
+
+// Highest trackable round trip, in microseconds
+const MAX_RTT_US: u64 = 60 * 1000 * 1000;
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1000000 + d.subsec_nanos() as u64 / 1000
+}
+
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, MAX_RTT_US, 3).unwrap()
+}
+
+// Send `size` bytes messages one at a time until `deadline` and record the round trips of the
+// ones sent after `measure_from`
+fn ping_pong(addr: &str,
+             size: usize,
+             measure_from: Instant,
+             deadline: Instant)
+             -> io::Result<Histogram<u64>> {
+    let mut stream = try!(TcpStream::connect(addr));
+    try!(stream.set_nodelay(true));
+
+    let msg = vec![0xa5u8; size];
+    let mut buf = vec![0u8; size];
+    let mut hist = new_histogram();
+
+    loop {
+        let started = Instant::now();
+        if started >= deadline {
+            return Ok(hist);
+        }
+
+        try!(stream.write_all(&msg));
+
+        let mut read = 0;
+        while read < size {
+            match try!(stream.read(&mut buf[read..])) {
+                0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed by server")),
+                len => read += len,
+            }
+        }
+
+        if started >= measure_from {
+            // Outliers beyond the trackable range are clamped instead of dropped
+            let rtt = cmp::max(1, cmp::min(micros(started.elapsed()), MAX_RTT_US));
+            hist.record(rtt).unwrap();
+        }
+    }
+}
+
+// Percentile distribution in the `.hgrm` format of HdrHistogram, values in milliseconds
+fn write_hgrm<W: Write>(out: &mut W, hist: &Histogram<u64>) -> io::Result<()> {
+    try!(writeln!(out,
+                  "{:>12} {:>14} {:>10} {:>14}\n",
+                  "Value",
+                  "Percentile",
+                  "TotalCount",
+                  "1/(1-Percentile)"));
+
+    // 5 ticks per halving of the distance to 100%, down to 99.999%
+    let mut percentile = 0.0;
+    let mut remaining = 100.0;
+    while remaining > 0.001 {
+        for _ in 0..5 {
+            let value = hist.value_at_percentile(percentile);
+            try!(writeln!(out,
+                          "{:>12.3} {:>14.12} {:>10} {:>14.2}",
+                          value as f64 / 1000.0,
+                          percentile / 100.0,
+                          hist.count_between(0, value),
+                          1.0 / (1.0 - percentile / 100.0)));
+            percentile += remaining / 10.0;
+        }
+        remaining /= 2.0;
+    }
+
+    try!(writeln!(out,
+                  "{:>12.3} {:>14.12} {:>10}",
+                  hist.max() as f64 / 1000.0,
+                  1.0,
+                  hist.len()));
+    try!(writeln!(out,
+                  "#[Mean    = {:>12.3}, StdDeviation   = {:>12.3}]",
+                  hist.mean() / 1000.0,
+                  hist.stdev() / 1000.0));
+    writeln!(out,
+             "#[Max     = {:>12.3}, Total count    = {:>12}]",
+             hist.max() as f64 / 1000.0,
+             hist.len())
+}
+
+fn main() {
+    env_logger::init().unwrap();
+
+    let matches = App::new("coio-tcp-latency-client")
+                      .version(env!("CARGO_PKG_VERSION"))
+                      .arg(Arg::with_name("ADDR")
+                               .short("a")
+                               .long("addr")
+                               .takes_value(true)
+                               .required(true)
+                               .help("Address of the echo server"))
+                      .arg(Arg::with_name("CONNECTIONS")
+                               .short("c")
+                               .long("connections")
+                               .takes_value(true)
+                               .help("Number of connections, one coroutine each"))
+                      .arg(Arg::with_name("LENGTH")
+                               .short("l")
+                               .long("length")
+                               .takes_value(true)
+                               .help("Message size in bytes"))
+                      .arg(Arg::with_name("DURATION")
+                               .short("d")
+                               .long("duration")
+                               .takes_value(true)
+                               .help("Seconds to measure"))
+                      .arg(Arg::with_name("WARMUP")
+                               .short("w")
+                               .long("warmup")
+                               .takes_value(true)
+                               .help("Seconds to run before measuring"))
+                      .arg(Arg::with_name("THREADS")
+                               .short("t")
+                               .long("threads")
+                               .takes_value(true)
+                               .help("Number of threads"))
+                      .arg(Arg::with_name("HGRM")
+                               .short("o")
+                               .long("hgrm")
+                               .takes_value(true)
+                               .help("Write the percentile distribution to this .hgrm file"))
+                      .get_matches();
+
+    let addr = matches.value_of("ADDR").unwrap().to_owned();
+    let connections: usize = matches.value_of("CONNECTIONS").unwrap_or("64").parse().unwrap();
+    let size: usize = matches.value_of("LENGTH").unwrap_or("64").parse().unwrap();
+    let duration: u64 = matches.value_of("DURATION").unwrap_or("10").parse().unwrap();
+    let warmup: u64 = matches.value_of("WARMUP").unwrap_or("1").parse().unwrap();
+    let hgrm_path = matches.value_of("HGRM").map(|path| path.to_owned());
+
+    let hist = Scheduler::new()
+                   .with_workers(matches.value_of("THREADS").unwrap_or("1").parse().unwrap())
+                   .run(move || {
+                       let measure_from = Instant::now() + Duration::from_secs(warmup);
+                       let deadline = measure_from + Duration::from_secs(duration);
+
+                       let handles: Vec<_> = (0..connections)
+                                                 .map(|_| {
+                                                     let addr = addr.clone();
+                                                     Scheduler::spawn(move || {
+                                                         ping_pong(&addr,
+                                                                   size,
+                                                                   measure_from,
+                                                                   deadline)
+                                                     })
+                                                 })
+                                                 .collect();
+
+                       let mut total = new_histogram();
+                       for hdl in handles {
+                           total.add(&hdl.join().unwrap().unwrap()).unwrap();
+                       }
+                       total
+                   })
+                   .unwrap();
+
+    println!("{} connections, {} bytes messages, {} round trips in {}s ({:.0}/s)",
+             connections,
+             size,
+             hist.len(),
+             duration,
+             hist.len() as f64 / duration as f64);
+    for &percentile in &[50.0, 90.0, 99.0, 99.9, 99.99] {
+        println!("p{:<6} {:>10.3}ms",
+                 percentile,
+                 hist.value_at_percentile(percentile) as f64 / 1000.0);
+    }
+    println!("max     {:>10.3}ms", hist.max() as f64 / 1000.0);
+
+    if let Some(path) = hgrm_path {
+        let mut file = File::create(&path).unwrap();
+        write_hgrm(&mut file, &hist).unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+extern crate clap;
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+
+extern crate coio;
+
+use std::io::{Read, Write};
+
+use clap::{Arg, App};
+
+use coio::Scheduler;
+use coio::net::tcp::TcpListener;
+
+// Echo server for `coio-tcp-latency-client`. Unlike `coio-tcp-echo-server` it does not log
+// per message, which would dominate the measured round trips.
+fn main() {
+    env_logger::init().unwrap();
+
+    let matches = App::new("coio-tcp-latency-server")
+                      .version(env!("CARGO_PKG_VERSION"))
+                      .arg(Arg::with_name("BIND")
+                               .short("b")
+                               .long("bind")
+                               .takes_value(true)
+                               .required(true)
+                               .help("Listening on this address"))
+                      .arg(Arg::with_name("THREADS")
+                               .short("t")
+                               .long("threads")
+                               .takes_value(true)
+                               .help("Number of threads"))
+                      .get_matches();
+
+    let bind_addr = matches.value_of("BIND").unwrap().to_owned();
+
+    Scheduler::new()
+        .with_workers(matches.value_of("THREADS").unwrap_or("1").parse().unwrap())
+        .run(move || {
+            let server = TcpListener::bind(&bind_addr[..]).unwrap();
+
+            info!("Listening on {:?}", server.local_addr().unwrap());
+
+            for stream in server.incoming() {
+                let (mut stream, addr) = stream.unwrap();
+                stream.set_nodelay(true).unwrap();
+
+                Scheduler::spawn(move || {
+                    let mut buf = [0; 1024 * 64];
+
+                    loop {
+                        match stream.read(&mut buf) {
+                            Ok(0) => break,
+                            Ok(len) => {
+                                if let Err(err) = stream.write_all(&buf[..len]) {
+                                    warn!("{:?} write failed: {:?}", addr, err);
+                                    break;
+                                }
+                            }
+                            Err(err) => {
+                                warn!("{:?} read failed: {:?}", addr, err);
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        })
+        .unwrap();
+}
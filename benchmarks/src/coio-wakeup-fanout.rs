@@ -0,0 +1,98 @@
+extern crate clap;
+extern crate env_logger;
+
+extern crate coio;
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use clap::{Arg, App};
+
+use coio::Scheduler;
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1000000.0
+}
+
+fn main() {
+    env_logger::init().unwrap();
+
+    let matches = App::new("coio-wakeup-fanout")
+                      .version(env!("CARGO_PKG_VERSION"))
+                      .arg(Arg::with_name("WAITERS")
+                               .short("w")
+                               .long("waiters")
+                               .takes_value(true)
+                               .help("Number of coroutines woken up by one broadcast"))
+                      .arg(Arg::with_name("ROUNDS")
+                               .short("r")
+                               .long("rounds")
+                               .takes_value(true)
+                               .help("Number of broadcasts"))
+                      .arg(Arg::with_name("THREADS")
+                               .short("t")
+                               .long("threads")
+                               .takes_value(true)
+                               .help("Number of threads"))
+                      .arg(Arg::with_name("SINGLE")
+                               .short("s")
+                               .long("single")
+                               .help("Wake up the coroutines one by one instead of in batches"))
+                      .get_matches();
+
+    let waiters: usize = matches.value_of("WAITERS").unwrap_or("50000").parse().unwrap();
+    let rounds: usize = matches.value_of("ROUNDS").unwrap_or("10").parse().unwrap();
+    let single = matches.is_present("SINGLE");
+
+    Scheduler::new()
+        .with_workers(matches.value_of("THREADS").unwrap_or("4").parse().unwrap())
+        .run(move || {
+            for round in 0..rounds {
+                let parked = Arc::new(Mutex::new(Vec::with_capacity(waiters)));
+                let resumed = Arc::new(AtomicUsize::new(0));
+
+                let handles: Vec<_> = (0..waiters)
+                                          .map(|_| {
+                                              let parked = parked.clone();
+                                              let resumed = resumed.clone();
+                                              Scheduler::spawn(move || {
+                                                  Scheduler::take_current_coroutine(|coro| {
+                                                      parked.lock().unwrap().push(coro);
+                                                  });
+                                                  resumed.fetch_add(1, Ordering::SeqCst);
+                                              })
+                                          })
+                                          .collect();
+
+                while parked.lock().unwrap().len() < waiters {
+                    Scheduler::sched();
+                }
+
+                let coros: Vec<_> = parked.lock().unwrap().drain(..).collect();
+
+                let started = Instant::now();
+                if single {
+                    for coro in coros {
+                        Scheduler::ready(coro);
+                    }
+                } else {
+                    Scheduler::ready_all(coros);
+                }
+                let broadcast = started.elapsed();
+
+                for hdl in handles {
+                    hdl.join().unwrap();
+                }
+                let resumed_all = started.elapsed();
+
+                assert_eq!(resumed.load(Ordering::SeqCst), waiters);
+                println!("Round {}: broadcast {:.3}ms, all {} resumed after {:.3}ms",
+                         round,
+                         millis(broadcast),
+                         waiters,
+                         millis(resumed_all));
+            }
+        })
+        .unwrap();
+}
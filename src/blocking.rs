@@ -0,0 +1,203 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Threads running blocking closures on behalf of the coroutines, see
+//! `Scheduler::run_blocking`
+
+use std::boxed::FnBox;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use runtime::Processor;
+use runtime::processor::ProcMessage;
+use scheduler::Scheduler;
+use stats::WaitReason;
+
+/// Number of threads running the closures of `Scheduler::run_blocking` unless set with
+/// `Scheduler::with_blocking_threads`
+pub const DEFAULT_BLOCKING_THREADS: usize = 16;
+
+type Job = Box<FnBox() + Send + 'static>;
+
+/// A fixed number of threads, started by the first job. They exit once the pool is dropped.
+#[doc(hidden)]
+pub struct BlockingPool {
+    name: &'static str,
+    threads: usize,
+    // The receiver is kept as well, so that queueing a job never fails
+    jobs: Mutex<Option<(Sender<Job>, Arc<Mutex<Receiver<Job>>>)>>,
+}
+
+impl BlockingPool {
+    /// The threads are named `name` followed by their index
+    pub fn new(name: &'static str, threads: usize) -> BlockingPool {
+        assert!(threads >= 1, "Must have at least one thread");
+
+        BlockingPool {
+            name: name,
+            threads: threads,
+            jobs: Mutex::new(None),
+        }
+    }
+
+    /// Number of threads
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    // Start the threads unless they are running already
+    fn start(&self) -> io::Result<()> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.is_some() {
+            return Ok(());
+        }
+
+        let (tx, rx) = channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for id in 0..self.threads {
+            let rx = rx.clone();
+            try!(thread::Builder::new()
+                     .name(format!("{} #{}", self.name, id))
+                     .spawn(move || {
+                         loop {
+                             let job = {
+                                 let rx = rx.lock().unwrap();
+                                 rx.recv()
+                             };
+
+                             match job {
+                                 Ok(job) => job.call_box(()),
+                                 Err(..) => break,
+                             }
+                         }
+                     }));
+        }
+
+        *jobs = Some((tx, rx));
+        Ok(())
+    }
+
+    fn submit(&self, job: Job) {
+        let jobs = self.jobs.lock().unwrap();
+        let &(ref tx, _) = jobs.as_ref().expect("BlockingPool is not started");
+        tx.send(job).unwrap();
+    }
+
+    /// Run `f` on one of the threads, blocking only the current coroutine until it has
+    /// returned. Outside of a coroutine, or if the threads could not be started, `f` runs
+    /// right away on the calling thread.
+    ///
+    /// Returns `Err` with the payload if `f` panicked. The wait cannot be cancelled,
+    /// `JoinHandle::cancel` takes effect once `f` has returned.
+    pub fn run<'a, F, T>(&self, f: F) -> thread::Result<T>
+        where F: FnOnce() -> T + Send + 'a,
+              T: Send + 'a
+    {
+        if Processor::current().and_then(|p| p.current_shared()).is_none() {
+            return unsafe { ::try(f) };
+        }
+
+        if let Err(err) = self.start() {
+            warn!("Failed to start the {} threads: {}", self.name, err);
+            return unsafe { ::try(f) };
+        }
+
+        let mut ret = None;
+        {
+            let ret1 = ResultWrapper(&mut ret);
+
+            Scheduler::take_current_coroutine(|coro| {
+                // Not `Other`, a long running job is no deadlock
+                coro.shared().set_wait_reason(WaitReason::Io);
+                let proc_hdl = Processor::current().unwrap().handle();
+
+                let job: Box<FnBox() + Send + 'a> = Box::new(move || {
+                    // `f` may borrow from the stack of the coroutine, which is owned by this
+                    // job and stays blocked until `f` has been consumed
+                    let r = unsafe { ::try(f) };
+                    unsafe {
+                        *ret1.0 = Some(r);
+                    }
+
+                    let _ = proc_hdl.send(ProcMessage::ready(coro));
+                });
+                self.submit(unsafe { mem::transmute(job) });
+            });
+        }
+        ret.expect("Coroutine resumed before its blocking job has finished")
+    }
+}
+
+// Result of the job, written by the thread while the coroutine is blocked
+struct ResultWrapper<T>(*mut Option<thread::Result<T>>);
+unsafe impl<T: Send> Send for ResultWrapper<T> {}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use scheduler::Scheduler;
+
+    #[test]
+    fn test_run_blocking() {
+        Scheduler::new()
+            .with_blocking_threads(2)
+            .run(|| {
+                let caller = thread::current().name().map(|name| name.to_owned());
+
+                // The Processor keeps running the other coroutines meanwhile
+                let (tx, rx) = mpsc::channel();
+                let ticker = Scheduler::spawn(move || {
+                    for _ in 0..5 {
+                        ::sleep(Duration::from_millis(10));
+                    }
+                    tx.send(()).unwrap();
+                });
+
+                let data = vec![1, 2, 3];
+                let data = &data;
+                let (sum, name) = Scheduler::run_blocking(move || {
+                                      let name = thread::current()
+                                                     .name()
+                                                     .map(|name| name.to_owned());
+                                      // Blocks the thread until the ticker is done
+                                      rx.recv().unwrap();
+                                      (data.iter().fold(0, |sum, x| sum + x), name)
+                                  })
+                                      .unwrap();
+                assert_eq!(sum, 6);
+                assert!(name != caller);
+                assert!(name.unwrap().starts_with("Blocking #"));
+                ticker.join().unwrap();
+
+                assert!(Scheduler::run_blocking(|| -> () { panic!("failed") }).is_err());
+            })
+            .unwrap();
+
+        // Outside of a Scheduler it runs on the calling thread
+        assert_eq!(Scheduler::run_blocking(|| 42).unwrap(), 42);
+    }
+}
@@ -19,8 +19,14 @@
 // IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
 // CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use std::any::Any;
 use std::boxed::FnBox;
-use std::cell::UnsafeCell;
+use std::cell::{Cell, UnsafeCell};
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::{Duration, Instant};
 
 #[cfg(debug_assertions)]
 use std::thread;
@@ -28,17 +34,77 @@ use std::thread;
 use libc;
 
 use context::{Context, Stack};
-use context::stack::StackPool;
 
 use runtime::processor::{Processor, WeakProcessor};
-use options::Options;
+use options::{Options, Priority, StackClass};
+use scheduler::Scheduler;
+use stats::WaitReason;
+
+static COROUTINE_ID: AtomicUsize = ATOMIC_USIZE_INIT;
 
 thread_local!(static STACK_POOL: UnsafeCell<StackPool> = UnsafeCell::new(StackPool::new()));
 
+/// Fair queuing group of the coroutines spawned outside of any group, see `Options::group`
+pub const DEFAULT_GROUP: usize = 0;
+
+/// Number of stacks per class kept for reuse by each Processor, unless configured with
+/// `Scheduler::with_stack_pool_cap`
+pub const DEFAULT_STACK_POOL_CAP: usize = 16;
+
+// Stacks of the finished coroutines of a Processor, kept per StackClass.
+//
+// Stack sizes are rounded up to the size of their class, so that any pooled stack fits any
+// coroutine of the class. Stacks exceeding all classes are never pooled, and without pooling
+// stacks keep their exact size.
+struct StackPool {
+    stacks: [Vec<Stack>; 4],
+}
+
+impl StackPool {
+    fn new() -> StackPool {
+        StackPool { stacks: [Vec::new(), Vec::new(), Vec::new(), Vec::new()] }
+    }
+
+    fn take(&mut self, size: usize, cap: usize) -> Stack {
+        let class = StackClass::of(size);
+        let size = if cap > 0 && size <= class.size() {
+            if let Some(stack) = self.stacks[class.index()].pop() {
+                return stack;
+            }
+            class.size()
+        } else {
+            size
+        };
+
+        if let Some(scheduler) = Scheduler::instance() {
+            scheduler.stack_allocated();
+        }
+        Stack::new(size)
+    }
+
+    fn give(&mut self, class: StackClass, stack: Stack, cap: usize) {
+        let size = stack.end() as usize - stack.start() as usize;
+        let pooled = &mut self.stacks[class.index()];
+        if size <= class.size() && pooled.len() < cap {
+            pooled.push(stack);
+        }
+    }
+}
+
+// Stacks kept per class by the Processors of the current Scheduler
+fn stack_pool_cap() -> usize {
+    Scheduler::instance().map(|s| s.stack_pool_cap()).unwrap_or(DEFAULT_STACK_POOL_CAP)
+}
+
 /// Initialization function for make context
-extern "C" fn coroutine_initialize(_: usize, f: *mut libc::c_void) -> ! {
+extern "C" fn coroutine_initialize(class: usize, f: *mut libc::c_void) -> ! {
     let f = unsafe { Box::from_raw(f as *mut Box<FnBox()>) };
 
+    // Released again in Scheduler::finished()
+    if let Some(scheduler) = Scheduler::instance() {
+        scheduler.stack_acquired(StackClass::all()[class]);
+    }
+
     f();
     Processor::current().unwrap().yield_with(State::Finished);
 
@@ -47,12 +113,457 @@ extern "C" fn coroutine_initialize(_: usize, f: *mut libc::c_void) -> ! {
 
 pub type Handle = Box<Coroutine>;
 
+/// Coroutine states that could be accessed by other coroutines or threads,
+/// even after the coroutine itself has been destroyed
+pub struct Shared {
+    id: usize,
+    name: Option<String>,
+    spawn_site: Option<&'static str>,
+    group: usize,
+    priority: Priority,
+    // Bound to the first Processor it has been readied on, see `Options::pin`
+    pinned: bool,
+    deadline: Option<Instant>,
+    parent: Option<Arc<Shared>>,
+    aborted: AtomicBool,
+    blocked: AtomicBool,
+    // Index of the WaitReason while blocked, 0 if it is not known
+    wait_reason: AtomicUsize,
+    // File descriptor + 1 of the I/O object blocked on, 0 if it is not known
+    wait_fd: AtomicUsize,
+    // Id + 1 of the Processor which has resumed the coroutine last, 0 before the first resume
+    processor: AtomicUsize,
+    running: AtomicBool,
+    finished: AtomicBool,
+    shutdown_notified: AtomicBool,
+    interrupt: Mutex<Interrupt>,
+    // Wakes the coroutine up from a blocking wait on I/O or on a channel, see `cancel`
+    cancel_hook: Mutex<CancelHook>,
+    // Signalled when a hook called by `cancel` has returned
+    cancel_hook_done: Condvar,
+    timing: Mutex<Timing>,
+    spans: Mutex<Vec<&'static str>>,
+    // Values of the `coroutine_local!` keys, by the address of the key
+    locals: Mutex<HashMap<usize, Box<Any + Send>>>,
+    // Heap memory attributed to the coroutine, see `metrics::memory`
+    allocated: AtomicUsize,
+    freed: AtomicUsize,
+}
+
+// Time spent running and blocked, accounted while the Scheduler has an observer
+struct Timing {
+    running: Duration,
+    blocked: Duration,
+    running_since: Option<Instant>,
+    blocked_since: Option<Instant>,
+}
+
+// Wakes the coroutine up from an interruptible wait
+type InterruptHook = Box<FnBox() + Send + 'static>;
+
+struct Interrupt {
+    pending: bool,
+    hook: Option<InterruptHook>,
+}
+
+struct CancelHook {
+    hook: Option<InterruptHook>,
+    // Incremented by every `set_cancel_hook`, so that 0 is never a valid generation
+    generation: usize,
+    // Generation of the hook `cancel` is calling right now, 0 if none
+    calling: usize,
+}
+
+impl Shared {
+    fn new(name: Option<String>, deadline: Option<Instant>, parent: Option<Arc<Shared>>) -> Shared {
+        // Children never outlive the deadline of the parent they inherit from
+        let deadline = match (deadline, parent.as_ref().and_then(|p| p.deadline)) {
+            (Some(a), Some(b)) => Some(if a < b { a } else { b }),
+            (a, b) => a.or(b),
+        };
+
+        Shared {
+            id: COROUTINE_ID.fetch_add(1, Ordering::Relaxed),
+            name: name,
+            spawn_site: None,
+            group: DEFAULT_GROUP,
+            priority: Priority::Normal,
+            pinned: false,
+            deadline: deadline,
+            parent: parent,
+            aborted: AtomicBool::new(false),
+            blocked: AtomicBool::new(false),
+            wait_reason: AtomicUsize::new(0),
+            wait_fd: AtomicUsize::new(0),
+            processor: AtomicUsize::new(0),
+            running: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+            shutdown_notified: AtomicBool::new(false),
+            interrupt: Mutex::new(Interrupt {
+                pending: false,
+                hook: None,
+            }),
+            cancel_hook: Mutex::new(CancelHook {
+                hook: None,
+                generation: 0,
+                calling: 0,
+            }),
+            cancel_hook_done: Condvar::new(),
+            timing: Mutex::new(Timing {
+                running: Duration::new(0, 0),
+                blocked: Duration::new(0, 0),
+                running_since: None,
+                blocked_since: None,
+            }),
+            spans: Mutex::new(Vec::new()),
+            locals: Mutex::new(HashMap::new()),
+            allocated: AtomicUsize::new(0),
+            freed: AtomicUsize::new(0),
+        }
+    }
+
+    /// Unique identifier of the coroutine
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|s| &s[..])
+    }
+
+    #[inline]
+    pub fn record_alloc(&self, size: usize) {
+        self.allocated.fetch_add(size, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn record_free(&self, size: usize) {
+        self.freed.fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// Bytes allocated and freed while the coroutine was running
+    pub fn memory(&self) -> (usize, usize) {
+        (self.allocated.load(Ordering::Relaxed), self.freed.load(Ordering::Relaxed))
+    }
+
+    /// Ask the coroutine to unwind at its next scheduling point, wakes it up if it is sleeping
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::SeqCst);
+        self.interrupt();
+    }
+
+    /// Like `abort`, but also wakes the coroutine up if it is blocked on I/O or on a channel
+    pub fn cancel(&self) {
+        let hook = {
+            let mut state = self.cancel_hook.lock().unwrap();
+            self.aborted.store(true, Ordering::SeqCst);
+
+            let hook = state.hook.take();
+            if hook.is_some() {
+                state.calling = state.generation;
+            }
+            hook
+        };
+
+        // Called without the lock, so that the hook may cancel other coroutines or install
+        // another hook. `clear_cancel_hook` waits until it has returned instead.
+        if let Some(hook) = hook {
+            hook.call_box(());
+
+            self.cancel_hook.lock().unwrap().calling = 0;
+            self.cancel_hook_done.notify_all();
+        }
+
+        self.interrupt();
+    }
+
+    /// Install the hook called by `cancel()`, replacing the previous one. Returns its
+    /// generation to be passed to `clear_cancel_hook`.
+    ///
+    /// The coroutine may have been cancelled before, check `is_aborted()` afterwards.
+    pub fn set_cancel_hook(&self, hook: InterruptHook) -> usize {
+        let mut state = self.cancel_hook.lock().unwrap();
+        state.generation += 1;
+        state.hook = Some(hook);
+        state.generation
+    }
+
+    /// Remove the hook of `generation` unless another one has been installed since. Waits for
+    /// it if `cancel()` is calling it right now, so that it may borrow from the caller.
+    pub fn clear_cancel_hook(&self, generation: usize) {
+        let mut state = self.cancel_hook.lock().unwrap();
+        if state.generation == generation {
+            state.hook = None;
+        }
+
+        while state.calling == generation {
+            state = self.cancel_hook_done.wait(state).unwrap();
+        }
+    }
+
+    /// Wake the coroutine up from an interruptible wait, like `sleep`.
+    ///
+    /// If it is not waiting, its next interruptible wait returns immediately.
+    pub fn interrupt(&self) {
+        let hook = {
+            let mut interrupt = self.interrupt.lock().unwrap();
+            match interrupt.hook.take() {
+                Some(hook) => hook,
+                None => {
+                    interrupt.pending = true;
+                    return;
+                }
+            }
+        };
+
+        hook.call_box(());
+    }
+
+    /// Install the hook called by `interrupt()`.
+    ///
+    /// Returns false and consumes the pending interruption instead if there is one.
+    pub fn set_interrupt_hook(&self, hook: InterruptHook) -> bool {
+        let mut interrupt = self.interrupt.lock().unwrap();
+        if interrupt.pending {
+            interrupt.pending = false;
+            return false;
+        }
+
+        interrupt.hook = Some(hook);
+        true
+    }
+
+    pub fn clear_interrupt_hook(&self) {
+        self.interrupt.lock().unwrap().hook = None;
+    }
+
+    /// Whether the coroutine should unwind, because it (or the parent it inherits from)
+    /// has been aborted or its deadline has passed
+    pub fn is_aborted(&self) -> bool {
+        if self.aborted.load(Ordering::SeqCst) {
+            return true;
+        }
+
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return true;
+            }
+        }
+
+        self.parent.as_ref().map(|p| p.is_aborted()).unwrap_or(false)
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn spawn_site(&self) -> Option<&'static str> {
+        self.spawn_site
+    }
+
+    /// Fair queuing group of the coroutine
+    pub fn group(&self) -> usize {
+        self.group
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Whether the coroutine never migrates to another Processor
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+
+    /// Mark the coroutine as blocked or not, returns the previous value
+    pub fn set_blocked(&self, blocked: bool) -> bool {
+        if !blocked {
+            self.wait_reason.store(0, Ordering::Relaxed);
+            self.wait_fd.store(0, Ordering::Relaxed);
+        }
+        self.blocked.swap(blocked, Ordering::SeqCst)
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.blocked.load(Ordering::SeqCst)
+    }
+
+    /// Record what the coroutine is about to block on, cleared once it is resumed
+    pub fn set_wait_reason(&self, reason: WaitReason) {
+        self.wait_reason.store(reason.index() + 1, Ordering::Relaxed);
+    }
+
+    /// Record the file descriptor the coroutine is about to wait for, cleared once it is
+    /// resumed
+    pub fn set_wait_fd(&self, fd: i32) {
+        if fd >= 0 {
+            self.wait_fd.store(fd as usize + 1, Ordering::Relaxed);
+        }
+    }
+
+    /// The file descriptor the coroutine is blocked on, if known
+    pub fn wait_fd(&self) -> Option<i32> {
+        match self.wait_fd.load(Ordering::Relaxed) {
+            0 => None,
+            fd => Some((fd - 1) as i32),
+        }
+    }
+
+    /// Mark the coroutine as being resumed by the Processor, or as suspended with `None`
+    pub fn set_running(&self, processor: Option<usize>) {
+        match processor {
+            Some(id) => {
+                self.processor.store(id + 1, Ordering::Relaxed);
+                self.running.store(true, Ordering::Relaxed);
+            }
+            None => self.running.store(false, Ordering::Relaxed),
+        }
+    }
+
+    /// Whether a Processor is running the coroutine right now
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Id of the Processor which has resumed the coroutine last
+    pub fn processor(&self) -> Option<usize> {
+        match self.processor.load(Ordering::Relaxed) {
+            0 => None,
+            id => Some(id - 1),
+        }
+    }
+
+    /// What the coroutine is blocked on, `None` if it is not blocked
+    pub fn wait_reason(&self) -> Option<WaitReason> {
+        if !self.is_blocked() {
+            return None;
+        }
+
+        match self.wait_reason.load(Ordering::Relaxed) {
+            0 => Some(WaitReason::Other),
+            idx => Some(WaitReason::from_index(idx - 1)),
+        }
+    }
+
+    pub fn set_finished(&self) {
+        self.finished.store(true, Ordering::SeqCst);
+
+        // Dropped outside of the lock, destructors may access other locals
+        let locals = mem::replace(&mut *self.locals.lock().unwrap(), HashMap::new());
+        drop(locals);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::SeqCst)
+    }
+
+    /// Mark the coroutine as notified of the shutdown, returns true the first time only
+    pub fn notify_shutdown(&self) -> bool {
+        !self.shutdown_notified.swap(true, Ordering::SeqCst)
+    }
+
+    /// The coroutine is about to be resumed
+    pub fn account_resumed(&self, now: Instant) {
+        let mut timing = self.timing.lock().unwrap();
+        if let Some(since) = timing.blocked_since.take() {
+            timing.blocked += now - since;
+        }
+        timing.running_since = Some(now);
+    }
+
+    /// The coroutine has been suspended, possibly to be blocked
+    pub fn account_suspended(&self, now: Instant, blocked: bool) {
+        let mut timing = self.timing.lock().unwrap();
+        if let Some(since) = timing.running_since.take() {
+            timing.running += now - since;
+        }
+        if blocked {
+            timing.blocked_since = Some(now);
+        }
+    }
+
+    /// Total time spent running and blocked until `now`
+    pub fn timing(&self, now: Instant) -> (Duration, Duration) {
+        let timing = self.timing.lock().unwrap();
+        let since = |s: Option<Instant>| s.map(|s| now - s).unwrap_or(Duration::new(0, 0));
+        (timing.running + since(timing.running_since),
+         timing.blocked + since(timing.blocked_since))
+    }
+
+    pub fn push_span(&self, name: &'static str) {
+        self.spans.lock().unwrap().push(name);
+    }
+
+    pub fn pop_span(&self) {
+        self.spans.lock().unwrap().pop();
+    }
+
+    /// Names of the entered spans, the innermost last
+    pub fn spans(&self) -> Vec<&'static str> {
+        self.spans.lock().unwrap().clone()
+    }
+
+    /// Value of the coroutine-local `key`, initialized with `init` on the first access.
+    ///
+    /// The value stays at the same address until the coroutine finishes.
+    pub fn local<F>(&self, key: usize, init: F) -> *const (Any + Send)
+        where F: FnOnce() -> Box<Any + Send>
+    {
+        {
+            let locals = self.locals.lock().unwrap();
+            if let Some(value) = locals.get(&key) {
+                return &**value as *const (Any + Send);
+            }
+        }
+
+        // Initialized outside of the lock, `init` may access other locals
+        let value = init();
+        let mut locals = self.locals.lock().unwrap();
+        &**locals.entry(key).or_insert(value) as *const (Any + Send)
+    }
+}
+
+/// Removes the cancel hook of the coroutine when the wait is over, even if it is unwound
+pub struct CancelHookGuard {
+    shared: Arc<Shared>,
+    generation: Cell<usize>,
+}
+
+impl CancelHookGuard {
+    pub fn new(shared: Arc<Shared>) -> CancelHookGuard {
+        CancelHookGuard {
+            shared: shared,
+            generation: Cell::new(0),
+        }
+    }
+
+    pub fn shared(&self) -> &Arc<Shared> {
+        &self.shared
+    }
+
+    /// Install the hook called by `Shared::cancel()`, removed again once the guard is dropped
+    pub fn set(&self, hook: InterruptHook) {
+        self.generation.set(self.shared.set_cancel_hook(hook));
+    }
+}
+
+impl Drop for CancelHookGuard {
+    fn drop(&mut self) {
+        if self.generation.get() != 0 {
+            self.shared.clear_cancel_hook(self.generation.get());
+        }
+    }
+}
+
 /// Coroutine is nothing more than a context and a stack
 #[cfg(debug_assertions)]
 pub struct Coroutine {
     context: Context,
     stack: Option<Stack>,
+    stack_class: Option<StackClass>,
+    stack_measured: bool,
     preferred_processor: Option<WeakProcessor>,
+    shared: Arc<Shared>,
 
     drop_allowed: bool,
 }
@@ -61,27 +572,46 @@ pub struct Coroutine {
 pub struct Coroutine {
     context: Context,
     stack: Option<Stack>,
+    stack_class: Option<StackClass>,
+    stack_measured: bool,
     preferred_processor: Option<WeakProcessor>,
+    shared: Arc<Shared>,
 }
 
 impl Coroutine {
     #[cfg(not(debug_assertions))]
-    fn new(ctx: Context, stack: Option<Stack>) -> Handle {
+    fn new(ctx: Context, stack: Option<(Stack, StackClass)>, shared: Shared) -> Handle {
+        let (stack, stack_class) = match stack {
+            Some((stack, class)) => (Some(stack), Some(class)),
+            None => (None, None),
+        };
+
         Box::new(Coroutine {
             context: ctx,
             stack: stack,
+            stack_class: stack_class,
+            stack_measured: false,
             preferred_processor: None,
+            shared: Arc::new(shared),
         })
     }
 
     #[cfg(debug_assertions)]
-    fn new(ctx: Context, stack: Option<Stack>) -> Handle {
+    fn new(ctx: Context, stack: Option<(Stack, StackClass)>, shared: Shared) -> Handle {
+        let (stack, stack_class) = match stack {
+            Some((stack, class)) => (Some(stack), Some(class)),
+            None => (None, None),
+        };
+
         let drop_allowed = stack.is_none();
 
         Box::new(Coroutine {
             context: ctx,
             stack: stack,
+            stack_class: stack_class,
+            stack_measured: false,
             preferred_processor: None,
+            shared: Arc::new(shared),
 
             drop_allowed: drop_allowed,
         })
@@ -113,49 +643,126 @@ impl Coroutine {
     }
 
     pub unsafe fn empty() -> Handle {
-        Coroutine::new(Context::empty(), None)
+        Coroutine::new(Context::empty(), None, Shared::new(None, None, None))
     }
 
-    pub fn spawn_opts(f: Box<FnBox()>, opts: Options) -> Handle {
+    /// Create a new coroutine, `parent` is the spawning coroutine if there is one
+    pub fn spawn_opts(f: Box<FnBox()>, opts: Options, parent: Option<Arc<Shared>>) -> Handle {
+        let group = opts.group
+                        .or_else(|| parent.as_ref().map(|p| p.group))
+                        .unwrap_or(DEFAULT_GROUP);
+        let parent = if opts.inherit_deadline {
+            parent
+        } else {
+            None
+        };
+
+        let cap = stack_pool_cap();
         let mut stack = STACK_POOL.with(|pool| unsafe {
-            (&mut *pool.get()).take_stack(opts.stack_size)
+            (&mut *pool.get()).take(opts.stack_size, cap)
         });
 
         // NOTE:
         //   We need to use Box<Box<FnBox()>> because Box<FnBox> uses a fat pointer
         //   and is thus 2 pointers wide instead of one, which is why it
         //   can't be transmuted to a single void pointer
+        let measured = Scheduler::instance().map(|s| s.stack_measurement()).unwrap_or(false);
+        if measured {
+            poison_stack(&stack);
+        }
+
+        let class = StackClass::of(opts.stack_size);
         let f = Box::into_raw(Box::new(f)) as *mut libc::c_void;
-        let ctx = Context::new(coroutine_initialize, 0, f, &mut stack);
+        let ctx = Context::new(coroutine_initialize, class.index(), f, &mut stack);
+
+        let mut shared = Shared::new(opts.name, opts.deadline, parent);
+        shared.spawn_site = opts.spawn_site;
+        shared.group = group;
+        shared.priority = opts.priority;
+        shared.pinned = opts.pin;
 
-        Coroutine::new(ctx, Some(stack))
+        let mut coro = Coroutine::new(ctx, Some((stack, class)), shared);
+        coro.stack_measured = measured;
+        coro
     }
 
     pub fn yield_to(&mut self, target: &Coroutine) {
         Context::swap(&mut self.context, &target.context);
     }
 
+    /// Pinned coroutines keep their first preferred Processor as long as it exists
     pub fn set_preferred_processor(&mut self, preferred_processor: Option<WeakProcessor>) {
+        if self.shared.is_pinned() && self.preferred_processor().is_some() {
+            return;
+        }
         self.preferred_processor = preferred_processor;
     }
 
     pub fn preferred_processor(&self) -> Option<Processor> {
         self.preferred_processor.as_ref().and_then(|p| p.upgrade())
     }
+
+    pub fn shared(&self) -> &Arc<Shared> {
+        &self.shared
+    }
+
+    /// Class of the stack, `None` for the main coroutines of the Processors
+    pub fn stack_class(&self) -> Option<StackClass> {
+        self.stack_class
+    }
+
+    /// Deepest stack usage in bytes, `None` if the stack has not been poisoned when spawning
+    pub fn stack_high_water_mark(&self) -> Option<usize> {
+        match self.stack {
+            Some(ref stack) if self.stack_measured => Some(stack_high_water_mark(stack)),
+            _ => None,
+        }
+    }
+}
+
+// Fills the unused part of a stack, so that the words touched since can be told apart
+const STACK_POISON: usize = !0 / 0xff * 0x5a;
+
+// Usable words of the stack, skipping the guard page at its bottom
+fn stack_bounds(stack: &Stack) -> (*mut usize, *mut usize) {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    ((stack.start() as usize + page_size) as *mut usize, stack.end() as *mut usize)
+}
+
+fn poison_stack(stack: &Stack) {
+    let (mut word, end) = stack_bounds(stack);
+    while word < end {
+        unsafe {
+            *word = STACK_POISON;
+            word = word.offset(1);
+        }
+    }
+}
+
+// The stack grows downwards, so the lowest word touched marks the deepest usage
+fn stack_high_water_mark(stack: &Stack) -> usize {
+    let (mut word, end) = stack_bounds(stack);
+    unsafe {
+        while word < end && *word == STACK_POISON {
+            word = word.offset(1);
+        }
+    }
+    end as usize - word as usize
 }
 
 impl Drop for Coroutine {
     fn drop(&mut self) {
         self.check_drop_allowed();
 
-        match self.stack.take() {
-            None => {}
-            Some(st) => {
+        match (self.stack.take(), self.stack_class) {
+            (Some(st), Some(class)) => {
+                let cap = stack_pool_cap();
                 STACK_POOL.with(|pool| unsafe {
                     let pool: &mut StackPool = &mut *pool.get();
-                    pool.give_stack(st);
+                    pool.give(class, st, cap);
                 })
             }
+            _ => {}
         }
     }
 }
@@ -173,3 +780,59 @@ pub type Result<T> = ::std::result::Result<T, ()>;
 #[derive(Copy, Clone, Debug)]
 pub struct SendableCoroutinePtr(pub *mut Coroutine);
 unsafe impl Send for SendableCoroutinePtr {}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::Shared;
+
+    #[test]
+    fn test_cancel_hook_reentrant() {
+        let shared = Arc::new(Shared::new(None, None, None));
+        let called = Arc::new(AtomicBool::new(false));
+
+        // Installing another hook from within the hook must not deadlock
+        let s = shared.clone();
+        let c = called.clone();
+        let generation = shared.set_cancel_hook(Box::new(move || {
+            s.set_cancel_hook(Box::new(move || c.store(true, Ordering::SeqCst)));
+        }));
+        shared.cancel();
+        assert!(shared.is_aborted());
+
+        // The old generation doesn't remove the new hook
+        shared.clear_cancel_hook(generation);
+        shared.cancel();
+        assert!(called.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_clear_cancel_hook_waits() {
+        let shared = Arc::new(Shared::new(None, None, None));
+        let started = Arc::new(AtomicBool::new(false));
+        let finished = Arc::new(AtomicBool::new(false));
+
+        let s = started.clone();
+        let f = finished.clone();
+        let generation = shared.set_cancel_hook(Box::new(move || {
+            s.store(true, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(50));
+            f.store(true, Ordering::SeqCst);
+        }));
+
+        let s = shared.clone();
+        let canceller = thread::spawn(move || s.cancel());
+        while !started.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // Returns only once the hook is done
+        shared.clear_cancel_hook(generation);
+        assert!(finished.load(Ordering::SeqCst));
+        canceller.join().unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Detecting the CPUs available to the process
+//!
+//! Containers commonly limit the CPU time of a process with a cgroup quota while still
+//! exposing all hardware threads of the host. Running one Processor per hardware thread
+//! then oversubscribes the quota and the Processors get throttled in turn.
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::mem;
+
+#[cfg(unix)]
+use libc;
+
+/// Number of online hardware threads
+#[cfg(unix)]
+pub fn hardware_threads() -> usize {
+    let n = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if n < 1 {
+        1
+    } else {
+        n as usize
+    }
+}
+
+/// Number of online hardware threads
+#[cfg(not(unix))]
+pub fn hardware_threads() -> usize {
+    1
+}
+
+/// CPU quota of the cgroup of the process in CPUs, e.g. `1.5` if it may use 150ms of CPU
+/// time every 100ms. Returns `None` if there is no quota.
+///
+/// Both cgroup v2 (`cpu.max`) and v1 (`cpu.cfs_quota_us`) are supported, assuming the
+/// cgroup hierarchy is mounted at `/sys/fs/cgroup` as it is inside of containers.
+pub fn cgroup_quota() -> Option<f64> {
+    if let Some(content) = read_file("/sys/fs/cgroup/cpu.max") {
+        return parse_cpu_max(&content);
+    }
+
+    for dir in &["/sys/fs/cgroup/cpu", "/sys/fs/cgroup/cpu,cpuacct"] {
+        let quota = read_file(&format!("{}/cpu.cfs_quota_us", dir));
+        let period = read_file(&format!("{}/cpu.cfs_period_us", dir));
+
+        if let (Some(quota), Some(period)) = (quota, period) {
+            return parse_cfs(&quota, &period);
+        }
+    }
+
+    None
+}
+
+/// Number of CPUs the process is able to keep busy: the hardware threads,
+/// limited by the cgroup quota rounded up
+pub fn available_cpus() -> usize {
+    cap_to_quota(hardware_threads())
+}
+
+/// Limit a number of threads to the cgroup CPU quota rounded up, so that they do not get
+/// throttled in turn. Unchanged if there is no quota.
+pub fn cap_to_quota(threads: usize) -> usize {
+    cap_threads(threads, cgroup_quota())
+}
+
+fn cap_threads(threads: usize, quota: Option<f64>) -> usize {
+    match quota {
+        Some(quota) => cmp::min(threads, cmp::max(1, quota.ceil() as usize)),
+        None => threads,
+    }
+}
+
+/// Highest number of CPUs `set_thread_affinity` could address
+pub const MAX_CPUS: usize = 1024;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[repr(C)]
+struct CpuSet {
+    bits: [libc::c_ulong; MAX_CPUS / 32],
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+extern "C" {
+    fn sched_setaffinity(pid: libc::pid_t, size: libc::size_t, mask: *const CpuSet) -> libc::c_int;
+}
+
+/// Restrict the current thread to run on the given CPUs only
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_thread_affinity(cpus: &[usize]) -> io::Result<()> {
+    let mut set = CpuSet { bits: [0; MAX_CPUS / 32] };
+    let word_bits = mem::size_of::<libc::c_ulong>() * 8;
+
+    for &cpu in cpus {
+        if cpu >= MAX_CPUS {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "CPU index out of range"));
+        }
+        set.bits[cpu / word_bits] |= 1 << (cpu % word_bits);
+    }
+
+    // Only the first MAX_CPUS bits are passed, even if c_ulong is 64 bits wide
+    let size = MAX_CPUS / 8;
+    if unsafe { sched_setaffinity(0, size as libc::size_t, &set) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Restrict the current thread to run on the given CPUs only
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_thread_affinity(_cpus: &[usize]) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "thread affinity is not supported"))
+}
+
+fn read_file(path: &str) -> Option<String> {
+    let mut content = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut content)) {
+        Ok(..) => Some(content),
+        Err(..) => None,
+    }
+}
+
+// cgroup v2: "$MAX $PERIOD", where $MAX may be "max"
+fn parse_cpu_max(content: &str) -> Option<f64> {
+    let mut fields = content.split_whitespace();
+    let max = fields.next();
+    let period = fields.next().and_then(|p| p.parse::<f64>().ok());
+
+    match (max, period) {
+        (Some("max"), _) => None,
+        (Some(max), Some(period)) if period > 0.0 => max.parse::<f64>().ok().map(|m| m / period),
+        _ => None,
+    }
+}
+
+// cgroup v1: a quota of -1 means unlimited
+fn parse_cfs(quota: &str, period: &str) -> Option<f64> {
+    let quota = match quota.trim().parse::<i64>() {
+        Ok(quota) if quota > 0 => quota,
+        _ => return None,
+    };
+
+    match period.trim().parse::<i64>() {
+        Ok(period) if period > 0 => Some(quota as f64 / period as f64),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cap_threads, parse_cfs, parse_cpu_max};
+
+    #[test]
+    fn test_parse_cgroup_quota() {
+        assert_eq!(parse_cpu_max("max 100000\n"), None);
+        assert_eq!(parse_cpu_max("150000 100000\n"), Some(1.5));
+        assert_eq!(parse_cpu_max(""), None);
+
+        assert_eq!(parse_cfs("-1\n", "100000\n"), None);
+        assert_eq!(parse_cfs("200000\n", "100000\n"), Some(2.0));
+    }
+
+    #[test]
+    fn test_cap_threads() {
+        assert_eq!(cap_threads(8, None), 8);
+        assert_eq!(cap_threads(8, Some(1.5)), 2);
+        assert_eq!(cap_threads(2, Some(4.0)), 2);
+        assert_eq!(cap_threads(4, Some(0.1)), 1);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_set_thread_affinity() {
+        use std::io;
+        use super::{set_thread_affinity, MAX_CPUS};
+
+        let err = set_thread_affinity(&[MAX_CPUS]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,255 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Bridging C libraries which wait on their own file descriptors, e.g. librdkafka or the
+//! multi interface of libcurl, onto the event loop of a Scheduler without extra threads
+//!
+//! Such libraries expose the descriptors they wait on and expect to be called back once those
+//! are ready. `watch_fd` registers a descriptor and runs a callback in a coroutine every time
+//! it becomes ready, `Bridge::post` queues a closure to run in a coroutine from any thread,
+//! e.g. from the library's own threads. C code reaches both through the `coio_*` functions,
+//! given a `Bridge` handed over as an opaque pointer.
+//!
+//! Descriptors are watched edge-triggered, so a callback must consume everything available,
+//! e.g. until the library reports `EAGAIN`, and must tolerate spurious calls.
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::Arc;
+
+use libc::{c_int, c_void};
+use mio::EventSet;
+
+use remote::Remote;
+use scheduler::{Scheduler, ShuttingDown, Subscription};
+
+/// `events` flag of `coio_watch_fd`, the descriptor is watched for reading
+pub const COIO_READABLE: c_int = 1;
+/// `events` flag of `coio_watch_fd`, the descriptor is watched for writing
+pub const COIO_WRITABLE: c_int = 2;
+
+/// Called with a descriptor which may be ready for the `events` it is watched for
+pub type FdCallback = extern "C" fn(fd: c_int, events: c_int, user_data: *mut c_void);
+
+/// Called with the `user_data` given to `coio_post`
+pub type PostCallback = extern "C" fn(user_data: *mut c_void);
+
+/// A descriptor watched by `watch_fd`, watching stops once it is cancelled or dropped
+pub struct FdWatch {
+    subscription: Arc<Subscription>,
+}
+
+impl FdWatch {
+    /// Stop watching, the callback is not called anymore unless it is running right now
+    pub fn cancel(&self) {
+        self.subscription.cancel();
+    }
+}
+
+impl Drop for FdWatch {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Watch `fd` for `interest` on the event loop of the current Scheduler and call `callback`
+/// with it in a coroutine each time the descriptor becomes ready.
+///
+/// Must be called inside a coroutine, see `Bridge::watch_fd` otherwise.
+pub fn watch_fd<F>(fd: RawFd, interest: EventSet, mut callback: F) -> io::Result<FdWatch>
+    where F: FnMut(EventSet) + Send + 'static
+{
+    let subscription = try!(Scheduler::instance().unwrap().subscribe_fd(fd, interest));
+
+    let sub = subscription.clone();
+    Scheduler::spawn(move || {
+        // Zero once the subscription has been cancelled
+        while sub.wait() > 0 {
+            callback(interest);
+        }
+    });
+
+    Ok(FdWatch { subscription: subscription })
+}
+
+/// Handle to a Scheduler for C libraries, usable from any thread
+#[derive(Clone)]
+pub struct Bridge {
+    remote: Remote,
+}
+
+impl Bridge {
+    pub fn new(remote: Remote) -> Bridge {
+        Bridge { remote: remote }
+    }
+
+    /// Run `f` in a new coroutine of the Scheduler
+    pub fn post<F>(&self, f: F) -> Result<(), ShuttingDown>
+        where F: FnOnce() + Send + 'static
+    {
+        self.remote.spawn(f).map(|_| ())
+    }
+
+    /// Like `watch_fd`, but callable outside of the Scheduler as well. Blocks until the
+    /// descriptor has been registered.
+    pub fn watch_fd<F>(&self, fd: RawFd, interest: EventSet, callback: F) -> io::Result<FdWatch>
+        where F: FnMut(EventSet) + Send + 'static
+    {
+        let hdl = try!(self.remote.spawn(move || watch_fd(fd, interest, callback)));
+        match hdl.join() {
+            Ok(r) => r,
+            Err(..) => Err(io::Error::new(io::ErrorKind::Other, "Failed to watch the descriptor")),
+        }
+    }
+}
+
+// User data of the C callbacks, which are responsible for its thread safety
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+/// Watch `fd` for `events`, a combination of `COIO_READABLE` and `COIO_WRITABLE`, and call
+/// `callback(fd, events, user_data)` in a coroutine each time it becomes ready.
+///
+/// Returns the watch to be passed to `coio_unwatch`, or NULL if the descriptor could not be
+/// registered.
+#[no_mangle]
+pub unsafe extern "C" fn coio_watch_fd(bridge: *const Bridge,
+                                       fd: c_int,
+                                       events: c_int,
+                                       callback: FdCallback,
+                                       user_data: *mut c_void)
+                                       -> *mut FdWatch {
+    let mut interest = EventSet::none();
+    if events & COIO_READABLE != 0 {
+        interest = interest | EventSet::readable();
+    }
+    if events & COIO_WRITABLE != 0 {
+        interest = interest | EventSet::writable();
+    }
+
+    let user_data = UserData(user_data);
+    match (*bridge).watch_fd(fd, interest, move |_| callback(fd, events, user_data.0)) {
+        Ok(watch) => Box::into_raw(Box::new(watch)),
+        Err(err) => {
+            error!("Failed to watch fd {}: {}", fd, err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Stop watching the descriptor and free the watch returned by `coio_watch_fd`
+#[no_mangle]
+pub unsafe extern "C" fn coio_unwatch(watch: *mut FdWatch) {
+    if !watch.is_null() {
+        drop(Box::from_raw(watch));
+    }
+}
+
+/// Call `callback(user_data)` in a new coroutine, returns -1 if the Scheduler is not running
+#[no_mangle]
+pub unsafe extern "C" fn coio_post(bridge: *const Bridge,
+                                   callback: PostCallback,
+                                   user_data: *mut c_void)
+                                   -> c_int {
+    let user_data = UserData(user_data);
+    match (*bridge).post(move || callback(user_data.0)) {
+        Ok(()) => 0,
+        Err(..) => -1,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use libc::{self, c_int, c_void};
+
+    use super::*;
+    use scheduler::Scheduler;
+
+    extern "C" fn on_readable(fd: c_int, _events: c_int, user_data: *mut c_void) {
+        let mut buf = [0u8; 64];
+        unsafe {
+            libc::read(fd, buf.as_mut_ptr() as *mut c_void, buf.len() as libc::size_t);
+        }
+
+        let calls = unsafe { &*(user_data as *const AtomicUsize) };
+        calls.fetch_add(1, Ordering::SeqCst);
+    }
+
+    extern "C" fn on_post(user_data: *mut c_void) {
+        let posted = unsafe { &*(user_data as *const AtomicBool) };
+        posted.store(true, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_bridge_from_thread() {
+        Scheduler::new()
+            .run(|| {
+                let bridge = Bridge::new(Scheduler::instance().unwrap().remote());
+                let calls = Arc::new(AtomicUsize::new(0));
+                let posted = Arc::new(AtomicBool::new(false));
+                let done = Arc::new(AtomicBool::new(false));
+
+                let (c, p, d) = (calls.clone(), posted.clone(), done.clone());
+                thread::spawn(move || unsafe {
+                    let mut fds = [0 as c_int; 2];
+                    assert_eq!(libc::pipe(fds.as_mut_ptr()), 0);
+
+                    let user_data = &*c as *const AtomicUsize as *mut c_void;
+                    let watch = coio_watch_fd(&bridge,
+                                              fds[0],
+                                              COIO_READABLE,
+                                              on_readable,
+                                              user_data);
+                    assert!(!watch.is_null());
+
+                    libc::write(fds[1], b"ping".as_ptr() as *const c_void, 4);
+                    while c.load(Ordering::SeqCst) == 0 {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    coio_unwatch(watch);
+
+                    let user_data = &*p as *const AtomicBool as *mut c_void;
+                    assert_eq!(coio_post(&bridge, on_post, user_data), 0);
+                    while !p.load(Ordering::SeqCst) {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+
+                    libc::close(fds[0]);
+                    libc::close(fds[1]);
+                    d.store(true, Ordering::SeqCst);
+                });
+
+                while !done.load(Ordering::SeqCst) {
+                    ::sleep(Duration::from_millis(10));
+                }
+                assert!(calls.load(Ordering::SeqCst) > 0);
+                assert!(posted.load(Ordering::SeqCst));
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! File system operations which block only the calling coroutine
+//!
+//! Reading a file may wait for the disk, which would stall every coroutine queued on the same
+//! Processor. These operations run on the blocking pool instead, see
+//! `Scheduler::run_blocking`. Each call costs a round trip to another thread, so small reads
+//! and writes are better buffered, e.g. with `std::io::BufReader`.
+
+use std::fs::{self, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use scheduler::Scheduler;
+
+// Run a blocking file operation on the blocking pool
+fn blocking<'a, F, T>(f: F) -> io::Result<T>
+    where F: FnOnce() -> io::Result<T> + Send + 'a,
+          T: Send + 'a
+{
+    match Scheduler::run_blocking(f) {
+        Ok(r) => r,
+        Err(..) => Err(io::Error::new(io::ErrorKind::Other, "file operation panicked")),
+    }
+}
+
+/// An open file, see `std::fs::File`
+#[derive(Debug)]
+pub struct File {
+    inner: fs::File,
+}
+
+impl File {
+    /// Open a file in read-only mode
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<File> {
+        let path = path.as_ref();
+        blocking(|| fs::File::open(path)).map(File::from_std)
+    }
+
+    /// Open a file in write-only mode, creating or truncating it
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<File> {
+        let path = path.as_ref();
+        blocking(|| fs::File::create(path)).map(File::from_std)
+    }
+
+    /// Open a file with the options of `opts`
+    pub fn open_with<P: AsRef<Path>>(path: P, opts: &OpenOptions) -> io::Result<File> {
+        let path = path.as_ref();
+        blocking(|| opts.open(path)).map(File::from_std)
+    }
+
+    /// Wrap a file opened with the standard library
+    pub fn from_std(file: fs::File) -> File {
+        File { inner: file }
+    }
+
+    /// Unwrap the file of the standard library
+    pub fn into_std(self) -> fs::File {
+        self.inner
+    }
+
+    /// Query the metadata of the file
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        let inner = &self.inner;
+        blocking(|| inner.metadata())
+    }
+
+    /// Truncate or extend the file to `size` bytes
+    pub fn set_len(&self, size: u64) -> io::Result<()> {
+        let inner = &self.inner;
+        blocking(|| inner.set_len(size))
+    }
+
+    /// Flush the data and the metadata of the file to the disk
+    pub fn sync_all(&self) -> io::Result<()> {
+        let inner = &self.inner;
+        blocking(|| inner.sync_all())
+    }
+
+    /// Flush the data of the file to the disk
+    pub fn sync_data(&self) -> io::Result<()> {
+        let inner = &self.inner;
+        blocking(|| inner.sync_data())
+    }
+}
+
+impl Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        blocking(move || inner.read(buf))
+    }
+
+    // Reads the whole file in one round trip
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        blocking(move || inner.read_to_end(buf))
+    }
+}
+
+impl Write for File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        blocking(move || inner.write(buf))
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let inner = &mut self.inner;
+        blocking(move || inner.write_all(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let inner = &mut self.inner;
+        blocking(move || inner.flush())
+    }
+}
+
+impl Seek for File {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let inner = &mut self.inner;
+        blocking(move || inner.seek(pos))
+    }
+}
+
+/// Read the whole file at `path`
+pub fn read_to_end<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let path = path.as_ref();
+    blocking(|| {
+        let mut buf = Vec::new();
+        try!(try!(fs::File::open(path)).read_to_end(&mut buf));
+        Ok(buf)
+    })
+}
+
+/// Read the whole file at `path`, which must be valid UTF-8
+pub fn read_to_string<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let path = path.as_ref();
+    blocking(|| {
+        let mut buf = String::new();
+        try!(try!(fs::File::open(path)).read_to_string(&mut buf));
+        Ok(buf)
+    })
+}
+
+/// Write `contents` to the file at `path`, creating or truncating it
+pub fn write<P: AsRef<Path>>(path: P, contents: &[u8]) -> io::Result<()> {
+    let path = path.as_ref();
+    blocking(|| try!(fs::File::create(path)).write_all(contents))
+}
+
+/// Query the metadata of the file at `path`, following symbolic links
+pub fn metadata<P: AsRef<Path>>(path: P) -> io::Result<Metadata> {
+    let path = path.as_ref();
+    blocking(|| fs::metadata(path))
+}
+
+/// Remove the file at `path`
+pub fn remove_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    blocking(|| fs::remove_file(path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::fs::OpenOptions;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use rand;
+
+    use scheduler::Scheduler;
+
+    #[test]
+    fn test_file() {
+        let path = env::temp_dir().join(format!("coio-test-fs-{}", rand::random::<u32>()));
+
+        Scheduler::new()
+            .run(move || {
+                write(&path, b"hello").unwrap();
+                assert_eq!(read_to_string(&path).unwrap(), "hello");
+
+                {
+                    let mut file = File::open_with(&path, OpenOptions::new().append(true))
+                                       .unwrap();
+                    file.write_all(b" world").unwrap();
+                    file.flush().unwrap();
+                }
+                assert_eq!(metadata(&path).unwrap().len(), 11);
+
+                let mut file = File::open(&path).unwrap();
+                file.seek(SeekFrom::Start(6)).unwrap();
+                let mut buf = [0; 5];
+                assert_eq!(file.read(&mut buf).unwrap(), 5);
+                assert_eq!(&buf, b"world");
+
+                remove_file(&path).unwrap();
+                assert!(read_to_end(&path).is_err());
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Passing file descriptors over Unix domain sockets
+//!
+//! Descriptors are sent as `SCM_RIGHTS` ancillary data of `sendmsg(2)`, the receiving process
+//! gets duplicates of them, e.g. to hand listening sockets over to a new version of a server.
+
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+use libc;
+
+use super::vectored::retry;
+use sys::{self, cmsg_align, cmsg_space};
+
+// Same value on Linux and the BSDs
+const SCM_RIGHTS: libc::c_int = 1;
+
+#[cfg(target_os = "linux")]
+const RECV_FLAGS: libc::c_int = 0x40000000; // MSG_CMSG_CLOEXEC
+#[cfg(not(target_os = "linux"))]
+const RECV_FLAGS: libc::c_int = 0;
+
+/// Most descriptors received by one call, the limit of Linux
+pub const MAX_FDS: usize = 253;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+type CmsgLen = libc::size_t;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+type CmsgLen = libc::socklen_t;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+type IovLen = libc::size_t;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+type IovLen = libc::c_int;
+
+fn cmsg_header() -> usize {
+    cmsg_align(mem::size_of::<sys::cmsghdr>())
+}
+
+// Buffer for the ancillary data, aligned for `cmsghdr`
+fn control_buf(len: usize) -> Vec<usize> {
+    vec![0; (len + mem::size_of::<usize>() - 1) / mem::size_of::<usize>()]
+}
+
+/// Send `bufs` in order along with the descriptors `fds` with a single `sendmsg(2)`,
+/// `Ok(None)` if it would block. The descriptors stay open in this process.
+pub fn try_send_msg(fd: RawFd, bufs: &[&[u8]], fds: &[RawFd]) -> io::Result<Option<usize>> {
+    assert!(fds.len() <= MAX_FDS, "Too many file descriptors for one message");
+
+    let mut iov: Vec<sys::iovec> = bufs.iter()
+                                       .map(|buf| {
+                                           sys::iovec {
+                                               iov_base: buf.as_ptr() as *mut libc::c_void,
+                                               iov_len: buf.len(),
+                                           }
+                                       })
+                                       .collect();
+
+    let fds_len = fds.len() * mem::size_of::<RawFd>();
+    let mut control = control_buf(cmsg_space(fds_len));
+
+    let mut msg: sys::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = iov.as_mut_ptr();
+    msg.msg_iovlen = iov.len() as IovLen;
+
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = cmsg_space(fds_len) as CmsgLen;
+
+        unsafe {
+            let cmsg = control.as_mut_ptr() as *mut sys::cmsghdr;
+            (*cmsg).cmsg_len = sys::cmsg_len(fds_len) as CmsgLen;
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = SCM_RIGHTS;
+
+            let data = (cmsg as *mut u8).offset(cmsg_header() as isize) as *mut RawFd;
+            ptr::copy_nonoverlapping(fds.as_ptr(), data, fds.len());
+        }
+    }
+
+    retry(|| unsafe { sys::sendmsg(fd, &msg, 0) })
+}
+
+/// Receive into `bufs` in order with a single `recvmsg(2)`, `Ok(None)` if it would block.
+///
+/// The descriptors sent along are appended to `fds`, they are close-on-exec and owned by the
+/// caller.
+pub fn try_recv_msg(fd: RawFd,
+                    bufs: &mut [&mut [u8]],
+                    fds: &mut Vec<RawFd>)
+                    -> io::Result<Option<usize>> {
+    let mut iov: Vec<sys::iovec> = bufs.iter_mut()
+                                       .map(|buf| {
+                                           sys::iovec {
+                                               iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                                               iov_len: buf.len(),
+                                           }
+                                       })
+                                       .collect();
+
+    let space = cmsg_space(MAX_FDS * mem::size_of::<RawFd>());
+    let mut control = control_buf(space);
+
+    let mut msg: sys::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = iov.as_mut_ptr();
+    msg.msg_iovlen = iov.len() as IovLen;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as CmsgLen;
+
+    let len = match try!(retry(|| unsafe { sys::recvmsg(fd, &mut msg, RECV_FLAGS) })) {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+
+    let base = control.as_ptr() as *const u8;
+    let control_len = msg.msg_controllen as usize;
+    let mut offset = 0;
+    while offset + mem::size_of::<sys::cmsghdr>() <= control_len {
+        let cmsg = unsafe { &*(base.offset(offset as isize) as *const sys::cmsghdr) };
+        let cmsg_len = cmsg.cmsg_len as usize;
+        if cmsg_len < cmsg_header() || offset + cmsg_len > control_len {
+            break;
+        }
+
+        if cmsg.cmsg_level == libc::SOL_SOCKET && cmsg.cmsg_type == SCM_RIGHTS {
+            let data = unsafe { base.offset((offset + cmsg_header()) as isize) as *const RawFd };
+            for idx in 0..(cmsg_len - cmsg_header()) / mem::size_of::<RawFd>() {
+                let received = unsafe { *data.offset(idx as isize) };
+                set_cloexec(received);
+                fds.push(received);
+            }
+        }
+
+        offset += cmsg_align(cmsg_len);
+    }
+
+    if msg.msg_flags & sys::MSG_CTRUNC != 0 {
+        warn!("Ancillary data received on fd {} has been truncated", fd);
+    }
+    Ok(Some(len))
+}
+
+#[cfg(target_os = "linux")]
+fn set_cloexec(_: RawFd) {
+    // Received with MSG_CMSG_CLOEXEC already
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_cloexec(fd: RawFd) {
+    unsafe {
+        libc::fcntl(fd, libc::F_SETFD, sys::FD_CLOEXEC);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    use libc;
+
+    use io::fd::set_nonblocking;
+    use net::unix::{self, UnixStream};
+    use scheduler::Scheduler;
+    use sys;
+
+    fn socket_pair() -> (UnixStream, UnixStream) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { sys::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, &mut fds[0]) },
+                   0);
+        set_nonblocking(fds[0]).unwrap();
+        set_nonblocking(fds[1]).unwrap();
+        unsafe { (UnixStream::from_raw_fd(fds[0]), UnixStream::from_raw_fd(fds[1])) }
+    }
+
+    #[test]
+    fn test_pass_fd() {
+        Scheduler::new()
+            .run(|| {
+                let (mut sender, mut receiver) = socket_pair();
+                let (mut rd, wr) = unix::pipe().unwrap();
+
+                let hdl = Scheduler::spawn(move || {
+                    let fd = receiver.recv_fd().unwrap();
+                    let mut wr = unsafe { unix::PipeWriter::from_raw_fd(fd) };
+                    wr.write_all(b"passed").unwrap();
+
+                    // The data sent along with the descriptors is received as well
+                    let mut head = [0u8; 4];
+                    let mut fds = Vec::new();
+                    let len = receiver.recv_msg(&mut [&mut head[..]], &mut fds).unwrap();
+                    assert_eq!(&head[..len], b"head");
+                    assert!(fds.is_empty());
+                });
+
+                sender.send_fd(wr.as_raw_fd()).unwrap();
+                // Closing our copy leaves the one of the receiver open
+                drop(wr);
+                sender.send_msg(&[&b"head"[..]], &[]).unwrap();
+                hdl.join().unwrap();
+
+                let mut buf = Vec::new();
+                rd.read_to_end(&mut buf).unwrap();
+                assert_eq!(buf, b"passed");
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Reading untrusted input to the end without unbounded memory use
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+
+use scheduler::Scheduler;
+
+/// Size of the first chunk read by `read_to_end_capped`, later ones double in size
+const INITIAL_CHUNK: usize = 8 * 1024;
+
+/// Error returned by `read_to_end_capped` when the input is longer than the limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LengthExceeded {
+    pub limit: usize,
+}
+
+impl fmt::Display for LengthExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "input exceeds the limit of {} bytes", self.limit)
+    }
+}
+
+impl Error for LengthExceeded {
+    fn description(&self) -> &str {
+        "input exceeds the length limit"
+    }
+}
+
+/// Read all bytes until EOF and append them to `buf`, but fail with a `LengthExceeded`
+/// error (of kind `InvalidData`) as soon as more than `max_len` bytes have been read.
+///
+/// The buffer grows geometrically, never beyond the limit, and the current coroutine is
+/// rescheduled between chunks, so a fast peer could not monopolize the Processor.
+/// Returns the number of bytes appended. On errors `buf` keeps the bytes read so far.
+pub fn read_to_end_capped<R: ?Sized>(reader: &mut R,
+                                     buf: &mut Vec<u8>,
+                                     max_len: usize)
+                                     -> io::Result<usize>
+    where R: Read
+{
+    let start = buf.len();
+    let mut filled = start;
+    let mut chunk = INITIAL_CHUNK;
+
+    let ret;
+    loop {
+        let read = filled - start;
+
+        if filled == buf.len() {
+            // One byte beyond the limit is enough to detect exceeding it
+            let grow = ::std::cmp::min(chunk, (max_len - read).saturating_add(1));
+            buf.resize(filled + grow, 0);
+            chunk = chunk.saturating_mul(2);
+
+            if read > 0 && Scheduler::instance().is_some() {
+                Scheduler::sched();
+            }
+        }
+
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => {
+                ret = Ok(read);
+                break;
+            }
+            Ok(len) => {
+                filled += len;
+                if filled - start > max_len {
+                    ret = Err(io::Error::new(io::ErrorKind::InvalidData,
+                                             LengthExceeded { limit: max_len }));
+                    break;
+                }
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => {
+                ret = Err(err);
+                break;
+            }
+        }
+    }
+
+    buf.truncate(filled);
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_read_to_end_capped() {
+        let data: Vec<u8> = (0..100 * 1024).map(|x| x as u8).collect();
+
+        let mut buf = b"head".to_vec();
+        let len = read_to_end_capped(&mut Cursor::new(&data[..]), &mut buf, data.len()).unwrap();
+        assert_eq!(len, data.len());
+        assert_eq!(&buf[4..], &data[..]);
+
+        let mut buf = Vec::new();
+        let err = read_to_end_capped(&mut Cursor::new(&data[..]), &mut buf, data.len() - 1)
+                      .err()
+                      .unwrap();
+        assert_eq!(err.kind(), ::std::io::ErrorKind::InvalidData);
+        assert!(err.get_ref().unwrap().is::<LengthExceeded>());
+        assert_eq!(buf.len(), data.len());
+        assert!(buf.capacity() <= 2 * data.len());
+    }
+}
@@ -0,0 +1,223 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Copying between readers and writers without monopolizing a Processor
+
+use std::cmp;
+use std::default::Default;
+use std::io::{self, Read, Write};
+
+use scheduler::Scheduler;
+
+/// Options of `copy_with`
+#[derive(Debug, Clone, Copy)]
+pub struct CopyOptions {
+    /// Smallest chunk size, the copy starts with it
+    pub min_chunk: usize,
+    /// Largest chunk size the copy may grow to
+    pub max_chunk: usize,
+    /// Give up the CPU after this amount of bytes has been copied without blocking
+    pub yield_every: usize,
+}
+
+impl CopyOptions {
+    pub fn new() -> CopyOptions {
+        CopyOptions {
+            min_chunk: 8 * 1024,
+            max_chunk: 256 * 1024,
+            yield_every: 1024 * 1024,
+        }
+    }
+
+    pub fn min_chunk(mut self, size: usize) -> CopyOptions {
+        self.min_chunk = size;
+        self
+    }
+
+    pub fn max_chunk(mut self, size: usize) -> CopyOptions {
+        self.max_chunk = size;
+        self
+    }
+
+    pub fn yield_every(mut self, bytes: usize) -> CopyOptions {
+        self.yield_every = bytes;
+        self
+    }
+}
+
+impl Default for CopyOptions {
+    fn default() -> CopyOptions {
+        CopyOptions::new()
+    }
+}
+
+/// Copy the entire content of a reader into a writer with the default options.
+///
+/// Equivalent to `std::io::copy`, but gives up the CPU regularly when called in a coroutine.
+pub fn copy<R: ?Sized, W: ?Sized>(reader: &mut R, writer: &mut W) -> io::Result<u64>
+    where R: Read,
+          W: Write
+{
+    copy_with(reader, writer, &CopyOptions::default())
+}
+
+/// Copy the entire content of a reader into a writer.
+///
+/// The chunk size doubles whenever a read fills the whole chunk, and halves whenever a
+/// read returns less than half of it, staying within `min_chunk` and `max_chunk`. Every
+/// `yield_every` bytes the current coroutine is rescheduled, so a large transfer whose
+/// reads and writes never block could not starve the other coroutines.
+pub fn copy_with<R: ?Sized, W: ?Sized>(reader: &mut R,
+                                       writer: &mut W,
+                                       opts: &CopyOptions)
+                                       -> io::Result<u64>
+    where R: Read,
+          W: Write
+{
+    let min_chunk = cmp::max(opts.min_chunk, 1);
+    let max_chunk = cmp::max(opts.max_chunk, min_chunk);
+
+    let mut buf = vec![0u8; min_chunk];
+    let mut chunk = min_chunk;
+    let mut written = 0u64;
+    let mut since_yield = 0usize;
+
+    loop {
+        let len = match reader.read(&mut buf[..chunk]) {
+            Ok(0) => return Ok(written),
+            Ok(len) => len,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+
+        try!(writer.write_all(&buf[..len]));
+        written += len as u64;
+
+        if len == chunk && chunk < max_chunk {
+            chunk = cmp::min(chunk * 2, max_chunk);
+            if buf.len() < chunk {
+                buf.resize(chunk, 0);
+            }
+        } else if len < chunk / 2 {
+            chunk = cmp::max(chunk / 2, min_chunk);
+        }
+
+        since_yield += len;
+        if opts.yield_every > 0 && since_yield >= opts.yield_every {
+            since_yield = 0;
+            if Scheduler::instance().is_some() {
+                Scheduler::sched();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::{Cursor, Read};
+
+    // Reader returning at most `limit` bytes per read
+    struct Trickle<R>(R, usize);
+
+    impl<R: Read> Read for Trickle<R> {
+        fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+            let len = ::std::cmp::min(buf.len(), self.1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_copy() {
+        let data: Vec<u8> = (0..1024 * 1024).map(|x| x as u8).collect();
+
+        let mut out = Vec::new();
+        let opts = CopyOptions::new().min_chunk(16).max_chunk(4096).yield_every(1024);
+        assert_eq!(copy_with(&mut Cursor::new(&data[..]), &mut out, &opts).unwrap(),
+                   data.len() as u64);
+        assert_eq!(out, data);
+
+        let mut out = Vec::new();
+        let mut reader = Trickle(Cursor::new(&data[..]), 100);
+        assert_eq!(copy_with(&mut reader, &mut out, &opts).unwrap(), data.len() as u64);
+        assert_eq!(out, data);
+    }
+
+    // Writer recording the ticker count seen by every write
+    struct Probe<'a> {
+        ticks: &'a ::std::sync::atomic::AtomicUsize,
+        seen: Vec<usize>,
+    }
+
+    impl<'a> Write for Probe<'a> {
+        fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+            self.seen.push(self.ticks.load(::std::sync::atomic::Ordering::SeqCst));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_copy_yields() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+        use scheduler::Scheduler;
+        use options::{Options, SpawnHint};
+
+        // A single worker: the ticker can only progress when the copy yields
+        Scheduler::new()
+            .with_workers(1)
+            .run(|| {
+                let ticks = Arc::new(AtomicUsize::new(0));
+                let done = Arc::new(AtomicBool::new(false));
+                let (ticks1, done1) = (ticks.clone(), done.clone());
+
+                let opts = Options::new().spawn_hint(SpawnHint::Deferred);
+                let ticker = Scheduler::spawn_opts(move || {
+                                                       while !done1.load(Ordering::SeqCst) {
+                                                           ticks1.fetch_add(1, Ordering::SeqCst);
+                                                           Scheduler::sched();
+                                                       }
+                                                   },
+                                                   opts);
+
+                let data = vec![0u8; 1024 * 1024];
+                let mut probe = Probe {
+                    ticks: &ticks,
+                    seen: Vec::new(),
+                };
+                let opts = CopyOptions::new().yield_every(64 * 1024);
+                copy_with(&mut Cursor::new(&data[..]), &mut probe, &opts).unwrap();
+                done.store(true, Ordering::SeqCst);
+
+                // The ticker did not run before the copy started, but kept running while it
+                // was in progress
+                assert_eq!(probe.seen[0], 0);
+                assert!(*probe.seen.last().unwrap() >= 8);
+                ticker.join().unwrap();
+            })
+            .unwrap();
+    }
+}
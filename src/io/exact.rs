@@ -0,0 +1,173 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Reading and writing whole buffers under a deadline
+//!
+//! `Read::read_exact` and `Write::write_all` lose track of the bytes already transferred when
+//! they fail half way. The functions here report them in an `Incomplete` error, so that a
+//! timed out transfer could be resumed with the rest of the buffer.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Streams whose reads and writes could be bounded by a deadline
+pub trait DeadlineIo {
+    /// Read into `buf`, failing with `TimedOut` if nothing arrived before `deadline`
+    fn read_until(&mut self, buf: &mut [u8], deadline: Option<Instant>) -> io::Result<usize>;
+
+    /// Write from `buf`, failing with `TimedOut` if nothing could be sent before `deadline`
+    fn write_until(&mut self, buf: &[u8], deadline: Option<Instant>) -> io::Result<usize>;
+
+    /// Read exactly `buf.len()` bytes within `timeout`, see `read_exact_until`
+    fn read_exact_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<()> {
+        read_exact_until(self, buf, Some(Instant::now() + timeout))
+    }
+
+    /// Write all of `buf` within `timeout`, see `write_all_until`
+    fn write_all_timeout(&mut self, buf: &[u8], timeout: Duration) -> io::Result<()> {
+        write_all_until(self, buf, Some(Instant::now() + timeout))
+    }
+}
+
+/// Payload of the errors of `read_exact_until` and `write_all_until`.
+///
+/// The error keeps the kind of its `cause`.
+#[derive(Debug)]
+pub struct Incomplete {
+    /// Number of bytes read or written before the failure
+    pub transferred: usize,
+    pub cause: io::Error,
+}
+
+impl fmt::Display for Incomplete {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} after transferring {} bytes", self.cause, self.transferred)
+    }
+}
+
+impl Error for Incomplete {
+    fn description(&self) -> &str {
+        "incomplete transfer"
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        Some(&self.cause)
+    }
+}
+
+fn incomplete(transferred: usize, cause: io::Error) -> io::Error {
+    io::Error::new(cause.kind(),
+                   Incomplete {
+                       transferred: transferred,
+                       cause: cause,
+                   })
+}
+
+/// Read exactly `buf.len()` bytes, failing with `TimedOut` once `deadline` has passed.
+///
+/// The deadline bounds the whole operation, not each of the reads. On errors the payload is
+/// an `Incomplete` telling how much of `buf` has been filled.
+pub fn read_exact_until<S: DeadlineIo + ?Sized>(stream: &mut S,
+                                                buf: &mut [u8],
+                                                deadline: Option<Instant>)
+                                                -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match stream.read_until(&mut buf[filled..], deadline) {
+            Ok(0) => {
+                let eof = io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill buffer");
+                return Err(incomplete(filled, eof));
+            }
+            Ok(len) => filled += len,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(incomplete(filled, err)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Write all of `buf`, failing with `TimedOut` once `deadline` has passed.
+///
+/// The deadline bounds the whole operation, not each of the writes. On errors the payload is
+/// an `Incomplete` telling how much of `buf` has been written.
+pub fn write_all_until<S: DeadlineIo + ?Sized>(stream: &mut S,
+                                               buf: &[u8],
+                                               deadline: Option<Instant>)
+                                               -> io::Result<()> {
+    let mut written = 0;
+    while written < buf.len() {
+        match stream.write_until(&buf[written..], deadline) {
+            Ok(0) => {
+                let zero = io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer");
+                return Err(incomplete(written, zero));
+            }
+            Ok(len) => written += len,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(incomplete(written, err)),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ErrorKind, Write};
+    use std::time::Duration;
+
+    use net::{TcpListener, TcpStream};
+    use scheduler::Scheduler;
+
+    use super::*;
+
+    #[test]
+    fn test_read_exact_resume() {
+        Scheduler::new()
+            .run(|| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+                let (mut stream, _) = listener.accept().unwrap();
+
+                client.write_all(b"pi").unwrap();
+
+                let mut buf = [0u8; 4];
+                let err = stream.read_exact_timeout(&mut buf, Duration::from_millis(50))
+                                .err()
+                                .unwrap();
+                assert_eq!(err.kind(), ErrorKind::TimedOut);
+                let transferred = err.get_ref()
+                                     .unwrap()
+                                     .downcast_ref::<Incomplete>()
+                                     .unwrap()
+                                     .transferred;
+                assert_eq!(transferred, 2);
+
+                client.write_all(b"ng").unwrap();
+                stream.read_exact_timeout(&mut buf[transferred..], Duration::from_millis(50))
+                      .unwrap();
+                assert_eq!(&buf, b"ping");
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,347 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Coroutine I/O on arbitrary file descriptors
+//!
+//! `CoIo` adapts pollable file descriptors, such as FIFOs, pipes and character devices,
+//! to the scheduler. Regular files, directories and block devices are always reported
+//! as ready by the kernel, waiting on them would spin, so they are rejected.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use libc;
+use mio::EventSet;
+
+use scheduler::{IoOwner, Scheduler};
+use super::vectored;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFIFO: u32 = 0o010000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFREG: u32 = 0o100000;
+const S_IFSOCK: u32 = 0o140000;
+
+/// Type of the file behind a file descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdKind {
+    /// FIFO special file or pipe
+    Fifo,
+    /// Character device, like a terminal
+    CharDevice,
+    /// Socket
+    Socket,
+    /// Regular file
+    Regular,
+    /// Directory
+    Directory,
+    /// Block device
+    BlockDevice,
+    /// Anything else
+    Other,
+}
+
+impl FdKind {
+    /// Get the type of the file behind `fd`
+    pub fn of(fd: RawFd) -> io::Result<FdKind> {
+        let mut st: libc::stat = unsafe { mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut st) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let kind = match st.st_mode as u32 & S_IFMT {
+            S_IFIFO => FdKind::Fifo,
+            S_IFCHR => FdKind::CharDevice,
+            S_IFSOCK => FdKind::Socket,
+            S_IFREG => FdKind::Regular,
+            S_IFDIR => FdKind::Directory,
+            S_IFBLK => FdKind::BlockDevice,
+            _ => FdKind::Other,
+        };
+
+        Ok(kind)
+    }
+
+    /// Whether the readiness of this kind of file could be polled
+    pub fn is_pollable(&self) -> bool {
+        match *self {
+            FdKind::Fifo | FdKind::CharDevice | FdKind::Socket => true,
+            _ => false,
+        }
+    }
+}
+
+/// Error returned when wrapping a file descriptor which could not be polled.
+///
+/// I/O on these files has to be offloaded to a thread instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedFd {
+    pub kind: FdKind,
+}
+
+impl fmt::Display for UnsupportedFd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "file descriptor of kind {:?} could not be polled", self.kind)
+    }
+}
+
+impl Error for UnsupportedFd {
+    fn description(&self) -> &str {
+        "file descriptor could not be polled"
+    }
+}
+
+fn unsupported(kind: FdKind) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, UnsupportedFd { kind: kind })
+}
+
+#[doc(hidden)]
+pub fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Coroutine-aware wrapper of a pollable file descriptor
+#[derive(Debug)]
+pub struct CoIo<T: AsRawFd> {
+    inner: T,
+    kind: FdKind,
+    owner: IoOwner,
+}
+
+impl<T: AsRawFd> CoIo<T> {
+    /// Wrap a FIFO, pipe, character device or socket and switch it to non-blocking mode.
+    ///
+    /// Returns an `UnsupportedFd` error for regular files, directories and block devices.
+    pub fn new(inner: T) -> io::Result<CoIo<T>> {
+        let kind = try!(FdKind::of(inner.as_raw_fd()));
+        if !kind.is_pollable() {
+            return Err(unsupported(kind));
+        }
+
+        CoIo::wrap(inner, kind)
+    }
+
+    /// Wrap a FIFO created by `mkfifo` or a pipe.
+    ///
+    /// Note that opening a FIFO for reading blocks the thread until a writer appears,
+    /// unless it is opened with `O_NONBLOCK` or `O_RDWR`.
+    pub fn fifo(inner: T) -> io::Result<CoIo<T>> {
+        CoIo::with_kind(inner, FdKind::Fifo)
+    }
+
+    /// Wrap a character device, like `/dev/tty`
+    pub fn char_device(inner: T) -> io::Result<CoIo<T>> {
+        CoIo::with_kind(inner, FdKind::CharDevice)
+    }
+
+    fn with_kind(inner: T, expected: FdKind) -> io::Result<CoIo<T>> {
+        let kind = try!(FdKind::of(inner.as_raw_fd()));
+        if kind != expected {
+            return Err(unsupported(kind));
+        }
+
+        CoIo::wrap(inner, kind)
+    }
+
+    fn wrap(inner: T, kind: FdKind) -> io::Result<CoIo<T>> {
+        try!(set_nonblocking(inner.as_raw_fd()));
+
+        Ok(CoIo {
+            inner: inner,
+            kind: kind,
+            owner: IoOwner::new(),
+        })
+    }
+
+    /// Type of the wrapped file
+    pub fn kind(&self) -> FdKind {
+        self.kind
+    }
+
+    /// Whether the wrapped file is a terminal
+    pub fn is_tty(&self) -> bool {
+        unsafe { libc::isatty(self.inner.as_raw_fd()) == 1 }
+    }
+
+    /// Unwrap the inner object. It is left in non-blocking mode.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Read into `bufs` in order with a single `readv(2)`
+    pub fn read_vectored(&mut self, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        loop {
+            match try!(vectored::try_read_vectored(self.inner.as_raw_fd(), bufs)) {
+                Some(len) => return Ok(len),
+                None => {
+                    debug!("CoIo readv WOULDBLOCK");
+                    try!(self.wait(EventSet::readable()));
+                }
+            }
+        }
+    }
+
+    /// Write `bufs` in order with a single `writev(2)`
+    pub fn write_vectored(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        loop {
+            match try!(vectored::try_write_vectored(self.inner.as_raw_fd(), bufs)) {
+                Some(len) => return Ok(len),
+                None => {
+                    debug!("CoIo writev WOULDBLOCK");
+                    try!(self.wait(EventSet::writable()));
+                }
+            }
+        }
+    }
+
+    fn wait(&self, interest: EventSet) -> io::Result<()> {
+        try!(self.owner.check());
+        let fd = self.inner.as_raw_fd();
+        Scheduler::instance().unwrap().wait_fd(fd, interest)
+    }
+}
+
+impl<T: AsRawFd + Read> Read for CoIo<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.inner.read(buf) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    debug!("CoIo read WOULDBLOCK");
+                    try!(self.wait(EventSet::readable()));
+                }
+                ret => return ret,
+            }
+        }
+    }
+}
+
+impl<T: AsRawFd + Write> Write for CoIo<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            match self.inner.write(buf) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    debug!("CoIo write WOULDBLOCK");
+                    try!(self.wait(EventSet::writable()));
+                }
+                ret => return ret,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: AsRawFd> AsRawFd for CoIo<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl<T: AsRawFd> Deref for CoIo<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: AsRawFd> DerefMut for CoIo<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    use libc;
+    use scheduler::Scheduler;
+
+    fn pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+    }
+
+    #[test]
+    fn test_fd_kind() {
+        let (rd, _) = pipe();
+        assert_eq!(FdKind::of(rd.as_raw_fd()).unwrap(), FdKind::Fifo);
+
+        let null = File::open("/dev/null").unwrap();
+        assert_eq!(FdKind::of(null.as_raw_fd()).unwrap(), FdKind::CharDevice);
+
+        let regular = File::open("Cargo.toml").unwrap();
+        assert_eq!(FdKind::of(regular.as_raw_fd()).unwrap(), FdKind::Regular);
+    }
+
+    #[test]
+    fn test_reject_regular_file() {
+        let err = CoIo::new(File::open("Cargo.toml").unwrap()).unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<UnsupportedFd>().unwrap();
+        assert_eq!(err.kind, FdKind::Regular);
+
+        assert!(CoIo::fifo(File::open("/dev/null").unwrap()).is_err());
+        assert!(CoIo::char_device(File::open("/dev/null").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_pipe_read_write() {
+        Scheduler::new()
+            .run(|| {
+                let (rd, wr) = pipe();
+                let mut rd = CoIo::fifo(rd).unwrap();
+                let mut wr = CoIo::fifo(wr).unwrap();
+
+                let writer = Scheduler::spawn(move || {
+                    Scheduler::sched();
+                    wr.write_all(b"hello").unwrap();
+                });
+
+                let mut buf = [0u8; 5];
+                rd.read_exact(&mut buf).unwrap();
+                assert_eq!(&buf, b"hello");
+
+                writer.join().unwrap();
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,48 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Coroutine I/O utilities
+
+#[cfg(unix)]
+pub use self::ancillary::{try_recv_msg, try_send_msg};
+pub use self::capped::{read_to_end_capped, LengthExceeded};
+pub use self::copy::{copy, copy_with, CopyOptions};
+pub use self::exact::{read_exact_until, write_all_until, DeadlineIo, Incomplete};
+#[cfg(unix)]
+pub use self::fd::{CoIo, FdKind, UnsupportedFd};
+#[cfg(unix)]
+pub use self::poll::poll_ready;
+pub use self::ring::RingBuf;
+#[cfg(unix)]
+pub use self::vectored::{try_read_vectored, try_write_vectored};
+
+#[cfg(unix)]
+pub mod ancillary;
+pub mod capped;
+pub mod copy;
+pub mod exact;
+#[cfg(unix)]
+pub mod fd;
+#[cfg(unix)]
+pub mod poll;
+pub mod ring;
+#[cfg(unix)]
+pub mod vectored;
@@ -0,0 +1,89 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Checking the readiness of I/O objects without parking
+//!
+//! A coroutine serving many streams could use `poll_ready` to batch the work of the ready
+//! ones before yielding, instead of blocking on the first one which has nothing to do.
+
+use std::os::unix::io::AsRawFd;
+
+use mio::EventSet;
+
+use sys;
+
+/// Whether `io` is ready for `interest` right now, checked with a non-blocking `poll(2)`.
+///
+/// Errors and hang-ups count as ready, so that the next operation reports them. Data held
+/// back in user space, like the read-ahead buffer of `TcpStream`, is not taken into account.
+pub fn poll_ready<T: AsRawFd>(io: &T, interest: EventSet) -> bool {
+    let mut events = 0;
+    if interest.is_readable() {
+        events |= sys::POLLIN;
+    }
+    if interest.is_writable() {
+        events |= sys::POLLOUT;
+    }
+
+    let mut pfd = sys::pollfd {
+        fd: io.as_raw_fd(),
+        events: events,
+        revents: 0,
+    };
+
+    match unsafe { sys::poll(&mut pfd, 1, 0) } {
+        0 => false,
+        n if n > 0 => pfd.revents & (events | sys::POLLERR | sys::POLLHUP) != 0,
+        // Let the operation itself fail
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use mio::EventSet;
+
+    use net::{TcpListener, TcpStream};
+    use scheduler::Scheduler;
+
+    use super::*;
+
+    #[test]
+    fn test_poll_ready() {
+        Scheduler::new()
+            .run(|| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+                let (stream, _) = listener.accept().unwrap();
+
+                assert!(!poll_ready(&stream, EventSet::readable()));
+                assert!(poll_ready(&stream, EventSet::writable()));
+
+                client.write_all(b"ping").unwrap();
+                while !poll_ready(&stream, EventSet::readable()) {
+                    Scheduler::sched();
+                }
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Fixed-size ring buffer which sockets could read into directly
+
+use std::cmp;
+use std::io::{self, Read};
+
+/// Ring buffer of bytes with a fixed capacity.
+///
+/// Both the buffered data and the free space may wrap around the end of the buffer,
+/// which is why they are exposed as pairs of slices.
+#[derive(Debug)]
+pub struct RingBuf {
+    buf: Box<[u8]>,
+    head: usize,
+    len: usize,
+}
+
+impl RingBuf {
+    pub fn with_capacity(cap: usize) -> RingBuf {
+        assert!(cap > 0, "Ring buffer must not be empty");
+
+        RingBuf {
+            buf: vec![0u8; cap].into_boxed_slice(),
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Number of buffered bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == self.buf.len()
+    }
+
+    /// Number of bytes which could be written into the buffer
+    pub fn free(&self) -> usize {
+        self.buf.len() - self.len
+    }
+
+    /// The buffered bytes, the second slice is non-empty if they wrap around
+    pub fn as_slices(&self) -> (&[u8], &[u8]) {
+        let cap = self.buf.len();
+        let end = self.head + self.len;
+
+        if end <= cap {
+            (&self.buf[self.head..end], &[])
+        } else {
+            (&self.buf[self.head..], &self.buf[..end - cap])
+        }
+    }
+
+    /// The free space, to be filled from the first slice on and committed with `commit()`
+    pub fn free_slices_mut(&mut self) -> (&mut [u8], &mut [u8]) {
+        let cap = self.buf.len();
+        let tail = (self.head + self.len) % cap;
+
+        if self.len == cap {
+            (&mut [], &mut [])
+        } else if tail >= self.head {
+            let (front, back) = self.buf.split_at_mut(tail);
+            (back, &mut front[..self.head])
+        } else {
+            (&mut self.buf[tail..self.head], &mut [])
+        }
+    }
+
+    /// Append `n` bytes which have been written into the free space
+    pub fn commit(&mut self, n: usize) {
+        assert!(n <= self.free(), "Committed more bytes than free");
+        self.len += n;
+    }
+
+    /// Discard the first `n` buffered bytes
+    pub fn consume(&mut self, n: usize) {
+        assert!(n <= self.len, "Consumed more bytes than buffered");
+        self.head = (self.head + n) % self.buf.len();
+        self.len -= n;
+
+        if self.len == 0 {
+            // Keep the data contiguous as long as possible
+            self.head = 0;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+}
+
+impl Read for RingBuf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = {
+            let (first, second) = self.as_slices();
+            let n1 = cmp::min(first.len(), buf.len());
+            buf[..n1].copy_from_slice(&first[..n1]);
+
+            let n2 = cmp::min(second.len(), buf.len() - n1);
+            buf[n1..n1 + n2].copy_from_slice(&second[..n2]);
+            n1 + n2
+        };
+
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use super::*;
+
+    fn fill(ring: &mut RingBuf, data: &[u8]) {
+        let written = {
+            let (first, second) = ring.free_slices_mut();
+            let n1 = ::std::cmp::min(first.len(), data.len());
+            first[..n1].copy_from_slice(&data[..n1]);
+            let n2 = data.len() - n1;
+            second[..n2].copy_from_slice(&data[n1..]);
+            data.len()
+        };
+        ring.commit(written);
+    }
+
+    #[test]
+    fn test_ring_wrap_around() {
+        let mut ring = RingBuf::with_capacity(8);
+        fill(&mut ring, b"abcdef");
+        ring.consume(4);
+        assert_eq!(ring.as_slices(), (&b"ef"[..], &b""[..]));
+
+        {
+            let (first, second) = ring.free_slices_mut();
+            assert_eq!((first.len(), second.len()), (2, 4));
+        }
+
+        fill(&mut ring, b"ghijk");
+        assert_eq!(ring.as_slices(), (&b"efgh"[..], &b"ijk"[..]));
+        assert_eq!(ring.free(), 1);
+
+        let mut out = [0u8; 16];
+        assert_eq!(ring.read(&mut out).unwrap(), 7);
+        assert_eq!(&out[..7], b"efghijk");
+        assert!(ring.is_empty());
+    }
+}
@@ -0,0 +1,117 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Scatter/gather I/O
+//!
+//! A frame made of a small header and a large body can be written with one `writev(2)`,
+//! instead of copying both into one buffer or paying for two system calls.
+
+use std::io;
+use std::os::unix::io::RawFd;
+
+use libc;
+
+use sys;
+
+// Both Linux and the BSDs accept at most this many buffers per call
+const IOV_MAX: usize = 1024;
+
+/// Read into `bufs` in order with a single `readv(2)`, `Ok(None)` if it would block
+pub fn try_read_vectored(fd: RawFd, bufs: &mut [&mut [u8]]) -> io::Result<Option<usize>> {
+    let iov: Vec<sys::iovec> = bufs.iter_mut()
+                                   .take(IOV_MAX)
+                                   .map(|buf| {
+                                       sys::iovec {
+                                           iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                                           iov_len: buf.len(),
+                                       }
+                                   })
+                                   .collect();
+
+    retry(|| unsafe { sys::readv(fd, iov.as_ptr(), iov.len() as libc::c_int) })
+}
+
+/// Write `bufs` in order with a single `writev(2)`, `Ok(None)` if it would block
+pub fn try_write_vectored(fd: RawFd, bufs: &[&[u8]]) -> io::Result<Option<usize>> {
+    let iov: Vec<sys::iovec> = bufs.iter()
+                                   .take(IOV_MAX)
+                                   .map(|buf| {
+                                       sys::iovec {
+                                           iov_base: buf.as_ptr() as *mut libc::c_void,
+                                           iov_len: buf.len(),
+                                       }
+                                   })
+                                   .collect();
+
+    retry(|| unsafe { sys::writev(fd, iov.as_ptr(), iov.len() as libc::c_int) })
+}
+
+#[doc(hidden)]
+pub fn retry<F: FnMut() -> libc::ssize_t>(mut f: F) -> io::Result<Option<usize>> {
+    loop {
+        let ret = f();
+        if ret >= 0 {
+            return Ok(Some(ret as usize));
+        }
+
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::WouldBlock => return Ok(None),
+            io::ErrorKind::Interrupted => {}
+            _ => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use net::unix;
+    use scheduler::Scheduler;
+
+    #[test]
+    fn test_pipe_vectored() {
+        Scheduler::new()
+            .run(|| {
+                let (mut rd, mut wr) = unix::pipe().unwrap();
+
+                let writer = Scheduler::spawn(move || {
+                    let len = wr.write_vectored(&[&b"head"[..], &b""[..], &b"body"[..]]).unwrap();
+                    assert_eq!(len, 8);
+                });
+
+                let mut head = [0u8; 4];
+                let mut body = [0u8; 2];
+                // Small writes to a pipe are atomic
+                let len = rd.read_vectored(&mut [&mut head[..], &mut body[..]]).unwrap();
+                assert_eq!(len, 6);
+                assert_eq!(&head, b"head");
+                assert_eq!(&body, b"bo");
+                writer.join().unwrap();
+
+                let mut rest = Vec::new();
+                rd.read_to_end(&mut rest).unwrap();
+                assert_eq!(rest, b"dy");
+            })
+            .unwrap();
+    }
+}
@@ -33,21 +33,58 @@ extern crate rand;
 extern crate libc;
 extern crate net2;
 
+/// The current source location, to be passed to `Options::spawn_site`
+#[macro_export]
+macro_rules! spawn_site {
+    () => (concat!(file!(), ":", line!()))
+}
+
 use std::thread;
 use std::panic;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-pub use scheduler::{Scheduler, JoinHandle};
-pub use options::Options;
+pub use scheduler::{Scheduler, JoinHandle, IdleStrategy, RegistrationLimitExceeded, ShuttingDown};
+pub use scheduler::{Cancelled, DeadlockDetection, LeakDetection, TimerHandle, WrongScheduler};
+pub use scheduler::{join_all, race, run_parallel, run_parallel_with, timeout, TimedOut};
+pub use options::{Options, Priority, SpawnHint, StackClass};
 pub use promise::Promise;
+pub use remote::Remote;
+pub use stats::{ChannelStats, CoroutineMemory, ProcessorStats, Stats, StackHighWaterMarks};
+pub use stats::{CoroutineSnapshot, CoroutineState, DeadlockReport, LeakedCoroutine, LeakReport,
+                StackUsage, WaitReason};
 
+#[macro_use]
+pub mod logging;
+#[macro_use]
+pub mod trace;
+pub mod blocking;
+pub mod cpu;
+#[cfg(unix)]
+pub mod ffi;
+pub mod fs;
+pub mod io;
+#[macro_use]
+pub mod local;
+pub mod metrics;
 pub mod net;
+pub mod observer;
 pub mod sync;
 pub mod scheduler;
 pub mod options;
+pub mod pool;
+pub mod prelude;
+#[cfg(unix)]
+pub mod process;
 pub mod promise;
+pub mod remote;
+pub mod stats;
+pub mod supervisor;
+pub mod time;
+pub mod timer;
 mod runtime;
 mod coroutine;
+#[cfg(unix)]
+mod sys;
 
 /// Spawn a new Coroutine
 #[inline(always)]
@@ -67,31 +104,73 @@ pub fn spawn_opts<F, T>(f: F, opts: Options) -> JoinHandle<T>
     Scheduler::spawn_opts(f, opts)
 }
 
+/// Spawn a new Coroutine which starts running after `delay`
+#[inline(always)]
+pub fn spawn_after<F, T>(delay: Duration, f: F) -> JoinHandle<T>
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static
+{
+    Scheduler::spawn_after(delay, f)
+}
+
+/// Run a blocking closure without stalling the Processor, see `Scheduler::run_blocking`
+#[inline]
+pub fn run_blocking<'a, F, T>(f: F) -> thread::Result<T>
+    where F: FnOnce() -> T + Send + 'a,
+          T: Send + 'a
+{
+    Scheduler::run_blocking(f)
+}
+
 /// Giveup the CPU
 #[inline(always)]
 pub fn sched() {
     Scheduler::sched()
 }
 
+/// Cancellation point for CPU-bound loops, see `Scheduler::check_cancelled`
+#[inline]
+pub fn check_cancelled() -> Result<(), Cancelled> {
+    Scheduler::check_cancelled()
+}
+
 /// Run the scheduler with threads
 // #[inline(always)]
 // pub fn run(threads: usize) {
 //     Scheduler::run(threads)
 // }
 
-/// Put the current coroutine to sleep for the specific amount of time
+/// Put the current coroutine to sleep for the specific amount of time.
+///
+/// Returns the remaining milliseconds if the sleep has been interrupted, 0 otherwise.
 #[inline]
-pub fn sleep_ms(ms: u64) {
-    if let Some(s) = Scheduler::instance() {
-        s.sleep_ms(ms).unwrap();
-    }
+pub fn sleep_ms(ms: u64) -> u64 {
+    let remaining = sleep(Duration::from_millis(ms));
+    remaining.as_secs() * 1_000 + remaining.subsec_nanos() as u64 / 1_000_000
 }
 
-/// Put the current coroutine to sleep for the specific amount of time
+/// Put the current coroutine to sleep for the specific amount of time.
+///
+/// Returns the remaining time if the sleep has been interrupted by `JoinHandle::interrupt`
+/// or the shutdown of the scheduler, zero otherwise.
 #[inline]
-pub fn sleep(duration: Duration) {
-    if let Some(s) = Scheduler::instance() {
-        s.sleep(duration).unwrap();
+pub fn sleep(duration: Duration) -> Duration {
+    match Scheduler::instance() {
+        Some(s) => remaining_or_shutdown(s.sleep(duration), duration),
+        None => duration,
+    }
+}
+
+// Sleeping returns early when the scheduler is shutting down
+fn remaining_or_shutdown(ret: std::io::Result<Duration>, duration: Duration) -> Duration {
+    match ret {
+        Ok(remaining) => remaining,
+        Err(err) => {
+            if err.get_ref().and_then(|e| e.downcast_ref::<ShuttingDown>()).is_none() {
+                panic!("{}", err);
+            }
+            duration
+        }
     }
 }
 
@@ -115,13 +194,41 @@ impl Builder {
         self
     }
 
-    /// Names the coroutine-to-be. Currently the name is used for identification only in panic messages.
+    /// Sets the stack size of the new coroutine to the size of the class.
+    #[inline]
+    pub fn stack_class(mut self, class: StackClass) -> Builder {
+        self.opts = self.opts.stack_class(class);
+        self
+    }
+
+    /// Names the coroutine-to-be. The name shows up in log records and `SlowIo` events.
     #[inline]
     pub fn name(mut self, name: Option<String>) -> Builder {
         self.opts.name = name;
         self
     }
 
+    /// Unwind the coroutine if it is resumed after the deadline.
+    #[inline]
+    pub fn deadline(mut self, deadline: Option<Instant>) -> Builder {
+        self.opts.deadline = deadline;
+        self
+    }
+
+    /// Bound the coroutine by the deadline of the spawning coroutine, and abort it together with it.
+    #[inline]
+    pub fn inherit_deadline(mut self, inherit: bool) -> Builder {
+        self.opts.inherit_deadline = inherit;
+        self
+    }
+
+    /// Whether the new coroutine runs right away, or after the spawning coroutine yields.
+    #[inline]
+    pub fn spawn_hint(mut self, hint: SpawnHint) -> Builder {
+        self.opts.spawn_hint = Some(hint);
+        self
+    }
+
     /// Spawn a new coroutine
     #[inline]
     pub fn spawn<F, T>(self, f: F) -> JoinHandle<T>
@@ -144,6 +251,52 @@ unsafe fn try<R, F: FnOnce() -> R>(f: F) -> thread::Result<R> {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_builder() {
+        Scheduler::new()
+            .run(|| {
+                let hdl = Builder::new()
+                              .stack_size(64 * 1024)
+                              .name(Some("conn-1234".to_owned()))
+                              .spawn(|| {
+                                  let shared = ::runtime::processor::Processor::current()
+                                                   .and_then(|p| p.current_shared())
+                                                   .unwrap();
+                                  shared.name().map(|n| n.to_owned())
+                              });
+                assert_eq!(hdl.join().unwrap(), Some("conn-1234".to_owned()));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_check_cancelled() {
+        use std::time::Duration;
+
+        fn spin() -> Cancelled {
+            loop {
+                if let Err(err) = check_cancelled() {
+                    return err;
+                }
+            }
+        }
+
+        Scheduler::new()
+            .run(|| {
+                assert_eq!(check_cancelled(), Ok(()));
+                assert_eq!(timeout(Duration::from_millis(20), spin).unwrap(), Cancelled);
+            })
+            .unwrap();
+
+        Scheduler::new()
+            .with_cancel_unwinding(true)
+            .run(|| {
+                let err = timeout(Duration::from_millis(20), spin).unwrap_err();
+                assert!(err.is::<TimedOut>());
+            })
+            .unwrap();
+    }
+
     #[test]
     fn test_sleep_ms() {
         Scheduler::new()
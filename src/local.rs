@@ -0,0 +1,161 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Coroutine-local storage
+//!
+//! `thread_local!` values are shared by all the coroutines resumed by a Processor, and a
+//! coroutine sees different values once it has been stolen by another Processor. Values
+//! declared with `coroutine_local!` belong to the coroutine instead:
+//!
+//! ```ignore
+//! coroutine_local!(static TRACE_ID: Cell<u64> = Cell::new(0));
+//!
+//! TRACE_ID.with(|id| id.set(request.trace_id));
+//! ```
+//!
+//! Every coroutine starts with the initial value, spawned coroutines do not inherit the
+//! values of their parent. The values are dropped once the coroutine finishes.
+
+use std::any::Any;
+
+use runtime::Processor;
+
+/// Declare a coroutine-local value, see `local`
+#[macro_export]
+macro_rules! coroutine_local {
+    (static $name:ident: $t:ty = $init:expr) => (
+        static $name: $crate::local::CoroutineLocal<$t> = {
+            fn __init() -> $t { $init }
+            $crate::local::CoroutineLocal { __init: __init }
+        };
+    );
+    (pub static $name:ident: $t:ty = $init:expr) => (
+        pub static $name: $crate::local::CoroutineLocal<$t> = {
+            fn __init() -> $t { $init }
+            $crate::local::CoroutineLocal { __init: __init }
+        };
+    );
+}
+
+/// Key of a coroutine-local value, declared with `coroutine_local!`
+pub struct CoroutineLocal<T: Send + 'static> {
+    #[doc(hidden)]
+    pub __init: fn() -> T,
+}
+
+impl<T: Send + 'static> CoroutineLocal<T> {
+    /// Call `f` with the value of the current coroutine, initializing it on the first access.
+    ///
+    /// Panics if called outside of a coroutine.
+    pub fn with<F, R>(&'static self, f: F) -> R
+        where F: FnOnce(&T) -> R
+    {
+        self.try_with(f).expect("Coroutine-local value accessed outside of a coroutine")
+    }
+
+    /// Like `with`, but returns `None` if called outside of a coroutine
+    pub fn try_with<F, R>(&'static self, f: F) -> Option<R>
+        where F: FnOnce(&T) -> R
+    {
+        let coro = match Processor::current().and_then(|p| p.current_shared()) {
+            Some(coro) => coro,
+            None => return None,
+        };
+
+        let key = self as *const CoroutineLocal<T> as usize;
+        let init = self.__init;
+        let value = coro.local(key, move || Box::new(init()) as Box<Any + Send>);
+
+        // Kept alive by `coro` and never moved nor removed before the coroutine finishes
+        let value = unsafe { (*value).downcast_ref::<T>().unwrap() };
+        Some(f(value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::{Cell, RefCell};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use scheduler::Scheduler;
+
+    coroutine_local!(static REQUEST_ID: Cell<usize> = Cell::new(0));
+    coroutine_local!(static TRACE: RefCell<Vec<&'static str>> = RefCell::new(Vec::new()));
+
+    struct DropCounter(Arc<AtomicUsize>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    coroutine_local!(static GUARD: RefCell<Option<DropCounter>> = RefCell::new(None));
+
+    #[test]
+    fn test_coroutine_local() {
+        assert!(REQUEST_ID.try_with(|id| id.get()).is_none());
+
+        Scheduler::new()
+            .with_workers(2)
+            .run(|| {
+                REQUEST_ID.with(|id| id.set(1));
+
+                let handles: Vec<_> = (2..10)
+                                          .map(|n| {
+                                              Scheduler::spawn(move || {
+                                                  // Not inherited from the parent
+                                                  assert_eq!(REQUEST_ID.with(|id| id.get()), 0);
+                                                  REQUEST_ID.with(|id| id.set(n));
+                                                  TRACE.with(|t| t.borrow_mut().push("spawned"));
+
+                                                  // Possibly resumed by another Processor
+                                                  for _ in 0..10 {
+                                                      Scheduler::sched();
+                                                  }
+
+                                                  assert_eq!(TRACE.with(|t| t.borrow().len()), 1);
+                                                  REQUEST_ID.with(|id| id.get()) == n
+                                              })
+                                          })
+                                          .collect();
+
+                for hdl in handles {
+                    assert!(hdl.join().unwrap());
+                }
+                assert_eq!(REQUEST_ID.with(|id| id.get()), 1);
+                assert!(TRACE.with(|t| t.borrow().is_empty()));
+
+                // Dropped once the coroutine finishes
+                let dropped = Arc::new(AtomicUsize::new(0));
+                let counter = DropCounter(dropped.clone());
+                let hdl = Scheduler::spawn(move || {
+                    GUARD.with(|g| *g.borrow_mut() = Some(counter));
+                });
+                hdl.join().unwrap();
+                while dropped.load(Ordering::SeqCst) == 0 {
+                    Scheduler::sched();
+                }
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Coroutine-aware logging
+//!
+//! The `co_error!`, `co_warn!`, `co_info!`, `co_debug!` and `co_trace!` macros work
+//! just like the ones in the `log` crate, but prefix each record with the Processor
+//! and the coroutine it was emitted from, e.g. `[P#1 C#42 conn-1234] message`.
+//!
+//! The calling crate has to import the `log` crate with `#[macro_use]`.
+
+use std::fmt;
+
+use runtime::Processor;
+
+/// Where a log record is emitted from
+pub struct Context {
+    processor_id: usize,
+    coroutine_id: Option<usize>,
+    coroutine_name: Option<String>,
+}
+
+impl Context {
+    /// Context of the caller, `None` if it is not running inside a Processor
+    pub fn current() -> Option<Context> {
+        Processor::current().map(|p| {
+            let shared = p.current_shared();
+
+            Context {
+                processor_id: p.id(),
+                coroutine_id: shared.as_ref().map(|s| s.id()),
+                coroutine_name: shared.as_ref().and_then(|s| s.name().map(|n| n.to_owned())),
+            }
+        })
+    }
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "[P#{}", self.processor_id));
+
+        if let Some(id) = self.coroutine_id {
+            try!(write!(f, " C#{}", id));
+        }
+
+        if let Some(ref name) = self.coroutine_name {
+            try!(write!(f, " {}", name));
+        }
+
+        write!(f, "]")
+    }
+}
+
+/// Prefix for log records, empty if the caller is not running inside a Processor
+#[doc(hidden)]
+pub struct Prefix(pub Option<Context>);
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(ref ctx) => write!(f, "{} ", ctx),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Log with the coroutine context of the caller
+#[macro_export]
+macro_rules! co_log {
+    ($lvl:expr, $($arg:tt)+) => {
+        log!($lvl, "{}{}",
+             $crate::logging::Prefix($crate::logging::Context::current()),
+             format_args!($($arg)+))
+    }
+}
+
+#[macro_export]
+macro_rules! co_error {
+    ($($arg:tt)*) => (co_log!(::log::LogLevel::Error, $($arg)*))
+}
+
+#[macro_export]
+macro_rules! co_warn {
+    ($($arg:tt)*) => (co_log!(::log::LogLevel::Warn, $($arg)*))
+}
+
+#[macro_export]
+macro_rules! co_info {
+    ($($arg:tt)*) => (co_log!(::log::LogLevel::Info, $($arg)*))
+}
+
+#[macro_export]
+macro_rules! co_debug {
+    ($($arg:tt)*) => (co_log!(::log::LogLevel::Debug, $($arg)*))
+}
+
+#[macro_export]
+macro_rules! co_trace {
+    ($($arg:tt)*) => (co_log!(::log::LogLevel::Trace, $($arg)*))
+}
@@ -0,0 +1,269 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Prometheus text format exporter for the Scheduler statistics
+
+use std::io::{self, Read, Write};
+
+use net::TcpListener;
+use scheduler::Scheduler;
+use stats::{ChannelStats, Stats};
+
+fn write_metric<W: Write>(w: &mut W,
+                          name: &str,
+                          kind: &str,
+                          help: &str,
+                          value: usize)
+                          -> io::Result<()> {
+    try!(writeln!(w, "# HELP {} {}", name, help));
+    try!(writeln!(w, "# TYPE {} {}", name, kind));
+    writeln!(w, "{} {}", name, value)
+}
+
+/// Write the statistics in the Prometheus text exposition format
+pub fn write_prometheus<W: Write>(stats: &Stats, w: &mut W) -> io::Result<()> {
+    try!(write_metric(w,
+                      "coio_coroutines_spawned_total",
+                      "counter",
+                      "Number of coroutines spawned",
+                      stats.coroutines_spawned));
+    try!(write_metric(w,
+                      "coio_coroutines_finished_total",
+                      "counter",
+                      "Number of coroutines run to completion",
+                      stats.coroutines_finished));
+    try!(write_metric(w,
+                      "coio_coroutines_active",
+                      "gauge",
+                      "Number of coroutines alive",
+                      stats.coroutines_active));
+    try!(write_metric(w,
+                      "coio_coroutines_blocked",
+                      "gauge",
+                      "Number of blocked coroutines",
+                      stats.coroutines_blocked));
+    try!(write_metric(w,
+                      "coio_io_registrations",
+                      "gauge",
+                      "Number of live I/O objects registered with the Scheduler",
+                      stats.io_registrations));
+    try!(write_metric(w,
+                      "coio_timers",
+                      "gauge",
+                      "Number of sleeping coroutines waiting for their timer",
+                      stats.timers));
+    try!(write_metric(w,
+                      "coio_event_loop_wakeups_total",
+                      "counter",
+                      "Number of times the event loop has returned from waiting for events",
+                      stats.event_loop_wakeups));
+    try!(write_metric(w,
+                      "coio_idle_wakeups_total",
+                      "counter",
+                      "Number of times an idle Processor has been woken up",
+                      stats.idle_wakeups));
+
+    let latency = stats.mean_wakeup_latency;
+    let latency_us = latency.as_secs() as usize * 1_000_000 +
+                     latency.subsec_nanos() as usize / 1_000;
+    try!(write_metric(w,
+                      "coio_mean_wakeup_latency_microseconds",
+                      "gauge",
+                      "Mean latency of waking up an idle Processor",
+                      latency_us));
+
+    try!(writeln!(w,
+                  "# HELP coio_mean_run_queue Mean length of the run queues of the Processors"));
+    try!(writeln!(w, "# TYPE coio_mean_run_queue gauge"));
+    try!(writeln!(w, "coio_mean_run_queue {:.3}", stats.mean_run_queue_len));
+
+    try!(writeln!(w,
+                  "# HELP coio_processor_run_queue Number of coroutines waiting in the run \
+                   queue of the Processor"));
+    try!(writeln!(w, "# TYPE coio_processor_run_queue gauge"));
+    for p in &stats.processors {
+        let tid = p.os_tid.map(|tid| tid.to_string()).unwrap_or_else(String::new);
+        try!(writeln!(w,
+                      "coio_processor_run_queue{{processor=\"{}\",thread=\"{}\",tid=\"{}\"}} {}",
+                      p.id,
+                      p.thread_name,
+                      tid,
+                      p.run_queue_len));
+    }
+
+    try!(writeln!(w,
+                  "# HELP coio_processor_load Smoothed number of runnable and recently run \
+                   coroutines of the Processor"));
+    try!(writeln!(w, "# TYPE coio_processor_load gauge"));
+    for p in &stats.processors {
+        try!(writeln!(w, "coio_processor_load{{processor=\"{}\"}} {:.3}", p.id, p.load));
+    }
+
+    try!(writeln!(w,
+                  "# HELP coio_processor_steals_attempted_total Number of attempts of the \
+                   Processor to steal a coroutine from a neighbor"));
+    try!(writeln!(w, "# TYPE coio_processor_steals_attempted_total counter"));
+    for p in &stats.processors {
+        try!(writeln!(w,
+                      "coio_processor_steals_attempted_total{{processor=\"{}\"}} {}",
+                      p.id,
+                      p.steals_attempted));
+    }
+
+    try!(writeln!(w,
+                  "# HELP coio_processor_steals_succeeded_total Number of coroutines the \
+                   Processor has stolen from its neighbors"));
+    try!(writeln!(w, "# TYPE coio_processor_steals_succeeded_total counter"));
+    for p in &stats.processors {
+        try!(writeln!(w,
+                      "coio_processor_steals_succeeded_total{{processor=\"{}\"}} {}",
+                      p.id,
+                      p.steals_succeeded));
+    }
+
+    Ok(())
+}
+
+/// Write the metrics of instrumented channels in the Prometheus text exposition format,
+/// labeled by the channel names
+pub fn write_channel_stats<W: Write>(channels: &[ChannelStats], w: &mut W) -> io::Result<()> {
+    try!(writeln!(w, "# HELP coio_channel_depth Number of values waiting in the channel"));
+    try!(writeln!(w, "# TYPE coio_channel_depth gauge"));
+    for chan in channels {
+        try!(writeln!(w, "coio_channel_depth{{channel=\"{}\"}} {}", chan.name, chan.depth));
+    }
+
+    try!(writeln!(w,
+                  "# HELP coio_channel_latency_microseconds Time values wait in the channel"));
+    try!(writeln!(w, "# TYPE coio_channel_latency_microseconds histogram"));
+    for chan in channels {
+        let mut cumulative = 0;
+        for (bound, count) in chan.latency.buckets() {
+            cumulative += count;
+            let bound_us = bound.as_secs() * 1_000_000 + bound.subsec_nanos() as u64 / 1_000;
+            try!(writeln!(w,
+                          "coio_channel_latency_microseconds_bucket{{channel=\"{}\",le=\"{}\"}} {}",
+                          chan.name,
+                          bound_us,
+                          cumulative));
+        }
+        try!(writeln!(w,
+                      "coio_channel_latency_microseconds_bucket{{channel=\"{}\",le=\"+Inf\"}} {}",
+                      chan.name,
+                      cumulative));
+        try!(writeln!(w,
+                      "coio_channel_latency_microseconds_count{{channel=\"{}\"}} {}",
+                      chan.name,
+                      cumulative));
+    }
+
+    Ok(())
+}
+
+/// Serve the statistics of the current Scheduler over HTTP, answering every request
+/// accepted from `listener` with the current metrics.
+///
+/// Must be called inside a coroutine, usually one spawned for this purpose only.
+pub fn serve(listener: TcpListener) -> io::Result<()> {
+    for conn in listener.incoming() {
+        let (mut stream, _) = try!(conn);
+
+        ::spawn(move || {
+            // The request itself doesn't matter, just wait until it has been sent
+            let mut buf = [0u8; 1024];
+            if let Err(err) = stream.read(&mut buf) {
+                debug!("Failed to read metrics request: {:?}", err);
+                return;
+            }
+
+            let mut body = Vec::new();
+            let scheduler = Scheduler::instance().unwrap();
+            write_prometheus(&scheduler.stats(), &mut body).unwrap();
+            write_channel_stats(&scheduler.channel_stats(), &mut body).unwrap();
+
+            let ret = write!(stream,
+                             "HTTP/1.0 200 OK\r\nContent-Type: text/plain; \
+                              version=0.0.4\r\nContent-Length: {}\r\n\r\n",
+                             body.len())
+                          .and_then(|_| stream.write_all(&body))
+                          .and_then(|_| stream.flush());
+
+            if let Err(err) = ret {
+                debug!("Failed to write metrics response: {:?}", err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use stats::{ProcessorStats, Stats};
+
+    #[test]
+    fn test_write_prometheus() {
+        let stats = Stats {
+            coroutines_spawned: 10,
+            coroutines_finished: 7,
+            coroutines_active: 3,
+            coroutines_blocked: 2,
+            io_registrations: 1,
+            timers: 4,
+            event_loop_wakeups: 20,
+            idle_wakeups: 0,
+            mean_wakeup_latency: Duration::from_millis(0),
+            mean_run_queue_len: 1.5,
+            processors: vec![ProcessorStats {
+                                 id: 0,
+                                 thread_name: "Processor #0".to_owned(),
+                                 os_tid: Some(42),
+                                 run_queue_len: 5,
+                                 mean_run_queue_len: 1.5,
+                                 load: 2.5,
+                                 steals_attempted: 6,
+                                 steals_succeeded: 2,
+                             }],
+        };
+
+        let mut buf = Vec::new();
+        write_prometheus(&stats, &mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+
+        assert!(text.contains("# TYPE coio_coroutines_active gauge\ncoio_coroutines_active 3\n"));
+        assert!(text.contains("coio_coroutines_blocked 2\n"));
+        assert!(text.contains("coio_io_registrations 1\n"));
+        assert!(text.contains("coio_processor_run_queue{processor=\"0\",thread=\"Processor #0\",\
+                               tid=\"42\"} 5\n"));
+        assert!(text.contains("coio_processor_load{processor=\"0\"} 2.500\n"));
+        assert!(text.contains("# TYPE coio_coroutines_spawned_total counter\n\
+                               coio_coroutines_spawned_total 10\n"));
+        assert!(text.contains("coio_coroutines_finished_total 7\n"));
+        assert!(text.contains("coio_timers 4\n"));
+        assert!(text.contains("coio_event_loop_wakeups_total 20\n"));
+        assert!(text.contains("coio_mean_run_queue 1.500\n"));
+        assert!(text.contains("coio_processor_steals_attempted_total{processor=\"0\"} 6\n"));
+        assert!(text.contains("coio_processor_steals_succeeded_total{processor=\"0\"} 2\n"));
+    }
+}
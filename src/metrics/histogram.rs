@@ -0,0 +1,197 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Lock-free latency and size histograms with power-of-two buckets
+
+use std::cmp;
+use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of buckets, the last one collects everything above ~1h
+const BUCKETS: usize = 33;
+
+/// Records durations in buckets of microseconds, bucket `i > 0` counts values
+/// in `[2^(i-1), 2^i)` and bucket 0 the ones below 1us
+pub struct Histogram {
+    buckets: Vec<AtomicUsize>,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram { buckets: (0..BUCKETS).map(|_| AtomicUsize::new(0)).collect() }
+    }
+
+    /// Count the duration
+    pub fn record(&self, d: Duration) {
+        let us = d.as_secs().saturating_mul(1_000_000) + d.subsec_nanos() as u64 / 1_000;
+        let idx = cmp::min(64 - us.leading_zeros() as usize, BUCKETS - 1);
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Copy of the current counts
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            counts: self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect(),
+        }
+    }
+}
+
+/// Counts of a `Histogram` at one point in time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    counts: Vec<usize>,
+}
+
+impl HistogramSnapshot {
+    /// Number of recorded durations
+    pub fn count(&self) -> usize {
+        self.counts.iter().fold(0, |sum, c| sum + c)
+    }
+
+    /// Pairs of the exclusive upper bound of each bucket and the number of durations in it
+    pub fn buckets(&self) -> Vec<(Duration, usize)> {
+        self.counts.iter().enumerate().map(|(idx, c)| (bucket_bound(idx), *c)).collect()
+    }
+
+    /// Upper bound of the bucket containing the `q`th quantile, `q` in `[0, 1]`.
+    /// Returns `None` if nothing has been recorded.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        quantile_index(&self.counts, q).map(bucket_bound)
+    }
+}
+
+fn bucket_bound(idx: usize) -> Duration {
+    let us = 1u64 << idx;
+    Duration::new(us / 1_000_000, (us % 1_000_000) as u32 * 1_000)
+}
+
+// Index of the bucket containing the `q`th quantile
+fn quantile_index(counts: &[usize], q: f64) -> Option<usize> {
+    let total = counts.iter().fold(0, |sum, c| sum + c);
+    if total == 0 {
+        return None;
+    }
+
+    let rank = cmp::max((total as f64 * q).ceil() as usize, 1);
+    let mut seen = 0;
+    for (idx, c) in counts.iter().enumerate() {
+        seen += *c;
+        if seen >= rank {
+            return Some(idx);
+        }
+    }
+    Some(counts.len() - 1)
+}
+
+/// Number of size buckets, the last one collects everything from 512MiB on
+const SIZE_BUCKETS: usize = 31;
+
+/// Records sizes in buckets of bytes, bucket `i > 0` counts values in `[2^(i-1), 2^i)`
+/// and bucket 0 the zeros. Also keeps track of the largest value.
+pub struct SizeHistogram {
+    buckets: Vec<AtomicUsize>,
+    max: AtomicUsize,
+}
+
+impl SizeHistogram {
+    pub fn new() -> SizeHistogram {
+        SizeHistogram {
+            buckets: (0..SIZE_BUCKETS).map(|_| AtomicUsize::new(0)).collect(),
+            max: AtomicUsize::new(0),
+        }
+    }
+
+    /// Count the size
+    pub fn record(&self, bytes: usize) {
+        let idx = cmp::min(64 - (bytes as u64).leading_zeros() as usize, SIZE_BUCKETS - 1);
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+
+        let mut max = self.max.load(Ordering::Relaxed);
+        while bytes > max {
+            let prev = self.max.compare_and_swap(max, bytes, Ordering::Relaxed);
+            if prev == max {
+                break;
+            }
+            max = prev;
+        }
+    }
+
+    /// Copy of the current counts
+    pub fn snapshot(&self) -> SizeHistogramSnapshot {
+        SizeHistogramSnapshot {
+            counts: self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect(),
+            max: self.max.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counts of a `SizeHistogram` at one point in time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeHistogramSnapshot {
+    counts: Vec<usize>,
+    max: usize,
+}
+
+impl SizeHistogramSnapshot {
+    /// Number of recorded sizes
+    pub fn count(&self) -> usize {
+        self.counts.iter().fold(0, |sum, c| sum + c)
+    }
+
+    /// Largest recorded size, 0 if nothing has been recorded
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Pairs of the exclusive upper bound of each bucket and the number of sizes in it
+    pub fn buckets(&self) -> Vec<(usize, usize)> {
+        self.counts.iter().enumerate().map(|(idx, c)| (1 << idx, *c)).collect()
+    }
+
+    /// Upper bound of the bucket containing the `q`th quantile, `q` in `[0, 1]`.
+    /// Returns `None` if nothing has been recorded.
+    pub fn quantile(&self, q: f64) -> Option<usize> {
+        quantile_index(&self.counts, q).map(|idx| 1 << idx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_histogram_quantile() {
+        let hist = Histogram::new();
+        assert_eq!(hist.snapshot().quantile(0.5), None);
+
+        for _ in 0..9 {
+            hist.record(Duration::new(0, 100_000)); // 100us
+        }
+        hist.record(Duration::from_millis(50));
+
+        let snapshot = hist.snapshot();
+        assert_eq!(snapshot.count(), 10);
+        assert_eq!(snapshot.quantile(0.5), Some(Duration::new(0, 128_000)));
+        assert_eq!(snapshot.quantile(1.0), Some(Duration::new(0, 65_536_000)));
+    }
+}
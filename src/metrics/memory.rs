@@ -0,0 +1,60 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Attributing heap memory to coroutines
+//!
+//! The standard library has no hooks into the allocator, so an allocator shim (a wrapper
+//! around `malloc` or the allocation hooks of jemalloc, for example) reports every
+//! allocation with `record_alloc` and `record_free`. The sizes are attributed to the coroutine
+//! running on the thread at that moment, see `Scheduler::with_memory_accounting()` and
+//! `Scheduler::memory_usage()`.
+//!
+//! The accounting is approximate: memory allocated by one coroutine and freed by another
+//! is counted as in use by the first one and as freed by the second one. Allocations made
+//! outside of coroutines are not attributed at all.
+//!
+//! Both functions never allocate, so they could be called from within an allocator.
+
+use runtime::processor::Processor;
+
+/// Attribute an allocation of `size` bytes to the running coroutine
+#[inline]
+pub fn record_alloc(size: usize) {
+    if let Some(processor) = Processor::current() {
+        if processor.scheduler().memory_accounting() {
+            if let Some(shared) = processor.current_shared() {
+                shared.record_alloc(size);
+            }
+        }
+    }
+}
+
+/// Attribute freeing `size` bytes to the running coroutine
+#[inline]
+pub fn record_free(size: usize) {
+    if let Some(processor) = Processor::current() {
+        if processor.scheduler().memory_accounting() {
+            if let Some(shared) = processor.current_shared() {
+                shared.record_free(size);
+            }
+        }
+    }
+}
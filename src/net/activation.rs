@@ -0,0 +1,267 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Socket activation, adopting sockets passed in by systemd or compatible supervisors
+//!
+//! The supervisor passes the sockets as the file descriptors starting at 3 and describes
+//! them with the `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES` environment variables.
+//! TCP listeners, UDP sockets and Unix listeners are recognized by inspecting the sockets.
+
+use std::cmp;
+use std::env;
+use std::i32;
+use std::io;
+use std::mem;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::{Mutex, Once, ONCE_INIT};
+
+use libc;
+
+use sys;
+
+use super::{getsockopt, TcpListener, UdpSocket, UnixListener};
+
+/// The first file descriptor passed by the supervisor
+pub const LISTEN_FDS_START: RawFd = 3;
+
+// The passed file descriptors and their names, which have not been adopted yet
+static PENDING_INIT: Once = ONCE_INIT;
+static mut PENDING: *const Mutex<Vec<(RawFd, Option<String>)>> = 0 as *const _;
+
+/// An inherited socket by its kind
+pub enum InheritedSocket {
+    TcpListener(TcpListener),
+    UdpSocket(UdpSocket),
+    UnixListener(UnixListener),
+    /// A socket of another kind, e.g. an Unix datagram socket, left for the caller
+    Other(RawFd),
+}
+
+/// An inherited socket and the name the supervisor gave it, if any
+pub struct Inherited {
+    pub name: Option<String>,
+    pub socket: InheritedSocket,
+}
+
+/// Adopt the sockets passed to this process, except the ones taken by `listen_fd_named`.
+///
+/// Returns an empty list if the environment variables are missing or meant for another
+/// process. The variables are removed so that child processes won't adopt the sockets again.
+///
+/// If a socket could not be adopted it is closed together with the ones adopted before it,
+/// while the sockets after it are left for the next call.
+pub fn listen_fds() -> io::Result<Vec<Inherited>> {
+    let fds = mem::replace(&mut *pending().lock().unwrap(), Vec::new());
+
+    let mut inherited = Vec::with_capacity(fds.len());
+    let mut fds = fds.into_iter();
+    while let Some((fd, name)) = fds.next() {
+        match adopt(fd) {
+            Ok(socket) => {
+                inherited.push(Inherited {
+                    name: name,
+                    socket: socket,
+                })
+            }
+            Err(err) => {
+                unsafe { libc::close(fd) };
+                pending().lock().unwrap().extend(fds);
+                return Err(err);
+            }
+        }
+    }
+    Ok(inherited)
+}
+
+/// Adopt only the socket named `name` passed to this process. The other sockets are left for
+/// later calls of `listen_fd_named` and `listen_fds`.
+///
+/// If the socket could not be adopted it is closed.
+pub fn listen_fd_named(name: &str) -> io::Result<Option<InheritedSocket>> {
+    let fd = match take_named(&mut *pending().lock().unwrap(), name) {
+        Some(fd) => fd,
+        None => return Ok(None),
+    };
+
+    match adopt(fd) {
+        Ok(socket) => Ok(Some(socket)),
+        Err(err) => {
+            unsafe { libc::close(fd) };
+            Err(err)
+        }
+    }
+}
+
+// The passed file descriptors which have not been adopted yet, read from the environment
+// on the first call
+fn pending() -> &'static Mutex<Vec<(RawFd, Option<String>)>> {
+    PENDING_INIT.call_once(|| {
+        let var = |name: &str| env::var(name).ok();
+        let fds = parse_env(var("LISTEN_PID").as_ref().map(|s| &s[..]),
+                            var("LISTEN_FDS").as_ref().map(|s| &s[..]),
+                            var("LISTEN_FDNAMES").as_ref().map(|s| &s[..]),
+                            unsafe { libc::getpid() } as u32,
+                            fd_limit());
+
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+
+        unsafe {
+            PENDING = Box::into_raw(Box::new(Mutex::new(fds)));
+        }
+    });
+
+    unsafe { &*PENDING }
+}
+
+// Take the file descriptor named `name` out of `fds`
+fn take_named(fds: &mut Vec<(RawFd, Option<String>)>, name: &str) -> Option<RawFd> {
+    let idx = match fds.iter().position(|&(_, ref n)| n.as_ref().map(|n| &n[..]) == Some(name)) {
+        Some(idx) => idx,
+        None => return None,
+    };
+    Some(fds.remove(idx).0)
+}
+
+// Upper bound of the file descriptors of the process
+fn fd_limit() -> RawFd {
+    match unsafe { libc::sysconf(libc::_SC_OPEN_MAX) } {
+        n if n > 0 => cmp::min(n, i32::MAX as libc::c_long) as RawFd,
+        _ => i32::MAX,
+    }
+}
+
+// The file descriptors meant for the process `pid` and their names. All of them have to be
+// below `fd_limit`.
+fn parse_env(listen_pid: Option<&str>,
+             listen_fds: Option<&str>,
+             listen_fdnames: Option<&str>,
+             pid: u32,
+             fd_limit: RawFd)
+             -> Vec<(RawFd, Option<String>)> {
+    if listen_pid.and_then(|p| p.trim().parse::<u32>().ok()) != Some(pid) {
+        return Vec::new();
+    }
+
+    // A bogus count must not make us allocate an entry for each of billions of descriptors
+    let count = match listen_fds.and_then(|n| n.trim().parse::<RawFd>().ok()) {
+        Some(count) if count > 0 && count <= fd_limit - LISTEN_FDS_START => count,
+        Some(count) if count > 0 => {
+            warn!("Ignoring LISTEN_FDS={} exceeding the descriptor limit of {}",
+                  count,
+                  fd_limit);
+            return Vec::new();
+        }
+        _ => return Vec::new(),
+    };
+
+    let mut names = listen_fdnames.map(|n| n.split(':').collect::<Vec<_>>()).unwrap_or(Vec::new());
+    names.resize(count as usize, "");
+
+    (0..count)
+        .zip(names)
+        .map(|(idx, name)| {
+            let name = if name.is_empty() {
+                None
+            } else {
+                Some(name.to_owned())
+            };
+            (LISTEN_FDS_START + idx, name)
+        })
+        .collect()
+}
+
+fn adopt(fd: RawFd) -> io::Result<InheritedSocket> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags | sys::FD_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    let ty: libc::c_int = try!(getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE));
+    let listening: libc::c_int = try!(getsockopt(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN));
+
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    if unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let inet = addr.ss_family as libc::c_int == libc::AF_INET ||
+               addr.ss_family as libc::c_int == libc::AF_INET6;
+    let unix = addr.ss_family as libc::c_int == libc::AF_UNIX;
+
+    let socket = unsafe {
+        match ty {
+            libc::SOCK_STREAM if inet && listening != 0 => {
+                InheritedSocket::TcpListener(TcpListener::from_raw_fd(fd))
+            }
+            libc::SOCK_DGRAM if inet => InheritedSocket::UdpSocket(UdpSocket::from_raw_fd(fd)),
+            libc::SOCK_STREAM if unix && listening != 0 => {
+                InheritedSocket::UnixListener(UnixListener::from_raw_fd(fd))
+            }
+            _ => InheritedSocket::Other(fd),
+        }
+    };
+
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod test {
+    use std::i32;
+
+    use super::{parse_env, take_named};
+
+    #[test]
+    fn test_parse_env() {
+        assert!(parse_env(Some("1"), Some("2"), None, 2, 1024).is_empty());
+        assert!(parse_env(None, Some("2"), None, 2, 1024).is_empty());
+
+        assert_eq!(parse_env(Some("2"), Some("3"), Some("http:dns"), 2, 1024),
+                   vec![(3, Some("http".to_owned())), (4, Some("dns".to_owned())), (5, None)]);
+        assert_eq!(parse_env(Some("2"), Some("1"), None, 2, 1024), vec![(3, None)]);
+
+        // The descriptors would not fit below the limit
+        assert!(parse_env(Some("2"), Some("1022"), None, 2, 1024).is_empty());
+        assert_eq!(parse_env(Some("2"), Some("1021"), None, 2, 1024).len(), 1021);
+        assert!(parse_env(Some("2"), Some("2147483647"), None, 2, i32::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_take_named() {
+        let mut fds = parse_env(Some("2"), Some("3"), Some("http:dns"), 2, 1024);
+
+        assert_eq!(take_named(&mut fds, "dns"), Some(4));
+        assert_eq!(take_named(&mut fds, "dns"), None);
+        assert_eq!(take_named(&mut fds, "ssh"), None);
+
+        // The others are kept for later
+        assert_eq!(fds, vec![(3, Some("http".to_owned())), (5, None)]);
+    }
+}
@@ -0,0 +1,128 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Backoff policy for accept loops running out of file descriptors
+
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::time::Duration;
+
+use libc;
+
+use scheduler::Scheduler;
+
+/// Returns true if the error is caused by exhausting the process or system file descriptors
+pub fn is_fd_exhausted(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(libc::EMFILE) | Some(libc::ENFILE) => true,
+        _ => false,
+    }
+}
+
+/// Keeps an accept loop alive when the process runs out of file descriptors.
+///
+/// Instead of returning `EMFILE`/`ENFILE` to the caller, `accept_with()` puts the
+/// coroutine to sleep with an exponential backoff and retries. Optionally a spare
+/// file descriptor is reserved, which will be released to accept and immediately
+/// close the pending connection, so that the peer is not left hanging in the backlog.
+pub struct AcceptBackoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+
+    reserve_spare: bool,
+    spare: Option<File>,
+}
+
+impl AcceptBackoff {
+    /// Backoff starting at 10ms up to 1s, without a spare file descriptor
+    pub fn new() -> AcceptBackoff {
+        AcceptBackoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_secs(1),
+            current: Duration::from_millis(10),
+
+            reserve_spare: false,
+            spare: None,
+        }
+    }
+
+    /// Set the initial and the maximum sleep duration
+    pub fn delay(mut self, initial: Duration, max: Duration) -> AcceptBackoff {
+        assert!(initial <= max, "Initial delay must not exceed the maximum delay");
+        self.initial = initial;
+        self.current = initial;
+        self.max = max;
+        self
+    }
+
+    /// Reserve a spare file descriptor for shedding connections while exhausted
+    pub fn reserve_spare_fd(mut self, reserve: bool) -> AcceptBackoff {
+        self.reserve_spare = reserve;
+        self.spare = if reserve {
+            open_spare()
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Called after a successful accept
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+
+        if self.reserve_spare && self.spare.is_none() {
+            self.spare = open_spare();
+        }
+    }
+
+    /// Release the spare file descriptor, returns false if there is none
+    pub fn release_spare(&mut self) -> bool {
+        self.spare.take().is_some()
+    }
+
+    /// Sleep for the current backoff delay, plus up to an eighth of it as jitter so that
+    /// many accept loops do not retry in lockstep, and advance it
+    pub fn wait(&mut self) {
+        warn!("Running out of file descriptors, accept backs off for {:?}",
+              self.current);
+
+        ::sleep(self.current + Scheduler::jitter(self.current / 8));
+        self.current = cmp::min(self.current * 2, self.max);
+    }
+}
+
+impl Default for AcceptBackoff {
+    fn default() -> AcceptBackoff {
+        AcceptBackoff::new()
+    }
+}
+
+#[cfg(unix)]
+fn open_spare() -> Option<File> {
+    File::open("/dev/null").ok()
+}
+
+#[cfg(not(unix))]
+fn open_spare() -> Option<File> {
+    None
+}
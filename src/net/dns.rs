@@ -0,0 +1,96 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Resolving host names without blocking the Processors
+//!
+//! `getaddrinfo` blocks the calling thread until the name servers have answered, which would
+//! stall every coroutine queued on the same Processor. Inside a Scheduler the lookups run on
+//! its resolver threads instead, see `Scheduler::with_resolver_threads`, and only the calling
+//! coroutine waits. Outside of a Scheduler they block the calling thread as usual.
+//!
+//! `TcpStream::connect` and friends accept the resolved addresses, e.g.
+//! `TcpStream::connect(&try!(dns::resolve("example.com:80"))[..])`.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+use scheduler::Scheduler;
+
+/// Number of resolver threads of a Scheduler unless set with
+/// `Scheduler::with_resolver_threads`
+pub const DEFAULT_RESOLVER_THREADS: usize = 4;
+
+/// Resolve `addr` like `ToSocketAddrs::to_socket_addrs` does, but block only the current
+/// coroutine while the lookup is running.
+///
+/// The lookup cannot be cancelled, `JoinHandle::cancel` takes effect once it is done.
+pub fn resolve<A: ToSocketAddrs + Send>(addr: A) -> io::Result<Vec<SocketAddr>> {
+    let scheduler = match Scheduler::instance() {
+        Some(scheduler) => scheduler,
+        None => return addr.to_socket_addrs().map(|addrs| addrs.collect()),
+    };
+
+    match scheduler.resolver().run(move || addr.to_socket_addrs().map(|addrs| addrs.collect())) {
+        Ok(r) => r,
+        Err(..) => Err(io::Error::new(io::ErrorKind::Other, "lookup panicked")),
+    }
+}
+
+/// Look up the IP addresses of `host`, see `resolve`
+pub fn lookup_host(host: &str) -> io::Result<Vec<IpAddr>> {
+    resolve((host, 0)).map(|addrs| addrs.iter().map(|addr| addr.ip()).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use scheduler::Scheduler;
+
+    #[test]
+    fn test_resolve() {
+        Scheduler::new()
+            .with_resolver_threads(1)
+            .run(|| {
+                let addrs = resolve("127.0.0.1:80").unwrap();
+                assert_eq!(addrs, vec!["127.0.0.1:80".parse::<SocketAddr>().unwrap()]);
+
+                let host = String::from("localhost");
+                let ips = lookup_host(&host).unwrap();
+                assert!(!ips.is_empty());
+                assert!(ips.iter().all(|ip| {
+                    match *ip {
+                        IpAddr::V4(ip) => ip.is_loopback(),
+                        IpAddr::V6(ip) => ip.is_loopback(),
+                    }
+                }));
+
+                assert!(resolve("not an address").is_err());
+            })
+            .unwrap();
+
+        // Outside of a Scheduler
+        assert_eq!(lookup_host("127.0.0.1").unwrap(),
+                   vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))]);
+    }
+}
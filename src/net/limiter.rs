@@ -0,0 +1,313 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Budget for outbound connection attempts
+//!
+//! During retry storms every client coroutine reconnecting at once may easily SYN-flood a
+//! dependency. A `ConnectLimiter` caps the number of concurrent connection attempts, both
+//! in total and per destination address. Attempts exceeding the caps wait in FIFO order.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use coroutine::Shared;
+use runtime::Processor;
+use scheduler::Scheduler;
+use super::TcpStream;
+
+// Waiters without a deadline still wake up this often
+const MAX_WAIT_SLICE: u64 = 60;
+
+// A coroutine waiting for a permit, woken up by interrupting its sleep
+struct Waiter {
+    addr: SocketAddr,
+    coro: Arc<Shared>,
+    granted: AtomicBool,
+}
+
+struct State {
+    total: usize,
+    per_destination: HashMap<SocketAddr, usize>,
+    waiters: VecDeque<Arc<Waiter>>,
+}
+
+impl State {
+    fn fits(&self, max_total: usize, max_per_destination: usize, addr: &SocketAddr) -> bool {
+        self.total < max_total &&
+        self.per_destination.get(addr).cloned().unwrap_or(0) < max_per_destination
+    }
+
+    fn take(&mut self, addr: SocketAddr) {
+        self.total += 1;
+        *self.per_destination.entry(addr).or_insert(0) += 1;
+    }
+
+    fn give_back(&mut self, addr: &SocketAddr) {
+        self.total -= 1;
+
+        let remove = match self.per_destination.get_mut(addr) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if remove {
+            self.per_destination.remove(addr);
+        }
+    }
+}
+
+struct LimiterInner {
+    max_total: usize,
+    max_per_destination: usize,
+    timeout: Option<Duration>,
+    state: Mutex<State>,
+}
+
+impl LimiterInner {
+    // Hand the freed capacity to the waiters, skipping the ones whose destination is still
+    // saturated so that they don't hold up the attempts to other destinations
+    fn grant_waiters(&self, state: &mut State) {
+        let mut idx = 0;
+        while idx < state.waiters.len() && state.total < self.max_total {
+            let addr = state.waiters[idx].addr;
+            if !state.fits(self.max_total, self.max_per_destination, &addr) {
+                idx += 1;
+                continue;
+            }
+
+            let waiter = state.waiters.remove(idx).unwrap();
+            state.take(addr);
+            waiter.granted.store(true, Ordering::SeqCst);
+            waiter.coro.interrupt();
+        }
+    }
+}
+
+/// Caps concurrent connection attempts globally and per destination.
+///
+/// Clones share the same budget.
+#[derive(Clone)]
+pub struct ConnectLimiter {
+    inner: Arc<LimiterInner>,
+}
+
+impl ConnectLimiter {
+    pub fn new(max_total: usize, max_per_destination: usize) -> ConnectLimiter {
+        assert!(max_total >= 1, "Must allow at least one connection attempt");
+        assert!(max_per_destination >= 1, "Must allow at least one attempt per destination");
+
+        ConnectLimiter {
+            inner: Arc::new(LimiterInner {
+                max_total: max_total,
+                max_per_destination: max_per_destination,
+                timeout: None,
+                state: Mutex::new(State {
+                    total: 0,
+                    per_destination: HashMap::new(),
+                    waiters: VecDeque::new(),
+                }),
+            }),
+        }
+    }
+
+    /// Fail attempts waiting longer than `timeout` for a permit with `ErrorKind::TimedOut`.
+    /// Must be called before the limiter is cloned.
+    pub fn with_timeout(mut self, timeout: Duration) -> ConnectLimiter {
+        Arc::get_mut(&mut self.inner).expect("ConnectLimiter already shared").timeout =
+            Some(timeout);
+        self
+    }
+
+    /// Number of connection attempts in progress
+    pub fn in_flight(&self) -> usize {
+        self.inner.state.lock().unwrap().total
+    }
+
+    /// Number of connection attempts to `addr` in progress
+    pub fn in_flight_to(&self, addr: &SocketAddr) -> usize {
+        self.inner.state.lock().unwrap().per_destination.get(addr).cloned().unwrap_or(0)
+    }
+
+    /// Number of attempts waiting for a permit
+    pub fn queued(&self) -> usize {
+        self.inner.state.lock().unwrap().waiters.len()
+    }
+
+    /// Wait for the permit to attempt a connection to `addr`, held until it is dropped.
+    ///
+    /// Must be called inside a coroutine. Fails with `ErrorKind::TimedOut` after the timeout,
+    /// or with `ErrorKind::Interrupted` if the coroutine gets interrupted.
+    pub fn acquire(&self, addr: SocketAddr) -> io::Result<ConnectPermit> {
+        let inner = &self.inner;
+
+        let waiter = {
+            let mut state = inner.state.lock().unwrap();
+            // Waiters would have been granted already if there was room for them
+            if state.fits(inner.max_total, inner.max_per_destination, &addr) {
+                state.take(addr);
+                return Ok(self.permit(addr));
+            }
+
+            let waiter = Arc::new(Waiter {
+                addr: addr,
+                coro: Processor::current().unwrap().current_shared().unwrap(),
+                granted: AtomicBool::new(false),
+            });
+            state.waiters.push_back(waiter.clone());
+            waiter
+        };
+
+        let deadline = inner.timeout.map(|t| Instant::now() + t);
+        let scheduler = Scheduler::instance().unwrap();
+
+        loop {
+            let slice = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if deadline > now {
+                        deadline - now
+                    } else {
+                        Duration::from_millis(0)
+                    }
+                }
+                None => Duration::from_secs(MAX_WAIT_SLICE),
+            };
+
+            let slept = scheduler.sleep(slice);
+
+            let mut state = inner.state.lock().unwrap();
+            if waiter.granted.load(Ordering::SeqCst) {
+                // The interruption may have arrived after the sleep ended by itself
+                drop(state);
+                if waiter.coro.set_interrupt_hook(Box::new(|| {})) {
+                    waiter.coro.clear_interrupt_hook();
+                }
+                return Ok(self.permit(addr));
+            }
+
+            let err = match slept {
+                Err(err) => err,
+                Ok(remaining) if remaining > Duration::from_millis(0) => {
+                    io::Error::new(io::ErrorKind::Interrupted, "connect attempt interrupted")
+                }
+                Ok(..) if deadline.map(|d| Instant::now() >= d).unwrap_or(false) => {
+                    io::Error::new(io::ErrorKind::TimedOut, "connect attempt queued too long")
+                }
+                Ok(..) => continue,
+            };
+
+            let ptr: *const Waiter = &*waiter;
+            state.waiters.retain(|w| &**w as *const Waiter != ptr);
+            return Err(err);
+        }
+    }
+
+    /// Connect to `addr`, trying the addresses it resolves to one after another, each one
+    /// within the budget. The permit is held until the connection is established or failed,
+    /// see `TcpStream::connect`.
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<TcpStream> {
+        let mut last_err = None;
+
+        for addr in try!(addr.to_socket_addrs()) {
+            let _permit = try!(self.acquire(addr));
+
+            match TcpStream::connect(addr) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")
+        }))
+    }
+
+    fn permit(&self, addr: SocketAddr) -> ConnectPermit {
+        ConnectPermit {
+            limiter: self.inner.clone(),
+            addr: addr,
+        }
+    }
+}
+
+/// Permission to attempt one connection, see `ConnectLimiter::acquire`
+pub struct ConnectPermit {
+    limiter: Arc<LimiterInner>,
+    addr: SocketAddr,
+}
+
+impl ConnectPermit {
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+}
+
+impl Drop for ConnectPermit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        state.give_back(&self.addr);
+        self.limiter.grant_waiters(&mut state);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use super::*;
+    use scheduler::Scheduler;
+
+    #[test]
+    fn test_connect_limiter_per_destination() {
+        Scheduler::new()
+            .run(|| {
+                let limiter = ConnectLimiter::new(4, 1).with_timeout(Duration::from_millis(100));
+                let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+                let b: SocketAddr = "127.0.0.1:2".parse().unwrap();
+
+                let permit = limiter.acquire(a).unwrap();
+                let _other = limiter.acquire(b).unwrap();
+                assert_eq!(limiter.in_flight(), 2);
+
+                let err = limiter.acquire(a).err().unwrap();
+                assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+                assert_eq!(limiter.queued(), 0);
+
+                let limiter1 = limiter.clone();
+                let waiting = Scheduler::spawn(move || limiter1.acquire(a).is_ok());
+                ::sleep_ms(10);
+                assert_eq!(limiter.queued(), 1);
+
+                drop(permit);
+                assert!(waiting.join().unwrap());
+                assert_eq!(limiter.in_flight_to(&a), 0);
+            })
+            .unwrap();
+    }
+}
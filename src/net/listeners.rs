@@ -0,0 +1,270 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! A set of listeners which could be changed at runtime
+//!
+//! `ListenerSet::rebind` applies a new list of addresses: listeners on unchanged addresses keep
+//! running, new ones are bound and removed ones stop accepting. Connections accepted by the
+//! removed listeners are served until they finish, so changing the configuration does not
+//! need a restart.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use scheduler::{JoinHandle, Scheduler};
+use super::backoff::AcceptBackoff;
+use super::tcp::{TcpListener, TcpStream};
+
+type Handler = Fn(TcpStream, SocketAddr) + Send + Sync + 'static;
+
+struct Bound {
+    addr: SocketAddr,
+    local_addr: SocketAddr,
+    active: Arc<AtomicUsize>,
+    acceptor: JoinHandle<()>,
+}
+
+/// Listeners on a set of addresses, all calling the same handler in a new coroutine for
+/// each accepted connection
+pub struct ListenerSet {
+    handler: Arc<Handler>,
+    bound: Vec<Bound>,
+    draining: Vec<Arc<AtomicUsize>>,
+}
+
+impl ListenerSet {
+    pub fn new<F>(handler: F) -> ListenerSet
+        where F: Fn(TcpStream, SocketAddr) + Send + Sync + 'static
+    {
+        ListenerSet {
+            handler: Arc::new(handler),
+            bound: Vec::new(),
+            draining: Vec::new(),
+        }
+    }
+
+    /// Listen on exactly `addrs`.
+    ///
+    /// Listeners on addresses which are listened on already are kept. If binding any of the
+    /// new addresses fails, the set is left unchanged. The new listeners are bound before the
+    /// removed ones are closed, which are closed before returning, so moving a port to
+    /// another address of the same host takes two calls.
+    pub fn rebind<A: ToSocketAddrs>(&mut self, addrs: &[A]) -> io::Result<()> {
+        let mut wanted = Vec::new();
+        for addr in addrs {
+            for addr in try!(addr.to_socket_addrs()) {
+                if !wanted.contains(&addr) {
+                    wanted.push(addr);
+                }
+            }
+        }
+
+        // Bind all of the new ones first, dropping them again on errors
+        let mut added = Vec::new();
+        for addr in wanted.iter() {
+            if self.bound.iter().all(|b| b.addr != *addr) {
+                let listener = try!(TcpListener::bind(addr));
+                let local_addr = try!(listener.local_addr());
+                added.push((*addr, local_addr, listener));
+            }
+        }
+
+        let (kept, removed): (Vec<Bound>, Vec<Bound>) =
+            self.bound.drain(..).partition(|b| wanted.contains(&b.addr));
+        self.bound = kept;
+
+        for (addr, local_addr, listener) in added {
+            debug!("ListenerSet listening on {:?}", local_addr);
+            let bound = self.start(addr, local_addr, listener);
+            self.bound.push(bound);
+        }
+
+        for bound in removed {
+            debug!("ListenerSet closing {:?}", bound.local_addr);
+            // Wakes the acceptor up from accepting, the listener is closed once it is unwound
+            bound.acceptor.cancel();
+            let _ = bound.acceptor.join();
+            self.draining.push(bound.active);
+        }
+
+        Ok(())
+    }
+
+    /// Addresses the listeners are bound to
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.bound.iter().map(|b| b.local_addr).collect()
+    }
+
+    /// Number of connections accepted by the listeners in the set which are still served
+    pub fn connections(&self) -> usize {
+        self.bound.iter().fold(0, |n, b| n + b.active.load(Ordering::SeqCst))
+    }
+
+    /// Number of connections accepted by removed listeners which are still served
+    pub fn draining(&mut self) -> usize {
+        self.draining.retain(|active| active.load(Ordering::SeqCst) > 0);
+        self.draining.iter().fold(0, |n, active| n + active.load(Ordering::SeqCst))
+    }
+
+    fn start(&self, addr: SocketAddr, local_addr: SocketAddr, listener: TcpListener) -> Bound {
+        let active = Arc::new(AtomicUsize::new(0));
+        let handler = self.handler.clone();
+
+        let acceptor = {
+            let active = active.clone();
+            Scheduler::spawn(move || accept_loop(listener, &active, handler))
+        };
+
+        Bound {
+            addr: addr,
+            local_addr: local_addr,
+            active: active,
+            acceptor: acceptor,
+        }
+    }
+}
+
+impl Drop for ListenerSet {
+    fn drop(&mut self) {
+        for bound in self.bound.iter() {
+            bound.acceptor.cancel();
+        }
+    }
+}
+
+// Decrements the number of active connections once the handler returned or panicked
+struct ActiveGuard(Arc<AtomicUsize>);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Runs until the acceptor is cancelled by removing its listener
+fn accept_loop(listener: TcpListener, active: &Arc<AtomicUsize>, handler: Arc<Handler>) {
+    let mut backoff = AcceptBackoff::new();
+
+    loop {
+        match listener.accept_with(&mut backoff) {
+            Ok((stream, addr)) => {
+                active.fetch_add(1, Ordering::SeqCst);
+                let guard = ActiveGuard(active.clone());
+                let handler = handler.clone();
+                let spawned = Scheduler::try_spawn(move || {
+                    let _guard = guard;
+                    handler(stream, addr);
+                });
+
+                // The scheduler is stopping, the connection has been closed already
+                if spawned.is_err() {
+                    break;
+                }
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => break,
+            Err(err) => {
+                error!("ListenerSet failed to accept on {:?}: {}", listener.local_addr(), err);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use net::TcpStream;
+    use scheduler::Scheduler;
+
+    use super::*;
+
+    #[test]
+    fn test_rebind() {
+        Scheduler::new()
+            .run(|| {
+                let mut set = ListenerSet::new(|mut stream, _| {
+                    let _ = stream.write_all(b"hi");
+                });
+
+                set.rebind(&["127.0.0.1:0"]).unwrap();
+                let first = set.local_addrs()[0];
+
+                // Unchanged addresses keep their listeners
+                set.rebind(&["127.0.0.1:0", "0.0.0.0:0"]).unwrap();
+                let addrs = set.local_addrs();
+                assert_eq!(addrs.len(), 2);
+                assert_eq!(addrs[0], first);
+                let second = ("127.0.0.1", addrs[1].port());
+
+                set.rebind(&["0.0.0.0:0"]).unwrap();
+                assert_eq!(set.local_addrs().len(), 1);
+                assert!(TcpStream::connect(first).is_err());
+
+                let mut buf = Vec::new();
+                TcpStream::connect(second).unwrap().read_to_end(&mut buf).unwrap();
+                assert_eq!(buf, b"hi");
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_draining() {
+        Scheduler::new()
+            .run(|| {
+                let release = Arc::new(AtomicBool::new(false));
+                let release1 = release.clone();
+                let mut set = ListenerSet::new(move |mut stream, _| {
+                    while !release1.load(Ordering::SeqCst) {
+                        ::sleep_ms(1);
+                    }
+                    let _ = stream.write_all(b"bye");
+                });
+
+                set.rebind(&["127.0.0.1:0"]).unwrap();
+                let addr = set.local_addrs()[0];
+                let mut client = TcpStream::connect(addr).unwrap();
+                while set.connections() == 0 {
+                    ::sleep_ms(1);
+                }
+
+                // The listener is closed right away, its connection is still served
+                let none: [&str; 0] = [];
+                set.rebind(&none).unwrap();
+                assert!(TcpStream::connect(addr).is_err());
+                assert_eq!(set.draining(), 1);
+
+                release.store(true, Ordering::SeqCst);
+                let mut buf = Vec::new();
+                client.read_to_end(&mut buf).unwrap();
+                assert_eq!(buf, b"bye");
+
+                while set.draining() > 0 {
+                    ::sleep_ms(1);
+                }
+            })
+            .unwrap();
+    }
+}
@@ -21,19 +21,230 @@
 
 //! Asynchronous network library
 
-pub use self::tcp::{TcpListener, TcpStream, Shutdown};
-pub use self::udp::UdpSocket;
+#[cfg(unix)]
+pub use self::activation::{listen_fds, listen_fd_named, Inherited, InheritedSocket};
+pub use self::backoff::AcceptBackoff;
+pub use self::limiter::{ConnectLimiter, ConnectPermit};
+pub use self::listeners::ListenerSet;
+pub use self::reaper::{Activity, IdleReaper, ReaperGuard};
+pub use self::serve::{serve, ListenerServeOptions, ServeQueue, OverflowPolicy};
+pub use self::tcp::{TcpListener, TcpListenerBuilder, TcpStream, Shutdown};
+pub use self::udp::{UdpBindOptions, UdpSocket, UdpSocketExt};
 #[cfg(unix)]
 pub use self::unix::{UnixListener, UnixStream, UnixSocket};
 
-use std::io;
+use std::io::{self, Read, Write};
 use std::net::{ToSocketAddrs, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use mio::EventSet;
 
+use scheduler::{EventedIo, Scheduler};
+
+#[cfg(unix)]
+pub mod activation;
+pub mod backoff;
+pub mod dns;
+pub mod limiter;
+pub mod listeners;
+pub mod reaper;
+pub mod serve;
 pub mod tcp;
 pub mod udp;
 #[cfg(unix)]
 pub mod unix;
 
+// Point in time an operation starting now has to be finished by
+fn deadline(timeout: Option<Duration>) -> Option<Instant> {
+    timeout.map(|t| Instant::now() + t)
+}
+
+// Wait for the I/O object to become ready, failing with `TimedOut` once `deadline` has passed
+fn wait_until<E: EventedIo>(io: &E,
+                            interest: EventSet,
+                            deadline: Option<Instant>)
+                            -> io::Result<()> {
+    let timeout = match deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                          "timed out waiting for I/O event"));
+            }
+            Some(deadline - now)
+        }
+        None => None,
+    };
+
+    Scheduler::instance().unwrap().wait_event_timeout(io, interest, timeout)
+}
+
+#[cfg(unix)]
+fn setsockopt<T>(fd: ::std::os::unix::io::RawFd,
+                 level: ::libc::c_int,
+                 name: ::libc::c_int,
+                 val: T)
+                 -> io::Result<()> {
+    use std::mem;
+    use libc;
+
+    let ret = unsafe {
+        libc::setsockopt(fd,
+                         level,
+                         name,
+                         &val as *const T as *const libc::c_void,
+                         mem::size_of::<T>() as libc::socklen_t)
+    };
+
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(unix)]
+fn getsockopt<T: Copy>(fd: ::std::os::unix::io::RawFd,
+                       level: ::libc::c_int,
+                       name: ::libc::c_int)
+                       -> io::Result<T> {
+    use std::mem;
+    use libc;
+    use sys;
+
+    let mut val: T = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<T>() as libc::socklen_t;
+
+    let ret = unsafe {
+        sys::getsockopt(fd,
+                        level,
+                        name,
+                        &mut val as *mut T as *mut libc::c_void,
+                        &mut len)
+    };
+
+    if ret == 0 {
+        Ok(val)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// Convert an address into the raw form expected by sendmsg(2) and friends
+#[cfg(unix)]
+fn to_sockaddr(addr: &SocketAddr) -> (::libc::sockaddr_storage, ::libc::socklen_t) {
+    use std::mem;
+    use libc;
+
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+
+    let len = match *addr {
+        SocketAddr::V4(ref a) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            let o = a.ip().octets();
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr.s_addr = ((o[0] as u32) << 24 | (o[1] as u32) << 16 |
+                                   (o[2] as u32) << 8 | o[3] as u32)
+                                      .to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(ref a) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_flowinfo = a.flowinfo().to_be();
+            sin6.sin6_scope_id = a.scope_id();
+            for (idx, seg) in a.ip().segments().iter().enumerate() {
+                sin6.sin6_addr.s6_addr[idx * 2] = (*seg >> 8) as u8;
+                sin6.sin6_addr.s6_addr[idx * 2 + 1] = *seg as u8;
+            }
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
+
+// Convert a raw address filled in by recvmsg(2) and friends
+#[cfg(unix)]
+fn from_sockaddr(storage: &::libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+    use libc;
+
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = u32::from_be(sin.sin_addr.s_addr);
+            let ip = Ipv4Addr::new((ip >> 24) as u8, (ip >> 16) as u8, (ip >> 8) as u8, ip as u8);
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let b = &sin6.sin6_addr.s6_addr;
+            let seg = |idx: usize| (b[idx * 2] as u16) << 8 | b[idx * 2 + 1] as u16;
+            let ip = Ipv6Addr::new(seg(0), seg(1), seg(2), seg(3), seg(4), seg(5), seg(6), seg(7));
+            Ok(SocketAddr::V6(SocketAddrV6::new(ip,
+                                                u16::from_be(sin6.sin6_port),
+                                                u32::from_be(sin6.sin6_flowinfo),
+                                                sin6.sin6_scope_id)))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "unsupported address family")),
+    }
+}
+
+/// Object-safe surface shared by the coroutine streams.
+///
+/// Servers and middleware could be written generically over `Box<CoStream>` instead of
+/// the concrete stream types.
+pub trait CoStream: Read + Write + Send {
+    /// Shut down the read, write, or both halves of the stream
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+    /// Create a new independently owned handle to the same stream
+    fn try_clone_stream(&self) -> io::Result<Box<CoStream>>;
+
+    /// Close the connection for all handles to the stream, waking up their pending operations
+    fn close_both(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+
+    /// Last I/O activity of the stream, if it is tracked
+    fn activity(&self) -> Option<Arc<Activity>> {
+        None
+    }
+}
+
+impl CoStream for TcpStream {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn try_clone_stream(&self) -> io::Result<Box<CoStream>> {
+        Ok(Box::new(try!(self.try_clone())))
+    }
+
+    fn close_both(&self) -> io::Result<()> {
+        TcpStream::close_both(self)
+    }
+
+    fn activity(&self) -> Option<Arc<Activity>> {
+        Some(TcpStream::activity(self).clone())
+    }
+}
+
+#[cfg(unix)]
+impl CoStream for UnixStream {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+
+    fn try_clone_stream(&self) -> io::Result<Box<CoStream>> {
+        Ok(Box::new(try!(self.try_clone())))
+    }
+}
+
 fn each_addr<A: ToSocketAddrs, F, T>(addr: A, mut f: F) -> io::Result<T>
     where F: FnMut(&SocketAddr) -> io::Result<T>
 {
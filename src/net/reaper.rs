@@ -0,0 +1,228 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Closing connections which have been idle for too long
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use scheduler::{JoinHandle, Scheduler};
+use super::{CoStream, Shutdown};
+
+/// Time of the last I/O activity of a connection
+#[derive(Debug)]
+pub struct Activity {
+    base: Instant,
+    // Milliseconds since `base`
+    last: AtomicUsize,
+}
+
+impl Activity {
+    pub fn new() -> Activity {
+        Activity {
+            base: Instant::now(),
+            last: AtomicUsize::new(0),
+        }
+    }
+
+    /// Record an activity now
+    #[inline]
+    pub fn touch(&self) {
+        self.last.store(millis(self.base.elapsed()), Ordering::Relaxed);
+    }
+
+    /// Time since the last activity
+    pub fn idle_for(&self) -> Duration {
+        let now = millis(self.base.elapsed());
+        let last = self.last.load(Ordering::Relaxed);
+        Duration::from_millis(now.saturating_sub(last) as u64)
+    }
+}
+
+fn millis(dur: Duration) -> usize {
+    dur.as_secs() as usize * 1_000 + dur.subsec_nanos() as usize / 1_000_000
+}
+
+struct Entry {
+    activity: Arc<Activity>,
+    stream: Box<CoStream>,
+}
+
+struct ReaperInner {
+    idle_timeout: Duration,
+    interval: Duration,
+    next_id: AtomicUsize,
+    conns: Mutex<HashMap<usize, Entry>>,
+}
+
+/// Shuts down the registered connections which have been idle for longer than the timeout.
+///
+/// Shutting down a connection wakes up the coroutines blocked on it, their reads return
+/// EOF and their writes fail.
+#[derive(Clone)]
+pub struct IdleReaper {
+    inner: Arc<ReaperInner>,
+}
+
+impl IdleReaper {
+    /// Create a reaper closing connections idle for longer than `idle_timeout`
+    pub fn new(idle_timeout: Duration) -> IdleReaper {
+        let interval = ::std::cmp::max(idle_timeout / 4, Duration::from_millis(10));
+
+        IdleReaper {
+            inner: Arc::new(ReaperInner {
+                idle_timeout: idle_timeout,
+                interval: interval,
+                next_id: AtomicUsize::new(0),
+                conns: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Watch a connection until the returned guard is dropped.
+    ///
+    /// Fails with `InvalidInput` if the stream does not track its activity.
+    pub fn register(&self, stream: &CoStream) -> io::Result<ReaperGuard> {
+        let activity = match stream.activity() {
+            Some(activity) => activity,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "stream does not track its activity"))
+            }
+        };
+
+        // Connections start out active
+        activity.touch();
+
+        let entry = Entry {
+            activity: activity,
+            stream: try!(stream.try_clone_stream()),
+        };
+
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        self.inner.conns.lock().unwrap().insert(id, entry);
+
+        Ok(ReaperGuard {
+            reaper: self.clone(),
+            id: id,
+        })
+    }
+
+    /// Number of watched connections
+    pub fn len(&self) -> usize {
+        self.inner.conns.lock().unwrap().len()
+    }
+
+    /// Shut down the idle connections once, returns how many have been shut down
+    pub fn reap(&self) -> usize {
+        let idle = {
+            let mut conns = self.inner.conns.lock().unwrap();
+            let ids: Vec<usize> = conns.iter()
+                                       .filter(|&(_, e)| {
+                                           e.activity.idle_for() >= self.inner.idle_timeout
+                                       })
+                                       .map(|(id, _)| *id)
+                                       .collect();
+            ids.into_iter().filter_map(|id| conns.remove(&id)).collect::<Vec<Entry>>()
+        };
+
+        for entry in idle.iter() {
+            debug!("Reaping connection idle for {:?}", entry.activity.idle_for());
+            if let Err(err) = entry.stream.shutdown(Shutdown::Both) {
+                debug!("Failed to shut down idle connection: {}", err);
+            }
+        }
+
+        idle.len()
+    }
+
+    /// Reap the idle connections periodically in the current coroutine, forever
+    pub fn run(&self) {
+        loop {
+            ::sleep(self.inner.interval);
+            self.reap();
+        }
+    }
+
+    /// Spawn a coroutine running `run()`
+    pub fn spawn(&self) -> JoinHandle<()> {
+        let reaper = self.clone();
+        Scheduler::spawn(move || reaper.run())
+    }
+}
+
+/// Stops watching the connection when dropped
+pub struct ReaperGuard {
+    reaper: IdleReaper,
+    id: usize,
+}
+
+impl Drop for ReaperGuard {
+    fn drop(&mut self) {
+        self.reaper.inner.conns.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Read;
+    use std::time::Duration;
+
+    use net::{TcpListener, TcpStream};
+    use scheduler::Scheduler;
+
+    #[test]
+    fn test_reap_idle() {
+        Scheduler::new()
+            .run(|| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                let client = Scheduler::spawn(move || {
+                    let stream = TcpStream::connect(addr).unwrap();
+                    ::sleep_ms(500);
+                    drop(stream);
+                });
+
+                let (mut stream, _) = listener.accept().unwrap();
+
+                let reaper = IdleReaper::new(Duration::from_millis(50));
+                let _guard = reaper.register(&stream).unwrap();
+                assert_eq!(reaper.len(), 1);
+                assert_eq!(reaper.reap(), 0);
+
+                let reaping = reaper.spawn();
+
+                let mut buf = [0u8; 16];
+                assert_eq!(stream.read(&mut buf).unwrap(), 0);
+                assert_eq!(reaper.len(), 0);
+
+                reaping.abort();
+                client.join().unwrap();
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,331 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Accept loop helper dispatching connections to coroutines
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+#[cfg(unix)]
+use libc;
+#[cfg(unix)]
+use sys;
+
+use scheduler::Scheduler;
+use sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncReceiver};
+use super::backoff::AcceptBackoff;
+use super::tcp::{TcpListener, TcpStream};
+
+/// Queue between the acceptor and the handlers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServeQueue {
+    /// Spawn a new coroutine for every accepted connection
+    Unbounded,
+    /// Queue at most `n` accepted connections for a fixed set of worker coroutines, `n` must
+    /// be at least 1
+    Bounded(usize),
+}
+
+/// What to do with a connection when the bounded queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Reset the new connection immediately
+    Reject,
+    /// Stop accepting until there is room in the queue
+    Block,
+    /// Reset the oldest queued connection to make room for the new one
+    DropOldest,
+}
+
+/// Options for `serve()`
+#[derive(Debug, Clone, Copy)]
+pub struct ListenerServeOptions {
+    pub queue: ServeQueue,
+    pub on_overflow: OverflowPolicy,
+    /// Number of worker coroutines handling connections from a bounded queue
+    pub workers: usize,
+}
+
+impl Default for ListenerServeOptions {
+    fn default() -> ListenerServeOptions {
+        ListenerServeOptions {
+            queue: ServeQueue::Unbounded,
+            on_overflow: OverflowPolicy::Block,
+            workers: 128,
+        }
+    }
+}
+
+// Close the connection with RST instead of the graceful FIN
+#[cfg(unix)]
+fn reset(stream: TcpStream) {
+    use std::os::unix::io::AsRawFd;
+
+    let linger = sys::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    let _ = super::setsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_LINGER, linger);
+}
+
+#[cfg(not(unix))]
+fn reset(stream: TcpStream) {
+    drop(stream);
+}
+
+/// Accept connections from `listener` forever and call `handler` for each of them in a coroutine.
+///
+/// Returns only if accepting fails with an error other than running out of file descriptors,
+/// or once the scheduler is stopping, see `Scheduler::shutdown_graceful`. Connections accepted
+/// meanwhile are closed, the queued ones are still handled.
+pub fn serve<F>(listener: &TcpListener, opts: ListenerServeOptions, handler: F) -> io::Result<()>
+    where F: Fn(TcpStream, SocketAddr) + Send + Sync + 'static
+{
+    let handler = Arc::new(handler);
+    let mut backoff = AcceptBackoff::new();
+
+    let capacity = match opts.queue {
+        ServeQueue::Unbounded => {
+            loop {
+                let (stream, addr) = try!(listener.accept_with(&mut backoff));
+                let handler = handler.clone();
+                if Scheduler::try_spawn(move || handler(stream, addr)).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        ServeQueue::Bounded(n) => n,
+    };
+
+    assert!(opts.workers >= 1, "Must have at least one worker");
+    assert!(capacity >= 1, "Must queue at least one connection");
+
+    // Held by the acceptor for every connection not handed to a worker yet, if it blocks
+    let (slots, free_slots) = sync_channel::<()>(capacity);
+    let (tx, rx) = channel();
+
+    {
+        let on_overflow = opts.on_overflow;
+        Scheduler::spawn(move || dispatch(rx, free_slots, capacity, on_overflow));
+    }
+
+    for _ in 0..opts.workers {
+        let tx = tx.clone();
+        let handler = handler.clone();
+        Scheduler::spawn(move || work(tx, handler));
+    }
+
+    // Lets the dispatcher finish once the acceptor returned
+    let _close = CloseGuard(tx.clone());
+
+    loop {
+        let (stream, addr) = try!(listener.accept_with(&mut backoff));
+
+        let scheduler = Scheduler::instance().unwrap();
+        if scheduler.is_stopping() || scheduler.is_shutting_down() {
+            return Ok(());
+        }
+
+        if opts.on_overflow == OverflowPolicy::Block && slots.send(()).is_err() {
+            return Ok(());
+        }
+        if tx.send(Dispatch::Conn(stream, addr)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// Messages to the dispatcher of a bounded queue, the only one receiving them, so that the
+// workers never wait for each other
+enum Dispatch {
+    // An accepted connection, handed to an idle worker or queued
+    Conn(TcpStream, SocketAddr),
+    // A worker waiting for its next connection
+    Idle(Sender<(TcpStream, SocketAddr)>),
+    // The acceptor has returned, the queued connections are still handled
+    Close,
+}
+
+struct CloseGuard(Sender<Dispatch>);
+
+impl Drop for CloseGuard {
+    fn drop(&mut self) {
+        let _ = self.0.send(Dispatch::Close);
+    }
+}
+
+fn dispatch(rx: Receiver<Dispatch>,
+            free_slots: SyncReceiver<()>,
+            capacity: usize,
+            on_overflow: OverflowPolicy) {
+    let mut queue = VecDeque::new();
+    let mut idle: VecDeque<Sender<(TcpStream, SocketAddr)>> = VecDeque::new();
+    let mut closed = false;
+
+    while !closed || !queue.is_empty() {
+        match rx.recv() {
+            Ok(Dispatch::Conn(stream, addr)) => {
+                if queue.len() < capacity {
+                    queue.push_back((stream, addr));
+                } else {
+                    match on_overflow {
+                        OverflowPolicy::Reject => {
+                            debug!("Serve queue is full, rejecting {:?}", addr);
+                            reset(stream);
+                        }
+                        // The acceptor waits for a free slot, the queue is never full
+                        OverflowPolicy::Block => queue.push_back((stream, addr)),
+                        OverflowPolicy::DropOldest => {
+                            if let Some((oldest, oldest_addr)) = queue.pop_front() {
+                                debug!("Serve queue is full, dropping {:?}", oldest_addr);
+                                reset(oldest);
+                            }
+                            queue.push_back((stream, addr));
+                        }
+                    }
+                }
+            }
+            Ok(Dispatch::Idle(worker)) => idle.push_back(worker),
+            Ok(Dispatch::Close) | Err(..) => closed = true,
+        }
+
+        while !queue.is_empty() && !idle.is_empty() {
+            let conn = queue.pop_front().unwrap();
+            // A worker only goes away while the scheduler shuts down
+            if let Err(err) = idle.pop_front().unwrap().send(conn) {
+                queue.push_front(err.0);
+                continue;
+            }
+            let _ = free_slots.try_recv();
+        }
+    }
+    // The idle workers exit once their senders are dropped
+}
+
+fn work<F>(tx: Sender<Dispatch>, handler: Arc<F>)
+    where F: Fn(TcpStream, SocketAddr) + Send + Sync + 'static
+{
+    loop {
+        let (worker, conn) = channel();
+        if tx.send(Dispatch::Idle(worker)).is_err() {
+            break;
+        }
+
+        let (stream, addr) = match conn.recv() {
+            Ok(conn) => conn,
+            Err(..) => break,
+        };
+
+        // Keep the worker alive even if the handler panicked
+        if let Err(..) = unsafe { ::try(|| handler(stream, addr)) } {
+            error!("Connection handler for {:?} panicked", addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use net::{TcpListener, TcpStream};
+    use scheduler::Scheduler;
+
+    use super::*;
+
+    #[test]
+    fn test_serve_bounded() {
+        Scheduler::new()
+            .run(|| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = listener.local_addr().unwrap();
+                let opts = ListenerServeOptions {
+                    queue: ServeQueue::Bounded(4),
+                    on_overflow: OverflowPolicy::Block,
+                    workers: 2,
+                };
+                Scheduler::spawn(move || {
+                    serve(&listener, opts, |mut stream, _| {
+                        let _ = stream.write_all(b"hi");
+                    })
+                });
+
+                for _ in 0..10 {
+                    let mut buf = Vec::new();
+                    TcpStream::connect(addr).unwrap().read_to_end(&mut buf).unwrap();
+                    assert_eq!(buf, b"hi");
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_serve_reject() {
+        Scheduler::new()
+            .run(|| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = listener.local_addr().unwrap();
+                let opts = ListenerServeOptions {
+                    queue: ServeQueue::Bounded(1),
+                    on_overflow: OverflowPolicy::Reject,
+                    workers: 1,
+                };
+
+                let started = Arc::new(AtomicUsize::new(0));
+                let release = Arc::new(AtomicBool::new(false));
+                let (started1, release1) = (started.clone(), release.clone());
+                Scheduler::spawn(move || {
+                    serve(&listener, opts, move |mut stream, _| {
+                        started1.fetch_add(1, Ordering::SeqCst);
+                        while !release1.load(Ordering::SeqCst) {
+                            ::sleep_ms(1);
+                        }
+                        let _ = stream.write_all(b"hi");
+                    })
+                });
+
+                // The only worker is busy with the first one, the second one is queued
+                let mut first = TcpStream::connect(addr).unwrap();
+                while started.load(Ordering::SeqCst) == 0 {
+                    ::sleep_ms(1);
+                }
+                let mut second = TcpStream::connect(addr).unwrap();
+                ::sleep_ms(20);
+
+                let mut buf = Vec::new();
+                let mut third = TcpStream::connect(addr).unwrap();
+                let _ = third.read_to_end(&mut buf);
+                assert!(buf.is_empty());
+
+                release.store(true, Ordering::SeqCst);
+                for stream in vec![&mut first, &mut second] {
+                    let mut buf = Vec::new();
+                    stream.read_to_end(&mut buf).unwrap();
+                    assert_eq!(buf, b"hi");
+                }
+                assert_eq!(started.load(Ordering::SeqCst), 2);
+            })
+            .unwrap();
+    }
+}
@@ -21,35 +21,72 @@
 
 //! TCP
 
-use std::io::{self, ErrorKind};
+use std::io::{self, ErrorKind, Read};
 use std::net::{ToSocketAddrs, SocketAddr};
 use std::ops::{Deref, DerefMut};
 use std::convert::From;
 use std::iter::Iterator;
-use net2::TcpStreamExt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 #[cfg(unix)]
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 
 use mio::{self, EventSet};
 
-use scheduler::Scheduler;
+use io::DeadlineIo;
+#[cfg(unix)]
+use io::{vectored, RingBuf};
+use scheduler::{IoOwner, Scheduler};
+use super::backoff::{self, AcceptBackoff};
+use super::reaper::Activity;
 
 #[derive(Debug)]
-pub struct TcpListener(::mio::tcp::TcpListener);
+pub struct TcpListener(::mio::tcp::TcpListener, IoOwner, Option<Duration>);
 
 impl TcpListener {
+    fn new(listener: ::mio::tcp::TcpListener) -> TcpListener {
+        TcpListener(listener, IoOwner::new(), None)
+    }
+
     pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
-        super::each_addr(addr, ::mio::tcp::TcpListener::bind).map(TcpListener)
+        super::each_addr(addr, ::mio::tcp::TcpListener::bind).map(TcpListener::new)
+    }
+
+    /// Set how long `accept` waits for a connection before failing with `TimedOut`,
+    /// `None` waits forever
+    pub fn set_timeout(&mut self, dur: Option<Duration>) {
+        self.2 = dur;
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.2
     }
 
     pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        self.accept_until(super::deadline(self.2))
+    }
+
+    /// Accept a new connection, waiting at most `dur` for it and returning `Ok(None)` once that
+    /// has expired
+    pub fn accept_timeout(&self, dur: Duration) -> io::Result<Option<(TcpStream, SocketAddr)>> {
+        match self.accept_until(super::deadline(Some(dur))) {
+            Ok(ret) => Ok(Some(ret)),
+            Err(ref err) if err.kind() == ErrorKind::TimedOut => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn accept_until(&self, deadline: Option<Instant>) -> io::Result<(TcpStream, SocketAddr)> {
+        try!(self.1.check());
+
         match self.0.accept() {
             Ok(None) => {
                 debug!("TcpListener accept WouldBlock; going to register into eventloop");
             }
             Ok(Some((stream, addr))) => {
-                return Ok((TcpStream(stream), addr));
+                return Ok((TcpStream::new(stream), addr));
             }
             Err(err) => {
                 return Err(err);
@@ -57,14 +94,14 @@ impl TcpListener {
         }
 
         loop {
-            try!(Scheduler::instance().unwrap().wait_event(&self.0, EventSet::readable()));
+            try!(super::wait_until(&self.0, EventSet::readable(), deadline));
 
             match self.0.accept() {
                 Ok(None) => {
                     warn!("TcpListener accept WouldBlock; Coroutine was awaked by readable event");
                 }
                 Ok(Some((stream, addr))) => {
-                    return Ok((TcpStream(stream), addr));
+                    return Ok((TcpStream::new(stream), addr));
                 }
                 Err(err) => {
                     return Err(err);
@@ -73,13 +110,105 @@ impl TcpListener {
         }
     }
 
+    /// Accept a new connection, riding through file descriptor exhaustion with `backoff`
+    pub fn accept_with(&self,
+                       backoff: &mut AcceptBackoff)
+                       -> io::Result<(TcpStream, SocketAddr)> {
+        loop {
+            match self.accept() {
+                Ok(ret) => {
+                    backoff.reset();
+                    return Ok(ret);
+                }
+                Err(ref err) if backoff::is_fd_exhausted(err) => {
+                    if backoff.release_spare() {
+                        // Shed the pending connection with the released descriptor
+                        if let Ok(Some((stream, addr))) = self.0.accept() {
+                            debug!("TcpListener dropping connection from {:?}", addr);
+                            drop(stream);
+                        }
+                    }
+
+                    backoff.wait();
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
     pub fn try_clone(&self) -> io::Result<TcpListener> {
-        Ok(TcpListener(try!(self.0.try_clone())))
+        Ok(TcpListener(try!(self.0.try_clone()), self.1.clone(), self.2))
     }
 
     pub fn incoming<'a>(&'a self) -> Incoming<'a> {
         Incoming(self)
     }
+
+    /// Only surface connections to `accept()` once data has arrived on them, waiting at most
+    /// `seconds` for it (TCP_DEFER_ACCEPT). Disabled with 0.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn set_defer_accept(&self, seconds: u32) -> io::Result<()> {
+        super::setsockopt(self.as_raw_fd(),
+                          ::libc::IPPROTO_TCP,
+                          TCP_DEFER_ACCEPT,
+                          seconds as ::libc::c_int)
+    }
+
+    /// Only surface connections to `accept()` once data has arrived on them, using the
+    /// "dataready" accept filter (SO_ACCEPTFILTER). The accf_data kernel module has to be
+    /// loaded. Disabled with 0, the filter does not time out otherwise.
+    #[cfg(target_os = "freebsd")]
+    pub fn set_defer_accept(&self, seconds: u32) -> io::Result<()> {
+        if seconds == 0 {
+            let ret = unsafe {
+                ::libc::setsockopt(self.as_raw_fd(),
+                                   ::libc::SOL_SOCKET,
+                                   SO_ACCEPTFILTER,
+                                   ::std::ptr::null(),
+                                   0)
+            };
+
+            return if ret == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            };
+        }
+
+        let mut arg = AcceptFilterArg {
+            af_name: [0; 16],
+            af_arg: [0; 256 - 16],
+        };
+        for (dst, src) in arg.af_name.iter_mut().zip(b"dataready".iter()) {
+            *dst = *src as ::libc::c_char;
+        }
+
+        super::setsockopt(self.as_raw_fd(), ::libc::SOL_SOCKET, SO_ACCEPTFILTER, arg)
+    }
+
+    /// Not supported on this platform, fails unless it is disabled with 0
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    pub fn set_defer_accept(&self, seconds: u32) -> io::Result<()> {
+        if seconds == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other,
+                               "deferred accept is not supported on this platform"))
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const TCP_DEFER_ACCEPT: ::libc::c_int = 9;
+
+#[cfg(target_os = "freebsd")]
+const SO_ACCEPTFILTER: ::libc::c_int = 0x1000;
+
+#[cfg(target_os = "freebsd")]
+#[repr(C)]
+struct AcceptFilterArg {
+    af_name: [::libc::c_char; 16],
+    af_arg: [::libc::c_char; 256 - 16],
 }
 
 impl Deref for TcpListener {
@@ -106,7 +235,97 @@ impl AsRawFd for TcpListener {
 #[cfg(unix)]
 impl FromRawFd for TcpListener {
     unsafe fn from_raw_fd(fd: RawFd) -> TcpListener {
-        TcpListener(FromRawFd::from_raw_fd(fd))
+        TcpListener::new(FromRawFd::from_raw_fd(fd))
+    }
+}
+
+/// Binds a `TcpListener` with socket options. The defaults are the ones of
+/// `TcpListener::bind`.
+///
+/// With `reuse_port` each Processor could listen on a socket of its own on the same port, see
+/// `Scheduler::spawn_on_each_worker`, and the kernel spreads the incoming connections over
+/// them instead of every accept loop contending for one socket.
+#[derive(Debug, Clone, Copy)]
+pub struct TcpListenerBuilder {
+    reuse_addr: bool,
+    reuse_port: bool,
+    only_v6: Option<bool>,
+    backlog: i32,
+}
+
+impl TcpListenerBuilder {
+    pub fn new() -> TcpListenerBuilder {
+        TcpListenerBuilder {
+            reuse_addr: true,
+            reuse_port: false,
+            only_v6: None,
+            backlog: 1024,
+        }
+    }
+
+    /// Set `SO_REUSEADDR`, so that the port could be bound again right after the listener is
+    /// closed, while connections are still in `TIME_WAIT`. Enabled by default.
+    pub fn reuse_addr(mut self, enabled: bool) -> TcpListenerBuilder {
+        self.reuse_addr = enabled;
+        self
+    }
+
+    /// Set `SO_REUSEPORT`, so that several listeners could bind to the same address and port
+    pub fn reuse_port(mut self, enabled: bool) -> TcpListenerBuilder {
+        self.reuse_port = enabled;
+        self
+    }
+
+    /// Set `IPV6_V6ONLY` on IPv6 listeners, the system default is kept unless set
+    pub fn only_v6(mut self, enabled: bool) -> TcpListenerBuilder {
+        self.only_v6 = Some(enabled);
+        self
+    }
+
+    /// Maximum number of connections waiting to be accepted, 1024 by default
+    pub fn backlog(mut self, backlog: i32) -> TcpListenerBuilder {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Bind a listener to the first of the addresses `addr` resolves to which could be bound
+    #[cfg(unix)]
+    pub fn bind<A: ToSocketAddrs>(&self, addr: A) -> io::Result<TcpListener> {
+        super::each_addr(addr, |addr| self.bind_addr(addr)).map(TcpListener::new)
+    }
+
+    #[cfg(unix)]
+    fn bind_addr(&self, addr: &SocketAddr) -> io::Result<::mio::tcp::TcpListener> {
+        use std::os::unix::io::IntoRawFd;
+
+        use net2::{TcpBuilder, TcpListenerExt};
+        use net2::unix::UnixTcpBuilderExt;
+
+        let builder = match *addr {
+            SocketAddr::V4(..) => try!(TcpBuilder::new_v4()),
+            SocketAddr::V6(..) => {
+                let builder = try!(TcpBuilder::new_v6());
+                if let Some(only_v6) = self.only_v6 {
+                    try!(builder.only_v6(only_v6));
+                }
+                builder
+            }
+        };
+        try!(builder.reuse_address(self.reuse_addr));
+        if self.reuse_port {
+            try!(builder.reuse_port(true));
+        }
+        try!(builder.bind(addr));
+
+        let listener = try!(builder.listen(self.backlog));
+        try!(listener.set_nonblocking(true));
+        Ok(unsafe { ::mio::tcp::TcpListener::from_raw_fd(listener.into_raw_fd()) })
+    }
+}
+
+impl Default for TcpListenerBuilder {
+    fn default() -> TcpListenerBuilder {
+        TcpListenerBuilder::new()
     }
 }
 
@@ -141,151 +360,778 @@ impl From<Shutdown> for mio::tcp::Shutdown {
     }
 }
 
+
+// Data drained from the socket ahead of the reads asking for it
 #[derive(Debug)]
-pub struct TcpStream(mio::tcp::TcpStream);
+struct ReadAhead {
+    // Zero-initialized, `buf[pos..filled]` has not been read yet
+    buf: Vec<u8>,
+    pos: usize,
+    filled: usize,
+    // Capacity set by `set_read_ahead`, applied once the buffered data has been read.
+    // `None` if read-ahead has been disabled.
+    cap: Option<usize>,
+}
+
+impl ReadAhead {
+    fn new(cap: usize) -> ReadAhead {
+        ReadAhead {
+            buf: vec![0; cap],
+            pos: 0,
+            filled: 0,
+            cap: Some(cap),
+        }
+    }
+
+    fn buffered(&self) -> &[u8] {
+        &self.buf[self.pos..self.filled]
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const TCP_CORK: ::libc::c_int = 3;
+
+#[cfg(any(target_os = "macos",
+          target_os = "ios",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "netbsd",
+          target_os = "openbsd"))]
+const TCP_NOPUSH: ::libc::c_int = 4;
+
+#[derive(Debug)]
+pub struct TcpStream {
+    stream: mio::tcp::TcpStream,
+    read_ahead: Option<ReadAhead>,
+    write_buf: Option<Vec<u8>>,
+    activity: Arc<Activity>,
+    closed: Arc<AtomicBool>,
+    owner: IoOwner,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
 
 impl TcpStream {
+    fn new(stream: mio::tcp::TcpStream) -> TcpStream {
+        TcpStream {
+            stream: stream,
+            read_ahead: None,
+            write_buf: None,
+            activity: Arc::new(Activity::new()),
+            closed: Arc::new(AtomicBool::new(false)),
+            owner: IoOwner::new(),
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+
+    /// Connect to `addr`. Inside a coroutine it blocks until the connection has been
+    /// established, so that errors like ECONNREFUSED are reported right here. Host names are
+    /// resolved by the calling thread, look them up with `net::dns::resolve` first to keep
+    /// the Processor running meanwhile.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
-        super::each_addr(addr, ::mio::tcp::TcpStream::connect).map(TcpStream)
+        super::each_addr(addr, |addr| {
+            let stream = TcpStream::new(try!(::mio::tcp::TcpStream::connect(addr)));
+            try!(stream.wait_connected());
+            Ok(stream)
+        })
+    }
+
+    fn wait_connected(&self) -> io::Result<()> {
+        let scheduler = match Scheduler::instance() {
+            Some(scheduler) => scheduler,
+            None => return Ok(()),
+        };
+
+        try!(self.owner.check());
+        try!(scheduler.wait_event(&self.stream, EventSet::writable()));
+        match try!(self.take_error()) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Get and clear the pending error of the socket (SO_ERROR)
+    #[cfg(unix)]
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        let err: ::libc::c_int = try!(super::getsockopt(self.as_raw_fd(),
+                                                        ::libc::SOL_SOCKET,
+                                                        ::libc::SO_ERROR));
+        if err == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(io::Error::from_raw_os_error(err)))
+        }
+    }
+
+    /// Get and clear the pending error of the socket (SO_ERROR)
+    #[cfg(not(unix))]
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        match self.stream.take_socket_error() {
+            Ok(()) => Ok(None),
+            Err(err) => Ok(Some(err)),
+        }
     }
 
     pub fn peer_addr(&self) -> io::Result<SocketAddr> {
-        self.0.peer_addr()
+        self.stream.peer_addr()
     }
 
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.0.local_addr()
+        self.stream.local_addr()
     }
 
+    /// Clone the stream. The clone shares the activity tracking and the closed state
+    /// (see `close_both`) with this stream.
     pub fn try_clone(&self) -> io::Result<TcpStream> {
-        let stream = try!(self.0.try_clone());
+        let stream = try!(self.stream.try_clone());
 
-        Ok(TcpStream(stream))
+        let mut cloned = TcpStream::new(stream);
+        cloned.activity = self.activity.clone();
+        cloned.closed = self.closed.clone();
+        cloned.owner = self.owner.clone();
+        cloned.read_timeout = self.read_timeout;
+        cloned.write_timeout = self.write_timeout;
+        Ok(cloned)
+    }
+
+    /// Close the connection for this stream and all of its clones.
+    ///
+    /// Coroutines parked reading from or writing to any of the clones are woken up, and
+    /// all further operations on them fail with `NotConnected`. Dropping a clone only
+    /// closes its own descriptor, so the others would be kept waiting for the peer.
+    pub fn close_both(&self) -> io::Result<()> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        match self.stream.shutdown(mio::tcp::Shutdown::Both) {
+            // The peer may have reset the connection already
+            Err(ref err) if err.kind() == ErrorKind::NotConnected => Ok(()),
+            ret => ret,
+        }
+    }
+
+    /// Whether `close_both` has been called on this stream or one of its clones
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    fn check_closed(&self) -> io::Result<()> {
+        try!(self.owner.check());
+
+        if self.is_closed() {
+            Err(io::Error::new(ErrorKind::NotConnected, "stream has been closed"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Time of the last successful read or write, used by `IdleReaper`
+    pub fn activity(&self) -> &Arc<Activity> {
+        &self.activity
     }
 
     pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
-        self.0.shutdown(From::from(how))
+        self.stream.shutdown(From::from(how))
     } 
 
-    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
-        // TcpStreamExt::set_read_timeout(&self, dur)
-        self.0.set_read_timeout(&self, dur)
+    /// Set how long a read waits for data before failing with `TimedOut`, `None` waits
+    /// forever. Applies to this handle only, clones made afterwards start with the same value.
+    pub fn set_read_timeout(&mut self, dur: Option<Duration>) {
+        self.read_timeout = dur;
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    /// Set how long a write or flush waits for the socket to become writable before failing
+    /// with `TimedOut`, `None` waits forever. Applies to this handle only, like
+    /// `set_read_timeout`.
+    pub fn set_write_timeout(&mut self, dur: Option<Duration>) {
+        self.write_timeout = dur;
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout
+    }
+
+    /// Enable read-ahead with an internal buffer of `cap` bytes, or disable it with `None`.
+    ///
+    /// With read-ahead enabled, every time the socket becomes readable it is drained into the
+    /// internal buffer (up to `cap` bytes), so that subsequent small reads are satisfied without
+    /// issuing any system calls. Data already buffered is handed out by the next reads before
+    /// the buffer is resized or dropped.
+    pub fn set_read_ahead(&mut self, cap: Option<usize>) {
+        if let Some(cap) = cap {
+            assert!(cap > 0, "Read-ahead buffer must not be empty");
+        }
+
+        if self.read_ahead.is_none() {
+            self.read_ahead = cap.map(ReadAhead::new);
+            return;
+        }
+
+        self.read_ahead.as_mut().unwrap().cap = cap;
+        self.resize_read_ahead();
+    }
+
+    // Apply the capacity set by `set_read_ahead` once the buffered data has been read
+    fn resize_read_ahead(&mut self) {
+        let cap = match self.read_ahead {
+            Some(ref ra) if ra.buffered().is_empty() => ra.cap,
+            _ => return,
+        };
+
+        match cap {
+            Some(cap) => {
+                let ra = self.read_ahead.as_mut().unwrap();
+                if ra.buf.len() != cap {
+                    ra.buf = vec![0; cap];
+                }
+            }
+            None => self.read_ahead = None,
+        }
+    }
+
+    /// Hold back partial frames (TCP_CORK on Linux, TCP_NOPUSH on BSDs) until uncorked
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn set_cork(&self, cork: bool) -> io::Result<()> {
+        super::setsockopt(self.as_raw_fd(), ::libc::IPPROTO_TCP, TCP_CORK, cork as ::libc::c_int)
+    }
+
+    /// Hold back partial frames (TCP_CORK on Linux, TCP_NOPUSH on BSDs) until uncorked
+    #[cfg(any(target_os = "macos",
+              target_os = "ios",
+              target_os = "freebsd",
+              target_os = "dragonfly",
+              target_os = "netbsd",
+              target_os = "openbsd"))]
+    pub fn set_cork(&self, cork: bool) -> io::Result<()> {
+        super::setsockopt(self.as_raw_fd(),
+                          ::libc::IPPROTO_TCP,
+                          TCP_NOPUSH,
+                          cork as ::libc::c_int)
+    }
+
+    /// Hold back partial frames (TCP_CORK on Linux, TCP_NOPUSH on BSDs) until uncorked
+    #[cfg(not(any(target_os = "linux",
+                  target_os = "android",
+                  target_os = "macos",
+                  target_os = "ios",
+                  target_os = "freebsd",
+                  target_os = "dragonfly",
+                  target_os = "netbsd",
+                  target_os = "openbsd")))]
+    pub fn set_cork(&self, _cork: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Run `f` with the stream corked, everything written in `f` is pushed out together
+    /// after it returns.
+    pub fn cork<F, R>(&mut self, f: F) -> io::Result<R>
+        where F: FnOnce(&mut TcpStream) -> io::Result<R>
+    {
+        try!(self.set_cork(true));
+        let ret = f(self);
+        let flushed = io::Write::flush(self);
+        let uncorked = self.set_cork(false);
+
+        let ret = try!(ret);
+        try!(flushed);
+        try!(uncorked);
+        Ok(ret)
+    }
+
+    /// Coalesce writes smaller than `cap` bytes into an internal buffer, or disable it with
+    /// `None`. Buffered data is sent when the buffer is full or on `flush()`.
+    ///
+    /// Dropping the stream flushes the buffer best-effort only: inside of its Scheduler it
+    /// waits up to the write timeout, elsewhere it sends what the socket takes right away.
+    /// Data which could not be sent is lost, call `flush()` to see the errors.
+    pub fn set_write_coalescing(&mut self, cap: Option<usize>) -> io::Result<()> {
+        try!(io::Write::flush(self));
+
+        self.write_buf = cap.map(|cap| {
+            assert!(cap > 0, "Write coalescing buffer must not be empty");
+            Vec::with_capacity(cap)
+        });
+        Ok(())
+    }
+
+    /// Number of bytes in the read-ahead buffer
+    pub fn read_ahead_len(&self) -> usize {
+        self.read_ahead.as_ref().map(|ra| ra.buffered().len()).unwrap_or(0)
     }
 }
 
+fn read_stream(stream: &mut mio::tcp::TcpStream,
+               buf: &mut [u8],
+               deadline: Option<Instant>)
+               -> io::Result<usize> {
+    use mio::TryRead;
+
+    loop {
+        match stream.try_read(buf) {
+            Ok(None) => {
+                debug!("TcpStream read WouldBlock");
+                break;
+            }
+            Ok(Some(len)) => {
+                debug!("TcpStream read {} bytes", len);
+                return Ok(len);
+            }
+            Err(ref err) if err.kind() == ErrorKind::NotConnected => {
+                // If the socket is still still connecting, just register it into the loop
+                debug!("Read: Going to register event, socket is not connected");
+                try!(super::wait_until(&*stream, EventSet::readable(), deadline));
+                debug!("Read: Got read event");
+                try!(stream.take_socket_error());
+            }
+            Err(err) => {
+                return Err(err);
+            }
+        }
+    }
+
+    loop {
+        debug!("Read: Going to register event");
+        try!(super::wait_until(&*stream, EventSet::readable(), deadline));
+        debug!("Read: Got read event");
+
+        match stream.try_read(buf) {
+            Ok(None) => {
+                debug!("TcpStream read WouldBlock");
+            }
+            Ok(Some(len)) => {
+                debug!("TcpStream read {} bytes", len);
+                return Ok(len);
+            }
+            Err(err) => {
+                return Err(err);
+            }
+        }
+    }
+}
+
+// Fill the read-ahead buffer, waiting for the socket to become readable only if it is empty
+fn fill_read_ahead(stream: &mut mio::tcp::TcpStream,
+                   ra: &mut ReadAhead,
+                   deadline: Option<Instant>)
+                   -> io::Result<()> {
+    use mio::TryRead;
+
+    ra.pos = 0;
+    ra.filled = 0;
+
+    let cap = ra.buf.len();
+    let mut filled = try!(read_stream(stream, &mut ra.buf[..], deadline));
+
+    // Drain whatever else the socket has got right now
+    while filled > 0 && filled < cap {
+        match stream.try_read(&mut ra.buf[filled..]) {
+            Ok(Some(0)) | Ok(None) => break,
+            Ok(Some(len)) => filled += len,
+            Err(..) => break,
+        }
+    }
+
+    ra.filled = filled;
+    Ok(())
+}
+
 impl io::Read for TcpStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        use mio::TryRead;
+        let deadline = super::deadline(self.read_timeout);
+        self.read_until(buf, deadline)
+    }
+}
+
+impl DeadlineIo for TcpStream {
+    fn read_until(&mut self, buf: &mut [u8], deadline: Option<Instant>) -> io::Result<usize> {
+        try!(self.check_closed());
+        let ret = self.read_buffered(buf, deadline);
+        // Woken up by `close_both` of a clone
+        try!(self.check_closed());
+
+        let len = try!(ret);
+        if len > 0 {
+            self.activity.touch();
+        }
+        Ok(len)
+    }
+
+    fn write_until(&mut self, buf: &[u8], deadline: Option<Instant>) -> io::Result<usize> {
+        try!(self.check_closed());
+        let ret = self.write_buffered(buf, deadline);
+        try!(self.check_closed());
+
+        let len = try!(ret);
+        if len > 0 {
+            self.activity.touch();
+        }
+        Ok(len)
+    }
+}
+
+impl TcpStream {
+    /// Read directly into the free space of `ring`, filling both parts of wrapped-around free
+    /// space with a single `readv(2)`. Returns the number of bytes read, 0 on EOF or if the
+    /// ring is full.
+    #[cfg(unix)]
+    pub fn read_into_ring(&mut self, ring: &mut RingBuf) -> io::Result<usize> {
+        try!(self.check_closed());
+
+        if ring.is_full() {
+            return Ok(0);
+        }
+
+        let ret = if self.read_ahead_len() > 0 {
+            // Hand out what has been read ahead already first
+            let (first, _) = ring.free_slices_mut();
+            let deadline = super::deadline(self.read_timeout);
+            self.read_buffered(first, deadline)
+        } else {
+            self.readv_ring(ring)
+        };
+        // Woken up by `close_both` of a clone
+        try!(self.check_closed());
+
+        let len = try!(ret);
+        ring.commit(len);
+        if len > 0 {
+            self.activity.touch();
+        }
+        Ok(len)
+    }
+
+    /// Read until EOF into `buf`, failing with `io::LengthExceeded` once more than
+    /// `max_len` bytes have been read, see `io::read_to_end_capped`
+    pub fn read_to_end_capped(&mut self, buf: &mut Vec<u8>, max_len: usize) -> io::Result<usize> {
+        ::io::read_to_end_capped(self, buf, max_len)
+    }
+
+    /// Read into `bufs` in order with a single `readv(2)`. Data read ahead already is handed
+    /// out first without touching the socket.
+    #[cfg(unix)]
+    pub fn read_vectored(&mut self, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        try!(self.check_closed());
+
+        let ret = if self.read_ahead_len() > 0 {
+            let ra = self.read_ahead.as_mut().unwrap();
+            let mut len = 0;
+            for buf in bufs.iter_mut() {
+                let n = try!(ra.buffered().read(buf));
+                ra.pos += n;
+                len += n;
+            }
+            Ok(len)
+        } else {
+            self.readv_stream(bufs)
+        };
+        try!(self.check_closed());
+
+        let len = try!(ret);
+        if len > 0 {
+            self.activity.touch();
+        }
+        Ok(len)
+    }
+
+    #[cfg(unix)]
+    fn readv_stream(&mut self, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        let deadline = super::deadline(self.read_timeout);
 
         loop {
-            match self.0.try_read(buf) {
-                Ok(None) => {
-                    debug!("TcpStream read WouldBlock");
-                    break;
-                }
+            match vectored::try_read_vectored(self.as_raw_fd(), bufs) {
                 Ok(Some(len)) => {
-                    debug!("TcpStream read {} bytes", len);
+                    debug!("TcpStream readv {} bytes", len);
                     return Ok(len);
                 }
-                Err(ref err) if err.kind() == ErrorKind::NotConnected => {
-                    // If the socket is still still connecting, just register it into the loop
-                    debug!("Read: Going to register event, socket is not connected");
-                    try!(Scheduler::instance().unwrap().wait_event(&self.0, EventSet::readable()));
-                    debug!("Read: Got read event");
-                    try!(self.take_socket_error());
+                Ok(None) => {
+                    debug!("TcpStream readv WouldBlock; going to register event");
                 }
-                Err(err) => {
-                    return Err(err);
+                Err(ref err) if err.kind() == ErrorKind::NotConnected => {
+                    debug!("TcpStream readv on a connecting socket; going to register event");
                 }
+                Err(err) => return Err(err),
             }
+
+            try!(super::wait_until(&self.stream, EventSet::readable(), deadline));
         }
+    }
+
+    #[cfg(unix)]
+    fn readv_ring(&mut self, ring: &mut RingBuf) -> io::Result<usize> {
+        let deadline = super::deadline(self.read_timeout);
+        let (first, second) = ring.free_slices_mut();
+        let iov = [::sys::iovec {
+                       iov_base: first.as_mut_ptr() as *mut ::libc::c_void,
+                       iov_len: first.len(),
+                   },
+                   ::sys::iovec {
+                       iov_base: second.as_mut_ptr() as *mut ::libc::c_void,
+                       iov_len: second.len(),
+                   }];
+        let iovcnt = if second.is_empty() { 1 } else { 2 };
 
         loop {
-            debug!("Read: Going to register event");
-            try!(Scheduler::instance().unwrap().wait_event(&self.0, EventSet::readable()));
-            debug!("Read: Got read event");
+            let ret = unsafe { ::sys::readv(self.as_raw_fd(), iov.as_ptr(), iovcnt) };
+            if ret >= 0 {
+                debug!("TcpStream readv {} bytes", ret);
+                return Ok(ret as usize);
+            }
 
-            match self.0.try_read(buf) {
-                Ok(None) => {
-                    debug!("TcpStream read WouldBlock");
-                }
-                Ok(Some(len)) => {
-                    debug!("TcpStream read {} bytes", len);
-                    return Ok(len);
-                }
-                Err(err) => {
-                    return Err(err);
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                ErrorKind::WouldBlock | ErrorKind::NotConnected => {
+                    debug!("TcpStream readv WouldBlock; going to register event");
+                    try!(super::wait_until(&self.stream, EventSet::readable(), deadline));
                 }
+                ErrorKind::Interrupted => {}
+                _ => return Err(err),
+            }
+        }
+    }
+
+    fn read_buffered(&mut self, buf: &mut [u8], deadline: Option<Instant>) -> io::Result<usize> {
+        self.resize_read_ahead();
+
+        let ra = match self.read_ahead {
+            Some(ref mut ra) => ra,
+            None => return read_stream(&mut self.stream, buf, deadline),
+        };
+
+        if ra.buffered().is_empty() {
+            // Large reads don't benefit from being buffered
+            if buf.len() >= ra.buf.len() {
+                return read_stream(&mut self.stream, buf, deadline);
             }
+
+            try!(fill_read_ahead(&mut self.stream, ra, deadline));
+        }
+
+        let len = try!(ra.buffered().read(buf));
+        ra.pos += len;
+        Ok(len)
+    }
+}
+
+fn write_stream(stream: &mut mio::tcp::TcpStream,
+                buf: &[u8],
+                deadline: Option<Instant>)
+                -> io::Result<usize> {
+    use mio::TryWrite;
+
+    loop {
+        match stream.try_write(buf) {
+            Ok(None) => {
+                debug!("TcpStream write WouldBlock");
+                break;
+            }
+            Ok(Some(len)) => {
+                debug!("TcpStream written {} bytes", len);
+                return Ok(len);
+            }
+            Err(ref err) if err.kind() == ErrorKind::NotConnected => {
+                // If the socket is still still connecting, just register it into the loop
+                debug!("Write: Going to register event, socket is not connected");
+                try!(super::wait_until(&*stream, EventSet::writable(), deadline));
+                debug!("Write: Got write event");
+                try!(stream.take_socket_error());
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    loop {
+        debug!("Write: Going to register event");
+        try!(super::wait_until(&*stream, EventSet::writable(), deadline));
+        debug!("Write: Got write event");
+
+        match stream.try_write(buf) {
+            Ok(None) => {
+                debug!("TcpStream write WouldBlock");
+            }
+            Ok(Some(len)) => {
+                debug!("TcpStream written {} bytes", len);
+                return Ok(len);
+            }
+            Err(err) => return Err(err),
         }
     }
 }
 
 impl io::Write for TcpStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        use mio::TryWrite;
+        let deadline = super::deadline(self.write_timeout);
+        self.write_until(buf, deadline)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.check_closed());
+        let deadline = super::deadline(self.write_timeout);
+        try!(self.flush_write_buf(deadline));
+        flush_stream(&mut self.stream, deadline)
+    }
+}
+
+impl TcpStream {
+    fn write_buffered(&mut self, buf: &[u8], deadline: Option<Instant>) -> io::Result<usize> {
+        let fits = match self.write_buf {
+            Some(ref wbuf) => wbuf.len() + buf.len() <= wbuf.capacity(),
+            None => return write_stream(&mut self.stream, buf, deadline),
+        };
+
+        if !fits {
+            try!(self.flush_write_buf(deadline));
+            try!(flush_stream(&mut self.stream, deadline));
+        }
+
+        match self.write_buf {
+            Some(ref mut wbuf) if buf.len() < wbuf.capacity() => {
+                wbuf.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            _ => write_stream(&mut self.stream, buf, deadline),
+        }
+    }
+
+    /// Write `bufs` in order with a single `writev(2)`. Data held back by write coalescing is
+    /// flushed first.
+    #[cfg(unix)]
+    pub fn write_vectored(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        try!(self.check_closed());
+        let deadline = super::deadline(self.write_timeout);
+        try!(self.flush_write_buf(deadline));
+        let ret = self.writev_stream(bufs, deadline);
+        try!(self.check_closed());
+
+        let len = try!(ret);
+        if len > 0 {
+            self.activity.touch();
+        }
+        Ok(len)
+    }
 
+    #[cfg(unix)]
+    fn writev_stream(&mut self, bufs: &[&[u8]], deadline: Option<Instant>) -> io::Result<usize> {
         loop {
-            match self.0.try_write(buf) {
-                Ok(None) => {
-                    debug!("TcpStream write WouldBlock");
-                    break;
-                }
+            match vectored::try_write_vectored(self.as_raw_fd(), bufs) {
                 Ok(Some(len)) => {
-                    debug!("TcpStream written {} bytes", len);
+                    debug!("TcpStream writev {} bytes", len);
                     return Ok(len);
                 }
+                Ok(None) => {
+                    debug!("TcpStream writev WouldBlock; going to register event");
+                }
                 Err(ref err) if err.kind() == ErrorKind::NotConnected => {
-                    // If the socket is still still connecting, just register it into the loop
-                    debug!("Write: Going to register event, socket is not connected");
-                    try!(Scheduler::instance().unwrap().wait_event(&self.0, EventSet::writable()));
-                    debug!("Write: Got write event");
-                    try!(self.take_socket_error());
+                    debug!("TcpStream writev on a connecting socket; going to register event");
                 }
                 Err(err) => return Err(err),
             }
-        }
 
-        loop {
-            debug!("Write: Going to register event");
-            try!(Scheduler::instance().unwrap().wait_event(&self.0, EventSet::writable()));
-            debug!("Write: Got write event");
+            try!(super::wait_until(&self.stream, EventSet::writable(), deadline));
+        }
+    }
 
-            match self.0.try_write(buf) {
-                Ok(None) => {
-                    debug!("TcpStream write WouldBlock");
+    fn flush_write_buf(&mut self, deadline: Option<Instant>) -> io::Result<()> {
+        if let Some(ref mut wbuf) = self.write_buf {
+            let mut written = 0;
+            while written < wbuf.len() {
+                match write_stream(&mut self.stream, &wbuf[written..], deadline) {
+                    Ok(0) => {
+                        wbuf.drain(..written);
+                        return Err(io::Error::new(ErrorKind::WriteZero,
+                                                  "failed to write the buffered data"));
+                    }
+                    Ok(len) => written += len,
+                    Err(err) => {
+                        wbuf.drain(..written);
+                        return Err(err);
+                    }
                 }
-                Ok(Some(len)) => {
-                    debug!("TcpStream written {} bytes", len);
-                    return Ok(len);
+            }
+            wbuf.clear();
+        }
+
+        Ok(())
+    }
+
+    // Send as much of the coalesced data as the socket takes without blocking
+    fn try_flush_write_buf(&mut self) -> io::Result<()> {
+        use mio::TryWrite;
+
+        if let Some(ref mut wbuf) = self.write_buf {
+            let mut written = 0;
+            let mut ret = Ok(());
+            while written < wbuf.len() {
+                match self.stream.try_write(&wbuf[written..]) {
+                    Ok(Some(0)) | Ok(None) => {
+                        ret = Err(io::Error::new(ErrorKind::WouldBlock, "socket buffer is full"));
+                        break;
+                    }
+                    Ok(Some(len)) => written += len,
+                    Err(err) => {
+                        ret = Err(err);
+                        break;
+                    }
                 }
-                Err(err) => return Err(err),
             }
+            wbuf.drain(..written);
+            return ret;
         }
+
+        Ok(())
     }
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        match self.0.flush() {
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let unflushed = self.write_buf.as_ref().map_or(0, |wbuf| wbuf.len());
+        if unflushed == 0 || self.is_closed() {
+            return;
+        }
+
+        // Best-effort, see `set_write_coalescing`
+        let ret = if Scheduler::instance().is_some() && self.owner.check().is_ok() {
+            let deadline = super::deadline(self.write_timeout);
+            self.flush_write_buf(deadline)
+        } else {
+            self.try_flush_write_buf()
+        };
+
+        if let Err(err) = ret {
+            let lost = self.write_buf.as_ref().map_or(0, |wbuf| wbuf.len());
+            warn!("TcpStream dropped with {} coalesced bytes unsent: {}", lost, err);
+        }
+    }
+}
+
+fn flush_stream(stream: &mut mio::tcp::TcpStream, deadline: Option<Instant>) -> io::Result<()> {
+    use std::io::Write;
+
+    match stream.flush() {
+        Ok(..) => return Ok(()),
+        Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+            debug!("TcpStream flush WouldBlock");
+        }
+        Err(err) => return Err(err),
+    }
+
+    loop {
+        debug!("Write: Going to register event");
+        try!(super::wait_until(&*stream, EventSet::writable(), deadline));
+        debug!("Write: Got write event");
+
+        match stream.flush() {
             Ok(..) => return Ok(()),
             Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
                 debug!("TcpStream flush WouldBlock");
             }
             Err(err) => return Err(err),
         }
-
-        loop {
-            debug!("Write: Going to register event");
-            try!(Scheduler::instance().unwrap().wait_event(&self.0, EventSet::writable()));
-            debug!("Write: Got write event");
-
-            match self.0.flush() {
-                Ok(..) => return Ok(()),
-                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
-                    debug!("TcpStream flush WouldBlock");
-                }
-                Err(err) => return Err(err),
-            }
-        }
     }
 }
 
@@ -293,26 +1139,364 @@ impl Deref for TcpStream {
     type Target = ::mio::tcp::TcpStream;
 
     fn deref(&self) -> &::mio::tcp::TcpStream {
-        &self.0
+        &self.stream
     }
 }
 
 impl DerefMut for TcpStream {
     fn deref_mut(&mut self) -> &mut ::mio::tcp::TcpStream {
-        &mut self.0
+        &mut self.stream
     }
 }
 
 #[cfg(unix)]
 impl AsRawFd for TcpStream {
     fn as_raw_fd(&self) -> RawFd {
-        self.0.as_raw_fd()
+        self.stream.as_raw_fd()
     }
 }
 
 #[cfg(unix)]
 impl FromRawFd for TcpStream {
     unsafe fn from_raw_fd(fd: RawFd) -> TcpStream {
-        TcpStream(FromRawFd::from_raw_fd(fd))
+        TcpStream::new(FromRawFd::from_raw_fd(fd))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use io::RingBuf;
+    use scheduler::Scheduler;
+
+    #[test]
+    fn test_connect_refused() {
+        Scheduler::new()
+            .run(|| {
+                let addr = {
+                    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                    listener.local_addr().unwrap()
+                };
+
+                let err = TcpStream::connect(addr).err().unwrap();
+                assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_connect_resolved() {
+        use std::net::{SocketAddr, ToSocketAddrs};
+        use std::rc::Rc;
+        use std::vec;
+
+        use net::dns;
+
+        // Not Send, so it has to be resolved by the calling coroutine
+        struct LocalAddr(Rc<SocketAddr>);
+
+        impl ToSocketAddrs for LocalAddr {
+            type Iter = vec::IntoIter<SocketAddr>;
+
+            fn to_socket_addrs(&self) -> io::Result<vec::IntoIter<SocketAddr>> {
+                Ok(vec![*self.0].into_iter())
+            }
+        }
+
+        Scheduler::new()
+            .run(|| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                TcpStream::connect(LocalAddr(Rc::new(addr))).unwrap();
+
+                let addrs = dns::resolve(("localhost", addr.port())).unwrap();
+                let addrs = addrs.into_iter()
+                                 .filter(|a| match *a {
+                                     SocketAddr::V4(..) => true,
+                                     SocketAddr::V6(..) => false,
+                                 })
+                                 .collect::<Vec<_>>();
+                TcpStream::connect(&addrs[..]).unwrap();
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_defer_accept() {
+        Scheduler::new()
+            .run(|| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = listener.local_addr().unwrap();
+                let ret = listener.set_defer_accept(5);
+
+                if cfg!(any(target_os = "linux", target_os = "android")) {
+                    ret.unwrap();
+
+                    // Surfaced only once the client has sent something
+                    let mut client = TcpStream::connect(addr).unwrap();
+                    let wait = Duration::from_millis(50);
+                    assert!(listener.accept_timeout(wait).unwrap().is_none());
+
+                    client.write_all(b"ping").unwrap();
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let mut buf = [0u8; 4];
+                    stream.read_exact(&mut buf).unwrap();
+                    assert_eq!(&buf, b"ping");
+
+                    listener.set_defer_accept(0).unwrap();
+                } else if !cfg!(target_os = "freebsd") {
+                    assert_eq!(ret.err().unwrap().kind(), io::ErrorKind::Other);
+                    listener.set_defer_accept(0).unwrap();
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_take_error() {
+        Scheduler::new()
+            .run(|| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                let mut stream = TcpStream::connect(addr).unwrap();
+                assert!(stream.take_error().unwrap().is_none());
+
+                stream.write_all(b"ping").unwrap();
+                assert!(stream.take_error().unwrap().is_none());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_close_both() {
+        Scheduler::new()
+            .run(|| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                let _client = TcpStream::connect(addr).unwrap();
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = stream.try_clone().unwrap();
+                let mut ring_reader = stream.try_clone().unwrap();
+
+                let hdl = Scheduler::spawn(move || {
+                    let mut buf = [0u8; 16];
+                    reader.read(&mut buf).err().unwrap().kind()
+                });
+                let ring_hdl = Scheduler::spawn(move || {
+                    let mut ring = RingBuf::with_capacity(16);
+                    ring_reader.read_into_ring(&mut ring).err().unwrap().kind()
+                });
+
+                // Let the readers park on the socket
+                Scheduler::sched();
+
+                stream.close_both().unwrap();
+                drop(stream);
+
+                assert_eq!(hdl.join().unwrap(), io::ErrorKind::NotConnected);
+                assert_eq!(ring_hdl.join().unwrap(), io::ErrorKind::NotConnected);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_timeouts() {
+        Scheduler::new()
+            .run(|| {
+                let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                listener.set_timeout(Some(Duration::from_millis(20)));
+                assert_eq!(listener.accept().err().unwrap().kind(), io::ErrorKind::TimedOut);
+
+                let mut client = TcpStream::connect(addr).unwrap();
+                let (mut stream, _) = listener.accept().unwrap();
+
+                stream.set_read_timeout(Some(Duration::from_millis(20)));
+                let mut buf = [0u8; 4];
+                assert_eq!(stream.read(&mut buf).err().unwrap().kind(), io::ErrorKind::TimedOut);
+
+                // Still usable after a timeout
+                client.write_all(b"ping").unwrap();
+                assert_eq!(stream.read(&mut buf).unwrap(), 4);
+            })
+            .unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_listener_builder() {
+        Scheduler::new()
+            .run(|| {
+                let builder = TcpListenerBuilder::new()
+                                  .reuse_port(cfg!(target_os = "linux"))
+                                  .backlog(16);
+                let first = builder.bind("127.0.0.1:0").unwrap();
+                let addr = first.local_addr().unwrap();
+
+                if cfg!(target_os = "linux") {
+                    let second = builder.bind(addr).unwrap();
+                    assert_eq!(second.local_addr().unwrap(), addr);
+                } else {
+                    assert!(builder.bind(addr).is_err());
+                }
+
+                // The second listener is closed already
+                let _client = TcpStream::connect(addr).unwrap();
+                assert!(first.accept_timeout(Duration::from_secs(1)).unwrap().is_some());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_accept_timeout() {
+        Scheduler::new()
+            .run(|| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                let started = Instant::now();
+                assert!(listener.accept_timeout(Duration::from_millis(20)).unwrap().is_none());
+                assert!(started.elapsed() >= Duration::from_millis(20));
+
+                let _client = TcpStream::connect(addr).unwrap();
+                assert!(listener.accept_timeout(Duration::from_secs(1)).unwrap().is_some());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_vectored() {
+        Scheduler::new()
+            .run(|| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+                let (mut stream, _) = listener.accept().unwrap();
+
+                let len = client.write_vectored(&[&b"head"[..], &b"body"[..]]).unwrap();
+                assert_eq!(len, 8);
+
+                let mut head = [0u8; 4];
+                let mut body = [0u8; 4];
+                let mut read = 0;
+                while read < 8 {
+                    let (h, b) = (&mut head[..], &mut body[..]);
+                    let len = if read < 4 {
+                        stream.read_vectored(&mut [&mut h[read..], b]).unwrap()
+                    } else {
+                        stream.read(&mut b[read - 4..]).unwrap()
+                    };
+                    assert!(len > 0);
+                    read += len;
+                }
+                assert_eq!(&head, b"head");
+                assert_eq!(&body, b"body");
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_read_ahead() {
+        Scheduler::new()
+            .run(|| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+                let (mut stream, _) = listener.accept().unwrap();
+
+                stream.set_read_ahead(Some(64));
+                client.write_all(b"hello world").unwrap();
+
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf).unwrap();
+                assert_eq!(&buf, b"hello");
+
+                // Disabled only after the buffered data has been read
+                let buffered = stream.read_ahead_len();
+                stream.set_read_ahead(None);
+                assert_eq!(stream.read_ahead_len(), buffered);
+
+                let mut rest = [0u8; 6];
+                stream.read_exact(&mut rest).unwrap();
+                assert_eq!(&rest, b" world");
+                assert_eq!(stream.read_ahead_len(), 0);
+                assert!(stream.read_ahead.is_none());
+
+                // Shrunk once drained
+                stream.set_read_ahead(Some(64));
+                client.write_all(b"abcdef").unwrap();
+                let mut buf = [0u8; 2];
+                stream.read_exact(&mut buf).unwrap();
+                stream.set_read_ahead(Some(4));
+                let mut rest = [0u8; 4];
+                stream.read_exact(&mut rest).unwrap();
+                assert_eq!(&rest, b"cdef");
+
+                client.write_all(b"gh").unwrap();
+                stream.read_exact(&mut buf).unwrap();
+                assert_eq!(&buf, b"gh");
+                assert_eq!(stream.read_ahead.as_ref().unwrap().buf.len(), 4);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_write_coalescing() {
+        Scheduler::new()
+            .run(|| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+                let (mut stream, _) = listener.accept().unwrap();
+
+                client.set_write_coalescing(Some(64)).unwrap();
+                client.write_all(b"ab").unwrap();
+                client.write_all(b"cd").unwrap();
+
+                // Held back until flushed
+                stream.set_read_timeout(Some(Duration::from_millis(20)));
+                let mut buf = [0u8; 4];
+                assert_eq!(stream.read(&mut buf).err().unwrap().kind(), io::ErrorKind::TimedOut);
+
+                client.flush().unwrap();
+                stream.set_read_timeout(Some(Duration::from_secs(1)));
+                stream.read_exact(&mut buf).unwrap();
+                assert_eq!(&buf, b"abcd");
+
+                // Sent when dropped without a flush
+                client.write_all(b"ef").unwrap();
+                drop(client);
+
+                let mut rest = Vec::new();
+                stream.read_to_end(&mut rest).unwrap();
+                assert_eq!(rest, b"ef");
+            })
+            .unwrap();
+    }
+
+    // The owner of an I/O object is only checked in debug builds
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_wrong_scheduler() {
+        let listener = Scheduler::new()
+                           .run(|| {
+                               let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                               let _client = TcpStream::connect(listener.local_addr().unwrap())
+                                                 .unwrap();
+                               listener.accept().unwrap();
+                               listener
+                           })
+                           .unwrap();
+
+        Scheduler::new()
+            .run(move || {
+                let err = listener.accept().err().unwrap();
+                assert!(err.get_ref().unwrap().is::<::scheduler::WrongScheduler>());
+            })
+            .unwrap();
     }
 }
@@ -21,51 +21,160 @@
 
 //! UDP
 
+use std::cmp;
 use std::ops::{Deref, DerefMut};
 use std::io;
-use std::net::{ToSocketAddrs, SocketAddr};
+#[cfg(unix)]
+use std::mem;
+use std::net::{ToSocketAddrs, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[cfg(unix)]
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 
 use mio::EventSet;
 
-use scheduler::Scheduler;
+use scheduler::{IoOwner, Scheduler, Subscription};
 
-pub struct UdpSocket(::mio::udp::UdpSocket);
+pub struct UdpSocket {
+    socket: ::mio::udp::UdpSocket,
+    subscription: Option<Arc<Subscription>>,
+    owner: IoOwner,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
 
 impl UdpSocket {
+    fn new(socket: ::mio::udp::UdpSocket) -> UdpSocket {
+        UdpSocket {
+            socket: socket,
+            subscription: None,
+            owner: IoOwner::new(),
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+
     /// Returns a new, unbound, non-blocking, IPv4 UDP socket
     pub fn v4() -> io::Result<UdpSocket> {
-        Ok(UdpSocket(try!(::mio::udp::UdpSocket::v4())))
+        Ok(UdpSocket::new(try!(::mio::udp::UdpSocket::v4())))
     }
 
     /// Returns a new, unbound, non-blocking, IPv6 UDP socket
     pub fn v6() -> io::Result<UdpSocket> {
-        Ok(UdpSocket(try!(::mio::udp::UdpSocket::v6())))
+        Ok(UdpSocket::new(try!(::mio::udp::UdpSocket::v6())))
     }
 
     pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSocket> {
-        super::each_addr(addr, |a| ::mio::udp::UdpSocket::bound(&a)).map(UdpSocket)
+        super::each_addr(addr, |a| ::mio::udp::UdpSocket::bound(&a)).map(UdpSocket::new)
     }
 
+    /// Clone the socket. The clone does not share the multi-shot subscription.
     pub fn try_clone(&self) -> io::Result<UdpSocket> {
-        Ok(UdpSocket(try!(self.0.try_clone())))
+        let mut cloned = UdpSocket::new(try!(self.socket.try_clone()));
+        cloned.owner = self.owner.clone();
+        cloned.read_timeout = self.read_timeout;
+        cloned.write_timeout = self.write_timeout;
+        Ok(cloned)
+    }
+
+    /// Set how long a receive waits for a datagram before failing with `TimedOut`,
+    /// `None` waits forever
+    pub fn set_read_timeout(&mut self, dur: Option<Duration>) {
+        self.read_timeout = dur;
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    /// Set how long a send waits for the socket to become writable before failing with
+    /// `TimedOut`, `None` waits forever
+    pub fn set_write_timeout(&mut self, dur: Option<Duration>) {
+        self.write_timeout = dur;
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout
+    }
+
+    /// Enable or disable the multi-shot registration.
+    ///
+    /// When enabled, the socket stays registered in the event loop and readiness
+    /// notifications are queued instead of re-registering the socket on every blocking
+    /// `recv_from` or `send_to`, which is cheaper for tight receive loops.
+    pub fn set_multishot(&mut self, enabled: bool) -> io::Result<()> {
+        if enabled == self.subscription.is_some() {
+            return Ok(());
+        }
+
+        if enabled {
+            try!(self.owner.check());
+            let sched = Scheduler::instance().unwrap();
+            let interest = EventSet::readable() | EventSet::writable();
+            self.subscription = Some(try!(sched.subscribe(&self.socket, interest)));
+        } else if let Some(sub) = self.subscription.take() {
+            sub.cancel();
+        }
+
+        Ok(())
+    }
+
+    /// Whether the multi-shot registration is enabled
+    pub fn is_multishot(&self) -> bool {
+        self.subscription.is_some()
+    }
+
+    fn wait(&self, interest: EventSet, deadline: Option<Instant>) -> io::Result<()> {
+        try!(self.owner.check());
+
+        let sub = match self.subscription {
+            Some(ref sub) => sub,
+            None => return super::wait_until(&self.socket, interest, deadline),
+        };
+
+        let n = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now < deadline {
+                    sub.wait_timeout(deadline - now)
+                } else {
+                    0
+                }
+            }
+            None => sub.wait(),
+        };
+
+        if n == 0 {
+            if sub.is_closed() {
+                return Err(io::Error::new(io::ErrorKind::Other, "subscription closed"));
+            }
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for I/O event"));
+        }
+        Ok(())
+    }
+
+    fn read_deadline(&self) -> Option<Instant> {
+        super::deadline(self.read_timeout)
+    }
+
+    fn write_deadline(&self) -> Option<Instant> {
+        super::deadline(self.write_timeout)
     }
 
     pub fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], target: A) -> io::Result<usize> {
+        let deadline = self.write_deadline();
         let mut last_err = Ok(0);
         for addr in try!(target.to_socket_addrs()) {
-            match self.0.send_to(buf, &addr) {
+            match self.socket.send_to(buf, &addr) {
                 Ok(None) => {
                     debug!("UdpSocket send_to WOULDBLOCK");
 
                     loop {
-                        try!(Scheduler::instance()
-                                 .unwrap()
-                                 .wait_event(&self.0, EventSet::writable()));
+                        try!(self.wait(EventSet::writable(), deadline));
 
-                        match self.0.send_to(buf, &addr) {
+                        match self.socket.send_to(buf, &addr) {
                             Ok(None) => {
                                 warn!("UdpSocket send_to WOULDBLOCK");
                             }
@@ -89,7 +198,8 @@ impl UdpSocket {
     }
 
     pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
-        match try!(self.0.recv_from(buf)) {
+        let deadline = self.read_deadline();
+        match try!(self.socket.recv_from(buf)) {
             None => {
                 debug!("UdpSocket recv_from WOULDBLOCK");
             }
@@ -99,11 +209,14 @@ impl UdpSocket {
         }
 
         loop {
-            try!(Scheduler::instance().unwrap().wait_event(&self.0, EventSet::readable()));
+            try!(self.wait(EventSet::readable(), deadline));
 
-            match try!(self.0.recv_from(buf)) {
+            match try!(self.socket.recv_from(buf)) {
                 None => {
-                    warn!("UdpSocket recv_from WOULDBLOCK");
+                    // Spurious with multi-shot, the queued event may be a writable one
+                    if self.subscription.is_none() {
+                        warn!("UdpSocket recv_from WOULDBLOCK");
+                    }
                 }
                 Some(ret) => {
                     return Ok(ret);
@@ -113,30 +226,604 @@ impl UdpSocket {
     }
 }
 
+/// Socket options applied before binding, see `UdpSocket::bind_with_options`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UdpBindOptions {
+    /// Set `SO_REUSEADDR`, so that sockets bound to the wildcard address and to specific
+    /// addresses could share a port
+    pub reuse_addr: bool,
+    /// Set `SO_REUSEPORT`, so that several sockets could bind to the same address and port.
+    /// The kernel spreads the incoming datagrams over them, e.g. one socket per Processor.
+    pub reuse_port: bool,
+}
+
+#[cfg(unix)]
+fn bind_socket(addr: &SocketAddr, opts: &UdpBindOptions) -> io::Result<::mio::udp::UdpSocket> {
+    use std::os::unix::io::IntoRawFd;
+
+    use net2::UdpBuilder;
+    use net2::UdpSocketExt as Net2UdpSocketExt;
+    use net2::unix::UnixUdpBuilderExt;
+
+    let builder = match *addr {
+        SocketAddr::V4(..) => try!(UdpBuilder::new_v4()),
+        SocketAddr::V6(..) => try!(UdpBuilder::new_v6()),
+    };
+    try!(builder.reuse_address(opts.reuse_addr));
+    if opts.reuse_port {
+        try!(builder.reuse_port(true));
+    }
+
+    let socket = try!(builder.bind(addr));
+    try!(socket.set_nonblocking(true));
+    Ok(unsafe { ::mio::udp::UdpSocket::from_raw_fd(socket.into_raw_fd()) })
+}
+
+// The same address with another port
+#[cfg(unix)]
+fn with_port(addr: &SocketAddr, port: u16) -> SocketAddr {
+    match *addr {
+        SocketAddr::V4(ref a) => SocketAddr::V4(SocketAddrV4::new(*a.ip(), port)),
+        SocketAddr::V6(ref a) => {
+            SocketAddr::V6(SocketAddrV6::new(*a.ip(), port, a.flowinfo(), a.scope_id()))
+        }
+    }
+}
+
+#[cfg(unix)]
+impl UdpSocket {
+    /// Bind to `addr` with the socket options set in `opts`
+    pub fn bind_with_options<A: ToSocketAddrs>(addr: A,
+                                               opts: UdpBindOptions)
+                                               -> io::Result<UdpSocket> {
+        super::each_addr(addr, |a| bind_socket(a, &opts)).map(UdpSocket::new)
+    }
+
+    /// Bind one socket to each of `addrs`, all on the same port, e.g. to answer from the
+    /// address a request has been sent to on a multi-homed host.
+    ///
+    /// Addresses with port 0 get the port of the first socket. Fails without keeping any of
+    /// the sockets if binding any of them fails.
+    pub fn bind_all(addrs: &[SocketAddr], opts: UdpBindOptions) -> io::Result<Vec<UdpSocket>> {
+        let mut sockets: Vec<UdpSocket> = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            let addr = match sockets.first() {
+                Some(first) if addr.port() == 0 => with_port(addr, try!(first.local_addr()).port()),
+                _ => *addr,
+            };
+            sockets.push(UdpSocket::new(try!(bind_socket(&addr, &opts))));
+        }
+        Ok(sockets)
+    }
+
+    /// Set the default destination of `send` and only receive datagrams from `addr`
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        super::each_addr(addr, |addr| {
+            let (storage, len) = super::to_sockaddr(&addr);
+            let ret = unsafe {
+                ::libc::connect(self.as_raw_fd(),
+                                &storage as *const _ as *const ::libc::sockaddr,
+                                len)
+            };
+
+            if ret == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        })
+    }
+
+    /// Send a datagram to the address the socket is connected to
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.retry(EventSet::writable(), self.write_deadline(), || unsafe {
+            ::libc::send(self.as_raw_fd(),
+                         buf.as_ptr() as *const ::libc::c_void,
+                         buf.len(),
+                         0)
+        })
+    }
+
+    /// Receive a datagram from the address the socket is connected to
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let fd = self.as_raw_fd();
+        self.retry(EventSet::readable(), self.read_deadline(), || unsafe {
+            ::libc::recv(fd, buf.as_mut_ptr() as *mut ::libc::c_void, buf.len(), 0)
+        })
+    }
+
+    /// Receive a datagram without removing it from the queue, so that the next receive
+    /// returns it again
+    pub fn peek_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        use libc;
+        use sys;
+
+        let fd = self.as_raw_fd();
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut addr_len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+        let len = try!(self.retry(EventSet::readable(), self.read_deadline(), || unsafe {
+            libc::recvfrom(fd,
+                           buf.as_mut_ptr() as *mut libc::c_void,
+                           buf.len(),
+                           sys::MSG_PEEK,
+                           &mut addr as *mut _ as *mut libc::sockaddr,
+                           &mut addr_len)
+        }));
+
+        Ok((len, try!(super::from_sockaddr(&addr))))
+    }
+
+    // Repeat the system call while it fails with EAGAIN, waiting for `interest` in between
+    fn retry<F>(&self, interest: EventSet, deadline: Option<Instant>, mut f: F) -> io::Result<usize>
+        where F: FnMut() -> ::libc::ssize_t
+    {
+        loop {
+            let ret = f();
+            if ret >= 0 {
+                return Ok(ret as usize);
+            }
+
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(::libc::EAGAIN) => try!(self.wait(interest, deadline)),
+                Some(::libc::EINTR) => {}
+                _ => return Err(err),
+            }
+        }
+    }
+}
+
+/// Segmentation offload (GSO) and receive offload (GRO) for UDP.
+///
+/// With GSO one system call hands a large buffer to the kernel, which splits it into
+/// datagrams of equal size; GRO coalesces received datagrams of one flow likewise.
+/// Both are supported on Linux only. Elsewhere segmented buffers are sent as one datagram
+/// per segment from userspace and received datagrams are never coalesced.
+pub trait UdpSocketExt {
+    /// Send `buf` as datagrams of `segment_size` bytes each (the last one may be shorter).
+    /// Returns the number of bytes sent.
+    ///
+    /// A system call carries at most `UDP_MAX_SEGMENTS` segments and 64KB, larger buffers are
+    /// sent in several batches.
+    fn send_to_segmented(&self,
+                         buf: &[u8],
+                         segment_size: usize,
+                         target: &SocketAddr)
+                         -> io::Result<usize>;
+
+    /// Let the kernel coalesce received datagrams, returns whether it is supported
+    fn set_gro(&self, enabled: bool) -> io::Result<bool>;
+
+    /// Receive a possibly coalesced datagram. Returns the number of bytes read, the size of
+    /// the segments it consists of, and the source address.
+    fn recv_from_gro(&self, buf: &mut [u8]) -> io::Result<(usize, usize, SocketAddr)>;
+}
+
+// Socket option level and options of <linux/udp.h>, which libc does not define
+#[cfg(target_os = "linux")]
+const SOL_UDP: ::libc::c_int = 17;
+#[cfg(target_os = "linux")]
+const UDP_SEGMENT: ::libc::c_int = 103;
+#[cfg(target_os = "linux")]
+const UDP_GRO: ::libc::c_int = 104;
+
+/// Most segments the kernel accepts in one `UDP_SEGMENT` send
+pub const UDP_MAX_SEGMENTS: usize = 64;
+
+// Largest UDP payload over IPv4, which bounds a segmented send as well
+#[cfg(target_os = "linux")]
+const UDP_MAX_PAYLOAD: usize = 65507;
+
+// Room for a single control message carrying an integer
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct Cmsg {
+    hdr: ::sys::cmsghdr,
+    data: [u8; 8],
+}
+
+// Segment size of the `UDP_GRO` control message received along with a datagram, `None` if
+// the datagram has not been coalesced. `controllen` is the length of the control data.
+#[cfg(target_os = "linux")]
+fn gro_segment_size(cmsg: &Cmsg, controllen: usize) -> Option<usize> {
+    let len = ::sys::cmsg_len(mem::size_of::<::libc::c_int>());
+    if controllen < len || (cmsg.hdr.cmsg_len as usize) < len || cmsg.hdr.cmsg_level != SOL_UDP ||
+       cmsg.hdr.cmsg_type != UDP_GRO {
+        return None;
+    }
+
+    Some(unsafe { *(cmsg.data.as_ptr() as *const ::libc::c_int) } as usize)
+}
+
+// Send the segments one by one, where the kernel doesn't do it
+fn send_segments(socket: &UdpSocket,
+                 buf: &[u8],
+                 segment_size: usize,
+                 target: &SocketAddr)
+                 -> io::Result<usize> {
+    let mut sent = 0;
+    for segment in buf.chunks(segment_size) {
+        sent += try!(socket.send_to(segment, target));
+    }
+    Ok(sent)
+}
+
+// Send one batch with `UDP_SEGMENT`, or segment by segment where the kernel does not support it
+#[cfg(target_os = "linux")]
+fn send_gso(socket: &UdpSocket,
+            buf: &[u8],
+            segment_size: usize,
+            target: &SocketAddr)
+            -> io::Result<usize> {
+    use libc;
+    use sys;
+
+    let (addr, addr_len) = super::to_sockaddr(target);
+
+    let mut cmsg = Cmsg {
+        hdr: unsafe { mem::zeroed() },
+        data: [0; 8],
+    };
+    cmsg.hdr.cmsg_len = sys::cmsg_len(mem::size_of::<u16>()) as _;
+    cmsg.hdr.cmsg_level = SOL_UDP;
+    cmsg.hdr.cmsg_type = UDP_SEGMENT;
+    unsafe {
+        *(cmsg.data.as_mut_ptr() as *mut u16) = segment_size as u16;
+    }
+
+    let mut iov = sys::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+
+    let mut msg: sys::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &addr as *const _ as *mut libc::c_void;
+    msg.msg_namelen = addr_len;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = &mut cmsg as *mut _ as *mut libc::c_void;
+    msg.msg_controllen = sys::cmsg_space(mem::size_of::<u16>()) as _;
+
+    let deadline = socket.write_deadline();
+    loop {
+        let ret = unsafe { sys::sendmsg(socket.as_raw_fd(), &msg, 0) };
+        if ret >= 0 {
+            return Ok(ret as usize);
+        }
+
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EAGAIN) => try!(socket.wait(EventSet::writable(), deadline)),
+            Some(libc::EINTR) => {}
+            // The kernel or the device does not support GSO
+            Some(libc::ENOPROTOOPT) | Some(libc::EIO) => {
+                return send_segments(socket, buf, segment_size, target);
+            }
+            _ => return Err(err),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl UdpSocketExt for UdpSocket {
+    fn send_to_segmented(&self,
+                         buf: &[u8],
+                         segment_size: usize,
+                         target: &SocketAddr)
+                         -> io::Result<usize> {
+        assert!(segment_size > 0 && segment_size <= u16::max_value() as usize,
+                "Invalid segment size");
+
+        let segments = cmp::max(1, cmp::min(UDP_MAX_SEGMENTS, UDP_MAX_PAYLOAD / segment_size));
+        let mut sent = 0;
+        for batch in buf.chunks(segments * segment_size) {
+            sent += if batch.len() <= segment_size {
+                try!(self.send_to(batch, target))
+            } else {
+                try!(send_gso(self, batch, segment_size, target))
+            };
+        }
+        Ok(sent)
+    }
+
+    fn set_gro(&self, enabled: bool) -> io::Result<bool> {
+        match super::setsockopt(self.as_raw_fd(), SOL_UDP, UDP_GRO, enabled as ::libc::c_int) {
+            Ok(()) => Ok(true),
+            Err(ref err) if err.raw_os_error() == Some(::libc::ENOPROTOOPT) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn recv_from_gro(&self, buf: &mut [u8]) -> io::Result<(usize, usize, SocketAddr)> {
+        use libc;
+        use sys;
+
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut cmsg = Cmsg {
+            hdr: unsafe { mem::zeroed() },
+            data: [0; 8],
+        };
+
+        let mut iov = sys::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+
+        let deadline = self.read_deadline();
+        loop {
+            let mut msg: sys::msghdr = unsafe { mem::zeroed() };
+            msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+            msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = &mut cmsg as *mut _ as *mut libc::c_void;
+            msg.msg_controllen = mem::size_of::<Cmsg>() as _;
+
+            let ret = unsafe { sys::recvmsg(self.as_raw_fd(), &mut msg, 0) };
+            if ret >= 0 {
+                let len = ret as usize;
+                let segment_size = gro_segment_size(&cmsg, msg.msg_controllen as usize)
+                                       .unwrap_or(len);
+
+                return Ok((len, segment_size, try!(super::from_sockaddr(&addr))));
+            }
+
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EAGAIN) => try!(self.wait(EventSet::readable(), deadline)),
+                Some(libc::EINTR) => {}
+                _ => return Err(err),
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl UdpSocketExt for UdpSocket {
+    fn send_to_segmented(&self,
+                         buf: &[u8],
+                         segment_size: usize,
+                         target: &SocketAddr)
+                         -> io::Result<usize> {
+        assert!(segment_size > 0, "Invalid segment size");
+        send_segments(self, buf, segment_size, target)
+    }
+
+    fn set_gro(&self, _enabled: bool) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    fn recv_from_gro(&self, buf: &mut [u8]) -> io::Result<(usize, usize, SocketAddr)> {
+        let (len, addr) = try!(self.recv_from(buf));
+        Ok((len, len, addr))
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        if let Some(sub) = self.subscription.take() {
+            sub.cancel();
+        }
+    }
+}
+
 impl Deref for UdpSocket {
     type Target = ::mio::udp::UdpSocket;
 
     fn deref(&self) -> &::mio::udp::UdpSocket {
-        &self.0
+        &self.socket
     }
 }
 
 impl DerefMut for UdpSocket {
     fn deref_mut(&mut self) -> &mut ::mio::udp::UdpSocket {
-        &mut self.0
+        &mut self.socket
     }
 }
 
 #[cfg(unix)]
 impl AsRawFd for UdpSocket {
     fn as_raw_fd(&self) -> RawFd {
-        self.0.as_raw_fd()
+        self.socket.as_raw_fd()
     }
 }
 
 #[cfg(unix)]
 impl FromRawFd for UdpSocket {
     unsafe fn from_raw_fd(fd: RawFd) -> UdpSocket {
-        UdpSocket(FromRawFd::from_raw_fd(fd))
+        UdpSocket::new(FromRawFd::from_raw_fd(fd))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::Duration;
+
+    use super::*;
+
+    use scheduler::Scheduler;
+
+    #[test]
+    fn test_bind_options() {
+        Scheduler::new()
+            .run(|| {
+                let opts = UdpBindOptions {
+                    reuse_addr: true,
+                    reuse_port: cfg!(target_os = "linux"),
+                };
+
+                let addrs = ["127.0.0.1:0".parse().unwrap(), "0.0.0.0:0".parse().unwrap()];
+                let sockets = UdpSocket::bind_all(&addrs, opts).unwrap();
+                let port = sockets[0].local_addr().unwrap().port();
+                assert_eq!(sockets[1].local_addr().unwrap().port(), port);
+
+                if opts.reuse_port {
+                    let first = UdpSocket::bind_with_options("127.0.0.1:0", opts).unwrap();
+                    let addr = first.local_addr().unwrap();
+                    let second = UdpSocket::bind_with_options(addr, opts).unwrap();
+                    assert_eq!(second.local_addr().unwrap(), addr);
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_multishot_again() {
+        Scheduler::new()
+            .run(|| {
+                let sched = Scheduler::instance().unwrap();
+                let mut receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+                let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+                let addr = receiver.local_addr().unwrap();
+
+                // Cancelling deregisters the socket, so that it could be subscribed again. The
+                // socket itself stays registered with the Scheduler until it is dropped.
+                for round in 0..3u8 {
+                    receiver.set_multishot(true).unwrap();
+                    assert_eq!(sched.io_registration_count(), 1);
+
+                    assert_eq!(sender.send_to(&[round], &addr).unwrap(), 1);
+                    let mut buf = [0u8; 16];
+                    assert_eq!(receiver.recv_from(&mut buf).unwrap().0, 1);
+                    assert_eq!(buf[0], round);
+
+                    receiver.set_multishot(false).unwrap();
+                }
+
+                assert_eq!(sched.io_registration_count(), 1);
+                drop(receiver);
+                assert_eq!(sched.io_registration_count(), 0);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_send_to_segmented() {
+        Scheduler::new()
+            .run(|| {
+                let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+                let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+                let addr = receiver.local_addr().unwrap();
+
+                let data: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+                assert_eq!(sender.send_to_segmented(&data, 1000, &addr).unwrap(), 2500);
+
+                let mut buf = [0u8; 4096];
+                let mut received = Vec::new();
+                for expected in &[1000, 1000, 500] {
+                    let (len, from) = receiver.recv_from(&mut buf).unwrap();
+                    assert_eq!(len, *expected);
+                    assert_eq!(from, sender.local_addr().unwrap());
+                    received.extend_from_slice(&buf[..len]);
+                }
+                assert_eq!(received, data);
+
+                // More segments than a single send may carry
+                let data: Vec<u8> = (0..100 * 100).map(|i| i as u8).collect();
+                assert_eq!(sender.send_to_segmented(&data, 100, &addr).unwrap(), data.len());
+
+                let mut received = Vec::new();
+                for _ in 0..100 {
+                    let (len, _) = receiver.recv_from(&mut buf).unwrap();
+                    assert_eq!(len, 100);
+                    received.extend_from_slice(&buf[..len]);
+                }
+                assert_eq!(received, data);
+            })
+            .unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_gro_segment_size() {
+        use std::mem;
+
+        use libc;
+        use sys;
+
+        use super::{gro_segment_size, Cmsg, SOL_UDP, UDP_GRO};
+
+        let mut cmsg = Cmsg {
+            hdr: unsafe { mem::zeroed() },
+            data: [0; 8],
+        };
+        let len = sys::cmsg_len(mem::size_of::<libc::c_int>());
+        cmsg.hdr.cmsg_len = len as _;
+        cmsg.hdr.cmsg_level = SOL_UDP;
+        cmsg.hdr.cmsg_type = UDP_GRO;
+        unsafe {
+            *(cmsg.data.as_mut_ptr() as *mut libc::c_int) = 1200;
+        }
+
+        // The control message fits into the buffer handed to recvmsg(2)
+        assert!(sys::cmsg_space(mem::size_of::<libc::c_int>()) <= mem::size_of::<Cmsg>());
+
+        assert_eq!(gro_segment_size(&cmsg, len), Some(1200));
+        // No or truncated control data
+        assert_eq!(gro_segment_size(&cmsg, 0), None);
+        assert_eq!(gro_segment_size(&cmsg, len - 1), None);
+
+        cmsg.hdr.cmsg_len = (len - 1) as _;
+        assert_eq!(gro_segment_size(&cmsg, len), None);
+
+        cmsg.hdr.cmsg_len = len as _;
+        cmsg.hdr.cmsg_type = UDP_GRO + 1;
+        assert_eq!(gro_segment_size(&cmsg, len), None);
+    }
+
+    #[test]
+    fn test_recv_from_gro() {
+        Scheduler::new()
+            .run(|| {
+                let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+                let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+                let addr = receiver.local_addr().unwrap();
+                let coalescing = receiver.set_gro(true).unwrap();
+
+                let data: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+                assert_eq!(sender.send_to_segmented(&data, 1000, &addr).unwrap(), 2500);
+
+                // Either coalesced into datagrams of whole segments, or one by one
+                let mut buf = [0u8; 4096];
+                let mut received = Vec::new();
+                while received.len() < data.len() {
+                    let (len, segment_size, from) = receiver.recv_from_gro(&mut buf).unwrap();
+                    assert_eq!(from, sender.local_addr().unwrap());
+                    if coalescing && len > segment_size {
+                        assert_eq!(segment_size, 1000);
+                    } else {
+                        assert_eq!(segment_size, len);
+                    }
+                    received.extend_from_slice(&buf[..len]);
+                }
+                assert_eq!(received, data);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_connected_timeouts() {
+        Scheduler::new()
+            .run(|| {
+                let mut receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+                let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+                sender.connect(receiver.local_addr().unwrap()).unwrap();
+                receiver.connect(sender.local_addr().unwrap()).unwrap();
+
+                receiver.set_read_timeout(Some(Duration::from_millis(20)));
+                let mut buf = [0u8; 16];
+                assert_eq!(receiver.recv(&mut buf).err().unwrap().kind(), io::ErrorKind::TimedOut);
+
+                assert_eq!(sender.send(b"ping").unwrap(), 4);
+                let (len, from) = receiver.peek_from(&mut buf).unwrap();
+                assert_eq!((len, from), (4, sender.local_addr().unwrap()));
+
+                // Still queued after peeking
+                assert_eq!(receiver.recv(&mut buf).unwrap(), 4);
+                assert_eq!(&buf[..4], b"ping");
+            })
+            .unwrap();
     }
 }
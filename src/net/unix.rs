@@ -23,13 +23,17 @@
 
 use std::io::{self, Read, Write, ErrorKind};
 use std::path::Path;
+use std::time::Instant;
 use std::ops::{Deref, DerefMut};
 use std::convert::From;
 use std::os::unix::io::{RawFd, AsRawFd, FromRawFd};
 
 use mio::{TryRead, TryWrite, TryAccept, EventSet};
 
-use scheduler::Scheduler;
+use io::{ancillary, vectored, DeadlineIo};
+use scheduler::{IoOwner, Scheduler};
+use super::backoff::{self, AcceptBackoff};
+use super::tcp::Shutdown;
 
 #[derive(Debug)]
 pub struct UnixSocket(::mio::unix::UnixSocket);
@@ -42,7 +46,7 @@ impl UnixSocket {
 
     /// Connect the socket to the specified address
     pub fn connect<P: AsRef<Path> + ?Sized>(self, addr: &P) -> io::Result<(UnixStream, bool)> {
-        self.0.connect(addr).map(|(s, completed)| (UnixStream(s), completed))
+        self.0.connect(addr).map(|(s, completed)| (UnixStream::from(s), completed))
     }
 
     /// Bind the socket to the specified address
@@ -52,7 +56,7 @@ impl UnixSocket {
 
     /// Listen for incoming requests
     pub fn listen(self, backlog: usize) -> io::Result<UnixListener> {
-        self.0.listen(backlog).map(UnixListener)
+        self.0.listen(backlog).map(UnixListener::from)
     }
 
     pub fn try_clone(&self) -> io::Result<UnixSocket> {
@@ -93,20 +97,160 @@ impl FromRawFd for UnixSocket {
 }
 
 #[derive(Debug)]
-pub struct UnixStream(::mio::unix::UnixStream);
+pub struct UnixStream(::mio::unix::UnixStream, IoOwner);
 
 impl UnixStream {
     pub fn connect<P: AsRef<Path> + ?Sized>(path: &P) -> io::Result<UnixStream> {
-        ::mio::unix::UnixStream::connect(path).map(UnixStream)
+        ::mio::unix::UnixStream::connect(path).map(UnixStream::from)
     }
 
     pub fn try_clone(&self) -> io::Result<UnixStream> {
-        self.0.try_clone().map(UnixStream)
+        Ok(UnixStream(try!(self.0.try_clone()), self.1.clone()))
+    }
+
+    /// Shut down the read, write, or both halves of the connection
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        use libc;
+
+        let how = match how {
+            Shutdown::Read => libc::SHUT_RD,
+            Shutdown::Write => libc::SHUT_WR,
+            Shutdown::Both => libc::SHUT_RDWR,
+        };
+
+        if unsafe { libc::shutdown(self.0.as_raw_fd(), how) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Read until EOF into `buf`, failing with `io::LengthExceeded` once more than
+    /// `max_len` bytes have been read, see `io::read_to_end_capped`
+    pub fn read_to_end_capped(&mut self, buf: &mut Vec<u8>, max_len: usize) -> io::Result<usize> {
+        ::io::read_to_end_capped(self, buf, max_len)
+    }
+
+    /// Read into `bufs` in order with a single `readv(2)`
+    pub fn read_vectored(&mut self, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        try!(self.1.check());
+
+        loop {
+            match try!(vectored::try_read_vectored(self.as_raw_fd(), bufs)) {
+                Some(len) => {
+                    debug!("UnixStream readv {} bytes", len);
+                    return Ok(len);
+                }
+                None => {
+                    debug!("UnixStream readv WouldBlock");
+                    try!(Scheduler::instance().unwrap().wait_event(&self.0, EventSet::readable()));
+                }
+            }
+        }
+    }
+
+    /// Write `bufs` in order with a single `writev(2)`
+    pub fn write_vectored(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        try!(self.1.check());
+
+        loop {
+            match try!(vectored::try_write_vectored(self.as_raw_fd(), bufs)) {
+                Some(len) => {
+                    debug!("UnixStream writev {} bytes", len);
+                    return Ok(len);
+                }
+                None => {
+                    debug!("UnixStream writev WouldBlock");
+                    try!(Scheduler::instance().unwrap().wait_event(&self.0, EventSet::writable()));
+                }
+            }
+        }
+    }
+
+    /// Send `bufs` in order along with the file descriptors `fds` with a single `sendmsg(2)`.
+    /// The descriptors stay open in this process, the peer receives duplicates of them.
+    ///
+    /// At least one byte has to be sent along with the descriptors.
+    pub fn send_msg(&mut self, bufs: &[&[u8]], fds: &[RawFd]) -> io::Result<usize> {
+        try!(self.1.check());
+
+        loop {
+            match try!(ancillary::try_send_msg(self.as_raw_fd(), bufs, fds)) {
+                Some(len) => {
+                    debug!("UnixStream sendmsg {} bytes, {} fds", len, fds.len());
+                    return Ok(len);
+                }
+                None => {
+                    debug!("UnixStream sendmsg WouldBlock");
+                    try!(Scheduler::instance().unwrap().wait_event(&self.0, EventSet::writable()));
+                }
+            }
+        }
+    }
+
+    /// Receive into `bufs` in order with a single `recvmsg(2)`, appending the file descriptors
+    /// sent along to `fds`. The caller owns them, they are close-on-exec.
+    pub fn recv_msg(&mut self,
+                    bufs: &mut [&mut [u8]],
+                    fds: &mut Vec<RawFd>)
+                    -> io::Result<usize> {
+        try!(self.1.check());
+
+        loop {
+            match try!(ancillary::try_recv_msg(self.as_raw_fd(), bufs, fds)) {
+                Some(len) => {
+                    debug!("UnixStream recvmsg {} bytes, {} fds", len, fds.len());
+                    return Ok(len);
+                }
+                None => {
+                    debug!("UnixStream recvmsg WouldBlock");
+                    try!(Scheduler::instance().unwrap().wait_event(&self.0, EventSet::readable()));
+                }
+            }
+        }
+    }
+
+    /// Pass the file descriptor `fd` to the peer, which receives it with `recv_fd`
+    pub fn send_fd(&mut self, fd: RawFd) -> io::Result<()> {
+        match try!(self.send_msg(&[&[0u8][..]], &[fd])) {
+            0 => Err(io::Error::new(ErrorKind::WriteZero, "failed to send the file descriptor")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Receive a file descriptor passed with `send_fd`, the caller owns it
+    pub fn recv_fd(&mut self) -> io::Result<RawFd> {
+        let mut byte = [0u8];
+        let mut fds = Vec::new();
+        if try!(self.recv_msg(&mut [&mut byte[..]], &mut fds)) == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof,
+                                      "connection closed before receiving a file descriptor"));
+        }
+
+        if fds.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "no file descriptor received"));
+        }
+
+        // Sent along by `send_msg` on the other end, nobody else would close them
+        for &extra in &fds[1..] {
+            unsafe {
+                ::libc::close(extra);
+            }
+        }
+        Ok(fds[0])
     }
 }
 
 impl Read for UnixStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_until(buf, None)
+    }
+}
+
+impl DeadlineIo for UnixStream {
+    fn read_until(&mut self, buf: &mut [u8], deadline: Option<Instant>) -> io::Result<usize> {
+        try!(self.1.check());
+
         match self.0.try_read(buf) {
             Ok(None) => {
                 debug!("UnixStream read WouldBlock");
@@ -123,7 +267,7 @@ impl Read for UnixStream {
 
         loop {
             debug!("Read: Going to register event");
-            try!(Scheduler::instance().unwrap().wait_event(&self.0, EventSet::readable()));
+            try!(super::wait_until(&self.0, EventSet::readable(), deadline));
             debug!("Read: Got read event");
 
             match self.0.try_read(buf) {
@@ -140,10 +284,10 @@ impl Read for UnixStream {
             }
         }
     }
-}
 
-impl Write for UnixStream {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    fn write_until(&mut self, buf: &[u8], deadline: Option<Instant>) -> io::Result<usize> {
+        try!(self.1.check());
+
         match self.0.try_write(buf) {
             Ok(None) => {
                 debug!("UnixStream write WouldBlock");
@@ -157,7 +301,7 @@ impl Write for UnixStream {
 
         loop {
             debug!("Write: Going to register event");
-            try!(Scheduler::instance().unwrap().wait_event(&self.0, EventSet::writable()));
+            try!(super::wait_until(&self.0, EventSet::writable(), deadline));
             debug!("Write: Got write event");
 
             match self.0.try_write(buf) {
@@ -172,8 +316,16 @@ impl Write for UnixStream {
             }
         }
     }
+}
+
+impl Write for UnixStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_until(buf, None)
+    }
 
     fn flush(&mut self) -> io::Result<()> {
+        try!(self.1.check());
+
         match self.0.flush() {
             Ok(..) => return Ok(()),
             Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
@@ -214,7 +366,7 @@ impl DerefMut for UnixStream {
 
 impl From<::mio::unix::UnixStream> for UnixStream {
     fn from(sock: ::mio::unix::UnixStream) -> UnixStream {
-        UnixStream(sock)
+        UnixStream(sock, IoOwner::new())
     }
 }
 
@@ -226,25 +378,27 @@ impl AsRawFd for UnixStream {
 
 impl FromRawFd for UnixStream {
     unsafe fn from_raw_fd(fd: RawFd) -> UnixStream {
-        UnixStream(FromRawFd::from_raw_fd(fd))
+        UnixStream::from(::mio::unix::UnixStream::from_raw_fd(fd))
     }
 }
 
 #[derive(Debug)]
-pub struct UnixListener(::mio::unix::UnixListener);
+pub struct UnixListener(::mio::unix::UnixListener, IoOwner);
 
 impl UnixListener {
     pub fn bind<P: AsRef<Path> + ?Sized>(addr: &P) -> io::Result<UnixListener> {
-        ::mio::unix::UnixListener::bind(addr).map(UnixListener)
+        ::mio::unix::UnixListener::bind(addr).map(UnixListener::from)
     }
 
     pub fn accept(&self) -> io::Result<UnixStream> {
+        try!(self.1.check());
+
         match self.0.accept() {
             Ok(None) => {
                 debug!("UnixListener accept WouldBlock; going to register into eventloop");
             }
             Ok(Some(stream)) => {
-                return Ok(UnixStream(stream));
+                return Ok(UnixStream::from(stream));
             }
             Err(err) => {
                 return Err(err);
@@ -259,7 +413,7 @@ impl UnixListener {
                     warn!("UnixListener accept WouldBlock; Coroutine was awaked by readable event");
                 }
                 Ok(Some(stream)) => {
-                    return Ok(UnixStream(stream));
+                    return Ok(UnixStream::from(stream));
                 }
                 Err(err) => {
                     return Err(err);
@@ -268,8 +422,32 @@ impl UnixListener {
         }
     }
 
+    /// Accept a new connection, riding through file descriptor exhaustion with `backoff`
+    pub fn accept_with(&self, backoff: &mut AcceptBackoff) -> io::Result<UnixStream> {
+        loop {
+            match self.accept() {
+                Ok(stream) => {
+                    backoff.reset();
+                    return Ok(stream);
+                }
+                Err(ref err) if backoff::is_fd_exhausted(err) => {
+                    if backoff.release_spare() {
+                        // Shed the pending connection with the released descriptor
+                        if let Ok(Some(stream)) = self.0.accept() {
+                            debug!("UnixListener dropping pending connection");
+                            drop(stream);
+                        }
+                    }
+
+                    backoff.wait();
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
     pub fn try_clone(&self) -> io::Result<UnixListener> {
-        self.0.try_clone().map(UnixListener)
+        Ok(UnixListener(try!(self.0.try_clone()), self.1.clone()))
     }
 }
 
@@ -289,7 +467,7 @@ impl DerefMut for UnixListener {
 
 impl From<::mio::unix::UnixListener> for UnixListener {
     fn from(listener: ::mio::unix::UnixListener) -> UnixListener {
-        UnixListener(listener)
+        UnixListener(listener, IoOwner::new())
     }
 }
 
@@ -301,7 +479,7 @@ impl AsRawFd for UnixListener {
 
 impl FromRawFd for UnixListener {
     unsafe fn from_raw_fd(fd: RawFd) -> UnixListener {
-        UnixListener(FromRawFd::from_raw_fd(fd))
+        UnixListener::from(::mio::unix::UnixListener::from_raw_fd(fd))
     }
 }
 
@@ -312,6 +490,24 @@ pub fn pipe() -> io::Result<(PipeReader, PipeWriter)> {
 #[derive(Debug)]
 pub struct PipeReader(::mio::unix::PipeReader);
 
+impl PipeReader {
+    /// Read into `bufs` in order with a single `readv(2)`
+    pub fn read_vectored(&mut self, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        loop {
+            match try!(vectored::try_read_vectored(self.as_raw_fd(), bufs)) {
+                Some(len) => {
+                    debug!("PipeReader readv {} bytes", len);
+                    return Ok(len);
+                }
+                None => {
+                    debug!("PipeReader readv WouldBlock");
+                    try!(Scheduler::instance().unwrap().wait_event(&self.0, EventSet::readable()));
+                }
+            }
+        }
+    }
+}
+
 impl Read for PipeReader {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match self.0.try_read(buf) {
@@ -384,6 +580,24 @@ impl FromRawFd for PipeReader {
 #[derive(Debug)]
 pub struct PipeWriter(::mio::unix::PipeWriter);
 
+impl PipeWriter {
+    /// Write `bufs` in order with a single `writev(2)`
+    pub fn write_vectored(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        loop {
+            match try!(vectored::try_write_vectored(self.as_raw_fd(), bufs)) {
+                Some(len) => {
+                    debug!("PipeWriter writev {} bytes", len);
+                    return Ok(len);
+                }
+                None => {
+                    debug!("PipeWriter writev WouldBlock");
+                    try!(Scheduler::instance().unwrap().wait_event(&self.0, EventSet::writable()));
+                }
+            }
+        }
+    }
+}
+
 impl Write for PipeWriter {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         match self.0.try_write(buf) {
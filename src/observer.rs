@@ -0,0 +1,113 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Observing the runtime behavior of the Scheduler
+
+use std::time::Duration;
+
+use mio::EventSet;
+
+/// Events emitted by the Scheduler
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The run queue of a Processor has grown above the high watermark
+    RunQueueHigh {
+        processor_id: usize,
+        length: usize,
+    },
+    /// The run queue of a Processor has shrunk below the low watermark again
+    RunQueueLow {
+        processor_id: usize,
+        length: usize,
+    },
+    /// The number of blocked coroutines has grown above the high watermark
+    BlockedHigh {
+        count: usize,
+    },
+    /// The number of blocked coroutines has shrunk below the low watermark again
+    BlockedLow {
+        count: usize,
+    },
+    /// A coroutine entered a span, see `trace::Span`
+    SpanEntered {
+        coroutine_id: usize,
+        name: &'static str,
+    },
+    /// A coroutine exited a span after `elapsed`, of which it spent `running` running and
+    /// `blocked` blocked. The rest it spent waiting in a run queue.
+    SpanExited {
+        coroutine_id: usize,
+        name: &'static str,
+        elapsed: Duration,
+        running: Duration,
+        blocked: Duration,
+    },
+    /// A coroutine has been waiting `waited` for `interest` on `fd`, longer than the threshold
+    /// set with `Scheduler::with_slow_io_threshold`, and is still waiting. `fd` is -1 where
+    /// there are no file descriptors. Delivered on the thread running the event loop.
+    SlowIo {
+        coroutine_id: usize,
+        name: Option<String>,
+        fd: i32,
+        interest: EventSet,
+        waited: Duration,
+    },
+}
+
+/// Receives events from the Scheduler.
+///
+/// Events are delivered synchronously on the Processor threads or the event loop, so
+/// implementations should return quickly and must not block.
+pub trait SchedulerObserver: Send + Sync {
+    fn on_event(&self, event: &Event);
+}
+
+/// A pair of watermarks. Crossing `high` emits the high event, the low event
+/// is emitted once the value falls below `low` again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watermarks {
+    pub low: usize,
+    pub high: usize,
+}
+
+impl Watermarks {
+    pub fn new(low: usize, high: usize) -> Watermarks {
+        assert!(low <= high, "Low watermark must not exceed the high watermark");
+
+        Watermarks {
+            low: low,
+            high: high,
+        }
+    }
+
+    /// Returns `Some(true)` when crossing the high watermark, `Some(false)` when
+    /// crossing the low watermark and `None` otherwise.
+    #[doc(hidden)]
+    pub fn check(&self, value: usize, is_high: bool) -> Option<bool> {
+        if !is_high && value > self.high {
+            Some(true)
+        } else if is_high && value < self.low {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
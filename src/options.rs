@@ -22,21 +22,126 @@
 //! Coroutine options
 
 use std::default::Default;
+use std::time::Instant;
 
 /// Coroutine options
 pub struct Options {
     pub stack_size: usize,
     pub name: Option<String>,
+    /// The coroutine will be unwound if it is resumed after the deadline
+    pub deadline: Option<Instant>,
+    /// Inherit the deadline and the abortion of the spawning coroutine
+    pub inherit_deadline: bool,
+    /// Whether the coroutine preempts the spawning one, defaults to the Scheduler's hint
+    pub spawn_hint: Option<SpawnHint>,
+    /// Where the coroutine has been spawned, e.g. `spawn_site!()`, reported for leaked coroutines
+    pub spawn_site: Option<&'static str>,
+    /// Group sharing the running time with the others, e.g. a tenant, see
+    /// `Scheduler::with_fair_queuing`. Defaults to the group of the spawning coroutine.
+    pub group: Option<usize>,
+    pub priority: Priority,
+    /// Never run on another Processor than the one it is spawned on, i.e. neither stolen nor
+    /// handed over while the Processor is drained, see `Scheduler::spawn_on`
+    pub pin: bool,
+}
+
+/// What happens to the spawning coroutine when a new coroutine is spawned inside of it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SpawnHint {
+    /// The spawning coroutine is suspended and the new one runs right away on the same
+    /// Processor. Good for latency of request handlers spawned by an accept loop.
+    Immediate,
+    /// The new coroutine is queued and the spawning one keeps running until its next
+    /// scheduling point. Good for loops spawning many coroutines at once.
+    Deferred,
+}
+
+impl Default for SpawnHint {
+    fn default() -> SpawnHint {
+        SpawnHint::Immediate
+    }
+}
+
+/// Order in which a Processor resumes the coroutines queued on it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Priority {
+    /// Resumed after all the others, unless it has been waiting longer than
+    /// `Scheduler::with_priority_aging`. Good for bulk work.
+    Low,
+    Normal,
+    /// Resumed before all the others. Good for short latency-sensitive work like accept loops
+    /// or health checks, busy ones would starve the normal coroutines.
+    High,
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::Normal
+    }
 }
 
 /// Default coroutine stack size, 128KB
 pub const DEFAULT_STACK: usize = 128 * 1024; // 128KB
 
+/// Named stack sizes. The Scheduler accounts how many coroutines use each of them,
+/// see `Scheduler::stack_usage()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StackClass {
+    /// 8KB, for handlers which neither recurse nor keep large buffers on the stack
+    Tiny,
+    /// 32KB
+    Small,
+    /// `DEFAULT_STACK`
+    Default,
+    /// 1MB
+    Large,
+}
+
+impl StackClass {
+    /// All classes, from the smallest to the largest
+    pub fn all() -> [StackClass; 4] {
+        [StackClass::Tiny, StackClass::Small, StackClass::Default, StackClass::Large]
+    }
+
+    /// Stack size of the class in bytes
+    pub fn size(&self) -> usize {
+        match *self {
+            StackClass::Tiny => 8 * 1024,
+            StackClass::Small => 32 * 1024,
+            StackClass::Default => DEFAULT_STACK,
+            StackClass::Large => 1024 * 1024,
+        }
+    }
+
+    /// The smallest class which is able to hold a stack of `size` bytes,
+    /// stacks exceeding all classes are accounted as `Large`
+    pub fn of(size: usize) -> StackClass {
+        for class in StackClass::all().iter() {
+            if size <= class.size() {
+                return *class;
+            }
+        }
+        StackClass::Large
+    }
+
+    #[doc(hidden)]
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
 impl Options {
     pub fn new() -> Options {
         Options {
             stack_size: DEFAULT_STACK,
             name: None,
+            deadline: None,
+            inherit_deadline: false,
+            spawn_hint: None,
+            spawn_site: None,
+            group: None,
+            priority: Priority::Normal,
+            pin: false,
         }
     }
 
@@ -45,10 +150,51 @@ impl Options {
         self
     }
 
+    /// Use the stack size of the class
+    pub fn stack_class(mut self, class: StackClass) -> Options {
+        self.stack_size = class.size();
+        self
+    }
+
     pub fn name(mut self, name: Option<String>) -> Options {
         self.name = name;
         self
     }
+
+    pub fn deadline(mut self, deadline: Option<Instant>) -> Options {
+        self.deadline = deadline;
+        self
+    }
+
+    pub fn inherit_deadline(mut self, inherit: bool) -> Options {
+        self.inherit_deadline = inherit;
+        self
+    }
+
+    pub fn spawn_hint(mut self, hint: SpawnHint) -> Options {
+        self.spawn_hint = Some(hint);
+        self
+    }
+
+    pub fn spawn_site(mut self, site: &'static str) -> Options {
+        self.spawn_site = Some(site);
+        self
+    }
+
+    pub fn group(mut self, group: usize) -> Options {
+        self.group = Some(group);
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Options {
+        self.priority = priority;
+        self
+    }
+
+    pub fn pin(mut self, enabled: bool) -> Options {
+        self.pin = enabled;
+        self
+    }
 }
 
 impl Default for Options {
@@ -56,3 +202,21 @@ impl Default for Options {
         Options::new()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stack_class_of() {
+        assert_eq!(StackClass::of(1024), StackClass::Tiny);
+        assert_eq!(StackClass::of(8 * 1024), StackClass::Tiny);
+        assert_eq!(StackClass::of(8 * 1024 + 1), StackClass::Small);
+        assert_eq!(StackClass::of(DEFAULT_STACK), StackClass::Default);
+        assert_eq!(StackClass::of(64 * 1024 * 1024), StackClass::Large);
+
+        for class in StackClass::all().iter() {
+            assert_eq!(StackClass::of(class.size()), *class);
+        }
+    }
+}
@@ -0,0 +1,167 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Pool of worker coroutines executing short tasks
+
+use std::boxed::FnBox;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use scheduler::{JoinHandle, Scheduler};
+use sync::Mutex;
+use sync::mpsc::{channel, Sender};
+
+type Task = Box<FnBox() + Send + 'static>;
+
+struct Counters {
+    queued: AtomicUsize,
+    busy: AtomicUsize,
+}
+
+/// Executes closures on a fixed set of worker coroutines.
+///
+/// Reusing the workers avoids the cost of spawning and tearing down a coroutine for every
+/// tiny task. The workers exit once the pool is dropped and the queue is drained.
+pub struct TaskPool {
+    tx: Sender<Task>,
+    counters: Arc<Counters>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl TaskPool {
+    /// Spawn `max_coroutines` worker coroutines, must be called inside the scheduler
+    pub fn new(max_coroutines: usize) -> TaskPool {
+        assert!(max_coroutines >= 1, "Must have at least one worker");
+
+        let (tx, rx) = channel::<Task>();
+        let rx = Arc::new(Mutex::new(rx));
+        let counters = Arc::new(Counters {
+            queued: AtomicUsize::new(0),
+            busy: AtomicUsize::new(0),
+        });
+
+        let workers = (0..max_coroutines)
+                          .map(|_| {
+                              let rx = rx.clone();
+                              let counters = counters.clone();
+
+                              Scheduler::spawn(move || {
+                                  loop {
+                                      let task = {
+                                          let rx = rx.lock().unwrap();
+                                          rx.recv()
+                                      };
+
+                                      let task = match task {
+                                          Ok(task) => task,
+                                          Err(..) => break,
+                                      };
+
+                                      counters.queued.fetch_sub(1, Ordering::SeqCst);
+                                      counters.busy.fetch_add(1, Ordering::SeqCst);
+
+                                      // Keep the worker alive even if the task panicked
+                                      if let Err(..) = unsafe { ::try(move || task.call_box(())) } {
+                                          error!("Task in the pool panicked");
+                                      }
+
+                                      counters.busy.fetch_sub(1, Ordering::SeqCst);
+                                  }
+                              })
+                          })
+                          .collect();
+
+        TaskPool {
+            tx: tx,
+            counters: counters,
+            workers: workers,
+        }
+    }
+
+    /// Queue a task to be run by one of the workers
+    pub fn execute<F>(&self, f: F)
+        where F: FnOnce() + Send + 'static
+    {
+        self.counters.queued.fetch_add(1, Ordering::SeqCst);
+        self.tx.send(Box::new(f)).expect("Workers of the TaskPool are gone");
+    }
+
+    /// Number of tasks waiting for a worker
+    pub fn queue_depth(&self) -> usize {
+        self.counters.queued.load(Ordering::SeqCst)
+    }
+
+    /// Number of workers running a task right now
+    pub fn busy(&self) -> usize {
+        self.counters.busy.load(Ordering::SeqCst)
+    }
+
+    /// Number of worker coroutines
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Wait until all queued tasks are done, then stop the workers
+    pub fn join(self) {
+        let TaskPool { tx, workers, .. } = self;
+        drop(tx);
+
+        for worker in workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use scheduler::Scheduler;
+
+    #[test]
+    fn test_task_pool() {
+        Scheduler::new()
+            .with_workers(2)
+            .run(|| {
+                let pool = TaskPool::new(4);
+                let sum = Arc::new(AtomicUsize::new(0));
+
+                for i in 0..1000 {
+                    let sum = sum.clone();
+                    pool.execute(move || {
+                        sum.fetch_add(i, Ordering::SeqCst);
+                    });
+                }
+
+                // A panicking task does not take its worker down
+                pool.execute(|| panic!("task panicked"));
+
+                assert_eq!(pool.workers(), 4);
+                pool.join();
+
+                assert_eq!(sum.load(Ordering::SeqCst), 999 * 1000 / 2);
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,53 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! The traits and types needed by most coroutine programs, to be glob imported
+//!
+//! ```ignore
+//! use coio::prelude::*;
+//! ```
+//!
+//! # Stability
+//!
+//! The items listed here are only ever added to within a major version. Their paths in the
+//! prelude stay the same even if the modules defining them are reorganized, so code which
+//! imports through the prelude keeps compiling across minor releases. Removing or renaming
+//! an item requires a new major version.
+//!
+//! Items are added with care, as a new trait brought into scope by a glob import could make
+//! method calls of downstream code ambiguous. The traits of `std::io` are included, since
+//! every coroutine stream is used through them.
+//!
+//! `tests/prelude.rs` uses every item through the prelude, so that a breaking change fails
+//! to compile.
+
+pub use std::io::{Read, Write};
+
+pub use io::DeadlineIo;
+#[cfg(unix)]
+pub use io::CoIo;
+pub use net::{CoStream, Shutdown, TcpListener, TcpStream, UdpSocket, UdpSocketExt};
+#[cfg(unix)]
+pub use net::{UnixListener, UnixStream};
+pub use options::{Options, Priority, SpawnHint};
+pub use scheduler::{JoinHandle, Scheduler, TimerHandle};
+pub use sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncReceiver, SyncSender};
+pub use super::{run_blocking, sched, sleep, spawn, spawn_opts};
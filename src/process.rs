@@ -0,0 +1,406 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Child processes which block only the calling coroutine
+//!
+//! `Command` mirrors `std::process::Command`, but the pipes to the child are non-blocking
+//! `PipeReader`s and `PipeWriter`s. `Child::wait` blocks in `waitpid` on a thread of the
+//! blocking pool, see `Scheduler::run_blocking`, so that the exit is noticed right away and
+//! nothing wakes up until then. Every child being waited for ties up one of those threads.
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{self, Read};
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::path::Path;
+use std::process;
+
+pub use std::process::Stdio;
+
+use libc;
+
+use io::fd::set_nonblocking;
+use net::unix::{PipeReader, PipeWriter};
+use scheduler::Scheduler;
+use sys;
+
+/// Builder of a child process, see `std::process::Command`
+#[derive(Debug)]
+pub struct Command {
+    inner: process::Command,
+}
+
+impl Command {
+    /// Run `program`, which is searched for in the `PATH` unless it is a path
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Command {
+        Command { inner: process::Command::new(program) }
+    }
+
+    /// Append an argument
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Command {
+        self.inner.arg(arg);
+        self
+    }
+
+    /// Append several arguments
+    pub fn args<S: AsRef<OsStr>>(&mut self, args: &[S]) -> &mut Command {
+        self.inner.args(args);
+        self
+    }
+
+    /// Set an environment variable of the child
+    pub fn env<K, V>(&mut self, key: K, val: V) -> &mut Command
+        where K: AsRef<OsStr>,
+              V: AsRef<OsStr>
+    {
+        self.inner.env(key, val);
+        self
+    }
+
+    /// Do not pass the environment variable `key` on to the child
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Command {
+        self.inner.env_remove(key);
+        self
+    }
+
+    /// Do not pass any environment variables on to the child, except the ones set with `env`
+    pub fn env_clear(&mut self) -> &mut Command {
+        self.inner.env_clear();
+        self
+    }
+
+    /// Set the working directory of the child
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Command {
+        self.inner.current_dir(dir);
+        self
+    }
+
+    /// Configure the standard input of the child, `Stdio::piped()` gives a `PipeWriter`
+    pub fn stdin(&mut self, cfg: Stdio) -> &mut Command {
+        self.inner.stdin(cfg);
+        self
+    }
+
+    /// Configure the standard output of the child, `Stdio::piped()` gives a `PipeReader`
+    pub fn stdout(&mut self, cfg: Stdio) -> &mut Command {
+        self.inner.stdout(cfg);
+        self
+    }
+
+    /// Configure the standard error of the child, `Stdio::piped()` gives a `PipeReader`
+    pub fn stderr(&mut self, cfg: Stdio) -> &mut Command {
+        self.inner.stderr(cfg);
+        self
+    }
+
+    /// Start the child
+    pub fn spawn(&mut self) -> io::Result<Child> {
+        let mut child = try!(self.inner.spawn());
+
+        // Taken before anything could fail, so that the pipes are closed in any case
+        let stdin = child.stdin.take().map(|pipe| pipe.into_raw_fd());
+        let stdout = child.stdout.take().map(|pipe| pipe.into_raw_fd());
+        let stderr = child.stderr.take().map(|pipe| pipe.into_raw_fd());
+
+        // The standard library does not reap the child on drop, it is up to `Child` now
+        let mut child = Child {
+            pid: child.id() as libc::pid_t,
+            status: None,
+            stdin: stdin.map(|fd| unsafe { PipeWriter::from_raw_fd(fd) }),
+            stdout: stdout.map(|fd| unsafe { PipeReader::from_raw_fd(fd) }),
+            stderr: stderr.map(|fd| unsafe { PipeReader::from_raw_fd(fd) }),
+        };
+
+        for fd in stdin.iter().chain(stdout.iter()).chain(stderr.iter()) {
+            if let Err(err) = set_nonblocking(*fd) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(err);
+            }
+        }
+        Ok(child)
+    }
+
+    /// Run the child to completion, capturing its standard output and error
+    pub fn output(&mut self) -> io::Result<Output> {
+        self.stdout(Stdio::piped());
+        self.stderr(Stdio::piped());
+        try!(self.spawn()).wait_with_output()
+    }
+
+    /// Run the child to completion
+    pub fn status(&mut self) -> io::Result<ExitStatus> {
+        try!(self.spawn()).wait()
+    }
+}
+
+/// A running or exited child process, see `std::process::Child`.
+///
+/// Dropping it neither kills nor reaps the child.
+#[derive(Debug)]
+pub struct Child {
+    pid: libc::pid_t,
+    status: Option<ExitStatus>,
+
+    /// Standard input of the child, if it is piped
+    pub stdin: Option<PipeWriter>,
+    /// Standard output of the child, if it is piped
+    pub stdout: Option<PipeReader>,
+    /// Standard error of the child, if it is piped
+    pub stderr: Option<PipeReader>,
+}
+
+impl Child {
+    /// Process id of the child
+    pub fn id(&self) -> u32 {
+        self.pid as u32
+    }
+
+    /// Kill the child with `SIGKILL`
+    pub fn kill(&mut self) -> io::Result<()> {
+        // The pid may belong to another process once the child has been reaped
+        if self.status.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "invalid argument: can't kill an exited process"));
+        }
+
+        if unsafe { libc::kill(self.pid, libc::SIGKILL) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// The exit status of the child if it has exited, without waiting
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.waitpid(sys::WNOHANG)
+    }
+
+    /// Wait until the child has exited. Its standard input is closed first, so that a child
+    /// reading it to the end does not wait forever.
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        drop(self.stdin.take());
+
+        if let Some(status) = self.status {
+            return Ok(status);
+        }
+
+        // Only the current coroutine waits, the Processor goes on running the others
+        let pid = self.pid;
+        let status = match Scheduler::run_blocking(move || waitpid(pid, 0)) {
+            Ok(ret) => try!(ret),
+            Err(..) => {
+                return Err(io::Error::new(io::ErrorKind::Other, "waiting for the child panicked"))
+            }
+        };
+
+        self.status = status;
+        Ok(status.expect("waitpid returned without a status"))
+    }
+
+    /// Wait until the child has exited, collecting its standard output and error
+    pub fn wait_with_output(mut self) -> io::Result<Output> {
+        drop(self.stdin.take());
+
+        // Read at the same time, a child blocked on writing one of them would never exit
+        let stderr = self.stderr.take().map(|mut pipe| {
+            Scheduler::spawn(move || {
+                let mut buf = Vec::new();
+                pipe.read_to_end(&mut buf).map(|_| buf)
+            })
+        });
+
+        let mut stdout = Vec::new();
+        if let Some(ref mut pipe) = self.stdout {
+            try!(pipe.read_to_end(&mut stdout));
+        }
+
+        let stderr = match stderr {
+            Some(hdl) => {
+                match hdl.join() {
+                    Ok(r) => try!(r),
+                    Err(..) => {
+                        return Err(io::Error::new(io::ErrorKind::Other,
+                                                  "reading the standard error panicked"))
+                    }
+                }
+            }
+            None => Vec::new(),
+        };
+
+        Ok(Output {
+            status: try!(self.wait()),
+            stdout: stdout,
+            stderr: stderr,
+        })
+    }
+
+    fn waitpid(&mut self, flags: libc::c_int) -> io::Result<Option<ExitStatus>> {
+        if self.status.is_none() {
+            self.status = try!(waitpid(self.pid, flags));
+        }
+        Ok(self.status)
+    }
+}
+
+// `None` if the child has not exited yet, which only happens with `WNOHANG`
+fn waitpid(pid: libc::pid_t, flags: libc::c_int) -> io::Result<Option<ExitStatus>> {
+    let mut status = 0;
+    loop {
+        match unsafe { libc::waitpid(pid, &mut status, flags) } {
+            0 => return Ok(None),
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            _ => return Ok(Some(ExitStatus(status))),
+        }
+    }
+}
+
+/// How a child has exited, see `std::process::ExitStatus`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStatus(libc::c_int);
+
+impl ExitStatus {
+    /// Whether the child has exited with code 0
+    pub fn success(&self) -> bool {
+        self.code() == Some(0)
+    }
+
+    /// Exit code of the child, `None` if it has been killed by a signal
+    pub fn code(&self) -> Option<i32> {
+        if self.0 & 0x7f == 0 {
+            Some((self.0 >> 8) & 0xff)
+        } else {
+            None
+        }
+    }
+
+    /// Signal which has killed the child
+    pub fn signal(&self) -> Option<i32> {
+        match self.0 & 0x7f {
+            0 => None,
+            sig => Some(sig),
+        }
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.code(), self.signal()) {
+            (Some(code), _) => write!(f, "exit code: {}", code),
+            (None, Some(sig)) => write!(f, "signal: {}", sig),
+            (None, None) => write!(f, "unrecognized wait status: {}", self.0),
+        }
+    }
+}
+
+/// Exit status and captured output of a finished child, see `Command::output`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    pub status: ExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    use scheduler::Scheduler;
+
+    #[test]
+    fn test_process() {
+        Scheduler::new()
+            .run(|| {
+                let mut child = Command::new("sh")
+                                    .arg("-c")
+                                    .arg("cat; echo failed >&2; exit 3")
+                                    .stdin(Stdio::piped())
+                                    .stdout(Stdio::piped())
+                                    .stderr(Stdio::piped())
+                                    .spawn()
+                                    .unwrap();
+                child.stdin.as_mut().unwrap().write_all(b"hello").unwrap();
+
+                let output = child.wait_with_output().unwrap();
+                assert_eq!(output.status.code(), Some(3));
+                assert!(!output.status.success());
+                assert_eq!(output.stdout, b"hello");
+                assert_eq!(output.stderr, b"failed\n");
+
+                // The Processor keeps running the other coroutines while waiting
+                let ticks = Arc::new(AtomicUsize::new(0));
+                let ticker = {
+                    let ticks = ticks.clone();
+                    Scheduler::spawn(move || {
+                        for _ in 0..3 {
+                            ::sleep(Duration::from_millis(10));
+                            ticks.fetch_add(1, Ordering::SeqCst);
+                        }
+                    })
+                };
+                let status = Command::new("sleep").arg("0.1").status().unwrap();
+                assert!(status.success());
+                assert_eq!(ticks.load(Ordering::SeqCst), 3);
+                ticker.join().unwrap();
+
+                let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+                assert_eq!(child.try_wait().unwrap(), None);
+                child.kill().unwrap();
+                assert_eq!(child.wait().unwrap().signal(), Some(9));
+                assert!(child.kill().is_err());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_wait_concurrent() {
+        Scheduler::new()
+            .run(|| {
+                // Each one waits on its own thread, instead of one after the other
+                let started = Instant::now();
+                let waiters: Vec<_> = (0..4)
+                                          .map(|_| {
+                                              Scheduler::spawn(|| {
+                                                  Command::new("sleep").arg("0.2").status()
+                                              })
+                                          })
+                                          .collect();
+                for waiter in waiters {
+                    assert!(waiter.join().unwrap().unwrap().success());
+                }
+                assert!(started.elapsed() < Duration::from_millis(600));
+            })
+            .unwrap();
+
+        // On the calling thread outside of a Scheduler
+        assert_eq!(Command::new("sh").arg("-c").arg("exit 2").status().unwrap().code(),
+                   Some(2));
+    }
+}
@@ -0,0 +1,173 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Spawning coroutines into another Scheduler
+//!
+//! Several Schedulers may run in one process to isolate subsystems from each other.
+//! A `Remote` lets one of them offload work into another one, and the channels in
+//! `sync::mpsc` could be used to communicate between coroutines of different Schedulers.
+
+use std::any::Any;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+
+use coroutine::Coroutine;
+use options::Options;
+use runtime::processor::ProcMessage;
+use scheduler::{JoinHandle, Scheduler, ShuttingDown};
+
+struct RemoteInner {
+    processors: Mutex<Vec<Sender<ProcMessage>>>,
+    next: AtomicUsize,
+}
+
+/// Handle of a Scheduler which could spawn coroutines into it from any thread,
+/// including the Processors of another Scheduler. See `Scheduler::remote`.
+#[derive(Clone)]
+pub struct Remote {
+    inner: Arc<RemoteInner>,
+}
+
+impl Remote {
+    #[doc(hidden)]
+    pub fn new() -> Remote {
+        Remote {
+            inner: Arc::new(RemoteInner {
+                processors: Mutex::new(Vec::new()),
+                next: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    // Called by the Scheduler when it starts and stops running
+    #[doc(hidden)]
+    pub fn attach(&self, processors: Vec<Sender<ProcMessage>>) {
+        *self.inner.processors.lock().unwrap() = processors;
+    }
+
+    #[doc(hidden)]
+    pub fn detach(&self) {
+        self.inner.processors.lock().unwrap().clear();
+    }
+
+    // Mailboxes of the Scheduler's Processors, indexed by Processor id
+    #[doc(hidden)]
+    pub fn processors(&self) -> Vec<Sender<ProcMessage>> {
+        self.inner.processors.lock().unwrap().clone()
+    }
+
+    /// Whether the Scheduler is running and accepts coroutines
+    pub fn is_running(&self) -> bool {
+        !self.inner.processors.lock().unwrap().is_empty()
+    }
+
+    /// Spawn a coroutine in the remote Scheduler
+    pub fn spawn<F, T>(&self, f: F) -> Result<JoinHandle<T>, ShuttingDown>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        self.spawn_opts(f, Options::default())
+    }
+
+    /// Spawn a coroutine with options in the remote Scheduler.
+    ///
+    /// Fails with `ShuttingDown` if the Scheduler is not running.
+    pub fn spawn_opts<F, T>(&self, f: F, opts: Options) -> Result<JoinHandle<T>, ShuttingDown>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        let processors = self.inner.processors.lock().unwrap();
+        if processors.is_empty() {
+            return Err(ShuttingDown);
+        }
+
+        let (tx, rx) = ::sync::mpsc::channel::<Result<T, Box<Any + Send + 'static>>>();
+        let wrapper = move || {
+            // Accounted in the Scheduler actually running it
+            Scheduler::instance().unwrap().add_work();
+
+            let ret = unsafe { ::try(move || f()) };
+            if let Err(ref payload) = ret {
+                Scheduler::report_panic(payload);
+            }
+            let _ = tx.send(ret);
+        };
+
+        let coro = Coroutine::spawn_opts(Box::new(wrapper), opts, None);
+        let shared = coro.shared().clone();
+
+        let idx = self.inner.next.fetch_add(1, Ordering::Relaxed) % processors.len();
+        if let Err(err) = processors[idx].send(ProcMessage::ready(coro)) {
+            if let ProcMessage::Ready(mut coro, _) = err.0 {
+                coro.set_drop_allowed();
+            }
+            return Err(ShuttingDown);
+        }
+
+        Ok(JoinHandle::new(rx, shared))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use scheduler::Scheduler;
+    use sync::mpsc::channel;
+
+    #[test]
+    fn test_spawn_into_remote() {
+        let mut remote_sched = Scheduler::new().with_workers(2);
+        let remote = remote_sched.remote();
+        let (done_tx, done_rx) = channel::<()>();
+        let remote1 = remote.clone();
+
+        let remote_thread = thread::spawn(move || {
+            remote_sched.run(move || {
+                            let _ = done_rx.recv();
+                        })
+                        .unwrap();
+        });
+
+        Scheduler::new()
+            .run(move || {
+                let local = Scheduler::instance().unwrap() as *const Scheduler as usize;
+
+                while !remote1.is_running() {
+                    ::sleep_ms(1);
+                }
+
+                let hdl = remote1.spawn(|| {
+                                     Scheduler::instance().unwrap() as *const Scheduler as usize
+                                 })
+                                 .unwrap();
+                let ran_on = hdl.join().unwrap();
+                assert!(ran_on != local);
+
+                done_tx.send(()).unwrap();
+            })
+            .unwrap();
+
+        remote_thread.join().unwrap();
+        assert!(remote.spawn(|| {}).is_err());
+    }
+}
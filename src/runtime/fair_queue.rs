@@ -0,0 +1,173 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Run queue sharing the running time of a Processor fairly between groups of coroutines
+//!
+//! Every group has a virtual time, the running time charged to it divided by its weight. The
+//! runnable coroutine of the group with the smallest virtual time is resumed next, so a group
+//! with thousands of runnable coroutines gets no more time than a group with a single one.
+//! Groups which had nothing to run start over from the smallest virtual time when they become
+//! runnable again, instead of catching up with the time they have missed.
+
+use std::collections::{HashMap, VecDeque};
+
+// Number of groups kept before the idle ones are forgotten
+const PRUNE_GROUPS_AT: usize = 64;
+
+struct Group<T> {
+    queue: VecDeque<T>,
+    // Nanoseconds charged to the group, divided by its weight
+    vtime: u64,
+}
+
+pub struct FairQueue<T> {
+    groups: HashMap<usize, Group<T>>,
+    // Virtual time of the group picked last
+    floor: u64,
+    len: usize,
+}
+
+impl<T> FairQueue<T> {
+    pub fn new() -> FairQueue<T> {
+        FairQueue {
+            groups: HashMap::new(),
+            floor: 0,
+            len: 0,
+        }
+    }
+
+    /// Number of queued items
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Queue an item of the group behind the others of the same group
+    pub fn push(&mut self, group: usize, item: T) {
+        let floor = self.floor;
+        let group = self.groups.entry(group).or_insert_with(|| {
+            Group {
+                queue: VecDeque::new(),
+                vtime: floor,
+            }
+        });
+
+        if group.queue.is_empty() && group.vtime < floor {
+            group.vtime = floor;
+        }
+        group.queue.push_back(item);
+        self.len += 1;
+    }
+
+    /// Take the first item of the group which has been charged the least
+    pub fn pop(&mut self) -> Option<T> {
+        let (id, vtime) = match self.groups
+                                    .iter()
+                                    .filter(|&(_, group)| !group.queue.is_empty())
+                                    .map(|(id, group)| (*id, group.vtime))
+                                    .min_by_key(|&(_, vtime)| vtime) {
+            Some(next) => next,
+            None => return None,
+        };
+        self.floor = vtime;
+
+        let item = self.groups.get_mut(&id).unwrap().queue.pop_front();
+        self.len -= 1;
+        item
+    }
+
+    /// Charge `nanos` of running time to the group, weighing more gets charged less
+    pub fn charge(&mut self, group: usize, weight: u32, nanos: u64) {
+        let floor = self.floor;
+        {
+            let group = self.groups.entry(group).or_insert_with(|| {
+                Group {
+                    queue: VecDeque::new(),
+                    vtime: floor,
+                }
+            });
+            group.vtime += nanos / weight as u64;
+        }
+
+        // Forget the idle groups which would start over from the floor anyway
+        if self.groups.len() > PRUNE_GROUPS_AT {
+            let idle: Vec<usize> = self.groups
+                                       .iter()
+                                       .filter(|&(_, g)| g.queue.is_empty() && g.vtime <= floor)
+                                       .map(|(id, _)| *id)
+                                       .collect();
+            for id in idle {
+                self.groups.remove(&id);
+            }
+        }
+    }
+
+    /// Take all queued items out of the queue
+    pub fn drain(&mut self) -> Vec<T> {
+        let mut items = Vec::with_capacity(self.len);
+        for (_, group) in self.groups.iter_mut() {
+            items.extend(group.queue.drain(..));
+        }
+        self.len = 0;
+        items
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fair_queue() {
+        let mut queue = FairQueue::new();
+        for i in 0..1000 {
+            queue.push(1, (1, i));
+        }
+        queue.push(2, (2, 0));
+        queue.push(2, (2, 1));
+        assert_eq!(queue.len(), 1002);
+
+        // Group 1 has been charged for its first item, group 2 gets its turn
+        let first = queue.pop().unwrap();
+        queue.charge(first.0, 1, 1_000);
+        let second = queue.pop().unwrap();
+        assert!(second.0 != first.0);
+        queue.charge(second.0, 1, 1_000);
+
+        // Twice the weight, twice the share
+        let mut picked = [0, 0, 0];
+        for _ in 0..30 {
+            let (group, _) = queue.pop().unwrap();
+            picked[group] += 1;
+            queue.charge(group, if group == 2 { 2 } else { 1 }, 1_000);
+            queue.push(group, (group, 0));
+        }
+        assert_eq!(picked[2], 20);
+        assert_eq!(picked[1], 10);
+
+        assert_eq!(queue.drain().len(), 1002);
+        assert!(queue.is_empty());
+        assert_eq!(queue.pop(), None);
+    }
+}
@@ -0,0 +1,159 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Smoothed load of the Processors
+//!
+//! The length of a run queue jumps with every spawned or woken up coroutine, far too noisy
+//! to drive load balancing or scaling decisions. The load is sampled periodically as the
+//! number of runnable coroutines plus the number of coroutines resumed per sample interval,
+//! and smoothed with an exponential moving average.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Minimum time between two samples
+pub const LOAD_SAMPLE_INTERVAL_MS: u64 = 100;
+
+/// Time constant of the moving average, the weight of a sample has decayed to 1/e after it
+pub const LOAD_WINDOW_MS: u64 = 1_000;
+
+// The smoothed load is kept in fixed point
+const LOAD_SCALE: f64 = 1_000.0;
+
+/// Exponential moving average of the load of a Processor
+pub struct LoadAverage {
+    value: AtomicUsize,
+    // Coroutines resumed since the last sample
+    resumed: AtomicUsize,
+    // Milliseconds since `base_ms` of the last sample. Kept relative and compared with
+    // wrapping arithmetic, so that it fits into an AtomicUsize on 32-bit platforms as well.
+    sampled_at: AtomicUsize,
+    sampled: AtomicBool,
+    base_ms: u64,
+}
+
+impl LoadAverage {
+    /// The times of the samples are measured from `base_ms`, e.g. the current time
+    pub fn new(base_ms: u64) -> LoadAverage {
+        LoadAverage {
+            value: AtomicUsize::new(0),
+            resumed: AtomicUsize::new(0),
+            sampled_at: AtomicUsize::new(0),
+            sampled: AtomicBool::new(false),
+            base_ms: base_ms,
+        }
+    }
+
+    /// Count a coroutine resumed by the Processor
+    #[inline]
+    pub fn record_resume(&self) {
+        self.resumed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Fold the number of runnable coroutines into the average, unless the previous sample
+    /// has been taken less than `LOAD_SAMPLE_INTERVAL_MS` before `now_ms`. Returns whether
+    /// the sample has been taken.
+    ///
+    /// Must not be called from several threads at the same time.
+    pub fn sample(&self, runnable: usize, now_ms: u64) -> bool {
+        let now = now_ms.saturating_sub(self.base_ms) as usize;
+        let elapsed = now.wrapping_sub(self.sampled_at.load(Ordering::Relaxed)) as u64;
+        let first = !self.sampled.load(Ordering::Relaxed);
+        if !first && elapsed < LOAD_SAMPLE_INTERVAL_MS {
+            return false;
+        }
+        self.sampled_at.store(now, Ordering::Relaxed);
+        self.sampled.store(true, Ordering::Relaxed);
+
+        let resumed = self.resumed.swap(0, Ordering::Relaxed) as f64;
+        if first {
+            // Nothing to compare the resumed coroutines with yet
+            return true;
+        }
+
+        let elapsed = elapsed as f64;
+        let sample = runnable as f64 + resumed * LOAD_SAMPLE_INTERVAL_MS as f64 / elapsed;
+        let alpha = 1.0 - (-elapsed / LOAD_WINDOW_MS as f64).exp();
+
+        let value = self.get();
+        let value = value + alpha * (sample - value);
+        self.value.store((value * LOAD_SCALE) as usize, Ordering::Relaxed);
+        true
+    }
+
+    /// The smoothed load
+    pub fn get(&self) -> f64 {
+        self.value.load(Ordering::Relaxed) as f64 / LOAD_SCALE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_average() {
+        let load = LoadAverage::new(0);
+        load.sample(0, 10_000);
+        assert_eq!(load.get(), 0.0);
+
+        for _ in 0..10 {
+            load.record_resume();
+        }
+        load.sample(5, 10_100);
+        let first = load.get();
+        assert!(first > 1.0 && first < 15.0);
+
+        // Too early for another sample
+        assert!(!load.sample(100, 10_150));
+        assert_eq!(load.get(), first);
+
+        // Keeps rising under constant load, without overshooting
+        let mut now = 10_100;
+        for _ in 0..100 {
+            now += LOAD_SAMPLE_INTERVAL_MS;
+            load.sample(15, now);
+        }
+        assert!(load.get() > 14.0 && load.get() <= 15.0);
+
+        // Decays once idle
+        for _ in 0..100 {
+            now += LOAD_SAMPLE_INTERVAL_MS;
+            load.sample(0, now);
+        }
+        assert!(load.get() < 0.1);
+    }
+
+    #[test]
+    fn test_load_average_epoch() {
+        // Milliseconds since the UNIX epoch do not fit into 32 bits
+        let base = 1_500_000_000_000;
+        let load = LoadAverage::new(base);
+        load.sample(0, base);
+
+        let mut now = base;
+        for _ in 0..100 {
+            now += LOAD_SAMPLE_INTERVAL_MS;
+            assert!(load.sample(15, now));
+        }
+        assert!(!load.sample(15, now + LOAD_SAMPLE_INTERVAL_MS - 1));
+        assert!(load.get() > 14.0 && load.get() <= 15.0);
+    }
+}
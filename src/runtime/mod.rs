@@ -21,4 +21,7 @@
 
 pub use self::processor::Processor;
 
+pub mod fair_queue;
+pub mod load;
 pub mod processor;
+pub mod timer_wheel;
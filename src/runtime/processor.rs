@@ -25,24 +25,169 @@ use rand::Rng;
 use std::any::Any;
 use std::boxed::FnBox;
 use std::cell::UnsafeCell;
+use std::collections::VecDeque;
 use std::mem;
 use std::ops::{Deref, DerefMut};
 use std::sync::{Arc, Weak};
-use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
 use std::thread::{self, Builder};
+use std::time::Instant;
 
 use deque::{BufferPool, Stolen, Worker, Stealer};
-use rand;
 
-use coroutine::{Coroutine, State, Handle};
-use options::Options;
-use scheduler::Scheduler;
+use coroutine::{Coroutine, State, Handle, Shared};
+use observer::Event;
+use runtime::fair_queue::FairQueue;
+use runtime::load::LoadAverage;
+use options::{Options, Priority, SpawnHint};
+use scheduler::{Cancelled, IdleStrategy, Scheduler, ShuttingDown};
 
 thread_local!(static PROCESSOR: UnsafeCell<Option<Processor>> = UnsafeCell::new(None));
 
 #[derive(Debug)]
 pub struct ForceUnwind;
 
+// At most this many coroutines are moved from the run queue into the FairQueue at a time,
+// the others could still be stolen by the neighbors
+const FAIR_QUEUE_BATCH: usize = 32;
+
+// Thread id assigned by the kernel, which differs from the pthread handle
+#[cfg(all(target_os = "linux",
+          any(target_arch = "x86", target_arch = "x86_64", target_arch = "arm",
+              target_arch = "aarch64")))]
+fn os_thread_id() -> Option<u64> {
+    Some(unsafe { ::sys::syscall(::sys::SYS_gettid) } as u64)
+}
+
+#[cfg(not(all(target_os = "linux",
+              any(target_arch = "x86", target_arch = "x86_64", target_arch = "arm",
+                  target_arch = "aarch64"))))]
+fn os_thread_id() -> Option<u64> {
+    None
+}
+
+// Counters of a Processor reported by `Scheduler::stats()`
+struct ProcessorCounters {
+    resumed: AtomicUsize,
+    // Steals from the neighbors made by the Processor
+    steals_attempted: AtomicUsize,
+    steals_succeeded: AtomicUsize,
+    // Run queue length summed over the load samples
+    sampled_len: AtomicUsize,
+    samples: AtomicUsize,
+}
+
+impl ProcessorCounters {
+    fn new() -> ProcessorCounters {
+        ProcessorCounters {
+            resumed: AtomicUsize::new(0),
+            steals_attempted: AtomicUsize::new(0),
+            steals_succeeded: AtomicUsize::new(0),
+            sampled_len: AtomicUsize::new(0),
+            samples: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Stealing side of a Processor's run queue, which keeps track of the queue length,
+/// of the smoothed load and of the counters of the Processor
+#[derive(Clone)]
+pub struct RunQueueStealer {
+    stealer: Stealer<Handle>,
+    high: Stealer<Handle>,
+    low: Stealer<(Handle, Instant)>,
+    // Coroutines in the queues of all priorities
+    len: Arc<AtomicUsize>,
+    // Coroutines queued on the Processor which could not be stolen
+    local_len: Arc<AtomicUsize>,
+    load: Arc<LoadAverage>,
+    counters: Arc<ProcessorCounters>,
+}
+
+impl RunQueueStealer {
+    /// Take the oldest coroutine of the highest priority
+    pub fn steal(&self) -> Option<Handle> {
+        let stolen = match self.high.steal() {
+            Stolen::Data(hdl) => Some(hdl),
+            _ => {
+                match self.stealer.steal() {
+                    Stolen::Data(hdl) => Some(hdl),
+                    _ => self.steal_low().map(|(hdl, _)| hdl),
+                }
+            }
+        };
+
+        if stolen.is_some() {
+            self.len.fetch_sub(1, Ordering::SeqCst);
+        }
+        stolen
+    }
+
+    fn steal_low(&self) -> Option<(Handle, Instant)> {
+        match self.low.steal() {
+            Stolen::Data(entry) => Some(entry),
+            _ => None,
+        }
+    }
+
+    /// Approximate length of the run queue, including the coroutines which could not be stolen
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst) + self.local_len.load(Ordering::SeqCst)
+    }
+
+    /// Approximate number of coroutines which could be stolen
+    pub fn stealable_len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    /// Smoothed load of the Processor, see `runtime::load`
+    pub fn load(&self) -> f64 {
+        self.load.get()
+    }
+
+    /// Take a sample of the load, called periodically by the Scheduler
+    pub fn sample_load(&self, now_ms: u64) {
+        let len = self.len();
+        if self.load.sample(len, now_ms) {
+            self.counters.sampled_len.fetch_add(len, Ordering::Relaxed);
+            self.counters.samples.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of times the Processor has resumed a coroutine
+    pub fn resumed(&self) -> usize {
+        self.counters.resumed.load(Ordering::Relaxed)
+    }
+
+    /// Number of times the Processor has tried to steal a coroutine from a neighbor
+    pub fn steals_attempted(&self) -> usize {
+        self.counters.steals_attempted.load(Ordering::Relaxed)
+    }
+
+    /// Number of coroutines the Processor has stolen from its neighbors
+    pub fn steals_succeeded(&self) -> usize {
+        self.counters.steals_succeeded.load(Ordering::Relaxed)
+    }
+
+    /// Mean length of the run queue over the load samples
+    pub fn mean_len(&self) -> f64 {
+        let samples = self.counters.samples.load(Ordering::Relaxed);
+        if samples == 0 {
+            return 0.0;
+        }
+        self.counters.sampled_len.load(Ordering::Relaxed) as f64 / samples as f64
+    }
+
+    // Count a steal from a neighbor made by the Processor owning this queue
+    fn record_steal(&self, succeeded: bool) {
+        self.counters.steals_attempted.fetch_add(1, Ordering::Relaxed);
+        if succeeded {
+            self.counters.steals_succeeded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Processor {
     inner: Arc<ProcessorInner>,
@@ -53,6 +198,7 @@ unsafe impl Sync for Processor {}
 
 /// Processing unit of a thread
 pub struct ProcessorInner {
+    id: usize,
     weak_self: WeakProcessor,
     scheduler: *mut Scheduler,
 
@@ -65,25 +211,45 @@ pub struct ProcessorInner {
     // NOTE: ONLY to be used to communicate the result from yield_with() to resume().
     last_state: State,
 
-    rng: rand::XorShiftRng,
+    rng: Box<Rng + Send>,
     queue_worker: Worker<Handle>,
-    queue_stealer: Stealer<Handle>,
-    neighbor_stealers: Vec<Stealer<Handle>>, // TODO: make it a Arc<Vec<>>
+    queue_stealer: RunQueueStealer,
+    queue_len: Arc<AtomicUsize>,
+    queue_high: bool,
+    // Coroutines taken from the queue while the Scheduler shares time between groups
+    fair_queue: Option<FairQueue<Handle>>,
+    // Coroutines of high and low priority, with the time the low ones have been queued at
+    high_queue: Worker<Handle>,
+    low_queue: Worker<(Handle, Instant)>,
+    // Oldest low priority coroutine, taken from its queue to check how long it has waited
+    low_next: Option<(Handle, Instant)>,
+    // Coroutines pinned to this Processor, which are not stolen by the neighbors
+    pinned_queue: VecDeque<Handle>,
+    pinned_turn: bool,
+    neighbor_stealers: Vec<RunQueueStealer>, // TODO: make it a Arc<Vec<>>
     take_coro_cb: Option<&'static mut FnMut(Handle)>,
 
     chan_sender: Sender<ProcMessage>,
     chan_receiver: Receiver<ProcMessage>,
 
     is_exiting: bool,
+    is_draining: bool,
 }
 
 impl Processor {
-    fn new_with_neighbors(sched: *mut Scheduler, neigh: Vec<Stealer<Handle>>) -> Processor {
+    fn new_with_neighbors(processor_id: usize,
+                          sched: *mut Scheduler,
+                          neigh: Vec<RunQueueStealer>)
+                          -> Processor {
         let (worker, stealer) = BufferPool::new().deque();
+        let (high_worker, high_stealer) = BufferPool::new().deque();
+        let (low_worker, low_stealer) = BufferPool::new().deque();
+        let queue_len = Arc::new(AtomicUsize::new(0));
         let (tx, rx) = mpsc::channel();
 
         let mut p = Processor {
             inner: Arc::new(ProcessorInner {
+                id: processor_id,
                 weak_self: unsafe { mem::zeroed() },
                 scheduler: sched,
 
@@ -91,9 +257,29 @@ impl Processor {
                 current_coro: None,
                 last_state: State::Suspended,
 
-                rng: rand::weak_rng(),
+                rng: unsafe { (*sched).new_rng(processor_id) },
                 queue_worker: worker,
-                queue_stealer: stealer,
+                queue_stealer: RunQueueStealer {
+                    stealer: stealer,
+                    high: high_stealer,
+                    low: low_stealer,
+                    len: queue_len.clone(),
+                    local_len: Arc::new(AtomicUsize::new(0)),
+                    load: Arc::new(LoadAverage::new(::time::recent_millis())),
+                    counters: Arc::new(ProcessorCounters::new()),
+                },
+                queue_len: queue_len,
+                queue_high: false,
+                fair_queue: if unsafe { (*sched).fair_queuing() } {
+                    Some(FairQueue::new())
+                } else {
+                    None
+                },
+                high_queue: high_worker,
+                low_queue: low_worker,
+                low_next: None,
+                pinned_queue: VecDeque::new(),
+                pinned_turn: false,
                 neighbor_stealers: neigh,
                 take_coro_cb: None,
 
@@ -101,6 +287,7 @@ impl Processor {
                 chan_receiver: rx,
 
                 is_exiting: false,
+                is_draining: false,
             }),
         };
 
@@ -123,16 +310,17 @@ impl Processor {
 
     pub fn run_with_neighbors(processor_id: usize,
                               sched: *mut Scheduler,
-                              neigh: Vec<Stealer<Handle>>)
-                              -> (thread::JoinHandle<()>, Sender<ProcMessage>, Stealer<Handle>) {
-        let mut p = Processor::new_with_neighbors(sched, neigh);
+                              neigh: Vec<RunQueueStealer>)
+                              -> (thread::JoinHandle<()>, Sender<ProcMessage>, RunQueueStealer) {
+        let mut p = Processor::new_with_neighbors(processor_id, sched, neigh);
         let msg = p.handle();
         let st = p.stealer();
 
         let hdl = Builder::new()
-                      .name(format!("Processor #{}", processor_id))
+                      .name(unsafe { (*sched).thread_name(processor_id) })
                       .spawn(move || {
                           Processor::set_tls(&mut p);
+                          p.register_thread();
                           p.schedule();
                       })
                       .unwrap();
@@ -145,20 +333,21 @@ impl Processor {
                           f: M)
                           -> (thread::JoinHandle<()>,
                               Sender<ProcMessage>,
-                              Stealer<Handle>,
+                              RunQueueStealer,
                               ::std::sync::mpsc::Receiver<Result<T, Box<Any + Send + 'static>>>)
         where M: FnOnce() -> T + Send + 'static,
               T: Send + 'static
     {
-        let mut p = Processor::new_with_neighbors(sched, Vec::new());
+        let mut p = Processor::new_with_neighbors(processor_id, sched, Vec::new());
         let (msg, st) = (p.handle(), p.stealer());
         let (tx, rx) = ::std::sync::mpsc::channel();
 
         let hdl =
             Builder::new()
-                .name(format!("Processor #{}", processor_id))
+                .name(unsafe { (*sched).thread_name(processor_id) })
                 .spawn(move || {
                     Processor::set_tls(&mut p);
+                    p.register_thread();
 
                     let wrapper = move || {
                         let ret = unsafe { ::try(move || f()) };
@@ -166,7 +355,8 @@ impl Processor {
                         // If sending fails Scheduler::run()'s loop would never quit --> unwrap.
                         tx.send(ret).unwrap();
                     };
-                    p.spawn_opts(Box::new(wrapper), Options::default());
+                    let shared = p.spawn_opts(Box::new(wrapper), Options::default());
+                    p.scheduler().set_main_coroutine(shared);
 
                     p.schedule();
                 })
@@ -175,6 +365,38 @@ impl Processor {
         (hdl, msg, st, rx)
     }
 
+    // Make the worker thread known to the Scheduler's statistics
+    fn register_thread(&self) {
+        if let Some(cpu) = self.scheduler().cpu_for(self.id) {
+            if let Err(err) = ::cpu::set_thread_affinity(&[cpu]) {
+                warn!("Failed to pin Processor #{} to CPU {}: {}", self.id, cpu, err);
+            }
+        }
+
+        let name = thread::current().name().unwrap_or("").to_owned();
+        self.scheduler().processor_started(self.id, name, os_thread_id(), self.stealer());
+    }
+
+    /// Next number of the Processor's random number generator
+    pub fn gen_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    /// Index of this Processor in the Scheduler
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Shared states of the currently running coroutine
+    pub fn current_shared(&self) -> Option<Arc<Shared>> {
+        self.current_coro.as_ref().map(|coro| coro.shared().clone())
+    }
+
+    /// Deepest stack usage of the currently running coroutine, if its stack is measured
+    pub fn current_stack_high_water_mark(&self) -> Option<usize> {
+        self.current_coro.as_ref().and_then(|coro| coro.stack_high_water_mark())
+    }
+
     pub fn scheduler(&self) -> &Scheduler {
         unsafe { &*self.scheduler }
     }
@@ -192,6 +414,62 @@ impl Processor {
     /// NOTE: DO NOT call any Scheduler or Processor method within the passed callback, other than ready().
     pub fn take_current_coroutine<U, F>(&mut self, f: F) -> U
         where F: FnOnce(Handle) -> U
+    {
+        let r = self.block_current(f);
+
+        // We are back! Exit right now!
+        if self.is_exiting {
+            panic!(ForceUnwind);
+        }
+
+        r
+    }
+
+    /// Like `take_current_coroutine()`, but reports a shutdown with an error instead of
+    /// unwinding the coroutine.
+    pub fn try_take_current_coroutine<U, F>(&mut self, f: F) -> Result<U, ShuttingDown>
+        where F: FnOnce(Handle) -> U
+    {
+        let r = self.block_current(f);
+        try!(self.check_shutdown());
+        Ok(r)
+    }
+
+    /// Returns `ShuttingDown` the first time the current coroutine observes a shutdown.
+    ///
+    /// Unwinds the coroutine if it has already been notified, so that a coroutine
+    /// retrying failed operations in a loop could not stall the shutdown.
+    pub fn check_shutdown(&self) -> Result<(), ShuttingDown> {
+        if !self.is_exiting && !self.scheduler().is_shutting_down() {
+            return Ok(());
+        }
+
+        if self.current_coro.as_ref().unwrap().shared().notify_shutdown() {
+            Err(ShuttingDown)
+        } else {
+            panic!(ForceUnwind);
+        }
+    }
+
+    /// Returns `Cancelled`, or unwinds if the Scheduler is configured so, once the current
+    /// coroutine has been aborted
+    pub fn check_cancelled(&self) -> Result<(), Cancelled> {
+        let aborted = match self.current_coro {
+            Some(ref coro) => coro.shared().is_aborted(),
+            None => false,
+        };
+
+        if !aborted {
+            Ok(())
+        } else if self.scheduler().cancel_unwinding() {
+            panic!(ForceUnwind);
+        } else {
+            Err(Cancelled)
+        }
+    }
+
+    fn block_current<U, F>(&mut self, f: F) -> U
+        where F: FnOnce(Handle) -> U
     {
         let mut f = Some(f);
         let mut r = None;
@@ -207,13 +485,17 @@ impl Processor {
 
             // Gets executed as soon as yield_with() returns in Processor::resume().
             self.take_coro_cb = Some(cb_ref_static);
-            self.yield_with(State::Blocked);
+            self.switch_to_main(State::Blocked);
+        }
+
+        if self.current_coro.as_ref().unwrap().shared().is_aborted() {
+            panic!(ForceUnwind);
         }
 
         r.unwrap()
     }
 
-    pub fn stealer(&self) -> Stealer<Handle> {
+    pub fn stealer(&self) -> RunQueueStealer {
         self.queue_stealer.clone()
     }
 
@@ -221,33 +503,39 @@ impl Processor {
         self.chan_sender.clone()
     }
 
-    pub fn spawn_opts(&mut self, f: Box<FnBox()>, opts: Options) {
-        let mut new_coro = Coroutine::spawn_opts(f, opts);
+    pub fn spawn_opts(&mut self, f: Box<FnBox()>, opts: Options) -> Arc<Shared> {
+        let hint = opts.spawn_hint.unwrap_or(self.scheduler().spawn_hint());
+        let parent = self.current_shared();
+        let mut new_coro = Coroutine::spawn_opts(f, opts, parent);
         new_coro.set_preferred_processor(Some(self.weak_self.clone()));
+        let shared = new_coro.shared().clone();
 
-        // NOTE: If Scheduler::spawn() is called we want to make
-        // sure that the spawned coroutine is executed immediately.
-        // TODO: Should we really do this?
-        if self.current_coro.is_some() {
+        // NOTE: With SpawnHint::Immediate the spawned coroutine is executed right away,
+        // otherwise it is queued and popped next once the current coroutine yields.
+        if self.current_coro.is_some() && hint == SpawnHint::Immediate {
             // Circumvent borrowck
-            let queue_worker = &self.queue_worker as *const Worker<Handle>;
+            let processor = self as *mut Processor;
 
             self.take_current_coroutine(|coro| unsafe {
-                // queue_worker.push() inserts at the front of the queue.
+                // push() inserts at the front of the queue.
                 // --> Insert new_coro last to ensure that it's at the front of the queue.
-                (&*queue_worker).push(coro);
-                (&*queue_worker).push(new_coro);
+                (&mut *processor).push(coro);
+                (&mut *processor).push(new_coro);
             });
         } else {
             self.ready(new_coro);
         }
+
+        shared
     }
 
     /// Run the processor
     fn schedule(&mut self) {
         'outerloop: loop {
+            ::time::update();
+
             // 1. Run all tasks in local queue
-            while let Some(hdl) = self.queue_worker.pop() {
+            while let Some(hdl) = self.pop_next() {
                 self.resume(hdl);
             }
 
@@ -271,11 +559,27 @@ impl Processor {
                             self.is_exiting = true;
                             resume_all_tasks = true;
                         }
-                        ProcMessage::Ready(mut coro) => {
-                            coro.set_preferred_processor(Some(self.weak_self.clone()));
-                            self.ready(coro);
-                            resume_all_tasks = true;
+                        ProcMessage::Ready(mut coro, _) => {
+                            if self.is_draining {
+                                self.hand_over(vec![coro]);
+                            } else {
+                                coro.set_preferred_processor(Some(self.weak_self.clone()));
+                                self.ready(coro);
+                                resume_all_tasks = true;
+                            }
+                        }
+                        ProcMessage::ReadyBatch(coros, _) => {
+                            if self.is_draining {
+                                self.hand_over(coros);
+                            } else {
+                                self.ready_batch(coros);
+                                resume_all_tasks = true;
+                            }
                         }
+                        ProcMessage::Drain => self.drain(),
+                        ProcMessage::Resume => self.is_draining = false,
+                        // Stale, the Processor has found work on its own meanwhile
+                        ProcMessage::Unpark(..) => {}
                     }
                 }
 
@@ -285,43 +589,212 @@ impl Processor {
                 }
             }
 
-            // 3. Randomly steal from neighbors as a last measure.
+            // 3. Randomly steal from neighbors as a last measure, unless drained.
             // TODO: To improve cache locality foreign lists should be split in half or so instead.
             let rand_idx = self.rng.gen::<usize>();
-            let total_stealers = self.neighbor_stealers.len();
+            let total_stealers = if self.is_draining {
+                0
+            } else {
+                self.neighbor_stealers.len()
+            };
 
             for idx in 0..total_stealers {
                 let idx = (rand_idx + idx) % total_stealers;
 
-                if let Stolen::Data(hdl) = self.neighbor_stealers[idx].steal() {
+                let stolen = self.neighbor_stealers[idx].steal();
+                self.queue_stealer.record_steal(stolen.is_some());
+
+                if let Some(hdl) = stolen {
                     self.resume(hdl);
                     continue 'outerloop;
                 }
             }
 
-            // Wait forever until we got notified
-            // TODO:
-            //   Could this be improved somehow?
-            //   Maybe by implementing a "processor-pool" akin to a thread-pool,
-            //   which would move park()ed Processors to a shared idle-queue.
-            //   Other Processors could then unpark() them as necessary in their own ready() method.
-            if let Ok(msg) = self.chan_receiver.recv() {
+            // Wait until we got notified, either with new work or by a neighbor which has
+            // queued more work than it could run right away
+            if let Some(msg) = self.wait_idle() {
                 match msg {
                     ProcMessage::NewNeighbor(nei) => self.neighbor_stealers.push(nei),
                     ProcMessage::Shutdown => {
                         self.is_exiting = true;
                         continue 'outerloop;
                     }
-                    ProcMessage::Ready(mut coro) => {
-                        coro.set_preferred_processor(Some(self.weak_self.clone()));
-                        self.ready(coro);
+                    ProcMessage::Ready(mut coro, sent_at) => {
+                        if self.is_draining {
+                            self.hand_over(vec![coro]);
+                        } else {
+                            self.scheduler().record_wakeup(sent_at.elapsed());
+                            coro.set_preferred_processor(Some(self.weak_self.clone()));
+                            self.ready(coro);
+                        }
+                    }
+                    ProcMessage::ReadyBatch(coros, sent_at) => {
+                        if self.is_draining {
+                            self.hand_over(coros);
+                        } else {
+                            self.scheduler().record_wakeup(sent_at.elapsed());
+                            self.ready_batch(coros);
+                        }
+                    }
+                    ProcMessage::Drain => self.drain(),
+                    ProcMessage::Resume => self.is_draining = false,
+                    ProcMessage::Unpark(sent_at) => {
+                        // Steal from the neighbors right away
+                        self.scheduler().record_wakeup(sent_at.elapsed());
                     }
                 }
             };
         }
     }
 
+    // Stop taking work and move the queued coroutines to the Processors in service
+    fn drain(&mut self) {
+        self.is_draining = true;
+
+        let mut coros = match self.fair_queue {
+            Some(ref mut fair_queue) => fair_queue.drain(),
+            None => Vec::new(),
+        };
+        self.queue_stealer.local_len.fetch_sub(coros.len(), Ordering::SeqCst);
+        while let Some(coro) = self.pop_next() {
+            coros.push(coro);
+        }
+        self.hand_over(coros);
+    }
+
+    // Send coroutines readied on this drained Processor to one in service
+    fn hand_over(&mut self, coros: Vec<Handle>) {
+        let (pinned, coros): (Vec<Handle>, Vec<Handle>) =
+            coros.into_iter().partition(|coro| coro.shared().is_pinned());
+
+        // Pinned coroutines stay, even though this Processor is out of service
+        for mut coro in pinned {
+            coro.set_preferred_processor(Some(self.weak_self.clone()));
+            self.ready(coro);
+        }
+
+        if coros.is_empty() {
+            return;
+        }
+
+        let coros = match self.scheduler().drain_target() {
+            Some(target) => {
+                match target.send(ProcMessage::ReadyBatch(coros, Instant::now())) {
+                    Ok(()) => return,
+                    Err(err) => {
+                        match err.0 {
+                            ProcMessage::ReadyBatch(coros, _) => coros,
+                            _ => unreachable!(),
+                        }
+                    }
+                }
+            }
+            None => coros,
+        };
+
+        // Nobody else is left to run them
+        self.ready_batch(coros);
+    }
+
+    // Wait for a message according to the Scheduler's IdleStrategy, listed as idle meanwhile.
+    // Returns None if the neighbors got work to be stolen in the meantime.
+    fn wait_idle(&mut self) -> Option<ProcMessage> {
+        // Drained Processors sleep until they are told otherwise
+        if self.is_draining {
+            return self.chan_receiver.recv().ok();
+        }
+
+        self.scheduler().enter_idle(self.id);
+
+        // Neighbors may have queued work before this Processor got listed
+        let msg = if self.neighbors_have_work() {
+            None
+        } else {
+            self.wait_message()
+        };
+
+        self.scheduler().leave_idle(self.id);
+        msg
+    }
+
+    fn wait_message(&mut self) -> Option<ProcMessage> {
+        match self.scheduler().idle_strategy() {
+            IdleStrategy::Park => self.chan_receiver.recv().ok(),
+            IdleStrategy::SpinThenPark(spin) => {
+                let started = Instant::now();
+
+                while started.elapsed() < spin {
+                    match self.chan_receiver.try_recv() {
+                        Ok(msg) => return Some(msg),
+                        Err(TryRecvError::Disconnected) => return None,
+                        Err(TryRecvError::Empty) => {}
+                    }
+
+                    if self.neighbors_have_work() {
+                        return None;
+                    }
+                }
+
+                self.chan_receiver.recv().ok()
+            }
+            IdleStrategy::YieldThenPark(rounds) => {
+                for _ in 0..rounds {
+                    match self.chan_receiver.try_recv() {
+                        Ok(msg) => return Some(msg),
+                        Err(TryRecvError::Disconnected) => return None,
+                        Err(TryRecvError::Empty) => {}
+                    }
+
+                    if self.neighbors_have_work() {
+                        return None;
+                    }
+
+                    thread::yield_now();
+                }
+
+                self.chan_receiver.recv().ok()
+            }
+            IdleStrategy::Backoff { min, max } => {
+                let mut delay = min;
+
+                loop {
+                    match self.chan_receiver.try_recv() {
+                        Ok(msg) => return Some(msg),
+                        Err(TryRecvError::Disconnected) => return None,
+                        Err(TryRecvError::Empty) => {}
+                    }
+
+                    if self.neighbors_have_work() {
+                        return None;
+                    }
+
+                    thread::sleep(delay);
+                    delay = ::std::cmp::min(delay * 2, max);
+                }
+            }
+        }
+    }
+
+    fn neighbors_have_work(&self) -> bool {
+        self.neighbor_stealers.iter().any(|st| st.stealable_len() > 0)
+    }
+
     fn resume(&mut self, coro: Handle) {
+        self.queue_stealer.load.record_resume();
+        self.queue_stealer.counters.resumed.fetch_add(1, Ordering::Relaxed);
+
+        if coro.shared().set_blocked(false) {
+            self.scheduler().coroutine_unblocked();
+        }
+
+        // Running and blocked times are only of interest to the spans reported to observers
+        let timed = self.scheduler().has_observer();
+        if timed {
+            coro.shared().account_resumed(Instant::now());
+        }
+        let fair_started = self.fair_queue.as_ref().map(|_| Instant::now());
+        coro.shared().set_running(Some(self.id));
+
         unsafe {
             let current_coro: *const Coroutine = &*coro;
             
@@ -330,15 +803,35 @@ impl Processor {
         }
 
         let coro = self.current_coro.take().unwrap();
+        coro.shared().set_running(None);
+
+        if let Some(started) = fair_started {
+            let group = coro.shared().group();
+            let weight = self.scheduler().group_weight(group);
+            let elapsed = started.elapsed();
+            let nanos = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+            self.fair_queue.as_mut().unwrap().charge(group, weight, nanos);
+        }
+
+        if timed {
+            let blocked = match self.last_state {
+                State::Blocked => true,
+                _ => false,
+            };
+            coro.shared().account_suspended(Instant::now(), blocked);
+        }
 
         match self.last_state {
             State::Suspended => {
                 self.ready(coro);
             }
             State::Blocked => {
+                coro.shared().set_blocked(true);
+                self.scheduler().coroutine_blocked();
                 self.take_coro_cb.take().unwrap()(coro);
             }
             State::Finished => {
+                coro.shared().set_finished();
                 Scheduler::finished(coro);
             }
         }
@@ -346,7 +839,199 @@ impl Processor {
 
     /// Enqueue a coroutine to be resumed as soon as possible (making it the head of the queue)
     pub fn ready(&mut self, coro: Handle) {
-        self.queue_worker.push(coro);
+        if let Some(coro) = self.forward_pinned(coro) {
+            self.push(coro);
+        }
+    }
+
+    fn ready_batch(&mut self, coros: Vec<Handle>) {
+        for mut coro in coros {
+            coro.set_preferred_processor(Some(self.weak_self.clone()));
+            self.ready(coro);
+        }
+    }
+
+    // Send a coroutine pinned to another Processor back there.
+    // Returns the coroutine if it may run on this Processor.
+    fn forward_pinned(&self, coro: Handle) -> Option<Handle> {
+        if !coro.shared().is_pinned() {
+            return Some(coro);
+        }
+
+        let owner = match coro.preferred_processor() {
+            Some(ref owner) if owner.id() != self.id => owner.handle(),
+            _ => return Some(coro),
+        };
+
+        match owner.send(ProcMessage::ready(coro)) {
+            Ok(()) => None,
+            // The owner has exited already, the Scheduler is shutting down
+            Err(err) => {
+                match err.0 {
+                    ProcMessage::Ready(coro, _) => Some(coro),
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+
+    /// Whether the coroutine could be pushed into this Processor's queue,
+    /// i.e. it does not belong to another Scheduler
+    pub fn is_local(&self, coro: &Handle) -> bool {
+        match coro.preferred_processor() {
+            Some(preferred) => {
+                preferred.scheduler() as *const Scheduler == self.scheduler() as *const Scheduler
+            }
+            None => true,
+        }
+    }
+
+    fn push(&mut self, coro: Handle) {
+        if coro.shared().is_pinned() {
+            self.queue_stealer.local_len.fetch_add(1, Ordering::SeqCst);
+            self.pinned_queue.push_back(coro);
+            self.check_queue_watermarks();
+            return;
+        }
+
+        // Like the run queue, the high priority queue is resumed from the most recent one
+        let len = self.queue_len.fetch_add(1, Ordering::SeqCst) + 1;
+        match coro.shared().priority() {
+            Priority::High => self.high_queue.push(coro),
+            Priority::Normal => self.queue_worker.push(coro),
+            Priority::Low => self.low_queue.push((coro, Instant::now())),
+        }
+        self.check_queue_watermarks();
+
+        // A single queued coroutine is resumed by this Processor next anyway
+        if len > 1 && !self.is_draining {
+            self.scheduler().unpark_idle();
+        }
+    }
+
+    // The coroutine to resume next: a low priority one waiting for too long, a high priority
+    // one, a normal or pinned one in turns or else a low priority one
+    fn pop_next(&mut self) -> Option<Handle> {
+        if self.low_next.is_none() {
+            // The low priority queue is resumed from the oldest one
+            self.low_next = self.queue_stealer.steal_low();
+            if self.low_next.is_some() {
+                self.queue_len.fetch_sub(1, Ordering::SeqCst);
+                self.queue_stealer.local_len.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let aged = match self.low_next {
+            Some((_, queued_at)) => queued_at.elapsed() >= self.scheduler().priority_aging(),
+            None => false,
+        };
+        if aged {
+            return self.take_low_next();
+        }
+
+        if let Some(coro) = self.high_queue.pop() {
+            self.queue_len.fetch_sub(1, Ordering::SeqCst);
+            self.check_queue_watermarks();
+            return Some(coro);
+        }
+
+        // Pinned coroutines are resumed in turns with the normal ones, so neither starves
+        self.pinned_turn = !self.pinned_turn;
+        if self.pinned_turn {
+            if let Some(coro) = self.pop_pinned() {
+                return Some(coro);
+            }
+        }
+
+        if let Some(coro) = self.pop_normal() {
+            return Some(coro);
+        }
+
+        match self.pop_pinned() {
+            Some(coro) => Some(coro),
+            None => self.take_low_next(),
+        }
+    }
+
+    fn pop_pinned(&mut self) -> Option<Handle> {
+        let coro = self.pinned_queue.pop_front();
+        if coro.is_some() {
+            self.queue_stealer.local_len.fetch_sub(1, Ordering::SeqCst);
+            self.check_queue_watermarks();
+        }
+        coro
+    }
+
+    fn take_low_next(&mut self) -> Option<Handle> {
+        let coro = self.low_next.take().map(|(coro, _)| coro);
+        if coro.is_some() {
+            self.queue_stealer.local_len.fetch_sub(1, Ordering::SeqCst);
+            self.check_queue_watermarks();
+        }
+        coro
+    }
+
+    // The one of the least served group if sharing time fairly, picked among a batch of the
+    // queued coroutines
+    fn pop_normal(&mut self) -> Option<Handle> {
+        if self.fair_queue.is_none() {
+            return self.pop();
+        }
+
+        while self.fair_queue.as_ref().unwrap().len() < FAIR_QUEUE_BATCH {
+            let coro = match self.queue_worker.pop() {
+                Some(coro) => coro,
+                None => break,
+            };
+            self.queue_len.fetch_sub(1, Ordering::SeqCst);
+            self.queue_stealer.local_len.fetch_add(1, Ordering::SeqCst);
+
+            let group = coro.shared().group();
+            self.fair_queue.as_mut().unwrap().push(group, coro);
+        }
+
+        let coro = self.fair_queue.as_mut().unwrap().pop();
+        if coro.is_some() {
+            self.queue_stealer.local_len.fetch_sub(1, Ordering::SeqCst);
+            self.check_queue_watermarks();
+        }
+        coro
+    }
+
+    fn pop(&mut self) -> Option<Handle> {
+        let coro = self.queue_worker.pop();
+
+        if coro.is_some() {
+            self.queue_len.fetch_sub(1, Ordering::SeqCst);
+            self.check_queue_watermarks();
+        }
+
+        coro
+    }
+
+    fn check_queue_watermarks(&mut self) {
+        let len = self.queue_stealer.len();
+        let crossed = match self.scheduler().run_queue_watermarks() {
+            Some(marks) => marks.check(len, self.queue_high),
+            None => return,
+        };
+
+        if let Some(is_high) = crossed {
+            self.queue_high = is_high;
+
+            let event = if is_high {
+                Event::RunQueueHigh {
+                    processor_id: self.id,
+                    length: len,
+                }
+            } else {
+                Event::RunQueueLow {
+                    processor_id: self.id,
+                    length: len,
+                }
+            };
+            self.scheduler().emit(event);
+        }
     }
 
     /// Suspends the current running coroutine, equivalent to `Scheduler::sched`
@@ -356,17 +1041,21 @@ impl Processor {
 
     /// Yield the current running coroutine with specified result
     pub fn yield_with(&mut self, r: State) {
+        self.switch_to_main(r);
+
+        // We are back! Exit right now!
+        if self.is_exiting || self.current_coro.as_ref().unwrap().shared().is_aborted() {
+            panic!(ForceUnwind);
+        }
+    }
+
+    fn switch_to_main(&mut self, r: State) {
         self.last_state = r;
 
         unsafe {
             let main_coro: *const Coroutine = &*self.main_coro;
             self.current_coro.as_mut().unwrap().yield_to(&*main_coro);
         }
-
-        // We are back! Exit right now!
-        if self.is_exiting {
-            panic!(ForceUnwind);
-        }
     }
 }
 
@@ -410,7 +1099,19 @@ impl WeakProcessor {
 }
 
 pub enum ProcMessage {
-    NewNeighbor(Stealer<Handle>),
-    Ready(Handle),
+    NewNeighbor(RunQueueStealer),
+    Ready(Handle, Instant),
+    ReadyBatch(Vec<Handle>, Instant),
+    Drain,
+    Resume,
+    // Sent to an idle Processor when a neighbor has queued work to be stolen
+    Unpark(Instant),
     Shutdown,
 }
+
+impl ProcMessage {
+    /// Ask the Processor to resume the coroutine, remembering when it was asked to
+    pub fn ready(coro: Handle) -> ProcMessage {
+        ProcMessage::Ready(coro, Instant::now())
+    }
+}
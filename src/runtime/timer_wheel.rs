@@ -0,0 +1,209 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Hashed timer wheel for the sleeping coroutines
+//!
+//! Timers are hashed into slots by their expiry tick. Inserting and cancelling take constant
+//! time, and every tick only looks at the timers in one slot, so hundreds of thousands of
+//! sleeping coroutines cost the event loop about as much as a few. Timers further away than
+//! one revolution stay in their slot and are skipped until their tick comes.
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/// Identifies a timer in the wheel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    slot: usize,
+    seq: u64,
+}
+
+struct Entry<T> {
+    seq: u64,
+    tick: u64,
+    value: T,
+}
+
+pub struct TimerWheel<T> {
+    start: Instant,
+    tick_ms: u64,
+    slots: Vec<Vec<Entry<T>>>,
+    // The next tick to be expired
+    current: u64,
+    next_seq: u64,
+    len: usize,
+}
+
+fn millis(dur: Duration) -> u64 {
+    dur.as_secs() * 1_000 + dur.subsec_nanos() as u64 / 1_000_000
+}
+
+impl<T> TimerWheel<T> {
+    /// Create a wheel of `slots` slots, which must be a power of two, advancing every `tick`
+    pub fn new(tick: Duration, slots: usize) -> TimerWheel<T> {
+        assert!(slots.is_power_of_two(), "Number of slots must be a power of two");
+
+        TimerWheel {
+            start: Instant::now(),
+            tick_ms: cmp::max(1, millis(tick)),
+            slots: (0..slots).map(|_| Vec::new()).collect(),
+            current: 0,
+            next_seq: 0,
+            len: 0,
+        }
+    }
+
+    /// Number of pending timers
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Add a timer expiring at `deadline`, rounded up to the next tick
+    pub fn insert(&mut self, deadline: Instant, value: T) -> TimerId {
+        let ms = if deadline > self.start {
+            millis(deadline.duration_since(self.start))
+        } else {
+            0
+        };
+        let tick = cmp::max(self.current, (ms + self.tick_ms - 1) / self.tick_ms);
+        let slot = tick as usize & (self.slots.len() - 1);
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.len += 1;
+
+        self.slots[slot].push(Entry {
+            seq: seq,
+            tick: tick,
+            value: value,
+        });
+
+        TimerId {
+            slot: slot,
+            seq: seq,
+        }
+    }
+
+    /// Remove a timer, `None` if it has expired already
+    pub fn cancel(&mut self, id: TimerId) -> Option<T> {
+        let slot = &mut self.slots[id.slot];
+        match slot.iter().position(|e| e.seq == id.seq) {
+            Some(pos) => {
+                self.len -= 1;
+                Some(slot.swap_remove(pos).value)
+            }
+            None => None,
+        }
+    }
+
+    /// Remove and return the timers which have expired by `now`
+    pub fn expire(&mut self, now: Instant) -> Vec<T> {
+        let mut expired = Vec::new();
+        if now < self.start {
+            return expired;
+        }
+
+        let now_tick = millis(now.duration_since(self.start)) / self.tick_ms;
+        if now_tick < self.current {
+            return expired;
+        }
+
+        // A whole revolution visits every slot, no matter how long the wheel has been idle
+        let last = cmp::min(now_tick, self.current + self.slots.len() as u64 - 1);
+        let mask = self.slots.len() - 1;
+        for tick in self.current..last + 1 {
+            let slot = &mut self.slots[tick as usize & mask];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].tick <= now_tick {
+                    expired.push(slot.swap_remove(i).value);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        self.current = now_tick + 1;
+        self.len -= expired.len();
+        expired
+    }
+
+    /// How long the event loop could wait for other events before the next timer may expire,
+    /// at most `max`
+    pub fn next_timeout(&self, now: Instant, max: Duration) -> Duration {
+        if self.len == 0 {
+            return max;
+        }
+
+        let max_ticks = cmp::min((millis(max) + self.tick_ms - 1) / self.tick_ms,
+                                 self.slots.len() as u64);
+        let mask = self.slots.len() - 1;
+        for tick in self.current..self.current + max_ticks {
+            if !self.slots[tick as usize & mask].is_empty() {
+                let at = self.start + Duration::from_millis(tick * self.tick_ms);
+                return if at > now {
+                    cmp::min(at.duration_since(now), max)
+                } else {
+                    Duration::new(0, 0)
+                };
+            }
+        }
+        max
+    }
+
+    /// Remove all the timers
+    pub fn drain(&mut self) -> Vec<T> {
+        self.len = 0;
+        self.slots.iter_mut().flat_map(|slot| slot.drain(..).map(|e| e.value)).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn test_timer_wheel() {
+        let mut wheel = TimerWheel::new(Duration::from_millis(1), 8);
+        let now = Instant::now();
+
+        wheel.insert(now + Duration::from_millis(5), 5);
+        // Beyond one revolution
+        wheel.insert(now + Duration::from_millis(20), 20);
+        let cancelled = wheel.insert(now + Duration::from_millis(10), 10);
+        assert_eq!(wheel.len(), 3);
+
+        assert_eq!(wheel.cancel(cancelled), Some(10));
+        assert_eq!(wheel.cancel(cancelled), None);
+        assert!(wheel.next_timeout(now, Duration::from_millis(100)) <= Duration::from_millis(5));
+
+        assert_eq!(wheel.expire(now + Duration::from_millis(10)), vec![5]);
+        assert!(wheel.expire(now + Duration::from_millis(15)).is_empty());
+        assert_eq!(wheel.expire(now + Duration::from_millis(1000)), vec![20]);
+        assert!(wheel.is_empty());
+    }
+}
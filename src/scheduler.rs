@@ -23,46 +23,331 @@
 
 use std::any::Any;
 use std::boxed::FnBox;
+use std::cmp;
+use std::collections::HashMap;
 use std::default::Default;
+use std::error::Error;
+use std::fmt;
 use std::io;
 use std::mem;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::mpsc::TryRecvError;
-use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::mpsc::{Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::usize;
 
 use mio::{EventLoop, Evented, Handler, Token, EventSet, PollOpt};
 use mio::util::Slab;
+#[cfg(unix)]
+use mio::unix::EventedFd;
+use rand::{self, Rng, SeedableRng, XorShiftRng};
 
-use runtime::processor::{Processor, ProcMessage};
-use coroutine::{SendableCoroutinePtr, Handle};
-use options::Options;
+use runtime::processor::{ForceUnwind, Processor, ProcMessage, RunQueueStealer};
+use runtime::timer_wheel::{TimerId, TimerWheel};
+use coroutine::{CancelHookGuard, Coroutine, SendableCoroutinePtr, Handle, Shared,
+                DEFAULT_STACK_POOL_CAP};
+use observer::{Event, SchedulerObserver, Watermarks};
+use options::{Options, SpawnHint, StackClass};
+use blocking::{BlockingPool, DEFAULT_BLOCKING_THREADS};
+use net::dns::DEFAULT_RESOLVER_THREADS;
+use remote::Remote;
+use stats::{ChannelStats, CoroutineMemory, ProcessorStats, Stats, StackHighWaterMarks};
+use stats::{CoroutineSnapshot, CoroutineState, DeadlockReport, LeakedCoroutine, LeakReport,
+            StackUsage, WaitReason};
+use sync::instrumented::ChannelMetrics;
+use metrics::histogram::SizeHistogram;
 
 /// A handle that could join the coroutine
 pub struct JoinHandle<T> {
     result: ::sync::mpsc::Receiver<Result<T, Box<Any + Send + 'static>>>,
+    shared: Arc<Shared>,
 }
 
 impl<T> JoinHandle<T> {
+    #[doc(hidden)]
+    pub fn new(result: ::sync::mpsc::Receiver<Result<T, Box<Any + Send + 'static>>>,
+               shared: Arc<Shared>)
+               -> JoinHandle<T> {
+        JoinHandle {
+            result: result,
+            shared: shared,
+        }
+    }
+
+    // Handle of a coroutine which has not been spawned, as the scheduler is shutting down
+    fn refused() -> JoinHandle<T>
+        where T: Send + 'static
+    {
+        let (tx, rx) = ::sync::mpsc::channel();
+        let _ = tx.send(Err(Box::new(ShuttingDown) as Box<Any + Send + 'static>));
+
+        let shared = unsafe { Coroutine::empty() }.shared().clone();
+        shared.set_finished();
+
+        JoinHandle {
+            result: rx,
+            shared: shared,
+        }
+    }
+
     /// Join the coroutine until it finishes.
     ///
-    /// If it already finished, this method will return immediately.
+    /// If it already finished, this method will return immediately. If it has been dropped
+    /// without running, e.g. because it was still queued when its scheduler shut down, the
+    /// `Err` contains `ShuttingDown`.
     pub fn join(&self) -> Result<T, Box<Any + Send + 'static>> {
-        self.result.recv().expect("Failed to receive from the channel")
+        match self.result.recv() {
+            Ok(ret) => ret,
+            Err(..) => Err(Box::new(ShuttingDown)),
+        }
+    }
+
+    /// Get the result without blocking, returns `None` if the coroutine is still running.
+    ///
+    /// Once this method returned `Some`, the result is consumed and must not be joined again.
+    pub fn try_join(&self) -> Option<Result<T, Box<Any + Send + 'static>>> {
+        match self.result.try_recv() {
+            Ok(ret) => Some(ret),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(Box::new(ShuttingDown))),
+        }
+    }
+
+    /// Whether the coroutine has finished, either by returning or by panicking
+    pub fn is_finished(&self) -> bool {
+        self.shared.is_finished()
+    }
+
+    /// Whether the coroutine has not yet finished
+    pub fn is_running(&self) -> bool {
+        !self.is_finished()
+    }
+
+    /// Abort the coroutine.
+    ///
+    /// The coroutine will be unwound the next time it is resumed by the scheduler,
+    /// and `join()` will return an `Err` afterwards.
+    pub fn abort(&self) {
+        self.shared.abort();
+    }
+
+    /// Cancel the coroutine.
+    ///
+    /// Like `abort`, but a coroutine blocked on I/O or on a channel is woken up as well,
+    /// so that it is unwound right away instead of once the wait is over.
+    pub fn cancel(&self) {
+        self.shared.cancel();
+    }
+
+    /// Wake the coroutine up if it is sleeping, `sleep` then returns the remaining time.
+    ///
+    /// If it is not sleeping right now, its next sleep returns immediately.
+    pub fn interrupt(&self) {
+        self.shared.interrupt();
     }
 }
 
 unsafe impl<T: Send> Send for JoinHandle<T> {}
 
+// The message of a panic payload, if it is a string
+fn panic_message(payload: &Box<Any + Send>) -> &str {
+    match payload.downcast_ref::<&'static str>() {
+        Some(msg) => *msg,
+        None => {
+            match payload.downcast_ref::<String>() {
+                Some(msg) => &msg[..],
+                None => "Box<Any>",
+            }
+        }
+    }
+}
+
+/// Join all the coroutines and collect their results in order
+pub fn join_all<T>(handles: Vec<JoinHandle<T>>) -> Vec<Result<T, Box<Any + Send + 'static>>> {
+    handles.iter().map(|hdl| hdl.join()).collect()
+}
+
+/// Error payload returned by `timeout()` when the deadline has passed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+/// Run `f` in a new coroutine which is unwound if it is still running after `dur`.
+///
+/// The deadline is checked whenever the coroutine is resumed. Coroutines spawned by `f`
+/// with `Options::inherit_deadline` are bounded by the same deadline. If the coroutine has
+/// been unwound because the deadline passed, the `Err` contains `TimedOut`, otherwise the
+/// payload of its panic.
+pub fn timeout<F, T>(dur: Duration, f: F) -> Result<T, Box<Any + Send + 'static>>
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static
+{
+    let deadline = Instant::now() + dur;
+    let hdl = Scheduler::spawn_opts(f, Options::new().deadline(Some(deadline)));
+
+    match hdl.join() {
+        Err(ref payload) if payload.is::<ForceUnwind>() && Instant::now() >= deadline => {
+            Err(Box::new(TimedOut))
+        }
+        ret => ret,
+    }
+}
+
+/// Wait until the first coroutine finishes.
+///
+/// Returns the index of the winner with its result. If `abort_losers` is true,
+/// all the other coroutines will be cancelled, see `JoinHandle::cancel`.
+pub fn race<T>(handles: Vec<JoinHandle<T>>,
+               abort_losers: bool)
+               -> (usize, Result<T, Box<Any + Send + 'static>>)
+    where T: Send + 'static
+{
+    assert!(!handles.is_empty(), "Must race at least one coroutine");
+
+    let (tx, rx) = ::sync::mpsc::channel();
+    let mut shareds = Vec::with_capacity(handles.len());
+
+    for (idx, hdl) in handles.into_iter().enumerate() {
+        shareds.push(hdl.shared.clone());
+
+        let tx = tx.clone();
+        Scheduler::spawn(move || {
+            // The receiver may have gone if we lose, just ignore it
+            let _ = tx.send((idx, hdl.join()));
+        });
+    }
+
+    let (winner, ret) = rx.recv().expect("Failed to receive from the channel");
+
+    if abort_losers {
+        for (idx, shared) in shareds.iter().enumerate() {
+            if idx != winner {
+                shared.cancel();
+            }
+        }
+    }
+
+    (winner, ret)
+}
+
+/// Run `f` on every item in its own coroutine and collect the results in the order of the items.
+///
+/// The coroutines are spread over all the Processors by work stealing. A panic in `f` is
+/// returned as the `Err` of its item.
+pub fn run_parallel<I, F, T>(items: I, f: F) -> Vec<Result<T, Box<Any + Send + 'static>>>
+    where I: IntoIterator,
+          I::Item: Send + 'static,
+          F: Fn(I::Item) -> T + Send + Sync + 'static,
+          T: Send + 'static
+{
+    run_parallel_with(items, usize::MAX, f)
+}
+
+/// Like `run_parallel`, but `f` runs on at most `concurrency` items at the same time.
+///
+/// An item whose coroutine is dropped without running, e.g. during the shutdown of the
+/// scheduler, gets `ShuttingDown` as its `Err`.
+pub fn run_parallel_with<I, F, T>(items: I,
+                                  concurrency: usize,
+                                  f: F)
+                                  -> Vec<Result<T, Box<Any + Send + 'static>>>
+    where I: IntoIterator,
+          I::Item: Send + 'static,
+          F: Fn(I::Item) -> T + Send + Sync + 'static,
+          T: Send + 'static
+{
+    assert!(concurrency > 0, "Concurrency must be at least 1");
+
+    let f = Arc::new(f);
+    let (tx, rx) = ::sync::mpsc::channel();
+    let mut results = Vec::new();
+    let mut running = 0;
+
+    for (idx, item) in items.into_iter().enumerate() {
+        if running == concurrency {
+            let (done, ret) = rx.recv().expect("Failed to receive from the channel");
+            results[done] = Some(ret);
+            running -= 1;
+        }
+
+        let f = f.clone();
+        let tx = tx.clone();
+        // Keep on spawning, the idle Processors will steal them from us
+        Scheduler::spawn_opts(move || {
+                                  let ret = unsafe { ::try(move || (*f)(item)) };
+                                  let _ = tx.send((idx, ret));
+                              },
+                              Options::new().spawn_hint(SpawnHint::Deferred));
+        results.push(None);
+        running += 1;
+    }
+
+    // Fails instead of waiting forever once the coroutines still running have all been dropped
+    drop(tx);
+    for _ in 0..running {
+        match rx.recv() {
+            Ok((done, ret)) => results[done] = Some(ret),
+            Err(..) => break,
+        }
+    }
+
+    results.into_iter()
+           .map(|ret| ret.unwrap_or_else(|| Err(Box::new(ShuttingDown) as Box<Any + Send>)))
+           .collect()
+}
+
+// Source of the unique Scheduler ids, 0 is never handed out
+static SCHEDULER_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Granularity of the timers of sleeping coroutines
+const SLEEP_TICK_MS: u64 = 1;
+/// Number of slots of the timer wheel, one revolution takes this many ticks
+const SLEEP_WHEEL_SLOTS: usize = 4096;
+/// Longest time the event loop waits for events, so that shutdown is noticed
+const EVENT_LOOP_TIMEOUT_MS: u64 = 100;
+
 struct IoHandler {
-    slab: Slab<Option<ReadyCallback<'static>>>,
+    slab: Slab<Option<IoEntry>>,
+    // Sleeping coroutines, kept out of the slab and the timer of the event loop
+    sleepers: TimerWheel<ReadyCallback<'static>>,
+    // Timers armed with `Scheduler::timer`, with the deadline they have been inserted for
+    timers: TimerWheel<(Arc<TimerState>, Instant)>,
 }
 
 type RegisterCallback<'a> = Box<FnBox(&mut EventLoop<IoHandler>, Token) -> bool + Send + 'a>;
 type ReadyCallback<'a> = Box<FnBox(&mut EventLoop<IoHandler>) + Send + 'a>;
 
-struct IoHandlerMessage {
-    register: RegisterCallback<'static>,
-    ready: ReadyCallback<'static>,
+enum IoEntry {
+    // Called once on the first event, then removed
+    Once(ReadyCallback<'static>),
+    // Stays registered until it is unsubscribed, with the file descriptor to deregister and
+    // the registration counted against the limits meanwhile
+    Persistent(Arc<Subscription>, i32, IoWaitGuard<'static>),
+}
+
+enum IoHandlerMessage {
+    Wait {
+        register: RegisterCallback<'static>,
+        ready: ReadyCallback<'static>,
+    },
+    Subscribe {
+        register: RegisterCallback<'static>,
+        subscription: Arc<Subscription>,
+        fd: i32,
+        guard: IoWaitGuard<'static>,
+    },
+    Unsubscribe(Token),
+    Sleep {
+        timer: Arc<SleepTimer>,
+        deadline: Instant,
+        ready: ReadyCallback<'static>,
+    },
+    CancelTimer(Arc<SleepTimer>),
+    CancelWait(Arc<IoWait>),
+    ArmTimer(Arc<TimerState>, Instant),
+    DisarmTimer(TimerId),
 }
 
 impl IoHandlerMessage {
@@ -78,11 +363,53 @@ impl IoHandlerMessage {
             mem::transmute::<ReadyCallback<'scope>, ReadyCallback<'static>>(Box::new(ready))
         };
 
-        IoHandlerMessage {
+        IoHandlerMessage::Wait {
             register: reg,
             ready: ready,
         }
     }
+
+    fn sleep<'scope, Ready>(timer: Arc<SleepTimer>,
+                            deadline: Instant,
+                            ready: Ready)
+                            -> IoHandlerMessage
+        where Ready: FnOnce(&mut EventLoop<IoHandler>) + Send + 'scope
+    {
+        let ready = unsafe {
+            mem::transmute::<ReadyCallback<'scope>, ReadyCallback<'static>>(Box::new(ready))
+        };
+
+        IoHandlerMessage::Sleep {
+            timer: timer,
+            deadline: deadline,
+            ready: ready,
+        }
+    }
+
+    fn subscribe<'scope, Reg>(reg: Reg,
+                              subscription: Arc<Subscription>,
+                              fd: i32,
+                              guard: IoWaitGuard<'scope>)
+                              -> IoHandlerMessage
+        where Reg: FnOnce(&mut EventLoop<IoHandler>, Token) -> bool + Send + 'scope
+    {
+        let reg = unsafe {
+            mem::transmute::<RegisterCallback<'scope>, RegisterCallback<'static>>(Box::new(reg))
+        };
+
+        // The event loop, which keeps the guard until the subscription is cancelled, does
+        // not outlive the Scheduler
+        let guard = unsafe {
+            mem::transmute::<IoWaitGuard<'scope>, IoWaitGuard<'static>>(guard)
+        };
+
+        IoHandlerMessage::Subscribe {
+            register: reg,
+            subscription: subscription,
+            fd: fd,
+            guard: guard,
+        }
+    }
 }
 
 unsafe impl Send for IoHandlerMessage {}
@@ -99,10 +426,21 @@ impl Handler for IoHandler {
             return;
         }
 
-        match self.slab.remove(token) {
-            Some(cb) => cb.unwrap().call_box((event_loop,)),
+        let subscription = match self.slab.get(token) {
+            Some(&Some(IoEntry::Persistent(ref sub, ..))) => Some(sub.clone()),
+            Some(..) => None,
             None => {
                 warn!("No coroutine is waiting on token {:?}", token);
+                return;
+            }
+        };
+
+        match subscription {
+            Some(sub) => sub.notify(),
+            None => {
+                if let Some(Some(IoEntry::Once(cb))) = self.slab.remove(token) {
+                    cb.call_box((event_loop,));
+                }
             }
         }
     }
@@ -116,340 +454,4327 @@ impl Handler for IoHandler {
         }
 
         match self.slab.remove(token) {
-            Some(cb) => cb.unwrap().call_box((event_loop,)),
+            Some(Some(IoEntry::Once(cb))) => cb.call_box((event_loop,)),
+            Some(entry) => {
+                error!("Received timeout event for a persistent subscription {:?}", token);
+                let _ = self.slab.insert(entry);
+            }
             None => {
                 warn!("No coroutine is waiting on token {:?}", token);
             }
         }
     }
 
+    fn tick(&mut self, event_loop: &mut EventLoop<Self>) {
+        if !self.sleepers.is_empty() {
+            for cb in self.sleepers.expire(Instant::now()) {
+                cb.call_box((event_loop,));
+            }
+        }
+
+        if !self.timers.is_empty() {
+            let now = Instant::now();
+            for (timer, armed) in self.timers.expire(now) {
+                // Postponed by `TimerHandle::reset`, moved in the wheel only now
+                if let Some(deadline) = timer.expire(armed, now) {
+                    self.arm_timer(timer, deadline);
+                }
+            }
+        }
+    }
+
     fn notify(&mut self, event_loop: &mut EventLoop<Self>, msg: Self::Message) {
-        self.slab
-            .insert_with(move |token| {
-                if msg.register.call_box((event_loop, token)) {
-                    Some(msg.ready)
+        let (register, entry) = match msg {
+            IoHandlerMessage::Wait { register, ready } => (register, IoEntry::Once(ready)),
+            IoHandlerMessage::Subscribe { register, subscription, fd, guard } => {
+                (register, IoEntry::Persistent(subscription, fd, guard))
+            }
+            IoHandlerMessage::Unsubscribe(token) => {
+                if let Some(Some(IoEntry::Persistent(sub, fd, _))) = self.slab.remove(token) {
+                    deregister_fd(event_loop, fd);
+                    sub.close();
+                }
+                return;
+            }
+            IoHandlerMessage::Sleep { timer, deadline, ready } => {
+                let mut registration = timer.registration.lock().unwrap();
+                if timer.cancelled.load(Ordering::SeqCst) {
+                    // Interrupted before the timer has been added
+                    ready.call_box((event_loop,));
                 } else {
-                    None
+                    *registration = Some(self.sleepers.insert(deadline, ready));
                 }
-            })
-            .unwrap();
+                return;
+            }
+            IoHandlerMessage::CancelWait(wait) => {
+                wait.cancelled.store(true, Ordering::SeqCst);
+
+                // Not registered yet otherwise, the registration will see the cancellation
+                let token = wait.token.lock().unwrap().take();
+                if let Some(token) = token {
+                    if let Some(Some(IoEntry::Once(cb))) = self.slab.remove(token) {
+                        cb.call_box((event_loop,));
+                    }
+                }
+                return;
+            }
+            IoHandlerMessage::ArmTimer(timer, deadline) => {
+                self.arm_timer(timer, deadline);
+                return;
+            }
+            IoHandlerMessage::DisarmTimer(id) => {
+                // Nothing to do if it has expired meanwhile
+                self.timers.cancel(id);
+                return;
+            }
+            IoHandlerMessage::CancelTimer(timer) => {
+                timer.cancelled.store(true, Ordering::SeqCst);
+
+                // Not added yet otherwise, the Sleep message will see the cancellation
+                if let Some(id) = timer.registration.lock().unwrap().take() {
+                    // Nothing to do if the timer has fired already
+                    if let Some(cb) = self.sleepers.cancel(id) {
+                        cb.call_box((event_loop,));
+                    }
+                }
+                return;
+            }
+        };
+
+        let token = self.slab
+                        .insert_with(move |token| {
+                            if register.call_box((event_loop, token)) {
+                                Some(entry)
+                            } else {
+                                None
+                            }
+                        })
+                        .unwrap();
+
+        // Registration failed, do not keep the slot occupied
+        if let Some(&None) = self.slab.get(token) {
+            self.slab.remove(token);
+        }
     }
 }
 
 impl IoHandler {
     fn new() -> IoHandler {
-        IoHandler { slab: Slab::new_starting_at(Token(1), 102400) }
+        IoHandler {
+            slab: Slab::new_starting_at(Token(1), 102400),
+            sleepers: TimerWheel::new(Duration::from_millis(SLEEP_TICK_MS), SLEEP_WHEEL_SLOTS),
+            timers: TimerWheel::new(Duration::from_millis(SLEEP_TICK_MS), SLEEP_WHEEL_SLOTS),
+        }
+    }
+
+    // Insert the timer into the wheel, replacing its previous entry, unless it has been
+    // cancelled or rearmed for another deadline since
+    fn arm_timer(&mut self, timer: Arc<TimerState>, deadline: Instant) {
+        let mut inner = timer.inner.lock().unwrap();
+        if inner.status != TimerStatus::Pending || inner.armed != Some(deadline) {
+            return;
+        }
+
+        if let Some(old) = inner.entry.take() {
+            self.timers.cancel(old);
+        }
+        inner.entry = Some(self.timers.insert(deadline, (timer.clone(), deadline)));
+    }
+
+    // Milliseconds to wait for events, at most `max_ms`, without delaying the next sleeper
+    // or timer
+    fn poll_timeout_ms(&self, max_ms: u64) -> usize {
+        let now = Instant::now();
+        let max = Duration::from_millis(max_ms);
+        let timeout = self.sleepers.next_timeout(now, max);
+        let timeout = self.timers.next_timeout(now, timeout);
+        // Rounded up, waking up early would only spin
+        (timeout.as_secs() * 1_000 + (timeout.subsec_nanos() as u64 + 999_999) / 1_000_000) as usize
     }
 
     fn wakeup_all(&mut self, event_loop: &mut EventLoop<Self>) {
-        for cb in self.slab.iter_mut() {
-            cb.take().unwrap().call_box((event_loop,));
+        for entry in self.slab.iter_mut() {
+            match entry.take() {
+                Some(IoEntry::Once(cb)) => cb.call_box((event_loop,)),
+                Some(IoEntry::Persistent(sub, ..)) => sub.close(),
+                None => {}
+            }
         }
 
         self.slab.clear();
+
+        for cb in self.sleepers.drain() {
+            cb.call_box((event_loop,));
+        }
+
+        for (timer, _) in self.timers.drain() {
+            timer.cancel();
+        }
     }
 }
 
-/// Coroutine scheduler
-pub struct Scheduler {
-    work_counts: AtomicUsize,
-    expected_worker_count: usize,
+// Remove the object of a cancelled subscription from the event loop, so that the descriptor
+// could be registered again and its events don't wake up the next owner of the token
+#[cfg(unix)]
+fn deregister_fd(event_loop: &mut EventLoop<IoHandler>, fd: i32) {
+    // Fails if the descriptor has been closed already, which has deregistered it as well
+    let _ = event_loop.deregister(&EventedFd(&fd));
+}
 
-    // Mio event loop and the handler
-    // It controls all I/O and timer waits
-    event_loop: EventLoop<IoHandler>,
-    io_handler: IoHandler,
+#[cfg(not(unix))]
+fn deregister_fd(_: &mut EventLoop<IoHandler>, _: i32) {}
+
+// Timer of a sleeping coroutine, which could be cancelled by interrupting the coroutine
+struct SleepTimer {
+    registration: Mutex<Option<TimerId>>,
+    cancelled: AtomicBool,
 }
 
-unsafe impl Send for Scheduler {}
-unsafe impl Sync for Scheduler {}
+// Registration of a coroutine waiting for an I/O event, which could be cancelled by
+// `JoinHandle::cancel`
+struct IoWait {
+    token: Mutex<Option<Token>>,
+    cancelled: AtomicBool,
+}
 
-impl Scheduler {
-    /// Create a scheduler with default configurations
-    pub fn new() -> Scheduler {
-        Scheduler {
-            work_counts: AtomicUsize::new(0),
-            expected_worker_count: 1,
+// Removes the interrupt hook when the sleep is over, even if the coroutine is unwound
+struct InterruptHookGuard(Arc<Shared>);
 
-            event_loop: EventLoop::new().unwrap(),
-            io_handler: IoHandler::new(),
-        }
+impl Drop for InterruptHookGuard {
+    fn drop(&mut self) {
+        self.0.clear_interrupt_hook();
     }
+}
 
-    /// Set the number of workers
-    pub fn with_workers(mut self, workers: usize) -> Scheduler {
-        assert!(workers >= 1, "Must have at least one worker");
-        self.expected_worker_count = workers;
-        self
+fn coroutine_snapshot(shared: &Shared) -> CoroutineSnapshot {
+    CoroutineSnapshot {
+        coroutine_id: shared.id(),
+        name: shared.name().map(|n| n.to_owned()),
+        spawn_site: shared.spawn_site(),
+        state: coroutine_state(shared),
+        processor: shared.processor(),
+        spans: shared.spans(),
     }
+}
 
-    /// Get the global Scheduler
-    #[doc(hidden)]
-    pub fn instance() -> Option<&'static Scheduler> {
-        Processor::current().and_then(|p| unsafe { Some(mem::transmute(p.scheduler())) })
+// What the coroutine is doing, for `Scheduler::dump_coroutines()`
+fn coroutine_state(shared: &Shared) -> CoroutineState {
+    if shared.is_running() {
+        return CoroutineState::Running;
     }
 
-    /// A coroutine is ready for schedule
-    #[doc(hidden)]
-    pub fn ready(coro: Handle) {
-        let current = Processor::current();
+    match shared.wait_reason() {
+        None => CoroutineState::Suspended,
+        Some(WaitReason::Io) => CoroutineState::BlockedOnIo(shared.wait_fd()),
+        Some(WaitReason::Sleep) => CoroutineState::Sleeping,
+        Some(WaitReason::Sync) => CoroutineState::BlockedOnSync,
+        Some(WaitReason::Other) => CoroutineState::Blocked,
+    }
+}
 
-        if let Some(mut preferred) = coro.preferred_processor() {
-            if let Some(current) = current {
-                if preferred == current {
-                    // We're on the same thread ---> use the faster ready() method.
-                    return preferred.ready(coro);
-                }
-            }
+/// A persistent readiness subscription of an I/O object.
+///
+/// Unlike `Scheduler::wait_event`, which registers the object for a single event and
+/// deregisters it afterwards, a subscription stays registered in the event loop. Every
+/// readiness notification is accumulated into a counter, so a receive loop could wait
+/// for the next event without re-registering on each call.
+pub struct Subscription {
+    token: AtomicUsize,
+    // Reported in `Event::SlowIo`
+    fd: i32,
+    interest: EventSet,
+    pending: AtomicUsize,
+    closed: AtomicBool,
+    waiters: Mutex<Vec<(Handle, Sender<ProcMessage>)>>,
+    // Coroutines in `wait_timeout`, interrupted to end their sleep early
+    sleepers: Mutex<Vec<Arc<Shared>>>,
+    channel: ::mio::Sender<IoHandlerMessage>,
+}
 
-            let _ = preferred.handle().send(ProcMessage::Ready(coro));
-            return;
+impl Subscription {
+    fn new(fd: i32,
+           interest: EventSet,
+           channel: ::mio::Sender<IoHandlerMessage>)
+           -> Subscription {
+        Subscription {
+            token: AtomicUsize::new(0),
+            fd: fd,
+            interest: interest,
+            pending: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            waiters: Mutex::new(Vec::new()),
+            sleepers: Mutex::new(Vec::new()),
+            channel: channel,
         }
+    }
 
-        if let Some(mut current) = current {
-            return current.ready(coro);
-        }
+    fn notify(&self) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        self.wake_waiters();
+    }
 
-        // TODO: Should we drop() the coro instead of panic?
-        panic!("Processor missing");
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.wake_waiters();
     }
 
-    /// A coroutine is finished
-    ///
-    /// The coroutine will be destroy, make sure that the coroutine pointer is unique!
-    #[doc(hidden)]
-    pub fn finished(mut coro: Handle) {
-        Scheduler::instance().unwrap().work_counts.fetch_sub(1, Ordering::SeqCst);
-        coro.set_drop_allowed();
+    fn wake_waiters(&self) {
+        let waiters = mem::replace(&mut *self.waiters.lock().unwrap(), Vec::new());
+        for (coro, proc_hdl) in waiters {
+            let _ = proc_hdl.send(ProcMessage::ready(coro));
+        }
+
+        let sleepers = mem::replace(&mut *self.sleepers.lock().unwrap(), Vec::new());
+        for shared in sleepers {
+            shared.interrupt();
+        }
     }
 
-    /// Total works
-    pub fn work_count(&self) -> usize {
-        self.work_counts.load(Ordering::SeqCst)
+    /// Number of readiness notifications that have not been consumed yet
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
     }
 
-    /// Spawn a new coroutine with default options
-    pub fn spawn<F, T>(f: F) -> JoinHandle<T>
-        where F: FnOnce() -> T + Send + 'static,
-              T: Send + 'static
-    {
-        Scheduler::spawn_opts(f, Default::default())
+    /// Whether the subscription has been cancelled or the scheduler is shutting down
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
     }
 
-    /// Spawn a new coroutine with options
-    pub fn spawn_opts<F, T>(f: F, opts: Options) -> JoinHandle<T>
-        where F: FnOnce() -> T + Send + 'static,
-              T: Send + 'static
-    {
-        let mut processor = Processor::current().unwrap();
+    /// Block the current coroutine until at least one notification arrived since the
+    /// last call, then consume all of them.
+    ///
+    /// Returns the number of consumed notifications, or 0 if the subscription was closed.
+    pub fn wait(&self) -> usize {
+        let mut slow_io = None;
+        loop {
+            let n = self.pending.swap(0, Ordering::SeqCst);
+            if n > 0 || self.is_closed() {
+                return n;
+            }
 
-        processor.scheduler().work_counts.fetch_add(1, Ordering::SeqCst);
+            if slow_io.is_none() {
+                slow_io = Scheduler::instance().unwrap().watch_slow_io(self.fd, self.interest);
+            }
 
-        let (tx, rx) = ::sync::mpsc::channel();
-        let wrapper = move || {
-            let ret = unsafe { ::try(move || f()) };
+            Scheduler::take_current_coroutine(|coro| {
+                coro.shared().set_wait_reason(WaitReason::Io);
+                let mut waiters = self.waiters.lock().unwrap();
+                if self.pending() > 0 || self.is_closed() {
+                    drop(waiters);
+                    Scheduler::ready(coro);
+                } else {
+                    waiters.push((coro, Processor::current().unwrap().handle()));
+                }
+            });
+        }
+    }
 
-            // No matter whether it is panicked or not, the result will be sent to the channel
-            let _ = tx.send(ret); // Just ignore if it failed
-        };
-        processor.spawn_opts(Box::new(wrapper), opts);
+    /// Like `wait`, but gives up after `timeout` and returns 0 without consuming anything
+    pub fn wait_timeout(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let shared = Processor::current().unwrap().current_shared().unwrap();
+        let _slow_io = Scheduler::instance().unwrap().watch_slow_io(self.fd, self.interest);
 
-        JoinHandle { result: rx }
-    }
+        loop {
+            let n = self.pending.swap(0, Ordering::SeqCst);
+            if n > 0 || self.is_closed() {
+                return n;
+            }
 
-    /// Run the scheduler
-    pub fn run<M, R>(&mut self, main_fn: M) -> Result<R, Box<Any + Send + 'static>>
-        where M: FnOnce() -> R + Send + 'static,
-              R: Send + 'static
-    {
-        let mut handles = Vec::with_capacity(self.expected_worker_count);
-        let mut handlers = Vec::with_capacity(self.expected_worker_count);
-        let mut stealers = Vec::with_capacity(self.expected_worker_count);
+            let now = Instant::now();
+            if now >= deadline {
+                return 0;
+            }
 
-        // The first worker (main function)
-        let main_coro_hdl = {
-            let (hdl, msg, st, main_hdl) = Processor::run_main(0, self, main_fn);
-            handles.push(hdl);
-            handlers.push(msg);
-            stealers.push(st);
+            {
+                let mut sleepers = self.sleepers.lock().unwrap();
+                if self.pending() > 0 || self.is_closed() {
+                    continue;
+                }
+                sleepers.push(shared.clone());
+            }
 
-            main_hdl
-        };
+            // Ends early if a notification interrupts the sleep
+            let _ = Scheduler::instance().unwrap().sleep(deadline - now);
 
-        // The others
-        for tid in 1..self.expected_worker_count {
+            let mut sleepers = self.sleepers.lock().unwrap();
+            let ptr = &*shared as *const Shared;
+            match sleepers.iter().position(|s| &**s as *const Shared == ptr) {
+                Some(idx) => {
+                    sleepers.swap_remove(idx);
+                }
+                None => {
+                    // Interrupted right after the timeout, don't cut the next sleep short
+                    if shared.set_interrupt_hook(Box::new(|| {})) {
+                        shared.clear_interrupt_hook();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Deregister the subscription from the event loop and wake up all the waiters
+    pub fn cancel(&self) {
+        let token = self.token.load(Ordering::SeqCst);
+        if token != 0 && !self.closed.swap(true, Ordering::SeqCst) {
+            let _ = self.channel.send(IoHandlerMessage::Unsubscribe(Token(token)));
+        }
+        self.wake_waiters();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimerStatus {
+    Pending,
+    Expired,
+    Cancelled,
+}
+
+struct TimerInner {
+    deadline: Instant,
+    // Deadline of the valid entry in the timer wheel, the other entries are stale
+    armed: Option<Instant>,
+    // The entry in the timer wheel once the event loop has inserted it
+    entry: Option<TimerId>,
+    status: TimerStatus,
+    waiters: Vec<(Handle, Sender<ProcMessage>)>,
+    // Called by the event loop once the timer expires
+    on_expire: Option<Box<FnBox() + Send>>,
+}
+
+// Shared state of the clones of a `TimerHandle` and of its entries in the timer wheel
+struct TimerState {
+    inner: Mutex<TimerInner>,
+    channel: ::mio::Sender<IoHandlerMessage>,
+}
+
+impl TimerState {
+    // Called by the event loop when the entry inserted for `armed` is due. Returns the
+    // deadline to insert the timer again for if it has been postponed meanwhile.
+    fn expire(&self, armed: Instant, now: Instant) -> Option<Instant> {
+        let (waiters, on_expire) = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.status != TimerStatus::Pending || inner.armed != Some(armed) {
+                return None;
+            }
+
+            if inner.deadline > now {
+                inner.armed = Some(inner.deadline);
+                inner.entry = None;
+                return inner.armed;
+            }
+
+            inner.status = TimerStatus::Expired;
+            inner.armed = None;
+            inner.entry = None;
+            (mem::replace(&mut inner.waiters, Vec::new()), inner.on_expire.take())
+        };
+
+        for (coro, proc_hdl) in waiters {
+            let _ = proc_hdl.send(ProcMessage::ready(coro));
+        }
+        if let Some(f) = on_expire {
+            f.call_box(());
+        }
+        None
+    }
+
+    fn cancel(&self) -> bool {
+        let waiters = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.status != TimerStatus::Pending {
+                return false;
+            }
+
+            inner.status = TimerStatus::Cancelled;
+            inner.armed = None;
+            inner.on_expire = None;
+            // Removed from the wheel right away, instead of staying until its deadline
+            if let Some(id) = inner.entry.take() {
+                let _ = self.channel.send(IoHandlerMessage::DisarmTimer(id));
+            }
+            mem::replace(&mut inner.waiters, Vec::new())
+        };
+
+        for (coro, proc_hdl) in waiters {
+            let _ = proc_hdl.send(ProcMessage::ready(coro));
+        }
+        true
+    }
+}
+
+/// A timer armed with `Scheduler::timer`, which could be cancelled or rescheduled cheaply.
+///
+/// Postponing the deadline only updates the handle, the timer is moved in the timer wheel
+/// of the event loop once the old deadline is due. Patterns like resetting an idle timer on
+/// every received message thus cost no round trip through the event loop. Only bringing
+/// the deadline forward, rearming an expired or cancelled timer, or cancelling it notifies
+/// the event loop.
+///
+/// `sleep` and `timeout` keep arming their own timers and do not return a handle. A coroutine
+/// whose deadline has to move, e.g. an idle timeout, waits for a handle with `wait` instead.
+#[derive(Clone)]
+pub struct TimerHandle {
+    state: Arc<TimerState>,
+}
+
+impl TimerHandle {
+    /// Time at which the timer expires
+    pub fn deadline(&self) -> Instant {
+        self.state.inner.lock().unwrap().deadline
+    }
+
+    /// Whether the deadline has been reached
+    pub fn is_expired(&self) -> bool {
+        self.state.inner.lock().unwrap().status == TimerStatus::Expired
+    }
+
+    /// Whether the timer has been cancelled, either by `cancel` or by the shutdown of the
+    /// scheduler
+    pub fn is_cancelled(&self) -> bool {
+        self.state.inner.lock().unwrap().status == TimerStatus::Cancelled
+    }
+
+    /// Move the deadline of the timer, rearming it if it has expired or been cancelled
+    pub fn reset(&self, deadline: Instant) {
+        let mut inner = self.state.inner.lock().unwrap();
+        inner.deadline = deadline;
+        inner.status = TimerStatus::Pending;
+
+        match inner.armed {
+            // Postponed, moved in the timer wheel once the old deadline is due
+            Some(armed) if armed <= deadline => {}
+            _ => {
+                inner.armed = Some(deadline);
+                let msg = IoHandlerMessage::ArmTimer(self.state.clone(), deadline);
+                if self.state.channel.send(msg).is_err() {
+                    // The event loop is gone, the scheduler has shut down
+                    inner.status = TimerStatus::Cancelled;
+                    inner.armed = None;
+                }
+            }
+        }
+    }
+
+    /// Move the deadline of the timer to `delay` from now, see `reset`
+    pub fn reset_after(&self, delay: Duration) {
+        self.reset(Instant::now() + delay)
+    }
+
+    /// Cancel the timer and wake up the coroutines waiting for it.
+    ///
+    /// Returns false if the timer has already expired or been cancelled.
+    pub fn cancel(&self) -> bool {
+        self.state.cancel()
+    }
+
+    /// Block the current coroutine until the timer expires or is cancelled.
+    ///
+    /// Returns true if the timer has expired, false if it has been cancelled.
+    pub fn wait(&self) -> bool {
+        loop {
+            match self.state.inner.lock().unwrap().status {
+                TimerStatus::Pending => {}
+                status => return status == TimerStatus::Expired,
+            }
+
+            Scheduler::take_current_coroutine(|coro| {
+                coro.shared().set_wait_reason(WaitReason::Sleep);
+                let mut inner = self.state.inner.lock().unwrap();
+                if inner.status != TimerStatus::Pending {
+                    drop(inner);
+                    Scheduler::ready(coro);
+                } else {
+                    inner.waiters.push((coro, Processor::current().unwrap().handle()));
+                }
+            });
+        }
+    }
+}
+
+/// Error returned by I/O operations when the scheduler has reached its limit of
+/// registered I/O objects. See `Scheduler::with_max_io_registrations`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegistrationLimitExceeded {
+    pub limit: usize,
+}
+
+impl fmt::Display for RegistrationLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "too many registered I/O objects (limit {})", self.limit)
+    }
+}
+
+impl Error for RegistrationLimitExceeded {
+    fn description(&self) -> &str {
+        "too many registered I/O objects"
+    }
+}
+
+/// Error returned by blocking operations and `Scheduler::try_spawn` after the scheduler
+/// has begun to shut down.
+///
+/// A coroutine observes it only once, any further blocking operation unwinds it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShuttingDown;
+
+impl fmt::Display for ShuttingDown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "scheduler is shutting down")
+    }
+}
+
+impl Error for ShuttingDown {
+    fn description(&self) -> &str {
+        "scheduler is shutting down"
+    }
+}
+
+impl From<ShuttingDown> for io::Error {
+    fn from(err: ShuttingDown) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, err)
+    }
+}
+
+/// Error returned by `check_cancelled()` once the current coroutine has been aborted or
+/// cancelled, or its deadline has passed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "coroutine has been cancelled")
+    }
+}
+
+impl Error for Cancelled {
+    fn description(&self) -> &str {
+        "coroutine has been cancelled"
+    }
+}
+
+impl From<Cancelled> for io::Error {
+    fn from(err: Cancelled) -> io::Error {
+        io::Error::new(io::ErrorKind::Interrupted, err)
+    }
+}
+
+/// Error returned in debug builds by I/O objects used in a Scheduler other than the one
+/// they have been used in first. Their readiness would be reported to the wrong event loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WrongScheduler;
+
+impl fmt::Display for WrongScheduler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "I/O object is owned by another scheduler")
+    }
+}
+
+impl Error for WrongScheduler {
+    fn description(&self) -> &str {
+        "I/O object is owned by another scheduler"
+    }
+}
+
+impl From<WrongScheduler> for io::Error {
+    fn from(err: WrongScheduler) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, err)
+    }
+}
+
+/// Scheduler owning an I/O object, taken from the first blocking operation on it.
+///
+/// The object is counted as a registration of that Scheduler from then on until it is
+/// dropped. The owner is only checked in debug builds, `check()` never fails with
+/// `WrongScheduler` otherwise.
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct IoOwner {
+    scheduler: AtomicUsize,
+    registered: AtomicBool,
+    registration: Mutex<Option<IoRegistration>>,
+}
+
+impl IoOwner {
+    pub fn new() -> IoOwner {
+        IoOwner {
+            scheduler: AtomicUsize::new(0),
+            registered: AtomicBool::new(false),
+            registration: Mutex::new(None),
+        }
+    }
+
+    /// Fail with `WrongScheduler` if the object is used outside of its Scheduler, or with
+    /// `RegistrationLimitExceeded` if the Scheduler cannot take another I/O object
+    pub fn check(&self) -> io::Result<()> {
+        let sched = match Scheduler::instance() {
+            Some(sched) => sched,
+            None => return Ok(()),
+        };
+
+        if cfg!(debug_assertions) {
+            let current = sched.id;
+            let owner = self.scheduler.compare_and_swap(0, current, Ordering::SeqCst);
+            if owner != 0 && owner != current {
+                error!("I/O object of Scheduler #{} used in Scheduler #{}", owner, current);
+                return Err(WrongScheduler.into());
+            }
+        }
+
+        if !self.registered.load(Ordering::Acquire) {
+            let mut registration = self.registration.lock().unwrap();
+            if registration.is_none() {
+                *registration = Some(try!(sched.acquire_registration()));
+                self.registered.store(true, Ordering::Release);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Clone for IoOwner {
+    // The clone is another object, registered on its own first blocking operation
+    fn clone(&self) -> IoOwner {
+        IoOwner {
+            scheduler: AtomicUsize::new(self.scheduler.load(Ordering::SeqCst)),
+            registered: AtomicBool::new(false),
+            registration: Mutex::new(None),
+        }
+    }
+}
+
+// An I/O object counted against the limits of a Scheduler, released when it is dropped
+#[derive(Debug)]
+struct IoRegistration(Arc<AtomicUsize>);
+
+impl Drop for IoRegistration {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Warn when the registrations exceed this percentage of RLIMIT_NOFILE
+const NOFILE_WARN_PERCENT: usize = 80;
+
+/// Warn when coroutines with `StackClass::Large` hold more than this percentage of the
+/// memory reserved for stacks...
+const LARGE_STACK_WARN_PERCENT: usize = 50;
+
+/// ...and the stacks reserve at least this many bytes
+const LARGE_STACK_WARN_BYTES: usize = 64 * 1024 * 1024;
+
+/// `Scheduler::ready_all` wakes up smaller sets of coroutines one by one
+const READY_BATCH_THRESHOLD: usize = 64;
+
+// Low priority coroutines waiting longer than this are resumed before the others, by default
+const DEFAULT_PRIORITY_AGING_MS: u64 = 10;
+
+#[cfg(unix)]
+fn nofile_limit() -> Option<usize> {
+    use libc;
+
+    let mut rlim: libc::rlimit = unsafe { mem::zeroed() };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0 {
+        return None;
+    }
+
+    if rlim.rlim_cur == libc::RLIM_INFINITY {
+        None
+    } else {
+        Some(rlim.rlim_cur as usize)
+    }
+}
+
+#[cfg(not(unix))]
+fn nofile_limit() -> Option<usize> {
+    None
+}
+
+// Counts a coroutine waiting for I/O until it is resumed or unwound, or a subscription until
+// it is cancelled
+struct IoWaitGuard<'a>(&'a Scheduler);
+
+impl<'a> Drop for IoWaitGuard<'a> {
+    fn drop(&mut self) {
+        self.0.io_waits.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// How an idle Processor waits for new work
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleStrategy {
+    /// Park the thread immediately
+    Park,
+    /// Busy-poll for new work for the given duration before parking the thread
+    SpinThenPark(Duration),
+    /// Poll for new work up to the given number of times, giving up the CPU to other threads
+    /// with `sched_yield(2)` in between, before parking the thread. Unlike spinning this lets
+    /// the other Processors run on hosts where they outnumber the available CPUs,
+    /// see `cpu::available_cpus()`.
+    YieldThenPark(usize),
+    /// Poll for new work, sleeping in between with delays doubling from `min` up to `max`
+    Backoff {
+        min: Duration,
+        max: Duration,
+    },
+}
+
+/// What to do about the coroutines still alive when the main function returns,
+/// see `Scheduler::with_leak_detection()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeakDetection {
+    /// Unwind them silently
+    Off,
+    /// Log a `LeakReport` as a warning before unwinding them
+    Log,
+    /// Make `run()` fail with the `LeakReport`, e.g. to fail tests which leak coroutines
+    Fail,
+}
+
+/// What to do about coroutines blocked forever, see `Scheduler::with_deadlock_detection()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadlockDetection {
+    /// Do not look for deadlocks
+    Off,
+    /// Log a `DeadlockReport` as an error and keep on waiting
+    Log,
+    /// Shut the scheduler down, `run()` fails with the `DeadlockReport`
+    Fail,
+}
+
+/// Minimum time without any coroutine resumed before the blocked coroutines are reported
+const DEADLOCK_CHECK_MS: u64 = 500;
+
+// Progress of the coroutines as of the last deadlock check
+struct DeadlockCheck {
+    checked_at: Instant,
+    resumed: usize,
+    reported: bool,
+}
+
+// A function to be run in a coroutine when the scheduler shuts down
+struct Finalizer {
+    deadline: Duration,
+    f: Box<FnBox() + Send + 'static>,
+}
+
+/// Creates the random number generator of a Processor, given the Processor id
+pub type RngFactory = Box<Fn(usize) -> Box<Rng + Send> + Send + Sync>;
+
+// Coroutines registered for memory accounting or leak detection, the finished ones are
+// pruned lazily
+struct Accounted {
+    coroutines: Vec<Weak<Shared>>,
+    prune_at: usize,
+}
+
+impl Accounted {
+    fn prune(&mut self) {
+        self.coroutines.retain(|c| c.upgrade().is_some());
+        self.prune_at = ::std::cmp::max(1024, self.coroutines.len() * 2);
+    }
+}
+
+// Worker thread of a running Processor
+struct ProcessorThread {
+    id: usize,
+    name: String,
+    os_tid: Option<u64>,
+    queue: RunQueueStealer,
+}
+
+/// Coroutine scheduler
+pub struct Scheduler {
+    // Unlike the address, never reused by a later Scheduler
+    id: usize,
+    work_counts: AtomicUsize,
+    finished_count: AtomicUsize,
+    expected_worker_count: usize,
+    // Keep more workers than the cgroup CPU quota allows
+    oversubscription: bool,
+    thread_name_prefix: String,
+    cpu_affinity: Vec<usize>,
+    processor_threads: Mutex<Vec<ProcessorThread>>,
+    spawn_hint: SpawnHint,
+
+    // Ids of the Processors taken out of service with drain_processor()
+    drained_processors: Mutex<Vec<usize>>,
+    drain_cursor: AtomicUsize,
+
+    // Processors retired with remove_workers() and workers still to be started by add_workers()
+    retired_processors: Mutex<Vec<usize>>,
+    pending_workers: Mutex<usize>,
+
+    // Number of live I/O objects used with this Scheduler, shared with their registrations
+    io_registrations: Arc<AtomicUsize>,
+    // Number of I/O waits and subscriptions in the event loop
+    io_waits: AtomicUsize,
+    max_io_registrations: Option<usize>,
+    nofile_limit: Option<usize>,
+    nofile_warned: AtomicBool,
+
+    // Stacks kept for reuse per StackClass by each Processor, and the stacks allocated so far
+    stack_pool_cap: usize,
+    stacks_allocated: AtomicUsize,
+
+    // Number of live coroutines per StackClass
+    stack_counts: [AtomicUsize; 4],
+    large_stacks_warned: AtomicBool,
+
+    // High-water marks of the finished coroutines per StackClass, if the stacks are measured
+    stack_measurement: bool,
+    stack_high_water_marks: Vec<SizeHistogram>,
+
+    // Coroutines whose heap memory is accounted, see `metrics::memory`, checked for leaks
+    // or deadlocks
+    memory_accounting: bool,
+    coroutine_dump: bool,
+    leak_detection: LeakDetection,
+    deadlock_detection: DeadlockDetection,
+    accounted: Mutex<Accounted>,
+    leak_report: Mutex<Option<LeakReport>>,
+    deadlock_report: Mutex<Option<DeadlockReport>>,
+    // Not spawned like the others, so that it is not reported as leaked
+    main_coroutine: Mutex<Option<Arc<Shared>>>,
+
+    // Instrumented channels created in this Scheduler
+    channels: Mutex<Vec<Weak<ChannelMetrics>>>,
+
+    rng_factory: Option<RngFactory>,
+    observer: Option<Box<SchedulerObserver>>,
+    panic_handler: Option<Box<Fn(Option<&str>, &(Any + Send)) + Send + Sync>>,
+    run_queue_watermarks: Option<Watermarks>,
+    blocked_watermarks: Option<Watermarks>,
+    slow_io_threshold: Option<Duration>,
+    cancel_unwinding: bool,
+    fair_queuing: bool,
+    group_weights: HashMap<usize, u32>,
+    priority_aging: Duration,
+    blocked_count: AtomicUsize,
+    blocked_high: AtomicBool,
+
+    finalizers: Mutex<Vec<Finalizer>>,
+    shutting_down: AtomicBool,
+    stopping: AtomicBool,
+    graceful_deadline: Mutex<Option<Instant>>,
+    remote: Remote,
+    blocking_pool: BlockingPool,
+    resolver: BlockingPool,
+
+    idle_strategy: IdleStrategy,
+    idle_wakeups: AtomicUsize,
+    idle_wakeup_latency_ns: AtomicUsize,
+
+    // Processors waiting for work, unparked by the others as they queue work
+    idle_processors: Mutex<Vec<usize>>,
+    idle_count: AtomicUsize,
+
+    // Mio event loop and the handler
+    // It controls all I/O and timer waits
+    event_loop: EventLoop<IoHandler>,
+    io_handler: IoHandler,
+    event_loop_wakeups: AtomicUsize,
+    // Pending sleep timers, updated by the event loop for stats()
+    timer_count: AtomicUsize,
+}
+
+unsafe impl Send for Scheduler {}
+unsafe impl Sync for Scheduler {}
+
+impl Scheduler {
+    /// Create a scheduler with default configurations
+    pub fn new() -> Scheduler {
+        Scheduler {
+            id: SCHEDULER_ID.fetch_add(1, Ordering::Relaxed) + 1,
+            work_counts: AtomicUsize::new(0),
+            finished_count: AtomicUsize::new(0),
+            expected_worker_count: 1,
+            oversubscription: false,
+            thread_name_prefix: "Processor #".to_owned(),
+            cpu_affinity: Vec::new(),
+            processor_threads: Mutex::new(Vec::new()),
+            spawn_hint: SpawnHint::Immediate,
+
+            drained_processors: Mutex::new(Vec::new()),
+            drain_cursor: AtomicUsize::new(0),
+
+            retired_processors: Mutex::new(Vec::new()),
+            pending_workers: Mutex::new(0),
+
+            io_registrations: Arc::new(AtomicUsize::new(0)),
+            io_waits: AtomicUsize::new(0),
+            max_io_registrations: None,
+            nofile_limit: nofile_limit(),
+            nofile_warned: AtomicBool::new(false),
+
+            stack_counts: [AtomicUsize::new(0),
+                           AtomicUsize::new(0),
+                           AtomicUsize::new(0),
+                           AtomicUsize::new(0)],
+            large_stacks_warned: AtomicBool::new(false),
+
+            stack_pool_cap: DEFAULT_STACK_POOL_CAP,
+            stacks_allocated: AtomicUsize::new(0),
+
+            stack_measurement: false,
+            stack_high_water_marks: StackClass::all()
+                                        .iter()
+                                        .map(|_| SizeHistogram::new())
+                                        .collect(),
+
+            memory_accounting: false,
+            coroutine_dump: false,
+            leak_detection: LeakDetection::Off,
+            deadlock_detection: DeadlockDetection::Off,
+            accounted: Mutex::new(Accounted {
+                coroutines: Vec::new(),
+                prune_at: 1024,
+            }),
+            leak_report: Mutex::new(None),
+            deadlock_report: Mutex::new(None),
+            main_coroutine: Mutex::new(None),
+
+            channels: Mutex::new(Vec::new()),
+
+            rng_factory: None,
+            observer: None,
+            panic_handler: None,
+            run_queue_watermarks: None,
+            blocked_watermarks: None,
+            slow_io_threshold: None,
+            cancel_unwinding: false,
+            fair_queuing: false,
+            group_weights: HashMap::new(),
+            priority_aging: Duration::from_millis(DEFAULT_PRIORITY_AGING_MS),
+            blocked_count: AtomicUsize::new(0),
+            blocked_high: AtomicBool::new(false),
+
+            finalizers: Mutex::new(Vec::new()),
+            shutting_down: AtomicBool::new(false),
+            stopping: AtomicBool::new(false),
+            graceful_deadline: Mutex::new(None),
+            remote: Remote::new(),
+            blocking_pool: BlockingPool::new("Blocking", DEFAULT_BLOCKING_THREADS),
+            resolver: BlockingPool::new("Resolver", DEFAULT_RESOLVER_THREADS),
+
+            idle_strategy: IdleStrategy::Park,
+            idle_wakeups: AtomicUsize::new(0),
+            idle_wakeup_latency_ns: AtomicUsize::new(0),
+
+            idle_processors: Mutex::new(Vec::new()),
+            idle_count: AtomicUsize::new(0),
+
+            event_loop: EventLoop::new().unwrap(),
+            io_handler: IoHandler::new(),
+            event_loop_wakeups: AtomicUsize::new(0),
+            timer_count: AtomicUsize::new(0),
+        }
+    }
+
+    /// Set the number of workers. `run()` limits them to the cgroup CPU quota of the process,
+    /// unless `with_oversubscription(true)` is set, see `cpu::cap_to_quota()`.
+    pub fn with_workers(mut self, workers: usize) -> Scheduler {
+        assert!(workers >= 1, "Must have at least one worker");
+        self.expected_worker_count = workers;
+        self
+    }
+
+    /// Start as many workers as set with `with_workers`, even if they outnumber the CPUs the
+    /// cgroup CPU quota allows and get throttled in turn. Defaults to false.
+    pub fn with_oversubscription(mut self, enabled: bool) -> Scheduler {
+        self.oversubscription = enabled;
+        self
+    }
+
+    /// Set the number of workers to the number of CPUs available to the process, honoring
+    /// cgroup CPU quotas, see `cpu::available_cpus()`
+    pub fn with_workers_auto(self) -> Scheduler {
+        self.with_workers(::cpu::available_cpus())
+    }
+
+    /// Set the number of workers to a fraction of the CPUs available to the process,
+    /// e.g. `0.5` for half of them, but at least one
+    pub fn with_workers_fraction(self, fraction: f64) -> Scheduler {
+        assert!(fraction > 0.0, "Fraction of the CPUs must be positive");
+
+        let workers = (::cpu::available_cpus() as f64 * fraction).round() as usize;
+        self.with_workers(cmp::max(workers, 1))
+    }
+
+    /// Set the prefix of the worker thread names, which are followed by the Processor id.
+    /// Defaults to `"Processor #"`.
+    pub fn with_thread_name_prefix<S: Into<String>>(mut self, prefix: S) -> Scheduler {
+        self.thread_name_prefix = prefix.into();
+        self
+    }
+
+    /// Pin the worker threads to CPUs, Processor `i` to `cpus[i % cpus.len()]`. Keeps the
+    /// Processors from migrating between cores or NUMA nodes, which costs cache locality.
+    ///
+    /// Only supported on Linux, elsewhere a warning is logged.
+    pub fn with_cpu_affinity(mut self, cpus: &[usize]) -> Scheduler {
+        self.cpu_affinity = cpus.to_vec();
+        self
+    }
+
+    #[doc(hidden)]
+    pub fn cpu_for(&self, processor_id: usize) -> Option<usize> {
+        if self.cpu_affinity.is_empty() {
+            None
+        } else {
+            Some(self.cpu_affinity[processor_id % self.cpu_affinity.len()])
+        }
+    }
+
+    /// Set whether coroutines spawned inside a coroutine preempt the spawning one,
+    /// unless `Options::spawn_hint` says otherwise. Defaults to `SpawnHint::Immediate`.
+    pub fn with_spawn_hint(mut self, hint: SpawnHint) -> Scheduler {
+        self.spawn_hint = hint;
+        self
+    }
+
+    #[doc(hidden)]
+    pub fn spawn_hint(&self) -> SpawnHint {
+        self.spawn_hint
+    }
+
+    #[doc(hidden)]
+    pub fn thread_name(&self, processor_id: usize) -> String {
+        format!("{}{}", self.thread_name_prefix, processor_id)
+    }
+
+    /// A Processor started running on its worker thread
+    #[doc(hidden)]
+    pub fn processor_started(&self,
+                             processor_id: usize,
+                             name: String,
+                             os_tid: Option<u64>,
+                             queue: RunQueueStealer) {
+        let mut threads = self.processor_threads.lock().unwrap();
+        threads.push(ProcessorThread {
+            id: processor_id,
+            name: name,
+            os_tid: os_tid,
+            queue: queue,
+        });
+        threads.sort_by(|a, b| a.id.cmp(&b.id));
+    }
+
+    /// Set the maximum number of live I/O objects that could be used with the Scheduler at the
+    /// same time. An object is registered by its first blocking operation and stays registered
+    /// until it is dropped, the operation fails with `RegistrationLimitExceeded` if the limit
+    /// has been reached.
+    pub fn with_max_io_registrations(mut self, limit: usize) -> Scheduler {
+        assert!(limit >= 1, "Must allow at least one registration");
+        self.max_io_registrations = Some(limit);
+        self
+    }
+
+    /// Set how idle Processors wait for new work, defaults to `IdleStrategy::Park`
+    pub fn with_idle_strategy(mut self, strategy: IdleStrategy) -> Scheduler {
+        if let IdleStrategy::Backoff { min, max } = strategy {
+            assert!(min <= max, "Minimum backoff must not exceed the maximum");
+        }
+
+        self.idle_strategy = strategy;
+        self
+    }
+
+    #[doc(hidden)]
+    pub fn idle_strategy(&self) -> IdleStrategy {
+        self.idle_strategy
+    }
+
+    /// Account the latency of waking up an idle Processor
+    #[doc(hidden)]
+    pub fn record_wakeup(&self, latency: Duration) {
+        let ns = latency.as_secs() as usize * 1_000_000_000 + latency.subsec_nanos() as usize;
+
+        self.idle_wakeups.fetch_add(1, Ordering::Relaxed);
+        self.idle_wakeup_latency_ns.fetch_add(ns, Ordering::Relaxed);
+    }
+
+    /// List the Processor as waiting for work, see `unpark_idle()`
+    #[doc(hidden)]
+    pub fn enter_idle(&self, processor_id: usize) {
+        let mut idle = self.idle_processors.lock().unwrap();
+        idle.push(processor_id);
+        self.idle_count.store(idle.len(), Ordering::SeqCst);
+    }
+
+    /// Remove the Processor from the idle list, unless it has been unparked already
+    #[doc(hidden)]
+    pub fn leave_idle(&self, processor_id: usize) {
+        let mut idle = self.idle_processors.lock().unwrap();
+        if let Some(idx) = idle.iter().position(|id| *id == processor_id) {
+            idle.swap_remove(idx);
+        }
+        self.idle_count.store(idle.len(), Ordering::SeqCst);
+    }
+
+    /// Wake up one idle Processor, if there is any, to steal the work queued by the caller
+    #[doc(hidden)]
+    pub fn unpark_idle(&self) {
+        if self.idle_count.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+
+        let processor_id = {
+            let mut idle = self.idle_processors.lock().unwrap();
+            let processor_id = idle.pop();
+            self.idle_count.store(idle.len(), Ordering::SeqCst);
+            processor_id
+        };
+
+        if let Some(processor_id) = processor_id {
+            if let Some(processor) = self.remote.processors().get(processor_id) {
+                let _ = processor.send(ProcMessage::Unpark(Instant::now()));
+            }
+        }
+    }
+
+    /// Register a finalizer to be run when the main function has returned.
+    ///
+    /// Finalizers run one after another in the order of registration, each in its own
+    /// coroutine, while the runtime is still fully functional. A finalizer which has not
+    /// finished within `deadline` is cancelled, and the next one starts once it has unwound,
+    /// or after another `deadline`. After all finalizers are done the remaining coroutines
+    /// are unwound and `run()` returns.
+    pub fn on_shutdown<F>(&self, deadline: Duration, f: F)
+        where F: FnOnce() + Send + 'static
+    {
+        self.finalizers.lock().unwrap().push(Finalizer {
+            deadline: deadline,
+            f: Box::new(f),
+        });
+    }
+
+    fn run_finalizers(&mut self, processor: &Sender<ProcMessage>) {
+        let finalizers = mem::replace(&mut *self.finalizers.lock().unwrap(), Vec::new());
+
+        for finalizer in finalizers {
+            let (tx, rx) = ::std::sync::mpsc::channel();
+            let f = finalizer.f;
+            let wrapper = move || {
+                if let Err(payload) = unsafe { ::try(move || f.call_box(())) } {
+                    if !payload.is::<ForceUnwind>() {
+                        error!("Shutdown finalizer panicked: {}", panic_message(&payload));
+                    }
+                }
+                let _ = tx.send(());
+            };
+
+            let coro = Coroutine::spawn_opts(Box::new(wrapper), Options::default(), None);
+            let shared = coro.shared().clone();
+
+            self.work_counts.fetch_add(1, Ordering::SeqCst);
+            if let Err(err) = processor.send(ProcMessage::ready(coro)) {
+                error!("Error while sending finalizer {:?}", err);
+                if let ProcMessage::Ready(mut coro, _) = err.0 {
+                    coro.set_drop_allowed();
+                }
+                self.work_counts.fetch_sub(1, Ordering::SeqCst);
+                continue;
+            }
+
+            // An aborted finalizer gets another `deadline` to unwind before the next one starts
+            let started = Instant::now();
+            let mut aborted = false;
+            loop {
+                // Keep on dispatching events, finalizers may perform I/O
+                self.poll_events(10);
+
+                match rx.try_recv() {
+                    Err(TryRecvError::Empty) => {}
+                    _ => break,
+                }
+
+                if !aborted && started.elapsed() >= finalizer.deadline {
+                    warn!("Shutdown finalizer exceeded its deadline of {:?}",
+                          finalizer.deadline);
+                    shared.cancel();
+                    aborted = true;
+                } else if aborted && started.elapsed() >= finalizer.deadline * 2 {
+                    warn!("Shutdown finalizer has not unwound after being aborted");
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Set the source of randomness of the Processors, used to pick the neighbor to steal
+    /// work from and for jitter. Defaults to unpredictably seeded generators.
+    pub fn with_rng<F>(mut self, factory: F) -> Scheduler
+        where F: Fn(usize) -> Box<Rng + Send> + Send + Sync + 'static
+    {
+        self.rng_factory = Some(Box::new(factory));
+        self
+    }
+
+    /// Seed the random number generators of the Processors, so that the same seed and
+    /// number of workers give the same sequences of random decisions
+    pub fn with_rng_seed(self, seed: u64) -> Scheduler {
+        self.with_rng(move |processor_id| {
+            // The constant keeps the seed from being all zeros
+            let seed = [(seed >> 32) as u32, seed as u32, processor_id as u32, 0x9e3779b9];
+            Box::new(XorShiftRng::from_seed(seed)) as Box<Rng + Send>
+        })
+    }
+
+    #[doc(hidden)]
+    pub fn new_rng(&self, processor_id: usize) -> Box<Rng + Send> {
+        match self.rng_factory {
+            Some(ref factory) => factory(processor_id),
+            None => Box::new(rand::weak_rng()),
+        }
+    }
+
+    /// Random duration up to `max`, drawn from the current Processor's generator
+    #[doc(hidden)]
+    pub fn jitter(max: Duration) -> Duration {
+        let max_ns = max.as_secs() * 1_000_000_000 + max.subsec_nanos() as u64;
+        if max_ns == 0 {
+            return max;
+        }
+
+        let ns = match Processor::current() {
+            Some(mut processor) => processor.gen_u64() % (max_ns + 1),
+            None => rand::thread_rng().gen::<u64>() % (max_ns + 1),
+        };
+        Duration::new(ns / 1_000_000_000, (ns % 1_000_000_000) as u32)
+    }
+
+    /// Set the observer receiving runtime events
+    pub fn with_observer<O>(mut self, observer: O) -> Scheduler
+        where O: SchedulerObserver + 'static
+    {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Call `handler` with the name and the panic payload of every spawned coroutine which
+    /// panics, instead of logging the panic as an error.
+    ///
+    /// The payload is still returned by `JoinHandle::join()`. Coroutines unwound by `abort`,
+    /// `cancel`, their deadline or the shutdown are not reported.
+    pub fn on_coroutine_panic<F>(mut self, handler: F) -> Scheduler
+        where F: Fn(Option<&str>, &(Any + Send)) + Send + Sync + 'static
+    {
+        self.panic_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Report the panic of the current coroutine, see `on_coroutine_panic`
+    #[doc(hidden)]
+    pub fn report_panic(payload: &Box<Any + Send>) {
+        if payload.is::<ForceUnwind>() {
+            return;
+        }
+
+        let scheduler = match Scheduler::instance() {
+            Some(scheduler) => scheduler,
+            None => return,
+        };
+        let coro = Processor::current().and_then(|p| p.current_shared());
+        let name = coro.as_ref().and_then(|c| c.name());
+
+        match scheduler.panic_handler {
+            Some(ref handler) => (**handler)(name, &**payload),
+            None => {
+                error!("Coroutine {} panicked: {}",
+                       name.unwrap_or("<unnamed>"),
+                       panic_message(payload));
+            }
+        }
+    }
+
+    /// Emit `RunQueueHigh`/`RunQueueLow` events when a Processor's run queue length
+    /// crosses the watermarks
+    pub fn with_run_queue_watermarks(mut self, low: usize, high: usize) -> Scheduler {
+        self.run_queue_watermarks = Some(Watermarks::new(low, high));
+        self
+    }
+
+    /// Emit `BlockedHigh`/`BlockedLow` events when the number of blocked coroutines
+    /// crosses the watermarks
+    pub fn with_blocked_watermarks(mut self, low: usize, high: usize) -> Scheduler {
+        self.blocked_watermarks = Some(Watermarks::new(low, high));
+        self
+    }
+
+    /// Emit `SlowIo` events when a coroutine waits longer than `threshold` for an I/O event,
+    /// as soon as the threshold has passed
+    pub fn with_slow_io_threshold(mut self, threshold: Duration) -> Scheduler {
+        self.slow_io_threshold = Some(threshold);
+        self
+    }
+
+    /// Run the closures of `run_blocking` on `threads` threads, 16 by default. They are
+    /// started by the first call.
+    pub fn with_blocking_threads(mut self, threads: usize) -> Scheduler {
+        assert!(threads >= 1, "Must have at least one blocking thread");
+        self.blocking_pool = BlockingPool::new("Blocking", threads);
+        self
+    }
+
+    /// Run the host name lookups of `net::dns` on `threads` threads, 4 by default. They are
+    /// started by the first lookup, and kept apart from the ones of `run_blocking` so that
+    /// slow jobs do not hold up the lookups.
+    pub fn with_resolver_threads(mut self, threads: usize) -> Scheduler {
+        assert!(threads >= 1, "Must have at least one resolver thread");
+        self.resolver = BlockingPool::new("Resolver", threads);
+        self
+    }
+
+    #[doc(hidden)]
+    pub fn resolver(&self) -> &BlockingPool {
+        &self.resolver
+    }
+
+    /// Unwind cancelled coroutines in `check_cancelled()` instead of returning `Cancelled`
+    pub fn with_cancel_unwinding(mut self, enabled: bool) -> Scheduler {
+        self.cancel_unwinding = enabled;
+        self
+    }
+
+    #[doc(hidden)]
+    pub fn cancel_unwinding(&self) -> bool {
+        self.cancel_unwinding
+    }
+
+    /// Share the running time of each Processor between the groups of coroutines set with
+    /// `Options::group`, in proportion to their weights, instead of between the coroutines.
+    ///
+    /// Then a tenant with thousands of busy connections gets no more time than a tenant with a
+    /// single one. Each Processor picks among a batch of its queued coroutines, which could not
+    /// be stolen by the other Processors meanwhile, the rest of its queue could.
+    pub fn with_fair_queuing(mut self, enabled: bool) -> Scheduler {
+        self.fair_queuing = enabled;
+        self
+    }
+
+    /// Weigh a group for fair queuing, it gets `weight` times the running time of a group of
+    /// weight 1, which is the default
+    pub fn with_group_weight(mut self, group: usize, weight: u32) -> Scheduler {
+        assert!(weight > 0, "Group weight must be positive");
+        self.group_weights.insert(group, weight);
+        self
+    }
+
+    /// Resume a low priority coroutine before the others once it has been queued for longer
+    /// than `aging`, so that it still runs while the Processor is busy. Defaults to 10 ms.
+    pub fn with_priority_aging(mut self, aging: Duration) -> Scheduler {
+        self.priority_aging = aging;
+        self
+    }
+
+    #[doc(hidden)]
+    pub fn priority_aging(&self) -> Duration {
+        self.priority_aging
+    }
+
+    #[doc(hidden)]
+    pub fn fair_queuing(&self) -> bool {
+        self.fair_queuing
+    }
+
+    #[doc(hidden)]
+    pub fn group_weight(&self, group: usize) -> u32 {
+        self.group_weights.get(&group).cloned().unwrap_or(1)
+    }
+
+    #[doc(hidden)]
+    pub fn run_queue_watermarks(&self) -> Option<Watermarks> {
+        self.run_queue_watermarks
+    }
+
+    #[doc(hidden)]
+    pub fn has_observer(&self) -> bool {
+        self.observer.is_some()
+    }
+
+    /// Deliver an event to the observer
+    #[doc(hidden)]
+    pub fn emit(&self, event: Event) {
+        if let Some(ref observer) = self.observer {
+            observer.on_event(&event);
+        }
+    }
+
+    /// Number of coroutines currently blocked on I/O, timers or synchronization primitives
+    pub fn blocked_count(&self) -> usize {
+        self.blocked_count.load(Ordering::SeqCst)
+    }
+
+    #[doc(hidden)]
+    pub fn coroutine_blocked(&self) {
+        let count = self.blocked_count.fetch_add(1, Ordering::SeqCst) + 1;
+        self.check_blocked_watermarks(count);
+    }
+
+    #[doc(hidden)]
+    pub fn coroutine_unblocked(&self) {
+        let count = self.blocked_count.fetch_sub(1, Ordering::SeqCst) - 1;
+        self.check_blocked_watermarks(count);
+    }
+
+    fn check_blocked_watermarks(&self, count: usize) {
+        let marks = match self.blocked_watermarks {
+            Some(marks) => marks,
+            None => return,
+        };
+
+        if let Some(is_high) = marks.check(count, self.blocked_high.load(Ordering::SeqCst)) {
+            // Only the thread flipping the flag reports the crossing
+            if self.blocked_high.swap(is_high, Ordering::SeqCst) != is_high {
+                if is_high {
+                    self.emit(Event::BlockedHigh { count: count });
+                } else {
+                    self.emit(Event::BlockedLow { count: count });
+                }
+            }
+        }
+    }
+
+    /// Snapshot of the runtime statistics
+    pub fn stats(&self) -> Stats {
+        let wakeups = self.idle_wakeups.load(Ordering::Relaxed);
+        let latency_ns = self.idle_wakeup_latency_ns.load(Ordering::Relaxed);
+        let mean_ns = if wakeups == 0 {
+            0
+        } else {
+            latency_ns / wakeups
+        };
+        // Loaded before the active coroutines, a coroutine finishing in between is not
+        // counted twice
+        let finished = self.finished_count.load(Ordering::Relaxed);
+        let active = self.work_count();
+
+        let processors = self.processor_threads
+                             .lock()
+                             .unwrap()
+                             .iter()
+                             .map(|t| {
+                                 ProcessorStats {
+                                     id: t.id,
+                                     thread_name: t.name.clone(),
+                                     os_tid: t.os_tid,
+                                     run_queue_len: t.queue.len(),
+                                     mean_run_queue_len: t.queue.mean_len(),
+                                     load: t.queue.load(),
+                                     steals_attempted: t.queue.steals_attempted(),
+                                     steals_succeeded: t.queue.steals_succeeded(),
+                                 }
+                             })
+                             .collect::<Vec<_>>();
+
+        let mean_run_queue_len = if processors.is_empty() {
+            0.0
+        } else {
+            processors.iter().fold(0.0, |sum, p| sum + p.mean_run_queue_len) /
+            processors.len() as f64
+        };
+
+        Stats {
+            coroutines_spawned: finished + active,
+            coroutines_finished: finished,
+            coroutines_active: active,
+            coroutines_blocked: self.blocked_count(),
+            io_registrations: self.io_registration_count(),
+            timers: self.timer_count.load(Ordering::Relaxed),
+            event_loop_wakeups: self.event_loop_wakeups.load(Ordering::Relaxed),
+            idle_wakeups: wakeups,
+            mean_wakeup_latency: Duration::new((mean_ns / 1_000_000_000) as u64,
+                                               (mean_ns % 1_000_000_000) as u32),
+            mean_run_queue_len: mean_run_queue_len,
+            processors: processors,
+        }
+    }
+
+    // Dispatch the I/O events and expired timers, waiting at most `max_ms` for them
+    fn poll_events(&mut self, max_ms: u64) {
+        let timeout = self.io_handler.poll_timeout_ms(max_ms);
+        self.event_loop.run_once(&mut self.io_handler, Some(timeout)).unwrap();
+
+        self.event_loop_wakeups.fetch_add(1, Ordering::Relaxed);
+        let timers = self.io_handler.sleepers.len() + self.io_handler.timers.len();
+        self.timer_count.store(timers, Ordering::Relaxed);
+    }
+
+    /// Smoothed load of every Processor ordered by the Processor id, see `ProcessorStats::load`
+    pub fn processor_loads(&self) -> Vec<f64> {
+        self.processor_threads.lock().unwrap().iter().map(|t| t.queue.load()).collect()
+    }
+
+    /// Attribute the heap memory reported through `metrics::memory` to the coroutines
+    pub fn with_memory_accounting(mut self, enabled: bool) -> Scheduler {
+        self.memory_accounting = enabled;
+        self
+    }
+
+    #[doc(hidden)]
+    #[inline]
+    pub fn memory_accounting(&self) -> bool {
+        self.memory_accounting
+    }
+
+    /// Heap memory attributed to the live coroutines, the largest users first.
+    ///
+    /// Empty unless enabled with `with_memory_accounting`.
+    pub fn memory_usage(&self) -> Vec<CoroutineMemory> {
+        if !self.memory_accounting {
+            return Vec::new();
+        }
+
+        let mut accounted = self.accounted.lock().unwrap();
+        accounted.prune();
+
+        let mut usage = Vec::with_capacity(accounted.coroutines.len());
+        for shared in accounted.coroutines.iter().filter_map(|c| c.upgrade()) {
+            let (allocated, freed) = shared.memory();
+            usage.push(CoroutineMemory {
+                coroutine_id: shared.id(),
+                name: shared.name().map(|n| n.to_owned()),
+                allocated: allocated,
+                freed: freed,
+            });
+        }
+        usage.sort_by(|a, b| b.in_use().cmp(&a.in_use()));
+        usage
+    }
+
+    fn track_coroutine(&self, shared: &Arc<Shared>) {
+        if self.memory_accounting || self.coroutine_dump ||
+           self.leak_detection != LeakDetection::Off ||
+           self.deadlock_detection != DeadlockDetection::Off {
+            let mut accounted = self.accounted.lock().unwrap();
+            if accounted.coroutines.len() >= accounted.prune_at {
+                accounted.prune();
+            }
+            accounted.coroutines.push(Arc::downgrade(shared));
+        }
+    }
+
+    /// Keep track of the spawned coroutines for `dump_coroutines()`
+    pub fn with_coroutine_dump(mut self, enabled: bool) -> Scheduler {
+        self.coroutine_dump = enabled;
+        self
+    }
+
+    /// What every live coroutine is doing right now, ordered by the coroutine id, e.g. to
+    /// find out why a process is stuck.
+    ///
+    /// Empty unless enabled with `with_coroutine_dump`.
+    pub fn dump_coroutines(&self) -> Vec<CoroutineSnapshot> {
+        if !self.coroutine_dump {
+            return Vec::new();
+        }
+
+        let mut accounted = self.accounted.lock().unwrap();
+        accounted.prune();
+
+        let mut dump: Vec<_> = accounted.coroutines
+                                        .iter()
+                                        .filter_map(|c| c.upgrade())
+                                        .filter(|shared| !shared.is_finished())
+                                        .map(|shared| coroutine_snapshot(&shared))
+                                        .collect();
+
+        dump.sort_by(|a, b| a.coroutine_id.cmp(&b.coroutine_id));
+        dump
+    }
+
+    /// Look for coroutines which are blocked forever, see `DeadlockDetection`.
+    ///
+    /// A deadlock is reported once no coroutine has been resumed for a while, and all the
+    /// coroutines, the main function included, are blocked on synchronization primitives or
+    /// channels while no timer or I/O object could wake any of them up. Coroutines woken up
+    /// only by threads outside of the scheduler, e.g. through `Remote` or a channel, would
+    /// be reported as well.
+    pub fn with_deadlock_detection(mut self, detection: DeadlockDetection) -> Scheduler {
+        self.deadlock_detection = detection;
+        self
+    }
+
+    /// The coroutines found blocked forever by the last check, unless deadlock detection
+    /// is `Off`
+    pub fn deadlock_report(&self) -> Option<DeadlockReport> {
+        self.deadlock_report.lock().unwrap().clone()
+    }
+
+    #[doc(hidden)]
+    pub fn set_main_coroutine(&self, shared: Arc<Shared>) {
+        *self.main_coroutine.lock().unwrap() = Some(shared);
+    }
+
+    // Called periodically by the event loop, returns the stuck coroutines once a deadlock
+    // has been found
+    fn check_deadlock(&self,
+                      check: &mut DeadlockCheck,
+                      stealers: &[RunQueueStealer])
+                      -> Option<DeadlockReport> {
+        if self.deadlock_detection == DeadlockDetection::Off {
+            return None;
+        }
+
+        let now = Instant::now();
+        if now < check.checked_at + Duration::from_millis(DEADLOCK_CHECK_MS) {
+            return None;
+        }
+
+        let resumed = stealers.iter().fold(0, |n, st| n + st.resumed());
+        let progressed = resumed != check.resumed;
+        check.checked_at = now;
+        check.resumed = resumed;
+
+        if progressed || stealers.iter().any(|st| st.len() > 0) ||
+           self.timer_count.load(Ordering::Relaxed) > 0 ||
+           self.io_waits.load(Ordering::SeqCst) > 0 {
+            check.reported = false;
+            return None;
+        }
+
+        let mut live = {
+            let mut accounted = self.accounted.lock().unwrap();
+            accounted.prune();
+            accounted.coroutines.iter().filter_map(|c| c.upgrade()).collect::<Vec<_>>()
+        };
+        if let Some(ref main) = *self.main_coroutine.lock().unwrap() {
+            live.push(main.clone());
+        }
+        live.retain(|shared| !shared.is_finished());
+
+        // Coroutines which are not tracked, e.g. waiting for `spawn_after`, could wake the
+        // others up
+        if live.is_empty() || live.len() < self.work_count() {
+            return None;
+        }
+
+        let stuck = live.iter().all(|shared| {
+            match shared.wait_reason() {
+                Some(WaitReason::Sync) | Some(WaitReason::Other) => !shared.is_running(),
+                _ => false,
+            }
+        });
+        if !stuck || check.reported {
+            return None;
+        }
+        check.reported = true;
+
+        let mut stuck: Vec<_> = live.iter().map(|shared| coroutine_snapshot(shared)).collect();
+        stuck.sort_by(|a, b| a.coroutine_id.cmp(&b.coroutine_id));
+        let report = DeadlockReport { stuck: stuck };
+        *self.deadlock_report.lock().unwrap() = Some(report.clone());
+        Some(report)
+    }
+
+    /// Report the coroutines still alive when the main function returns, see `LeakDetection`.
+    /// They are spawned coroutines which have been neither joined nor finished on their own,
+    /// e.g. forgotten background loops, and would be unwound silently otherwise.
+    ///
+    /// The report tells their `Options::spawn_site` and what they were waiting for.
+    pub fn with_leak_detection(mut self, detection: LeakDetection) -> Scheduler {
+        self.leak_detection = detection;
+        self
+    }
+
+    /// The coroutines leaked by the last `run()`, unless leak detection is `Off`
+    pub fn leak_report(&self) -> Option<LeakReport> {
+        self.leak_report.lock().unwrap().clone()
+    }
+
+    // Coroutines still alive once the main function and the finalizers are done
+    fn detect_leaks(&self) -> Option<LeakReport> {
+        if self.leak_detection == LeakDetection::Off {
+            return None;
+        }
+
+        let mut accounted = self.accounted.lock().unwrap();
+        accounted.prune();
+
+        let mut leaked: Vec<_> = accounted.coroutines
+                                          .iter()
+                                          .filter_map(|c| c.upgrade())
+                                          .filter(|shared| !shared.is_finished())
+                                          .map(|shared| {
+                                              LeakedCoroutine {
+                                                  coroutine_id: shared.id(),
+                                                  name: shared.name().map(|n| n.to_owned()),
+                                                  spawn_site: shared.spawn_site(),
+                                                  wait_reason: shared.wait_reason(),
+                                                  spans: shared.spans(),
+                                              }
+                                          })
+                                          .collect();
+        if leaked.is_empty() {
+            return None;
+        }
+
+        leaked.sort_by(|a, b| a.coroutine_id.cmp(&b.coroutine_id));
+        Some(LeakReport { leaked: leaked })
+    }
+
+    /// Number of live coroutines per stack class
+    pub fn stack_usage(&self) -> StackUsage {
+        let count = |class: StackClass| self.stack_counts[class.index()].load(Ordering::Relaxed);
+
+        StackUsage {
+            tiny: count(StackClass::Tiny),
+            small: count(StackClass::Small),
+            default: count(StackClass::Default),
+            large: count(StackClass::Large),
+        }
+    }
+
+    #[doc(hidden)]
+    pub fn register_channel(&self, metrics: &Arc<ChannelMetrics>) {
+        let mut channels = self.channels.lock().unwrap();
+        channels.retain(|c| c.upgrade().is_some());
+        channels.push(Arc::downgrade(metrics));
+    }
+
+    /// Snapshots of the instrumented channels which are still in use
+    pub fn channel_stats(&self) -> Vec<ChannelStats> {
+        let mut channels = self.channels.lock().unwrap();
+        channels.retain(|c| c.upgrade().is_some());
+        channels.iter().filter_map(|c| c.upgrade()).map(|c| c.snapshot()).collect()
+    }
+
+    /// Poison the stacks of the coroutines when spawning them, to measure how deep they have
+    /// been used when finishing, see `stack_high_water_marks()`. Makes spawning slower, as
+    /// the whole stack is written to. Defaults to false.
+    pub fn with_stack_measurement(mut self, enabled: bool) -> Scheduler {
+        self.stack_measurement = enabled;
+        self
+    }
+
+    #[doc(hidden)]
+    pub fn stack_measurement(&self) -> bool {
+        self.stack_measurement
+    }
+
+    /// Distributions of the deepest stack usage of the finished coroutines per stack class,
+    /// empty unless enabled with `with_stack_measurement()`
+    pub fn stack_high_water_marks(&self) -> StackHighWaterMarks {
+        let snapshot = |class: StackClass| self.stack_high_water_marks[class.index()].snapshot();
+
+        StackHighWaterMarks {
+            tiny: snapshot(StackClass::Tiny),
+            small: snapshot(StackClass::Small),
+            default: snapshot(StackClass::Default),
+            large: snapshot(StackClass::Large),
+        }
+    }
+
+    /// Deepest stack usage in bytes of the current coroutine so far.
+    /// Returns `None` if its stack is not measured or outside of a coroutine.
+    pub fn stack_high_water_mark() -> Option<usize> {
+        Processor::current().and_then(|p| p.current_stack_high_water_mark())
+    }
+
+    /// Keep up to `cap` stacks of each class of the finished coroutines on every Processor
+    /// for reuse, instead of unmapping them. 0 disables the pooling.
+    pub fn with_stack_pool_cap(mut self, cap: usize) -> Scheduler {
+        self.stack_pool_cap = cap;
+        self
+    }
+
+    #[doc(hidden)]
+    pub fn stack_pool_cap(&self) -> usize {
+        self.stack_pool_cap
+    }
+
+    /// Number of stacks allocated so far, which could not be taken from the pools
+    pub fn stacks_allocated(&self) -> usize {
+        self.stacks_allocated.load(Ordering::Relaxed)
+    }
+
+    #[doc(hidden)]
+    pub fn stack_allocated(&self) {
+        self.stacks_allocated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A coroutine with a stack of the class started running
+    #[doc(hidden)]
+    pub fn stack_acquired(&self, class: StackClass) {
+        self.stack_counts[class.index()].fetch_add(1, Ordering::Relaxed);
+
+        if class != StackClass::Large {
+            return;
+        }
+
+        let usage = self.stack_usage();
+        let total = usage.bytes();
+        let large = usage.large * StackClass::Large.size();
+
+        if total >= LARGE_STACK_WARN_BYTES && large >= total / 100 * LARGE_STACK_WARN_PERCENT {
+            if !self.large_stacks_warned.swap(true, Ordering::Relaxed) {
+                warn!("{} coroutines with large stacks reserve {}KB of {}KB stack memory",
+                      usage.large,
+                      large / 1024,
+                      total / 1024);
+            }
+        } else if large < total / 100 * LARGE_STACK_WARN_PERCENT / 2 {
+            self.large_stacks_warned.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of live I/O objects registered with the Scheduler
+    pub fn io_registration_count(&self) -> usize {
+        self.io_registrations.load(Ordering::SeqCst)
+    }
+
+    fn enter_io_wait(&self) -> IoWaitGuard {
+        self.io_waits.fetch_add(1, Ordering::SeqCst);
+        IoWaitGuard(self)
+    }
+
+    fn acquire_registration(&self) -> io::Result<IoRegistration> {
+        let count = self.io_registrations.fetch_add(1, Ordering::SeqCst) + 1;
+        let guard = IoRegistration(self.io_registrations.clone());
+
+        if let Some(limit) = self.max_io_registrations {
+            if count > limit {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          RegistrationLimitExceeded { limit: limit }));
+            }
+        }
+
+        if let Some(nofile) = self.nofile_limit {
+            let threshold = nofile / 100 * NOFILE_WARN_PERCENT;
+
+            if count >= threshold {
+                if !self.nofile_warned.swap(true, Ordering::SeqCst) {
+                    warn!("{} I/O objects registered, approaching RLIMIT_NOFILE ({})",
+                          count,
+                          nofile);
+                }
+            } else if count < threshold / 2 {
+                self.nofile_warned.store(false, Ordering::SeqCst);
+            }
+        }
+
+        Ok(guard)
+    }
+
+    /// Get the global Scheduler
+    #[doc(hidden)]
+    pub fn instance() -> Option<&'static Scheduler> {
+        Processor::current().and_then(|p| unsafe { Some(mem::transmute(p.scheduler())) })
+    }
+
+    /// A coroutine is ready for schedule
+    #[doc(hidden)]
+    pub fn ready(coro: Handle) {
+        let current = Processor::current();
+
+        if let Some(mut preferred) = coro.preferred_processor() {
+            if let Some(current) = current {
+                if preferred == current {
+                    // We're on the same thread ---> use the faster ready() method.
+                    return preferred.ready(coro);
+                }
+            }
+
+            let _ = preferred.handle().send(ProcMessage::ready(coro));
+            return;
+        }
+
+        if let Some(mut current) = current {
+            return current.ready(coro);
+        }
+
+        // TODO: Should we drop() the coro instead of panic?
+        panic!("Processor missing");
+    }
+
+    /// Many coroutines are ready for schedule, e.g. all waiters of a closed channel
+    ///
+    /// Instead of sending one message per coroutine, the coroutines are split into one chunk
+    /// per Processor: the current Processor keeps the first chunk and every other Processor
+    /// receives the rest in a single message each.
+    #[doc(hidden)]
+    pub fn ready_all(coros: Vec<Handle>) {
+        let mut current = match Processor::current() {
+            Some(ref current) if coros.len() > READY_BATCH_THRESHOLD => current.clone(),
+            _ => {
+                for coro in coros {
+                    Scheduler::ready(coro);
+                }
+                return;
+            }
+        };
+
+        // Coroutines of other Schedulers have to stay there
+        let (local, foreign): (Vec<Handle>, Vec<Handle>) =
+            coros.into_iter().partition(|coro| current.is_local(coro));
+
+        for coro in foreign {
+            Scheduler::ready(coro);
+        }
+
+        let processors = current.scheduler().remote.processors();
+        let chunks = cmp::max(processors.len(), 1);
+        let chunk_size = (local.len() + chunks - 1) / chunks;
+        let mut local = local.into_iter();
+
+        for coro in local.by_ref().take(chunk_size) {
+            current.ready(coro);
+        }
+
+        let now = Instant::now();
+        for (id, processor) in processors.iter().enumerate() {
+            if id == current.id() {
+                continue;
+            }
+
+            let chunk: Vec<Handle> = local.by_ref().take(chunk_size).collect();
+            if chunk.is_empty() {
+                break;
+            }
+
+            if let Err(err) = processor.send(ProcMessage::ReadyBatch(chunk, now)) {
+                // The Processor is gone, so resume them here instead
+                if let ProcMessage::ReadyBatch(chunk, _) = err.0 {
+                    for coro in chunk {
+                        current.ready(coro);
+                    }
+                }
+            }
+        }
+
+        // Leftovers if the Scheduler is not running anymore
+        for coro in local {
+            current.ready(coro);
+        }
+    }
+
+    /// A coroutine is finished
+    ///
+    /// The coroutine will be destroy, make sure that the coroutine pointer is unique!
+    #[doc(hidden)]
+    pub fn finished(mut coro: Handle) {
+        let scheduler = Scheduler::instance().unwrap();
+        scheduler.finished_count.fetch_add(1, Ordering::Relaxed);
+        scheduler.work_counts.fetch_sub(1, Ordering::SeqCst);
+
+        if let Some(class) = coro.stack_class() {
+            scheduler.stack_counts[class.index()].fetch_sub(1, Ordering::Relaxed);
+
+            if let Some(bytes) = coro.stack_high_water_mark() {
+                scheduler.stack_high_water_marks[class.index()].record(bytes);
+            }
+        }
+
+        coro.set_drop_allowed();
+    }
+
+    /// Total works
+    pub fn work_count(&self) -> usize {
+        self.work_counts.load(Ordering::SeqCst)
+    }
+
+    #[doc(hidden)]
+    pub fn add_work(&self) {
+        self.work_counts.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Take a Processor out of service, e.g. before unplugging its CPU or to rule out a suspect
+    /// worker. It stops stealing work, hands its queued and newly readied coroutines over to
+    /// the other Processors and parks its thread until `resume_processor()`.
+    ///
+    /// Takes effect once the coroutine currently running on it yields. Returns false if there
+    /// is no such Processor, it is already drained or it is the last one in service.
+    pub fn drain_processor(&self, processor_id: usize) -> bool {
+        let processors = self.remote.processors();
+        if processor_id >= processors.len() {
+            return false;
+        }
+
+        {
+            let mut drained = self.drained_processors.lock().unwrap();
+            if drained.contains(&processor_id) || drained.len() + 1 >= processors.len() {
+                return false;
+            }
+            drained.push(processor_id);
+        }
+
+        let _ = processors[processor_id].send(ProcMessage::Drain);
+        true
+    }
+
+    /// Put a Processor drained with `drain_processor()` back into service.
+    /// Returns false if it has not been drained.
+    pub fn resume_processor(&self, processor_id: usize) -> bool {
+        {
+            let mut drained = self.drained_processors.lock().unwrap();
+            match drained.iter().position(|id| *id == processor_id) {
+                Some(idx) => drained.swap_remove(idx),
+                None => return false,
+            };
+        }
+
+        if let Some(processor) = self.remote.processors().get(processor_id) {
+            let _ = processor.send(ProcMessage::Resume);
+        }
+        true
+    }
+
+    /// Whether the Processor has been taken out of service with `drain_processor()`
+    pub fn is_processor_drained(&self, processor_id: usize) -> bool {
+        self.drained_processors.lock().unwrap().contains(&processor_id)
+    }
+
+    /// Grow the worker pool by `n` Processors while the Scheduler is running.
+    ///
+    /// Processors retired by `remove_workers()` are put back into service first, the remaining
+    /// ones are started by the scheduler loop within one event loop tick.
+    pub fn add_workers(&self, n: usize) {
+        let mut resumed = 0;
+        {
+            let mut retired = self.retired_processors.lock().unwrap();
+            while resumed < n {
+                match retired.pop() {
+                    // Unless it has been resumed with resume_processor() in the meantime
+                    Some(processor_id) => {
+                        if self.resume_processor(processor_id) {
+                            resumed += 1;
+                        }
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        *self.pending_workers.lock().unwrap() += n - resumed;
+    }
+
+    /// Shrink the worker pool by up to `n` Processors while the Scheduler is running, always
+    /// keeping one in service. Returns the number of workers removed.
+    ///
+    /// The Processors with the highest ids are drained like with `drain_processor()`, their
+    /// threads stay parked until `add_workers()` or the Scheduler shuts down.
+    pub fn remove_workers(&self, n: usize) -> usize {
+        let mut removed = 0;
+
+        // Workers not started yet go first
+        {
+            let mut pending = self.pending_workers.lock().unwrap();
+            let cancelled = cmp::min(*pending, n);
+            *pending -= cancelled;
+            removed += cancelled;
+        }
+
+        let mut retired = self.retired_processors.lock().unwrap();
+        for processor_id in (0..self.remote.processors().len()).rev() {
+            if removed == n {
+                break;
+            }
+
+            if self.drain_processor(processor_id) {
+                retired.push(processor_id);
+                removed += 1;
+            }
+        }
+
+        removed
+    }
+
+    /// Number of Processors currently in service
+    pub fn worker_count(&self) -> usize {
+        let drained = self.drained_processors.lock().unwrap().len();
+        self.remote.processors().len() - drained
+    }
+
+    /// Ids of the Processors currently in service, see `spawn_on`
+    pub fn worker_ids(&self) -> Vec<usize> {
+        let drained = self.drained_processors.lock().unwrap();
+        (0..self.remote.processors().len()).filter(|id| !drained.contains(id)).collect()
+    }
+
+    /// Mailbox of a Processor in service to hand the coroutines of a drained one over to,
+    /// picking them in turns
+    #[doc(hidden)]
+    pub fn drain_target(&self) -> Option<Sender<ProcMessage>> {
+        let processors = self.remote.processors();
+        let drained = self.drained_processors.lock().unwrap();
+
+        let start = self.drain_cursor.fetch_add(1, Ordering::Relaxed);
+        (0..processors.len())
+            .map(|offset| (start + offset) % processors.len())
+            .find(|id| !drained.contains(id))
+            .map(|id| processors[id].clone())
+    }
+
+    /// Get a handle to spawn coroutines into this Scheduler from other threads or
+    /// Schedulers while it is running
+    pub fn remote(&self) -> Remote {
+        self.remote.clone()
+    }
+
+    /// Spawn a new coroutine with default options.
+    ///
+    /// Once the scheduler is stopping or shutting down the coroutine is not spawned anymore,
+    /// and joining the returned handle fails with `ShuttingDown`, see `try_spawn`.
+    pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        Scheduler::spawn_opts(f, Default::default())
+    }
+
+    /// Spawn a new coroutine with options
+    pub fn spawn_opts<F, T>(f: F, opts: Options) -> JoinHandle<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        let mut processor = Processor::current().unwrap();
+
+        if processor.scheduler().is_shutting_down() || processor.scheduler().is_stopping() {
+            return JoinHandle::refused();
+        }
+
+        processor.scheduler().work_counts.fetch_add(1, Ordering::SeqCst);
+
+        let (tx, rx) = ::sync::mpsc::channel();
+        let wrapper = move || {
+            let ret = unsafe { ::try(move || f()) };
+            if let Err(ref payload) = ret {
+                Scheduler::report_panic(payload);
+            }
+
+            // No matter whether it is panicked or not, the result will be sent to the channel
+            let _ = tx.send(ret); // Just ignore if it failed
+        };
+        let shared = processor.spawn_opts(Box::new(wrapper), opts);
+        processor.scheduler().track_coroutine(&shared);
+
+        JoinHandle {
+            result: rx,
+            shared: shared,
+        }
+    }
+
+    /// Spawn a new coroutine pinned to the Processor with the id `worker_id`, see
+    /// `Options::pin` and `worker_ids`. Fails with `ShuttingDown` like `try_spawn`.
+    ///
+    /// Panics if there is no such Processor.
+    pub fn spawn_on<F, T>(worker_id: usize, f: F) -> Result<JoinHandle<T>, ShuttingDown>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        Scheduler::spawn_on_opts(worker_id, f, Default::default())
+    }
+
+    /// Spawn a new coroutine with options pinned to the Processor with the id `worker_id`
+    pub fn spawn_on_opts<F, T>(worker_id: usize,
+                               f: F,
+                               opts: Options)
+                               -> Result<JoinHandle<T>, ShuttingDown>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        let scheduler = Scheduler::instance().unwrap();
+        let processors = scheduler.remote.processors();
+        assert!(worker_id < processors.len(),
+                "Processor {} does not exist",
+                worker_id);
+
+        scheduler.spawn_into(&processors[worker_id], f, opts.pin(true))
+    }
+
+    /// Spawn one coroutine running `f` on each Processor in service, e.g. one accept loop per
+    /// worker on listeners bound with `TcpListenerBuilder::reuse_port`. The coroutines are
+    /// pinned to their Processors. Returns the handles in the order of the Processor ids,
+    /// or `ShuttingDown` like `try_spawn`.
+    pub fn spawn_on_each_worker<F, T>(f: F) -> Result<Vec<JoinHandle<T>>, ShuttingDown>
+        where F: Fn() -> T + Send + Sync + 'static,
+              T: Send + 'static
+    {
+        let scheduler = Scheduler::instance().unwrap();
+        let drained = scheduler.drained_processors.lock().unwrap().clone();
+        let f = Arc::new(f);
+
+        scheduler.remote
+                 .processors()
+                 .iter()
+                 .enumerate()
+                 .filter(|&(processor_id, _)| !drained.contains(&processor_id))
+                 .map(|(_, processor)| {
+                     let f = f.clone();
+                     scheduler.spawn_into(processor, move || (*f)(), Options::new().pin(true))
+                 })
+                 .collect()
+    }
+
+    // Spawn a coroutine into the run queue of the Processor behind the mailbox `processor`
+    fn spawn_into<F, T>(&self,
+                        processor: &Sender<ProcMessage>,
+                        f: F,
+                        opts: Options)
+                        -> Result<JoinHandle<T>, ShuttingDown>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        if self.is_shutting_down() || self.is_stopping() {
+            return Err(ShuttingDown);
+        }
+
+        self.work_counts.fetch_add(1, Ordering::SeqCst);
+
+        let (tx, rx) = ::sync::mpsc::channel();
+        let wrapper = move || {
+            let ret = unsafe { ::try(move || f()) };
+            if let Err(ref payload) = ret {
+                Scheduler::report_panic(payload);
+            }
+            let _ = tx.send(ret);
+        };
+        let parent = Processor::current().and_then(|p| p.current_shared());
+        let coro = Coroutine::spawn_opts(Box::new(wrapper), opts, parent);
+        let shared = coro.shared().clone();
+        self.track_coroutine(&shared);
+
+        // Only fails once the Processor has exited, the scheduler is shutting down
+        if let Err(err) = processor.send(ProcMessage::ready(coro)) {
+            if let ProcMessage::Ready(mut coro, _) = err.0 {
+                coro.set_drop_allowed();
+            }
+            self.work_counts.fetch_sub(1, Ordering::SeqCst);
+            return Err(ShuttingDown);
+        }
+
+        Ok(JoinHandle {
+            result: rx,
+            shared: shared,
+        })
+    }
+
+    /// Spawn a new coroutine which starts running after `delay`.
+    ///
+    /// Until then only a timer is registered in the event loop and the coroutine is not
+    /// scheduled at all, unlike a coroutine which begins with sleeping.
+    pub fn spawn_after<F, T>(delay: Duration, f: F) -> JoinHandle<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        let processor = Processor::current().unwrap();
+        let scheduler = processor.scheduler();
+
+        scheduler.work_counts.fetch_add(1, Ordering::SeqCst);
+
+        let (tx, rx) = ::sync::mpsc::channel();
+        let wrapper = move || {
+            let ret = unsafe { ::try(move || f()) };
+            if let Err(ref payload) = ret {
+                Scheduler::report_panic(payload);
+            }
+            let _ = tx.send(ret);
+        };
+        let coro = Coroutine::spawn_opts(Box::new(wrapper),
+                                         Options::default(),
+                                         processor.current_shared());
+        let shared = coro.shared().clone();
+        scheduler.track_coroutine(&shared);
+
+        let delay_ms = delay.as_secs() * 1_000 + delay.subsec_nanos() as u64 / 1_000_000;
+        let proc_hdl1 = processor.handle();
+        let proc_hdl2 = proc_hdl1.clone();
+        let coro1 = SendableCoroutinePtr(Box::into_raw(coro));
+        let coro2 = coro1;
+
+        let reg = move |evloop: &mut EventLoop<IoHandler>, token| {
+            if let Err(err) = evloop.timeout_ms(token, delay_ms) {
+                error!("Failed to add the timer of a delayed coroutine: {:?}, starting it now",
+                       err);
+                let _ = proc_hdl1.send(ProcMessage::ready(unsafe { Box::from_raw(coro1.0) }));
+                return false;
+            }
+            true
+        };
+
+        let ready = move |_: &mut EventLoop<IoHandler>| {
+            let _ = proc_hdl2.send(ProcMessage::ready(unsafe { Box::from_raw(coro2.0) }));
+        };
+
+        let _ = scheduler.event_loop.channel().send(IoHandlerMessage::new(reg, ready));
+
+        JoinHandle {
+            result: rx,
+            shared: shared,
+        }
+    }
+
+    /// Spawn a new coroutine which starts running once `deadline` has passed,
+    /// see `spawn_after`
+    pub fn spawn_at<F, T>(deadline: Instant, f: F) -> JoinHandle<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        let now = Instant::now();
+        let delay = if deadline > now {
+            deadline - now
+        } else {
+            Duration::from_millis(0)
+        };
+
+        Scheduler::spawn_after(delay, f)
+    }
+
+    /// Spawn a new coroutine, or return `ShuttingDown` if the scheduler is shutting down
+    pub fn try_spawn<F, T>(f: F) -> Result<JoinHandle<T>, ShuttingDown>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        Scheduler::try_spawn_opts(f, Default::default())
+    }
+
+    /// Spawn a new coroutine with options, or return `ShuttingDown` if the scheduler is
+    /// shutting down
+    pub fn try_spawn_opts<F, T>(f: F, opts: Options) -> Result<JoinHandle<T>, ShuttingDown>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        let scheduler = Scheduler::instance().unwrap();
+        if scheduler.is_shutting_down() || scheduler.is_stopping() {
+            return Err(ShuttingDown);
+        }
+
+        Ok(Scheduler::spawn_opts(f, opts))
+    }
+
+    /// Whether the main function has returned and the scheduler is shutting down
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Begin a graceful shutdown, e.g. before handing over to a new process.
+    ///
+    /// From now on no coroutines are spawned anymore and `try_spawn` fails with `ShuttingDown`,
+    /// while the running coroutines keep on working. Once all of them, including the main
+    /// function, have finished, or at the latest after `timeout`, the shutdown continues as if
+    /// the main function had returned: the finalizers run and the stragglers are unwound. If
+    /// the main function had to be unwound, `run()` returns the payload of the unwinding.
+    pub fn shutdown_graceful(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+
+        let mut graceful_deadline = self.graceful_deadline.lock().unwrap();
+        match *graceful_deadline {
+            // Calling it again never extends the deadline
+            Some(current) if current <= deadline => {}
+            _ => *graceful_deadline = Some(deadline),
+        }
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether a graceful shutdown has begun, see `shutdown_graceful()`
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Run the scheduler
+    pub fn run<M, R>(&mut self, main_fn: M) -> Result<R, Box<Any + Send + 'static>>
+        where M: FnOnce() -> R + Send + 'static,
+              R: Send + 'static
+    {
+        if !self.oversubscription {
+            let workers = ::cpu::cap_to_quota(self.expected_worker_count);
+            if workers < self.expected_worker_count {
+                info!("Starting {} instead of {} Processors to fit the CPU quota",
+                      workers,
+                      self.expected_worker_count);
+                self.expected_worker_count = workers;
+            }
+        }
+
+        let cpus = ::cpu::available_cpus();
+        if self.expected_worker_count > cpus {
+            if let IdleStrategy::SpinThenPark(..) = self.idle_strategy {
+                warn!("{} Processors spinning on {} available CPUs, consider \
+                       IdleStrategy::YieldThenPark",
+                      self.expected_worker_count,
+                      cpus);
+            }
+        }
+
+        let mut handles = Vec::with_capacity(self.expected_worker_count);
+        let mut handlers = Vec::with_capacity(self.expected_worker_count);
+        let mut stealers = Vec::with_capacity(self.expected_worker_count);
+
+        // The first worker (main function)
+        self.work_counts.fetch_add(1, Ordering::SeqCst);
+        let main_coro_hdl = {
+            let (hdl, msg, st, main_hdl) = Processor::run_main(0, self, main_fn);
+            handles.push(hdl);
+            handlers.push(msg);
+            stealers.push(st);
+
+            main_hdl
+        };
+
+        // The others
+        let others = self.expected_worker_count - 1;
+        self.start_workers(others, &mut handles, &mut handlers, &mut stealers);
+
+        self.remote.attach(handlers.clone());
+
+        // The scheduler loop
+        let mut main_ret = None;
+        let mut deadlock_check = DeadlockCheck {
+            checked_at: Instant::now(),
+            resumed: 0,
+            reported: false,
+        };
+        let mut deadlock = None;
+        loop {
+            self.poll_events(EVENT_LOOP_TIMEOUT_MS);
+            let now_ms = ::time::update();
+
+            // Workers requested with add_workers()
+            let pending = mem::replace(&mut *self.pending_workers.lock().unwrap(), 0);
+            if pending > 0 && !self.shutting_down.load(Ordering::SeqCst) {
+                self.start_workers(pending, &mut handles, &mut handlers, &mut stealers);
+                self.remote.attach(handlers.clone());
+            }
+
+            for st in stealers.iter() {
+                st.sample_load(now_ms);
+            }
+
+            if main_ret.is_none() {
+                match main_coro_hdl.try_recv() {
+                    Ok(ret) => main_ret = Some(ret),
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => {
+                        panic!("Main coro is disconnected");
+                    }
+                }
+            }
+
+            if main_ret.is_none() {
+                if let Some(report) = self.check_deadlock(&mut deadlock_check, &stealers) {
+                    error!("{}", report);
+                    if self.deadlock_detection == DeadlockDetection::Fail {
+                        deadlock = Some(report);
+                    }
+                }
+            }
+
+            // A graceful shutdown waits for all the coroutines, up to its deadline
+            let done = match *self.graceful_deadline.lock().unwrap() {
+                Some(deadline) => self.work_count() == 0 || Instant::now() >= deadline,
+                None => main_ret.is_some(),
+            };
+            let done = done || deadlock.is_some();
+
+            if done {
+                self.run_finalizers(&handlers[0]);
+                let leaks = self.detect_leaks();
+
+                // Blocked coroutines are woken up before the Processors exit,
+                // so they could observe the ShuttingDown error.
+                self.shutting_down.store(true, Ordering::SeqCst);
+                self.remote.detach();
+                self.io_handler.wakeup_all(&mut self.event_loop);
+
+                for msg in handlers.iter() {
+                    msg.send(ProcMessage::Shutdown).unwrap();
+                }
+
+                // NOTE: It's critical that all threads are joined since Processor
+                // maintains a reference to this Scheduler using raw pointers.
+                for hdl in handles {
+                    let _ = hdl.join();
+                }
+                self.processor_threads.lock().unwrap().clear();
+                self.drained_processors.lock().unwrap().clear();
+                self.retired_processors.lock().unwrap().clear();
+                *self.pending_workers.lock().unwrap() = 0;
+
+                *self.main_coroutine.lock().unwrap() = None;
+
+                // The main function is unwound if it outlived a graceful shutdown, but it
+                // is left behind if it is blocked forever
+                let ret = match (main_ret, deadlock) {
+                    (Some(ret), _) => ret,
+                    (None, Some(report)) => return Err(Box::new(report) as Box<Any + Send>),
+                    (None, None) => main_coro_hdl.recv().expect("Main coro is disconnected"),
+                };
+
+                *self.leak_report.lock().unwrap() = leaks.clone();
+                return match leaks {
+                    Some(report) => {
+                        match self.leak_detection {
+                            LeakDetection::Fail if ret.is_ok() => {
+                                Err(Box::new(report) as Box<Any + Send>)
+                            }
+                            _ => {
+                                warn!("{}", report);
+                                ret
+                            }
+                        }
+                    }
+                    None => ret,
+                };
+            }
+        }
+    }
+
+    // Start `n` more Processors and introduce them to the running ones as neighbors
+    fn start_workers(&mut self,
+                     n: usize,
+                     handles: &mut Vec<thread::JoinHandle<()>>,
+                     handlers: &mut Vec<Sender<ProcMessage>>,
+                     stealers: &mut Vec<RunQueueStealer>) {
+        for _ in 0..n {
+            let tid = handles.len();
             let (hdl, msg, st) = Processor::run_with_neighbors(tid, self, stealers.clone());
 
-            // Notify previously created Processors of their new neighbor
-            for msg in handlers.iter() {
-                if let Err(err) = msg.send(ProcMessage::NewNeighbor(st.clone())) {
-                    error!("Error while sending NewNeighbor {:?}", err);
+            // Notify previously created Processors of their new neighbor
+            for msg in handlers.iter() {
+                if let Err(err) = msg.send(ProcMessage::NewNeighbor(st.clone())) {
+                    error!("Error while sending NewNeighbor {:?}", err);
+                }
+            }
+
+            handles.push(hdl);
+            handlers.push(msg);
+            stealers.push(st);
+        }
+    }
+
+    /// Suspend the current coroutine
+    pub fn sched() {
+        Processor::current().unwrap().sched();
+    }
+
+    /// Run a blocking closure, e.g. calling a synchronous library, on a thread of the blocking
+    /// pool, see `with_blocking_threads`. Only the current coroutine waits for it, the
+    /// Processor goes on running the others.
+    ///
+    /// Returns `Err` with the payload if `f` panicked. Outside of a Scheduler `f` runs on the
+    /// calling thread.
+    pub fn run_blocking<'a, F, T>(f: F) -> thread::Result<T>
+        where F: FnOnce() -> T + Send + 'a,
+              T: Send + 'a
+    {
+        match Scheduler::instance() {
+            Some(scheduler) => scheduler.blocking_pool.run(f),
+            None => unsafe { ::try(f) },
+        }
+    }
+
+    /// Block the current coroutine
+    #[inline]
+    pub fn take_current_coroutine<U, F>(f: F) -> U
+        where F: FnOnce(Handle) -> U
+    {
+        Processor::current().unwrap().take_current_coroutine(f)
+    }
+
+    /// Block the current coroutine, returns `ShuttingDown` instead of unwinding if the
+    /// scheduler begins to shut down in the meantime
+    #[doc(hidden)]
+    #[inline]
+    pub fn try_take_current_coroutine<U, F>(f: F) -> Result<U, ShuttingDown>
+        where F: FnOnce(Handle) -> U
+    {
+        Processor::current().unwrap().try_take_current_coroutine(f)
+    }
+
+    /// Cancellation point for loops which never block or yield otherwise.
+    ///
+    /// Returns `Cancelled` if the current coroutine has been aborted or cancelled, or its
+    /// deadline has passed. It is unwound instead if the Scheduler has been built
+    /// `with_cancel_unwinding(true)`.
+    pub fn check_cancelled() -> Result<(), Cancelled> {
+        match Processor::current() {
+            Some(processor) => processor.check_cancelled(),
+            None => Ok(()),
+        }
+    }
+
+    // Fail early instead of registering new events during the shutdown
+    fn check_shutdown(&self) -> io::Result<()> {
+        if self.is_shutting_down() {
+            try!(Processor::current().unwrap().check_shutdown());
+        }
+        Ok(())
+    }
+}
+
+struct ResultWrapper(*mut io::Result<()>);
+unsafe impl Send for ResultWrapper {}
+unsafe impl Sync for ResultWrapper {}
+
+/// I/O objects the Scheduler can wait on
+#[doc(hidden)]
+pub trait EventedIo: Evented {
+    /// File descriptor reported in `Event::SlowIo`
+    fn io_fd(&self) -> i32;
+}
+
+#[cfg(unix)]
+impl<E: Evented + AsRawFd> EventedIo for E {
+    fn io_fd(&self) -> i32 {
+        self.as_raw_fd()
+    }
+}
+
+#[cfg(not(unix))]
+impl<E: Evented> EventedIo for E {
+    fn io_fd(&self) -> i32 {
+        -1
+    }
+}
+
+struct EventedWrapper<E>(*const E);
+unsafe impl<E> Send for EventedWrapper<E> {}
+unsafe impl<E> Sync for EventedWrapper<E> {}
+
+struct SchedulerPtr(*const Scheduler);
+unsafe impl Send for SchedulerPtr {}
+
+// Emits `SlowIo` unless the I/O wait it watches is over before the threshold
+struct SlowIoWatch(TimerHandle);
+
+impl Drop for SlowIoWatch {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+impl Scheduler {
+    /// Block the current coroutine and wait for I/O event
+    #[doc(hidden)]
+    pub fn wait_event<'scope, E: EventedIo>(&self,
+                                            fd: &'scope E,
+                                            interest: EventSet)
+                                            -> io::Result<()> {
+        self.wait_event_timeout(fd, interest, None)
+    }
+
+    /// Like `wait_event`, but fails with `TimedOut` if the event has not arrived in time
+    #[doc(hidden)]
+    pub fn wait_event_timeout<'scope, E: EventedIo>(&self,
+                                                    fd: &'scope E,
+                                                    interest: EventSet,
+                                                    timeout: Option<Duration>)
+                                                    -> io::Result<()> {
+        self.wait_io(fd, fd.io_fd(), interest, timeout)
+    }
+
+    /// Block the current coroutine and wait for I/O event on a raw file descriptor
+    #[cfg(unix)]
+    #[doc(hidden)]
+    pub fn wait_fd(&self, fd: RawFd, interest: EventSet) -> io::Result<()> {
+        self.wait_io(&EventedFd(&fd), fd, interest, None)
+    }
+
+    fn wait_io<'scope, E: Evented>(&self,
+                                   fd: &'scope E,
+                                   raw_fd: i32,
+                                   interest: EventSet,
+                                   timeout: Option<Duration>)
+                                   -> io::Result<()> {
+        try!(self.check_shutdown());
+        let _guard = self.enter_io_wait();
+        let _slow_io = self.watch_slow_io(raw_fd, interest);
+        let mut ret = Ok(());
+
+        // Rounded up, so that short timeouts don't expire before waiting at all
+        let timeout_ms = timeout.map(|t| {
+            t.as_secs() * 1_000 + (t.subsec_nanos() as u64 + 999_999) / 1_000_000
+        });
+        let timer = Arc::new(Mutex::new(None));
+        let wait = Arc::new(IoWait {
+            token: Mutex::new(None),
+            cancelled: AtomicBool::new(false),
+        });
+
+        // Woken up by `JoinHandle::cancel` and unwound
+        let guard = Processor::current().and_then(|p| p.current_shared()).map(|shared| {
+            let wait = wait.clone();
+            let channel = self.event_loop.channel();
+            let guard = CancelHookGuard::new(shared);
+            guard.set(Box::new(move || {
+                let _ = channel.send(IoHandlerMessage::CancelWait(wait));
+            }));
+            guard
+        });
+        if guard.as_ref().map_or(false, |g| g.shared().is_aborted()) {
+            wait.cancelled.store(true, Ordering::SeqCst);
+        }
+
+        try!(Scheduler::try_take_current_coroutine(|coro| {
+            coro.shared().set_wait_reason(WaitReason::Io);
+            coro.shared().set_wait_fd(raw_fd);
+            let proc_hdl1 = Processor::current().unwrap().handle();
+            let proc_hdl2 = proc_hdl1.clone();
+            let channel = self.event_loop.channel();
+
+            let fd1 = EventedWrapper(fd);
+            let fd2 = EventedWrapper(fd);
+            let ret1 = ResultWrapper(&mut ret);
+            let ret2 = ResultWrapper(&mut ret);
+            let timer1 = timer.clone();
+            let timer2 = timer.clone();
+            let wait1 = wait.clone();
+            let wait2 = wait.clone();
+            let coro1 = SendableCoroutinePtr(Box::into_raw(coro));
+            let coro2 = coro1;
+
+            let reg = move |evloop: &mut EventLoop<IoHandler>, token| {
+                let fd = unsafe { &*fd1.0 };
+                let ret = unsafe { &mut *ret1.0 };
+
+                if wait1.cancelled.load(Ordering::SeqCst) {
+                    // Cancelled before the registration
+                    *ret = Err(io::Error::new(io::ErrorKind::Interrupted,
+                                              "cancelled waiting for I/O event"));
+                    proc_hdl1.send(ProcMessage::ready(unsafe { Box::from_raw(coro1.0) }))
+                             .unwrap();
+                    return false;
+                }
+
+                let mut r = evloop.register(fd,
+                                            token,
+                                            interest,
+                                            PollOpt::edge() | PollOpt::oneshot());
+
+                // The timer shares the token, whichever fires first resumes the coroutine
+                match timeout_ms {
+                    Some(delay_ms) if r.is_ok() => {
+                        match evloop.timeout_ms(token, delay_ms) {
+                            Ok(timeout) => *timer1.lock().unwrap() = Some(timeout),
+                            Err(..) => {
+                                let _ = evloop.deregister(fd);
+                                r = Err(io::Error::new(io::ErrorKind::Other,
+                                                       "failed to add timer"));
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+
+                match r {
+                    Ok(..) => {
+                        *wait1.token.lock().unwrap() = Some(token);
+                        true
+                    }
+                    Err(..) => {
+                        *ret = r;
+                        proc_hdl1.send(ProcMessage::ready(unsafe { Box::from_raw(coro1.0) }))
+                                 .unwrap();
+                        false
+                    }
+                }
+            };
+
+            let ready = move |evloop: &mut EventLoop<IoHandler>| {
+                // The token may be reused once the entry has been removed from the slab
+                wait2.token.lock().unwrap().take();
+
+                // The timer is gone already if it has fired
+                let timed_out = match timer2.lock().unwrap().take() {
+                    Some(timeout) => !evloop.clear_timeout(timeout),
+                    None => false,
+                };
+
+                let fd = unsafe { &*fd2.0 };
+                let ret = unsafe { &mut *ret2.0 };
+                if timed_out {
+                    let _ = evloop.deregister(fd);
+                    *ret = Err(io::Error::new(io::ErrorKind::TimedOut,
+                                              "timed out waiting for I/O event"));
+                } else if wait2.cancelled.load(Ordering::SeqCst) {
+                    let _ = evloop.deregister(fd);
+                    *ret = Err(io::Error::new(io::ErrorKind::Interrupted,
+                                              "cancelled waiting for I/O event"));
+                } else if cfg!(not(any(target_os = "macos",
+                                       target_os = "ios",
+                                       target_os = "freebsd",
+                                       target_os = "dragonfly",
+                                       target_os = "netbsd"))) {
+                    *ret = evloop.deregister(fd);
+                }
+
+                proc_hdl2.send(ProcMessage::ready(unsafe { Box::from_raw(coro2.0) })).unwrap();
+            };
+
+            channel.send(IoHandlerMessage::new(reg, ready)).unwrap();
+        }));
+
+        ret
+    }
+
+    // Arm a timer emitting `SlowIo` once the I/O wait of the current coroutine exceeds the
+    // threshold, while it is still waiting
+    fn watch_slow_io(&self, fd: i32, interest: EventSet) -> Option<SlowIoWatch> {
+        let threshold = match self.slow_io_threshold {
+            Some(threshold) if self.has_observer() => threshold,
+            _ => return None,
+        };
+
+        let shared = Processor::current().and_then(|p| p.current_shared());
+        let coroutine_id = shared.as_ref().map_or(0, |s| s.id());
+        let name = shared.as_ref().and_then(|s| s.name()).map(|n| n.to_owned());
+        let scheduler = SchedulerPtr(self);
+        let started = Instant::now();
+
+        let on_expire = move || {
+            // Called by the event loop, which does not outlive the Scheduler
+            let scheduler = unsafe { &*scheduler.0 };
+            scheduler.emit(Event::SlowIo {
+                coroutine_id: coroutine_id,
+                name: name,
+                fd: fd,
+                interest: interest,
+                waited: started.elapsed(),
+            });
+        };
+
+        self.arm_timer(started + threshold, Some(Box::new(on_expire)))
+            .ok()
+            .map(SlowIoWatch)
+    }
+
+    /// Register the I/O object for a persistent readiness subscription.
+    ///
+    /// The object stays registered until `Subscription::cancel` is called, so it must not
+    /// be passed to `wait_event` while the subscription is alive.
+    #[doc(hidden)]
+    pub fn subscribe<E: EventedIo>(&self,
+                                   fd: &E,
+                                   interest: EventSet)
+                                   -> io::Result<Arc<Subscription>> {
+        self.subscribe_io(fd, fd.io_fd(), interest)
+    }
+
+    /// Register a raw file descriptor for a persistent readiness subscription
+    #[cfg(unix)]
+    #[doc(hidden)]
+    pub fn subscribe_fd(&self, fd: RawFd, interest: EventSet) -> io::Result<Arc<Subscription>> {
+        self.subscribe_io(&EventedFd(&fd), fd, interest)
+    }
+
+    fn subscribe_io<E: Evented>(&self,
+                                fd: &E,
+                                raw_fd: i32,
+                                interest: EventSet)
+                                -> io::Result<Arc<Subscription>> {
+        try!(self.check_shutdown());
+        // Counted until the subscription is cancelled
+        let guard = self.enter_io_wait();
+        let subscription = Arc::new(Subscription::new(raw_fd, interest, self.event_loop.channel()));
+        let mut ret = Ok(());
+
+        Scheduler::take_current_coroutine(|coro| {
+            coro.shared().set_wait_reason(WaitReason::Io);
+            let proc_hdl = Processor::current().unwrap().handle();
+            let channel = self.event_loop.channel();
+
+            let fd = EventedWrapper(fd);
+            let ret1 = ResultWrapper(&mut ret);
+            let sub = subscription.clone();
+
+            let reg = move |evloop: &mut EventLoop<IoHandler>, token: Token| {
+                let fd = unsafe { &*fd.0 };
+                let ret = unsafe { &mut *ret1.0 };
+                let r = evloop.register(fd, token, interest, PollOpt::edge());
+                let ok = r.is_ok();
+
+                if ok {
+                    sub.token.store(token.as_usize(), Ordering::SeqCst);
+                }
+                *ret = r;
+
+                proc_hdl.send(ProcMessage::ready(coro)).unwrap();
+                ok
+            };
+
+            let msg = IoHandlerMessage::subscribe(reg, subscription.clone(), raw_fd, guard);
+            channel.send(msg).unwrap();
+        });
+
+        try!(ret);
+        Ok(subscription)
+    }
+
+    /// Arm a timer expiring after `delay`, see `TimerHandle`
+    pub fn timer(&self, delay: Duration) -> io::Result<TimerHandle> {
+        self.timer_at(Instant::now() + delay)
+    }
+
+    /// Arm a timer expiring at `deadline`, see `TimerHandle`
+    pub fn timer_at(&self, deadline: Instant) -> io::Result<TimerHandle> {
+        self.arm_timer(deadline, None)
+    }
+
+    fn arm_timer(&self,
+                 deadline: Instant,
+                 on_expire: Option<Box<FnBox() + Send>>)
+                 -> io::Result<TimerHandle> {
+        try!(self.check_shutdown());
+
+        let state = Arc::new(TimerState {
+            inner: Mutex::new(TimerInner {
+                deadline: deadline,
+                armed: Some(deadline),
+                entry: None,
+                status: TimerStatus::Pending,
+                waiters: Vec::new(),
+                on_expire: on_expire,
+            }),
+            channel: self.event_loop.channel(),
+        });
+
+        let msg = IoHandlerMessage::ArmTimer(state.clone(), deadline);
+        try!(self.event_loop
+                 .channel()
+                 .send(msg)
+                 .map_err(|_| io::Error::from(ShuttingDown)));
+        Ok(TimerHandle { state: state })
+    }
+
+    /// Block the current coroutine for the specific amount of milliseconds.
+    ///
+    /// Returns the remaining time if the coroutine has been interrupted.
+    #[doc(hidden)]
+    pub fn sleep_ms(&self, delay: u64) -> io::Result<Duration> {
+        self.sleep(Duration::from_millis(delay))
+    }
+
+    /// Block the current coroutine for the specific amount of time.
+    ///
+    /// Returns the remaining time if the coroutine has been interrupted by
+    /// `JoinHandle::interrupt`, or zero if it slept for the whole duration.
+    #[doc(hidden)]
+    pub fn sleep(&self, delay: Duration) -> io::Result<Duration> {
+        try!(self.check_shutdown());
+
+        let timer = Arc::new(SleepTimer {
+            registration: Mutex::new(None),
+            cancelled: AtomicBool::new(false),
+        });
+
+        let shared = Processor::current().unwrap().current_shared().unwrap();
+        let hook = {
+            let channel = self.event_loop.channel();
+            let timer = timer.clone();
+            move || {
+                let _ = channel.send(IoHandlerMessage::CancelTimer(timer));
+            }
+        };
+
+        if !shared.set_interrupt_hook(Box::new(hook)) {
+            // Interrupted before falling asleep
+            return Ok(delay);
+        }
+        let _guard = InterruptHookGuard(shared);
+
+        let started = Instant::now();
+        let deadline = started + delay;
+
+        try!(Scheduler::try_take_current_coroutine(|coro| {
+            coro.shared().set_wait_reason(WaitReason::Sleep);
+            let proc_hdl = Processor::current().unwrap().handle();
+            let channel = self.event_loop.channel();
+            let coro = SendableCoroutinePtr(Box::into_raw(coro));
+
+            let ready = move |_: &mut EventLoop<IoHandler>| {
+                proc_hdl.send(ProcMessage::ready(unsafe { Box::from_raw(coro.0) })).unwrap();
+            };
+
+            channel.send(IoHandlerMessage::sleep(timer.clone(), deadline, ready)).unwrap();
+        }));
+
+        let elapsed = started.elapsed();
+        if timer.cancelled.load(Ordering::SeqCst) && elapsed < delay {
+            Ok(delay - elapsed)
+        } else {
+            Ok(Duration::new(0, 0))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use observer::{Event, SchedulerObserver};
+    use options::Priority;
+
+    #[test]
+    fn test_join_basic() {
+        Scheduler::new()
+            .run(|| {
+                let guard = Scheduler::spawn(|| 1);
+
+                assert_eq!(1, guard.join().unwrap());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_join_panic() {
+        Scheduler::new()
+            .run(|| {
+                let guard = Scheduler::spawn(|| -> usize { panic!("boom") });
+
+                let payload = guard.join().err().unwrap();
+                assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_join_dropped() {
+        Scheduler::new()
+            .run(|| {
+                // As if the coroutine has been dropped before it could run
+                let (tx, rx) = ::sync::mpsc::channel::<Result<(), Box<Any + Send>>>();
+                drop(tx);
+                let shared = unsafe { Coroutine::empty() }.shared().clone();
+                let hdl = JoinHandle::new(rx, shared);
+
+                assert!(hdl.try_join().unwrap().unwrap_err().is::<ShuttingDown>());
+                assert!(hdl.join().unwrap_err().is::<ShuttingDown>());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_join_all() {
+        Scheduler::new()
+            .run(|| {
+                let hdls = (0..10).map(|i| Scheduler::spawn(move || i)).collect();
+
+                let rets: Vec<usize> = join_all(hdls).into_iter().map(|r| r.unwrap()).collect();
+                assert_eq!(rets, (0..10).collect::<Vec<usize>>());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_run_parallel() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        Scheduler::new()
+            .with_workers(4)
+            .run(|| {
+                let in_flight = Arc::new(AtomicUsize::new(0));
+                let max_in_flight = Arc::new(AtomicUsize::new(0));
+                let (in_flight1, max_in_flight1) = (in_flight.clone(), max_in_flight.clone());
+
+                let rets = run_parallel_with(0..20, 3, move |i: usize| {
+                    let n = in_flight1.fetch_add(1, Ordering::SeqCst) + 1;
+                    loop {
+                        let max = max_in_flight1.load(Ordering::SeqCst);
+                        if n <= max ||
+                           max_in_flight1.compare_and_swap(max, n, Ordering::SeqCst) == max {
+                            break;
+                        }
+                    }
+
+                    ::sleep_ms((20 - i as u64) % 7);
+                    in_flight1.fetch_sub(1, Ordering::SeqCst);
+                    if i == 5 {
+                        panic!("Five");
+                    }
+                    i * 2
+                });
+
+                let max = max_in_flight.load(Ordering::SeqCst);
+                assert!(max >= 1 && max <= 3, "{} items in flight", max);
+                assert_eq!(rets.len(), 20);
+                for (i, ret) in rets.into_iter().enumerate() {
+                    match ret {
+                        Ok(v) => assert_eq!(v, i * 2),
+                        Err(..) => assert_eq!(i, 5),
+                    }
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_timeout_inherit_deadline() {
+        use runtime::processor::ForceUnwind;
+
+        Scheduler::new()
+            .run(|| {
+                let (tx, rx) = ::sync::mpsc::channel::<JoinHandle<()>>();
+                let ret: Result<(), _> = timeout(Duration::from_millis(20), move || {
+                    let child = Scheduler::spawn_opts(|| {
+                                                          loop {
+                                                              Scheduler::sched();
+                                                          }
+                                                      },
+                                                      Options::new().inherit_deadline(true));
+                    tx.send(child).unwrap();
+                    loop {
+                        Scheduler::sched();
+                    }
+                });
+                assert!(ret.unwrap_err().is::<TimedOut>());
+
+                // The child is unwound at the same deadline
+                let child = rx.recv().unwrap();
+                assert!(child.join().unwrap_err().is::<ForceUnwind>());
+
+                // A panic after the deadline is not mistaken for a timeout
+                let ret: Result<(), _> = timeout(Duration::from_millis(20), || {
+                    let started = Instant::now();
+                    while started.elapsed() < Duration::from_millis(40) {}
+                    panic!("boom");
+                });
+                let err = ret.unwrap_err();
+                assert_eq!(err.downcast_ref::<&'static str>(), Some(&"boom"));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_race_abort_losers() {
+        Scheduler::new()
+            .run(|| {
+                // Blocked until it is woken up by the cancellation
+                let (tx, rx) = ::sync::mpsc::channel::<()>();
+                let slow = Scheduler::spawn(move || {
+                    let _ = rx.recv();
+                    1
+                });
+                let slow_shared = slow.shared.clone();
+                let fast = Scheduler::spawn(|| 2);
+
+                let (idx, ret) = race(vec![slow, fast], true);
+                assert_eq!(idx, 1);
+                assert_eq!(ret.unwrap(), 2);
+                assert!(slow_shared.is_aborted());
+
+                let started = Instant::now();
+                while !slow_shared.is_finished() {
+                    assert!(started.elapsed() < Duration::from_secs(1));
+                    ::sleep_ms(1);
+                }
+                drop(tx);
+            })
+            .unwrap();
+    }
+
+    struct EventRecorder(Arc<Mutex<Vec<Event>>>);
+
+    impl SchedulerObserver for EventRecorder {
+        fn on_event(&self, event: &Event) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn test_blocked_watermarks() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        Scheduler::new()
+            .with_observer(EventRecorder(events.clone()))
+            .with_blocked_watermarks(2, 5)
+            .run(|| {
+                let hdls = (0..10).map(|_| Scheduler::spawn(|| ::sleep_ms(100))).collect();
+                join_all(hdls);
+            })
+            .unwrap();
+
+        let events = events.lock().unwrap();
+        assert!(events.iter().any(|e| {
+            match *e {
+                Event::BlockedHigh { .. } => true,
+                _ => false,
+            }
+        }));
+        assert!(events.iter().any(|e| {
+            match *e {
+                Event::BlockedLow { .. } => true,
+                _ => false,
+            }
+        }));
+    }
+
+    #[test]
+    fn test_io_registrations() {
+        use net::TcpListener;
+
+        Scheduler::new()
+            .with_max_io_registrations(1)
+            .run(|| {
+                let sched = Scheduler::instance().unwrap();
+                let first = TcpListener::bind("127.0.0.1:0").unwrap();
+                let second = TcpListener::bind("127.0.0.1:0").unwrap();
+                assert_eq!(sched.io_registration_count(), 0);
+
+                // Registered by the first blocking operation, however long it waits
+                let wait = Duration::from_millis(1);
+                for _ in 0..3 {
+                    assert!(first.accept_timeout(wait).unwrap().is_none());
+                    assert_eq!(sched.io_registration_count(), 1);
+                }
+
+                let err = second.accept_timeout(wait).err().unwrap();
+                assert!(err.get_ref().unwrap().is::<RegistrationLimitExceeded>());
+                assert_eq!(sched.io_registration_count(), 1);
+
+                // Released when the object is dropped
+                drop(first);
+                assert_eq!(sched.io_registration_count(), 0);
+                assert!(second.accept_timeout(wait).unwrap().is_none());
+                assert_eq!(sched.io_registration_count(), 1);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_slow_io() {
+        use std::io::{Read, Write};
+        use std::os::unix::io::AsRawFd;
+        use std::time::Duration;
+        use mio::EventSet;
+
+        fn reported(events: &Mutex<Vec<Event>>, reader: &str) -> bool {
+            events.lock().unwrap().iter().any(|e| {
+                match *e {
+                    Event::SlowIo { ref name, interest, waited, .. } => {
+                        name.as_ref().map(|n| &n[..]) == Some(reader) &&
+                        interest.is_readable() &&
+                        waited >= Duration::from_millis(20)
+                    }
+                    _ => false,
+                }
+            })
+        }
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events1 = events.clone();
+
+        Scheduler::new()
+            .with_observer(EventRecorder(events.clone()))
+            .with_slow_io_threshold(Duration::from_millis(20))
+            .run(move || {
+                let (mut rd, mut wr) = ::net::unix::pipe().unwrap();
+                let reader = Scheduler::spawn_opts(move || {
+                                                       let mut buf = [0u8; 1];
+                                                       rd.read(&mut buf).unwrap()
+                                                   },
+                                                   Options::new()
+                                                       .name(Some("reader".to_owned())));
+
+                // Reported while the wait is still pending
+                ::sleep_ms(50);
+                assert!(reported(&events1, "reader"));
+                wr.write_all(b"x").unwrap();
+                assert_eq!(reader.join().unwrap(), 1);
+
+                // Waiting on a subscription is reported as well
+                let (mut rd, mut wr) = ::net::unix::pipe().unwrap();
+                let sub = Scheduler::instance()
+                              .unwrap()
+                              .subscribe_fd(rd.as_raw_fd(), EventSet::readable())
+                              .unwrap();
+                let subscriber = Scheduler::spawn_opts(move || sub.wait(),
+                                                       Options::new()
+                                                           .name(Some("subscriber".to_owned())));
+
+                ::sleep_ms(50);
+                assert!(reported(&events1, "subscriber"));
+                wr.write_all(b"x").unwrap();
+                assert!(subscriber.join().unwrap() > 0);
+
+                let mut buf = [0u8; 1];
+                assert_eq!(rd.read(&mut buf).unwrap(), 1);
+            })
+            .unwrap();
+
+        // Waits shorter than the threshold are not reported
+        let count = events.lock()
+                          .unwrap()
+                          .iter()
+                          .filter(|e| {
+                              match **e {
+                                  Event::SlowIo { .. } => true,
+                                  _ => false,
+                              }
+                          })
+                          .count();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        Scheduler::new()
+            .with_memory_accounting(true)
+            .run(|| {
+                let (tx, rx) = ::sync::mpsc::channel::<()>();
+                let hdl = Scheduler::spawn_opts(move || {
+                                                    ::metrics::memory::record_alloc(4096);
+                                                    ::metrics::memory::record_free(1024);
+                                                    rx.recv().unwrap();
+                                                },
+                                                Options::new().name(Some("leaky".to_owned())));
+
+                let is_leaky = |m: &CoroutineMemory| m.name == Some("leaky".to_owned());
+
+                Scheduler::sched();
+                let usage = Scheduler::instance().unwrap().memory_usage();
+                assert_eq!(usage.iter().find(|m| is_leaky(m)).unwrap().in_use(), 3072);
+
+                tx.send(()).unwrap();
+                hdl.join().unwrap();
+                drop(hdl);
+
+                // Gone once the coroutine has been dropped
+                while Scheduler::instance().unwrap().memory_usage().iter().any(|m| is_leaky(m)) {
+                    Scheduler::sched();
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_stack_pool() {
+        Scheduler::new()
+            .with_workers(1)
+            .with_stack_pool_cap(4)
+            .run(|| {
+                let allocated = Scheduler::instance().unwrap().stacks_allocated();
+                for _ in 0..100 {
+                    Scheduler::spawn(|| {}).join().unwrap();
+                }
+
+                // Only the first one is allocated, the others reuse its stack
+                let allocated = Scheduler::instance().unwrap().stacks_allocated() - allocated;
+                assert!(allocated <= 1, "{} stacks allocated", allocated);
+            })
+            .unwrap();
+
+        Scheduler::new()
+            .with_workers(1)
+            .with_stack_pool_cap(0)
+            .run(|| {
+                let allocated = Scheduler::instance().unwrap().stacks_allocated();
+                for _ in 0..20 {
+                    Scheduler::spawn(|| {}).join().unwrap();
+                }
+
+                // Stacks pooled on this thread earlier are not taken either
+                let allocated = Scheduler::instance().unwrap().stacks_allocated() - allocated;
+                assert_eq!(allocated, 20);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_finalizers() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        let order = Arc::new(AtomicUsize::new(0));
+        let (first, second) = (order.clone(), order.clone());
+
+        let mut sched = Scheduler::new();
+        sched.on_shutdown(Duration::from_secs(1), move || {
+            ::sleep_ms(10);
+            assert_eq!(first.fetch_add(1, Ordering::SeqCst), 0);
+        });
+        sched.on_shutdown(Duration::from_secs(1), move || {
+            assert_eq!(second.fetch_add(1, Ordering::SeqCst), 1);
+        });
+        sched.run(|| {}).unwrap();
+
+        assert_eq!(order.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_shutdown_finalizer_aborted() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::Duration;
+
+        struct Unwound(Arc<AtomicBool>);
+
+        impl Drop for Unwound {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let unwound = Arc::new(AtomicBool::new(false));
+        let (first, second) = (unwound.clone(), unwound.clone());
+        let next_saw_unwound = Arc::new(AtomicBool::new(false));
+        let next_saw_unwound1 = next_saw_unwound.clone();
+
+        let mut sched = Scheduler::new();
+        sched.on_shutdown(Duration::from_millis(50), move || {
+            let _unwound = Unwound(first);
+            loop {
+                ::sleep_ms(10);
+            }
+        });
+        sched.on_shutdown(Duration::from_secs(1), move || {
+            next_saw_unwound1.store(second.load(Ordering::SeqCst), Ordering::SeqCst);
+        });
+        sched.run(|| {}).unwrap();
+
+        assert!(next_saw_unwound.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_shutdown_graceful() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::Duration;
+
+        let finished = Arc::new(AtomicBool::new(false));
+        let finished1 = finished.clone();
+
+        let started = Instant::now();
+        let ret = Scheduler::new()
+                      .run(move || {
+                          Scheduler::spawn(move || {
+                              ::sleep_ms(50);
+                              finished1.store(true, Ordering::SeqCst);
+                          });
+                          // Straggler
+                          Scheduler::spawn(|| ::sleep_ms(10_000));
+
+                          let scheduler = Scheduler::instance().unwrap();
+                          scheduler.shutdown_graceful(Duration::from_millis(200));
+                          assert!(Scheduler::try_spawn(|| {}).is_err());
+
+                          let refused = Scheduler::spawn(|| 2);
+                          assert!(refused.is_finished());
+                          assert!(refused.join().unwrap_err().is::<ShuttingDown>());
+                          1
+                      })
+                      .unwrap();
+        assert_eq!(ret, 1);
+        assert!(finished.load(Ordering::SeqCst));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        // The main function itself is unwound once the deadline has passed
+        let ret = Scheduler::new().run(|| {
+            Scheduler::instance().unwrap().shutdown_graceful(Duration::from_millis(50));
+            loop {
+                ::sleep_ms(10);
+            }
+        });
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn test_shutting_down_error() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let observed = Arc::new(AtomicBool::new(false));
+        let observed1 = observed.clone();
+
+        Scheduler::new()
+            .run(move || {
+                Scheduler::spawn(move || {
+                    let err = Scheduler::instance().unwrap().sleep_ms(10_000).unwrap_err();
+                    if err.get_ref().and_then(|e| e.downcast_ref::<ShuttingDown>()).is_some() {
+                        observed1.store(true, Ordering::SeqCst);
+                    }
+                    assert!(Scheduler::try_spawn(|| {}).is_err());
+                });
+
+                ::sleep_ms(100);
+            })
+            .unwrap();
+
+        assert!(observed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_interrupt_sleep() {
+        use std::time::Duration;
+
+        Scheduler::new()
+            .run(|| {
+                let hdl = Scheduler::spawn(|| ::sleep(Duration::from_secs(10)));
+                ::sleep_ms(50);
+                hdl.interrupt();
+                assert!(hdl.join().unwrap() > Duration::from_secs(9));
+
+                // Interrupted before sleeping
+                let hdl = Scheduler::spawn(|| {
+                    Scheduler::sched();
+                    ::sleep_ms(10_000)
+                });
+                hdl.interrupt();
+                assert_eq!(hdl.join().unwrap(), 10_000);
+
+                let hdl = Scheduler::spawn(|| ::sleep_ms(10_000));
+                ::sleep_ms(50);
+                hdl.abort();
+                assert!(hdl.join().is_err());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_cancel() {
+        use std::io::Read;
+        use net::{TcpListener, TcpStream};
+
+        Scheduler::new()
+            .run(|| {
+                let (tx, rx) = ::sync::mpsc::channel::<()>();
+                let hdl = Scheduler::spawn(move || rx.recv());
+                ::sleep_ms(50);
+                hdl.cancel();
+                assert!(hdl.join().is_err());
+                // The receiver is gone, but the waiter must not be woken up anymore
+                assert!(tx.send(()).is_err());
+
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+                let (mut stream, _) = listener.accept().unwrap();
+                let hdl = Scheduler::spawn(move || stream.read(&mut [0u8; 4]));
+                ::sleep_ms(50);
+                hdl.cancel();
+                assert!(hdl.join().is_err());
+
+                // Cancelled while running, unwound at the next scheduling point
+                let hdl = Scheduler::spawn(|| {
+                    loop {
+                        Scheduler::sched();
+                    }
+                });
+                hdl.cancel();
+                assert!(hdl.join().is_err());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_try_join() {
+        Scheduler::new()
+            .run(|| {
+                let hdl = Scheduler::spawn(|| {
+                    ::sleep_ms(100);
+                    1
+                });
+
+                assert!(hdl.is_running());
+                assert!(hdl.try_join().is_none());
+
+                ::sleep_ms(200);
+                assert!(hdl.is_finished());
+                assert_eq!(hdl.try_join().unwrap().unwrap(), 1);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_stack_usage() {
+        Scheduler::new()
+            .run(|| {
+                let opts = Options::new().stack_class(StackClass::Tiny);
+                let hdl = Scheduler::spawn_opts(|| ::sleep_ms(100), opts);
+
+                ::sleep_ms(50);
+                let scheduler = Scheduler::instance().unwrap();
+                assert_eq!(scheduler.stack_usage().tiny, 1);
+
+                hdl.join().unwrap();
+                ::sleep_ms(50);
+                assert_eq!(scheduler.stack_usage().tiny, 0);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_with_workers_fraction() {
+        let cpus = ::cpu::available_cpus();
+
+        assert_eq!(Scheduler::new().with_workers_auto().expected_worker_count, cpus);
+        assert_eq!(Scheduler::new().with_workers_fraction(1.0).expected_worker_count, cpus);
+        assert_eq!(Scheduler::new().with_workers_fraction(0.0001).expected_worker_count, 1);
+    }
+
+    #[test]
+    fn test_cpu_affinity() {
+        let sched = Scheduler::new().with_cpu_affinity(&[2, 3]);
+        assert_eq!(sched.cpu_for(0), Some(2));
+        assert_eq!(sched.cpu_for(3), Some(3));
+        assert_eq!(Scheduler::new().cpu_for(0), None);
+
+        // Runs even if the CPU could not be pinned
+        Scheduler::new().with_workers(2).with_cpu_affinity(&[0]).run(|| {}).unwrap();
+    }
+
+    #[test]
+    fn test_processor_stats() {
+        Scheduler::new()
+            .with_workers(2)
+            .with_thread_name_prefix("worker-")
+            .run(|| {
+                assert_eq!(::std::thread::current().name(), Some("worker-0"));
+
+                // The other worker registers itself asynchronously
+                let mut processors = Vec::new();
+                while processors.len() < 2 {
+                    Scheduler::sched();
+                    processors = Scheduler::instance().unwrap().stats().processors;
+                }
+
+                assert_eq!(processors[0].id, 0);
+                assert_eq!(processors[1].thread_name, "worker-1");
+                if cfg!(target_os = "linux") {
+                    assert!(processors[0].os_tid.is_some());
+                    assert!(processors[0].os_tid != processors[1].os_tid);
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_timer_handle() {
+        Scheduler::new()
+            .run(|| {
+                let sched = Scheduler::instance().unwrap();
+
+                // Postponed on every "message", never expires meanwhile
+                let idle = sched.timer(Duration::from_millis(50)).unwrap();
+                for _ in 0..10 {
+                    ::sleep(Duration::from_millis(10));
+                    idle.reset_after(Duration::from_millis(50));
                 }
-            }
+                assert!(!idle.is_expired());
 
-            handles.push(hdl);
-            handlers.push(msg);
-            stealers.push(st);
-        }
+                let started = Instant::now();
+                assert!(idle.wait());
+                assert!(idle.is_expired());
+                assert!(started.elapsed() >= Duration::from_millis(40));
 
-        // The scheduler loop
-        loop {
-            self.event_loop.run_once(&mut self.io_handler, Some(100)).unwrap();
+                // Brought forward
+                let timer = sched.timer(Duration::from_secs(10)).unwrap();
+                timer.reset_after(Duration::from_millis(10));
+                assert!(timer.wait());
+                assert!(!timer.cancel());
 
-            match main_coro_hdl.try_recv() {
-                Ok(main_ret) => {
-                    for msg in handlers.iter() {
-                        msg.send(ProcMessage::Shutdown).unwrap();
-                    }
+                // Cancelled while a coroutine is waiting
+                let timer = sched.timer(Duration::from_secs(10)).unwrap();
+                let waiter = {
+                    let timer = timer.clone();
+                    Scheduler::spawn(move || timer.wait())
+                };
+                assert!(timer.cancel());
+                assert_eq!(waiter.join().unwrap(), false);
+                assert!(timer.is_cancelled());
 
-                    self.io_handler.wakeup_all(&mut self.event_loop);
+                // Rearmed after being cancelled
+                timer.reset_after(Duration::from_millis(10));
+                assert!(timer.wait());
+            })
+            .unwrap();
+    }
 
-                    // NOTE: It's critical that all threads are joined since Processor
-                    // maintains a reference to this Scheduler using raw pointers.
-                    for hdl in handles {
-                        let _ = hdl.join();
+    #[test]
+    fn test_timer_handle_disarm() {
+        Scheduler::new()
+            .run(|| {
+                let sched = Scheduler::instance().unwrap();
+                // The timer count is refreshed by the event loop
+                let wait_timers = |pred: &Fn(usize) -> bool| {
+                    let started = Instant::now();
+                    while !pred(sched.stats().timers) {
+                        assert!(started.elapsed() < Duration::from_secs(1));
+                        Scheduler::sched();
                     }
+                };
+
+                let timers: Vec<_> = (0..100)
+                                         .map(|_| sched.timer(Duration::from_secs(3600)).unwrap())
+                                         .collect();
+                wait_timers(&|n| n == 100);
+
+                // Removed from the wheel instead of staying until their deadline
+                for timer in &timers {
+                    assert!(timer.cancel());
+                }
+                wait_timers(&|n| n == 0);
+
+                // Brought forward, the entry for the old deadline is removed as well
+                let timer = sched.timer(Duration::from_secs(3600)).unwrap();
+                timer.reset_after(Duration::from_millis(10));
+                assert!(timer.wait());
+                wait_timers(&|n| n == 0);
+            })
+            .unwrap();
+    }
 
-                    return main_ret;
+    #[test]
+    fn test_stats_counters() {
+        Scheduler::new()
+            .run(|| {
+                let handles: Vec<_> = (0..10).map(|_| Scheduler::spawn(|| {})).collect();
+                for hdl in handles {
+                    hdl.join().unwrap();
                 }
-                Err(TryRecvError::Empty) => {}
-                Err(TryRecvError::Disconnected) => {
-                    panic!("Main coro is disconnected");
+
+                let sleeper = Scheduler::spawn(|| ::sleep(Duration::from_millis(200)));
+                // The timer count is refreshed by the event loop
+                let started = Instant::now();
+                while Scheduler::instance().unwrap().stats().timers == 0 {
+                    assert!(started.elapsed() < Duration::from_secs(1));
+                    Scheduler::sched();
                 }
-            }
-        }
+                sleeper.join().unwrap();
+
+                let stats = Scheduler::instance().unwrap().stats();
+                // The main coroutine at least is still active
+                assert!(stats.coroutines_finished >= 10);
+                assert!(stats.coroutines_active >= 1);
+                assert_eq!(stats.coroutines_spawned,
+                           stats.coroutines_finished + stats.coroutines_active);
+                assert!(stats.event_loop_wakeups > 0);
+            })
+            .unwrap();
     }
 
-    /// Suspend the current coroutine
-    pub fn sched() {
-        Processor::current().unwrap().sched();
+    #[test]
+    fn test_processor_loads() {
+        Scheduler::new()
+            .run(|| {
+                // Keeps the Processor busy with yielding coroutines
+                let started = Instant::now();
+                let hdls = (0..4)
+                               .map(|_| {
+                                   Scheduler::spawn(move || {
+                                       while started.elapsed() < Duration::from_millis(500) {
+                                           Scheduler::sched();
+                                       }
+                                   })
+                               })
+                               .collect();
+                join_all(hdls);
+
+                assert!(Scheduler::instance().unwrap().processor_loads()[0] > 1.0);
+            })
+            .unwrap();
     }
 
-    /// Block the current coroutine
-    #[inline]
-    pub fn take_current_coroutine<U, F>(f: F) -> U
-        where F: FnOnce(Handle) -> U
-    {
-        Processor::current().unwrap().take_current_coroutine(f)
+    #[test]
+    fn test_stack_high_water_marks() {
+        Scheduler::new()
+            .with_stack_measurement(true)
+            .run(|| {
+                Scheduler::spawn(|| {
+                        let mut buf = [0u8; 16 * 1024];
+                        for (idx, b) in buf.iter_mut().enumerate() {
+                            *b = idx as u8;
+                        }
+                        assert_eq!(buf[buf.len() - 1], 255);
+
+                        assert!(Scheduler::stack_high_water_mark().unwrap() >= 16 * 1024);
+                    })
+                    .join()
+                    .unwrap();
+
+                let marks = Scheduler::instance().unwrap().stack_high_water_marks();
+                assert_eq!(marks.class(StackClass::Default).count(), 1);
+                assert!(marks.class(StackClass::Default).max() >= 16 * 1024);
+                assert_eq!(marks.class(StackClass::Tiny).count(), 0);
+            })
+            .unwrap();
     }
-}
 
-struct ResultWrapper(*mut io::Result<()>);
-unsafe impl Send for ResultWrapper {}
-unsafe impl Sync for ResultWrapper {}
+    #[test]
+    fn test_drain_processor() {
+        Scheduler::new()
+            .with_workers(2)
+            .run(|| {
+                let sched = Scheduler::instance().unwrap();
+                assert!(!sched.drain_processor(2));
+                assert!(sched.drain_processor(1));
+                assert!(!sched.drain_processor(1));
+                // The last Processor in service could not be drained
+                assert!(!sched.drain_processor(0));
+                assert!(sched.is_processor_drained(1));
 
-impl Scheduler {
-    /// Block the current coroutine and wait for I/O event
-    #[doc(hidden)]
-    pub fn wait_event<'scope, E: Evented>(&self,
-                                          fd: &'scope E,
-                                          interest: EventSet)
-                                          -> io::Result<()> {
-        let mut ret = Ok(());
+                // Let the drained Processor receive the message
+                sched.sleep(Duration::from_millis(50)).unwrap();
 
-        Scheduler::take_current_coroutine(|coro| {
-            let proc_hdl1 = Processor::current().unwrap().handle();
-            let proc_hdl2 = proc_hdl1.clone();
-            let channel = self.event_loop.channel();
+                let ids = Arc::new(Mutex::new(Vec::new()));
+                let handles: Vec<_> = (0..50)
+                                          .map(|_| {
+                                              let ids = ids.clone();
+                                              Scheduler::spawn(move || {
+                                                  Scheduler::sched();
+                                                  let id = Processor::current().unwrap().id();
+                                                  ids.lock().unwrap().push(id);
+                                              })
+                                          })
+                                          .collect();
+                for hdl in handles {
+                    hdl.join().unwrap();
+                }
+                assert!(ids.lock().unwrap().iter().all(|id| *id == 0));
 
-            struct EventedWrapper<E>(*const E);
-            unsafe impl<E> Send for EventedWrapper<E> {}
-            unsafe impl<E> Sync for EventedWrapper<E> {}
+                assert!(sched.resume_processor(1));
+                assert!(!sched.resume_processor(1));
+                assert!(!sched.is_processor_drained(1));
+            })
+            .unwrap();
+    }
 
-            let fd1 = EventedWrapper(fd);
-            let fd2 = EventedWrapper(fd);
-            let ret1 = ResultWrapper(&mut ret);
-            let ret2 = ResultWrapper(&mut ret);
-            let coro1 = SendableCoroutinePtr(Box::into_raw(coro));
-            let coro2 = coro1;
+    #[test]
+    fn test_spawn_on_each_worker() {
+        Scheduler::new()
+            .with_workers(3)
+            .run(|| {
+                // Skipped while out of service
+                assert!(Scheduler::instance().unwrap().drain_processor(2));
 
-            let reg = move |evloop: &mut EventLoop<IoHandler>, token| {
-                let fd = unsafe { &*fd1.0 };
-                let ret = unsafe { &mut *ret1.0 };
-                let r = evloop.register(fd, token, interest, PollOpt::edge() | PollOpt::oneshot());
+                let current_id = || Processor::current().unwrap().id();
+                let handles = Scheduler::spawn_on_each_worker(current_id).unwrap();
+                let ids: Vec<_> = handles.into_iter().map(|hdl| hdl.join().unwrap()).collect();
+                assert_eq!(ids, vec![0, 1]);
 
-                match r {
-                    Ok(..) => true,
-                    Err(..) => {
-                        *ret = r;
-                        proc_hdl1.send(ProcMessage::Ready(unsafe { Box::from_raw(coro1.0) }))
-                                 .unwrap();
-                        false
-                    }
-                }
-            };
+                Scheduler::instance().unwrap().shutdown_graceful(Duration::from_secs(1));
+                assert!(Scheduler::spawn_on_each_worker(|| {}).is_err());
+                assert!(Scheduler::spawn_on(0, || {}).is_err());
+            })
+            .unwrap();
+    }
 
-            let ready = move |evloop: &mut EventLoop<IoHandler>| {
-                if cfg!(not(any(target_os = "macos",
-                                target_os = "ios",
-                                target_os = "freebsd",
-                                target_os = "dragonfly",
-                                target_os = "netbsd"))) {
-                    let fd = unsafe { &*fd2.0 };
-                    let ret = unsafe { &mut *ret2.0 };
-                    *ret = evloop.deregister(fd);
+    #[test]
+    fn test_spawn_on() {
+        // Yielding gives the other Processors plenty of chances to steal the coroutine
+        fn stays_on(worker_id: usize) -> bool {
+            (0..100).all(|_| {
+                Scheduler::sched();
+                Processor::current().unwrap().id() == worker_id
+            })
+        }
+
+        Scheduler::new()
+            .with_workers(4)
+            .run(|| {
+                let worker_ids = Scheduler::instance().unwrap().worker_ids();
+                assert_eq!(worker_ids, vec![0, 1, 2, 3]);
+
+                let handles: Vec<_> =
+                    worker_ids.into_iter()
+                              .map(|id| Scheduler::spawn_on(id, move || stays_on(id)).unwrap())
+                              .collect();
+
+                for hdl in handles {
+                    assert!(hdl.join().unwrap());
                 }
+            })
+            .unwrap();
+    }
 
-                proc_hdl2.send(ProcMessage::Ready(unsafe { Box::from_raw(coro2.0) })).unwrap();
-            };
+    #[test]
+    fn test_leak_detection() {
+        let mut scheduler = Scheduler::new().with_leak_detection(LeakDetection::Fail);
+        let ret = scheduler.run(|| {
+            Scheduler::spawn(|| {}).join().unwrap();
 
-            channel.send(IoHandlerMessage::new(reg, ready)).unwrap();
+            let opts = Options::new()
+                           .name(Some("forgotten".to_owned()))
+                           .spawn_site(spawn_site!());
+            Scheduler::spawn_opts(|| ::sleep(Duration::from_secs(60)), opts);
         });
 
-        ret
+        let report = ret.unwrap_err().downcast::<LeakReport>().unwrap();
+        assert_eq!(report.leaked.len(), 1);
+        assert_eq!(report.leaked[0].name, Some("forgotten".to_owned()));
+        assert!(report.leaked[0].spawn_site.unwrap().starts_with("src/scheduler.rs:"));
+        assert_eq!(report.leaked[0].wait_reason, Some(WaitReason::Sleep));
+        assert_eq!(scheduler.leak_report(), Some(*report));
     }
 
-    /// Block the current coroutine until the specific time
-    #[doc(hidden)]
-    pub fn sleep_ms(&self, delay: u64) -> io::Result<()> {
-        let mut ret = Ok(());
+    #[test]
+    fn test_coroutine_panic_handler() {
+        let panics = Arc::new(Mutex::new(Vec::new()));
+        let recorded = panics.clone();
 
-        Scheduler::take_current_coroutine(|coro| {
-            let proc_hdl = Processor::current().unwrap().handle();
-            let channel = self.event_loop.channel();
+        Scheduler::new()
+            .on_coroutine_panic(move |name, payload| {
+                let msg = payload.downcast_ref::<&'static str>().cloned();
+                recorded.lock().unwrap().push((name.map(|n| n.to_owned()), msg));
+            })
+            .run(|| {
+                let opts = Options::new().name(Some("worker".to_owned()));
+                let hdl = Scheduler::spawn_opts(|| panic!("boom"), opts);
+                let payload = hdl.join().unwrap_err();
+                assert_eq!(payload.downcast_ref::<&'static str>(), Some(&"boom"));
 
-            let ret1 = ResultWrapper(&mut ret);
+                // Unwound on purpose, not a panic of the coroutine
+                let (_tx, rx) = ::sync::mpsc::channel::<()>();
+                let hdl = Scheduler::spawn(move || rx.recv());
+                hdl.cancel();
+                assert!(hdl.join().is_err());
+            })
+            .unwrap();
 
-            let reg = |evloop: &mut EventLoop<IoHandler>, token| {
-                let ret = unsafe { &mut *ret1.0 };
+        assert_eq!(*panics.lock().unwrap(),
+                   vec![(Some("worker".to_owned()), Some("boom"))]);
+    }
 
-                match evloop.timeout_ms(token, delay) {
-                    Ok(..) => true,
-                    Err(..) => {
-                        *ret = Err(io::Error::new(io::ErrorKind::Other, "failed to add timer"));
-                        false
+    #[test]
+    fn test_deadlock_detection() {
+        let mut scheduler = Scheduler::new().with_deadlock_detection(DeadlockDetection::Fail);
+        let ret = scheduler.run(|| {
+            let (tx, rx) = ::sync::mpsc::channel::<()>();
+
+            // Waits for the main function, which waits for it in turn
+            let opts = Options::new().name(Some("receiver".to_owned()));
+            let receiver = Scheduler::spawn_opts(move || rx.recv().is_ok(), opts);
+            receiver.join().unwrap();
+            drop(tx);
+        });
+
+        let report = ret.unwrap_err().downcast::<DeadlockReport>().unwrap();
+        assert_eq!(report.stuck.len(), 2);
+        assert!(report.stuck.iter().all(|c| c.state == CoroutineState::BlockedOnSync));
+        assert!(report.stuck.iter().any(|c| c.name == Some("receiver".to_owned())));
+        assert_eq!(scheduler.deadlock_report(), Some(*report));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_dump_coroutines() {
+        use std::os::unix::io::AsRawFd;
+
+        use net::tcp::TcpListener;
+
+        Scheduler::new()
+            .with_coroutine_dump(true)
+            .run(|| {
+                let listener = Arc::new(TcpListener::bind("127.0.0.1:0").unwrap());
+                let fd = listener.as_raw_fd();
+
+                let opts = Options::new().name(Some("acceptor".to_owned()));
+                let acceptor = Scheduler::spawn_opts(move || listener.accept().is_ok(), opts);
+                let opts = Options::new().name(Some("sleeper".to_owned()));
+                let sleeper = Scheduler::spawn_opts(|| ::sleep(Duration::from_secs(60)), opts);
+                Scheduler::sched();
+
+                let dump = Scheduler::instance().unwrap().dump_coroutines();
+                assert_eq!(dump.len(), 2);
+                assert_eq!(dump[0].name, Some("acceptor".to_owned()));
+                assert_eq!(dump[0].state, CoroutineState::BlockedOnIo(Some(fd)));
+                assert_eq!(dump[0].processor, Some(0));
+                assert_eq!(dump[1].state, CoroutineState::Sleeping);
+                assert!(dump[1].to_string().contains("sleeper"));
+
+                acceptor.cancel();
+                sleeper.interrupt();
+                let _ = acceptor.join();
+                sleeper.join().unwrap();
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_resize_workers() {
+        Scheduler::new()
+            .with_workers(1)
+            .run(|| {
+                let sched = Scheduler::instance().unwrap();
+                sched.add_workers(2);
+                while sched.worker_count() < 3 {
+                    sched.sleep(Duration::from_millis(10)).unwrap();
+                }
+                assert_eq!(sched.stats().processors.len(), 3);
+
+                // The last Processor in service is kept
+                assert_eq!(sched.remove_workers(5), 2);
+                assert_eq!(sched.worker_count(), 1);
+                assert!(sched.is_processor_drained(2));
+                assert!(sched.is_processor_drained(1));
+
+                // Retired Processors are put back into service first
+                sched.add_workers(1);
+                assert_eq!(sched.worker_count(), 2);
+                assert!(!sched.is_processor_drained(1));
+
+                let handles: Vec<_> = (0..50)
+                                          .map(|_| Scheduler::spawn(|| Scheduler::sched()))
+                                          .collect();
+                for hdl in handles {
+                    hdl.join().unwrap();
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_unpark_idle() {
+        Scheduler::new()
+            .with_workers(2)
+            .with_spawn_hint(SpawnHint::Deferred)
+            .run(|| {
+                let ids = Arc::new(Mutex::new(Vec::new()));
+                let handles: Vec<_> = (0..10)
+                                          .map(|_| {
+                                              let ids = ids.clone();
+                                              Scheduler::spawn(move || {
+                                                  let id = Processor::current().unwrap().id();
+                                                  ids.lock().unwrap().push(id);
+                                                  // Keep this Processor busy
+                                                  ::std::thread::sleep(Duration::from_millis(5));
+                                              })
+                                          })
+                                          .collect();
+                for hdl in handles {
+                    hdl.join().unwrap();
+                }
+
+                // The parked Processor has been woken up to steal the queued coroutines
+                assert!(ids.lock().unwrap().contains(&1));
+                assert!(Scheduler::instance().unwrap().stats().idle_wakeups > 0);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_fair_queuing() {
+        fn work(units: Arc<AtomicUsize>, stop: Arc<AtomicBool>) {
+            while !stop.load(Ordering::SeqCst) {
+                let started = Instant::now();
+                while started.elapsed() < Duration::from_millis(1) {}
+                units.fetch_add(1, Ordering::SeqCst);
+                Scheduler::sched();
+            }
+        }
+
+        Scheduler::new()
+            .with_fair_queuing(true)
+            .run(|| {
+                let stop = Arc::new(AtomicBool::new(false));
+                let crowded = Arc::new(AtomicUsize::new(0));
+                let single = Arc::new(AtomicUsize::new(0));
+
+                let mut handles = Vec::new();
+                for _ in 0..20 {
+                    let (units, stop) = (crowded.clone(), stop.clone());
+                    handles.push(Scheduler::spawn_opts(move || work(units, stop),
+                                                       Options::new().group(1)));
+                }
+                let (units, stop2) = (single.clone(), stop.clone());
+                handles.push(Scheduler::spawn_opts(move || work(units, stop2),
+                                                   Options::new().group(2)));
+
+                ::sleep(Duration::from_millis(200));
+                stop.store(true, Ordering::SeqCst);
+                for hdl in handles {
+                    hdl.join().unwrap();
+                }
+
+                // Both groups got about the same time, instead of 1/21 for the single one
+                let (crowded, single) = (crowded.load(Ordering::SeqCst),
+                                         single.load(Ordering::SeqCst));
+                assert!(single * 3 > crowded, "{} vs {}", single, crowded);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_fair_queuing_steals() {
+        Scheduler::new()
+            .with_workers(2)
+            .with_fair_queuing(true)
+            .run(|| {
+                let ids = Arc::new(Mutex::new(Vec::new()));
+                let handles: Vec<_> = (0..100)
+                                          .map(|_| {
+                                              let ids = ids.clone();
+                                              Scheduler::spawn(move || {
+                                                  Scheduler::sched();
+                                                  let started = Instant::now();
+                                                  while started.elapsed() <
+                                                        Duration::from_millis(1) {
+                                                  }
+                                                  let id = Processor::current().unwrap().id();
+                                                  ids.lock().unwrap().push(id);
+                                              })
+                                          })
+                                          .collect();
+                for hdl in handles {
+                    hdl.join().unwrap();
+                }
+
+                // The queued coroutines are not all kept away from the idle neighbor
+                let ids = ids.lock().unwrap();
+                assert!(ids.contains(&0) && ids.contains(&1));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_priorities_steals() {
+        Scheduler::new()
+            .with_workers(2)
+            .run(|| {
+                for &priority in &[Priority::Low, Priority::High] {
+                    let ids = Arc::new(Mutex::new(Vec::new()));
+                    let handles: Vec<_> = (0..100)
+                                              .map(|_| {
+                                                  let ids = ids.clone();
+                                                  let f = move || {
+                                                      Scheduler::sched();
+                                                      let started = Instant::now();
+                                                      while started.elapsed() <
+                                                            Duration::from_millis(1) {
+                                                      }
+                                                      let id = Processor::current().unwrap().id();
+                                                      ids.lock().unwrap().push(id);
+                                                  };
+                                                  Scheduler::spawn_opts(f,
+                                                                        Options::new()
+                                                                            .priority(priority))
+                                              })
+                                              .collect();
+                    for hdl in handles {
+                        hdl.join().unwrap();
                     }
+
+                    // Coroutines of any priority are stolen by the idle neighbor
+                    let ids = ids.lock().unwrap();
+                    assert!(ids.contains(&0) && ids.contains(&1), "{:?}", priority);
                 }
-            };
+            })
+            .unwrap();
+    }
 
-            let ready = move |_: &mut EventLoop<IoHandler>| {
-                proc_hdl.send(ProcMessage::Ready(coro)).unwrap();
-            };
+    #[test]
+    fn test_priorities() {
+        Scheduler::new()
+            .with_spawn_hint(SpawnHint::Deferred)
+            .with_priority_aging(Duration::from_millis(20))
+            .run(|| {
+                let order = Arc::new(Mutex::new(Vec::new()));
+                let spawn = |priority: Priority| {
+                    let order = order.clone();
+                    Scheduler::spawn_opts(move || order.lock().unwrap().push(priority),
+                                          Options::new().priority(priority))
+                };
+                let handles = vec![spawn(Priority::Low),
+                                   spawn(Priority::Normal),
+                                   spawn(Priority::High)];
+                for hdl in handles {
+                    hdl.join().unwrap();
+                }
+                assert_eq!(*order.lock().unwrap(),
+                           vec![Priority::High, Priority::Normal, Priority::Low]);
 
-            channel.send(IoHandlerMessage::new(reg, ready)).unwrap();
-        });
+                // Busy normal coroutines don't starve the low priority one
+                let started = Instant::now();
+                let low = Scheduler::spawn_opts(move || started.elapsed(),
+                                                Options::new().priority(Priority::Low));
+                let busy: Vec<_> = (0..4)
+                                       .map(|_| {
+                                           Scheduler::spawn(move || {
+                                               while started.elapsed() < Duration::from_secs(1) {
+                                                   Scheduler::sched();
+                                               }
+                                           })
+                                       })
+                                       .collect();
+                assert!(low.join().unwrap() < Duration::from_millis(500));
+                for hdl in busy {
+                    hdl.join().unwrap();
+                }
+            })
+            .unwrap();
+    }
 
-        ret
+    #[test]
+    fn test_spawn_hint() {
+        Scheduler::new()
+            .run(|| {
+                let started = Arc::new(AtomicBool::new(false));
+
+                let child_started = started.clone();
+                let hdl = Scheduler::spawn(move || child_started.store(true, Ordering::SeqCst));
+                assert!(started.load(Ordering::SeqCst));
+                hdl.join().unwrap();
+
+                started.store(false, Ordering::SeqCst);
+                let child_started = started.clone();
+                let opts = Options::new().spawn_hint(SpawnHint::Deferred);
+                let f = move || child_started.store(true, Ordering::SeqCst);
+                let hdl = Scheduler::spawn_opts(f, opts);
+                assert!(!started.load(Ordering::SeqCst));
+                hdl.join().unwrap();
+                assert!(started.load(Ordering::SeqCst));
+            })
+            .unwrap();
+
+        Scheduler::new()
+            .with_spawn_hint(SpawnHint::Deferred)
+            .run(|| {
+                let started = Arc::new(AtomicBool::new(false));
+
+                let child_started = started.clone();
+                let hdl = Scheduler::spawn(move || child_started.store(true, Ordering::SeqCst));
+                assert!(!started.load(Ordering::SeqCst));
+                hdl.join().unwrap();
+            })
+            .unwrap();
     }
 
-    /// Block the current coroutine until the specific time
-    #[doc(hidden)]
-    pub fn sleep(&self, delay: Duration) -> io::Result<()> {
-        self.sleep_ms(delay.as_secs() * 1_000 + delay.subsec_nanos() as u64 / 1_000_000)
+    #[test]
+    fn test_rng_seed() {
+        let sample = |sched: &Scheduler, processor_id| {
+            let mut rng = sched.new_rng(processor_id);
+            (0..8).map(|_| rng.gen::<u32>()).collect::<Vec<u32>>()
+        };
+
+        let a = Scheduler::new().with_rng_seed(42);
+        let b = Scheduler::new().with_rng_seed(42);
+        assert_eq!(sample(&a, 0), sample(&b, 0));
+        assert!(sample(&a, 0) != sample(&a, 1));
+        assert!(sample(&a, 0) != sample(&Scheduler::new().with_rng_seed(43), 0));
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    #[test]
+    fn test_spawn_after() {
+        Scheduler::new()
+            .run(|| {
+                let started = Instant::now();
+                let hdl = Scheduler::spawn_after(Duration::from_millis(100), move || {
+                    started.elapsed()
+                });
+
+                assert!(hdl.is_running());
+                assert!(hdl.join().unwrap() >= Duration::from_millis(100));
+
+                let hdl = Scheduler::spawn_at(started, || 1);
+                assert_eq!(hdl.join().unwrap(), 1);
+            })
+            .unwrap();
+    }
 
     #[test]
-    fn test_join_basic() {
+    fn test_many_sleepers() {
         Scheduler::new()
+            .with_workers(2)
             .run(|| {
-                let guard = Scheduler::spawn(|| 1);
+                let started = Instant::now();
+                let hdls = (0..10000)
+                               .map(|i| Scheduler::spawn(move || ::sleep_ms(10 + i % 20)))
+                               .collect();
 
-                assert_eq!(1, guard.join().unwrap());
+                for ret in join_all(hdls) {
+                    ret.unwrap();
+                }
+                assert!(started.elapsed() >= Duration::from_millis(29));
             })
             .unwrap();
     }
@@ -0,0 +1,316 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Runtime statistics of the Scheduler
+
+use std::fmt;
+use std::time::Duration;
+
+use metrics::histogram::{HistogramSnapshot, SizeHistogramSnapshot};
+use options::StackClass;
+
+/// Snapshot of the Scheduler's counters, see `Scheduler::stats()`.
+///
+/// Only `PartialEq`, not `Eq`, since the run queue and load figures are floating point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    /// Number of coroutines spawned since the Scheduler has been started
+    pub coroutines_spawned: usize,
+    /// Number of coroutines which have run to completion
+    pub coroutines_finished: usize,
+    /// Number of coroutines alive
+    pub coroutines_active: usize,
+    /// Number of coroutines blocked on I/O, timers or synchronization primitives
+    pub coroutines_blocked: usize,
+    /// Number of live I/O objects registered with the Scheduler
+    pub io_registrations: usize,
+    /// Number of sleeping coroutines waiting for their timer
+    pub timers: usize,
+    /// Number of times the event loop has returned from waiting for events
+    pub event_loop_wakeups: usize,
+    /// Number of times an idle Processor has been woken up with new work
+    pub idle_wakeups: usize,
+    /// Mean time between new work being sent to an idle Processor and it receiving the work
+    pub mean_wakeup_latency: Duration,
+    /// Mean of `ProcessorStats::mean_run_queue_len` over the Processors
+    pub mean_run_queue_len: f64,
+    /// Per-Processor statistics, ordered by the Processor id
+    pub processors: Vec<ProcessorStats>,
+}
+
+/// Statistics of a single Processor, identifying its worker thread for OS tooling.
+///
+/// Only `PartialEq`, not `Eq`, since the run queue and load figures are floating point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessorStats {
+    /// Index of the Processor in the Scheduler
+    pub id: usize,
+    /// Name of the worker thread
+    pub thread_name: String,
+    /// Thread id assigned by the kernel (gettid), as shown by `top -H` or `perf`. Only known
+    /// on Linux.
+    pub os_tid: Option<u64>,
+    /// Approximate number of coroutines waiting in the run queue
+    pub run_queue_len: usize,
+    /// Mean length of the run queue, sampled every 100 ms
+    pub mean_run_queue_len: f64,
+    /// Exponential moving average of the runnable coroutines plus the coroutines resumed
+    /// per 100 ms, smoothed over about a second
+    pub load: f64,
+    /// Number of times the Processor has tried to steal a coroutine from a neighbor
+    pub steals_attempted: usize,
+    /// Number of coroutines the Processor has stolen from its neighbors
+    pub steals_succeeded: usize,
+}
+
+/// Number of live coroutines per stack class, see `Scheduler::stack_usage()`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct StackUsage {
+    pub tiny: usize,
+    pub small: usize,
+    pub default: usize,
+    pub large: usize,
+}
+
+impl StackUsage {
+    /// Number of coroutines using stacks of the class
+    pub fn count(&self, class: StackClass) -> usize {
+        match class {
+            StackClass::Tiny => self.tiny,
+            StackClass::Small => self.small,
+            StackClass::Default => self.default,
+            StackClass::Large => self.large,
+        }
+    }
+
+    /// Memory reserved for the stacks, assuming every stack has the size of its class
+    pub fn bytes(&self) -> usize {
+        StackClass::all().iter().fold(0, |bytes, class| bytes + self.count(*class) * class.size())
+    }
+}
+
+/// Deepest stack usage in bytes of the finished coroutines per stack class,
+/// see `Scheduler::with_stack_measurement()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackHighWaterMarks {
+    pub tiny: SizeHistogramSnapshot,
+    pub small: SizeHistogramSnapshot,
+    pub default: SizeHistogramSnapshot,
+    pub large: SizeHistogramSnapshot,
+}
+
+impl StackHighWaterMarks {
+    /// High-water marks of the coroutines using stacks of the class
+    pub fn class(&self, class: StackClass) -> &SizeHistogramSnapshot {
+        match class {
+            StackClass::Tiny => &self.tiny,
+            StackClass::Small => &self.small,
+            StackClass::Default => &self.default,
+            StackClass::Large => &self.large,
+        }
+    }
+}
+
+/// Heap memory attributed to a live coroutine, see `Scheduler::memory_usage()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoroutineMemory {
+    pub coroutine_id: usize,
+    pub name: Option<String>,
+    /// Bytes allocated while the coroutine was running
+    pub allocated: usize,
+    /// Bytes freed while the coroutine was running
+    pub freed: usize,
+}
+
+impl CoroutineMemory {
+    /// Bytes allocated but not freed by the coroutine. Memory handed over to other
+    /// coroutines and freed by them is still counted here.
+    pub fn in_use(&self) -> usize {
+        self.allocated.saturating_sub(self.freed)
+    }
+}
+
+/// What a blocked coroutine is waiting for
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WaitReason {
+    /// Readiness of an I/O object
+    Io,
+    /// A timer, e.g. `Scheduler::sleep()`
+    Sleep,
+    /// A synchronization primitive or a channel
+    Sync,
+    /// Anything else blocking with `Scheduler::take_current_coroutine()`
+    Other,
+}
+
+impl WaitReason {
+    #[doc(hidden)]
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    #[doc(hidden)]
+    pub fn from_index(idx: usize) -> WaitReason {
+        match idx {
+            0 => WaitReason::Io,
+            1 => WaitReason::Sleep,
+            2 => WaitReason::Sync,
+            _ => WaitReason::Other,
+        }
+    }
+}
+
+/// A coroutine which was still alive when the main function returned,
+/// see `Scheduler::with_leak_detection()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeakedCoroutine {
+    pub coroutine_id: usize,
+    pub name: Option<String>,
+    /// Set with `Options::spawn_site`
+    pub spawn_site: Option<&'static str>,
+    /// What the coroutine was blocked on, `None` if it was runnable
+    pub wait_reason: Option<WaitReason>,
+    /// Names of the entered spans, the innermost last
+    pub spans: Vec<&'static str>,
+}
+
+/// The coroutines left behind by the main function, which are unwound on shutdown
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeakReport {
+    pub leaked: Vec<LeakedCoroutine>,
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{} coroutines leaked", self.leaked.len()));
+        for coro in self.leaked.iter() {
+            try!(write!(f,
+                        "\n  #{} {} spawned at {} ",
+                        coro.coroutine_id,
+                        coro.name.as_ref().map(|n| &n[..]).unwrap_or("<unnamed>"),
+                        coro.spawn_site.unwrap_or("<unknown>")));
+            try!(match coro.wait_reason {
+                Some(reason) => write!(f, "blocked on {:?}", reason),
+                None => write!(f, "runnable"),
+            });
+            if !coro.spans.is_empty() {
+                try!(write!(f, " in {}", coro.spans.join(" > ")));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a coroutine is doing, see `Scheduler::dump_coroutines()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoroutineState {
+    /// Being resumed by a Processor
+    Running,
+    /// Waiting in a run queue to be resumed
+    Suspended,
+    /// Waiting for the readiness of an I/O object, with its file descriptor if known
+    BlockedOnIo(Option<i32>),
+    /// Waiting for a timer
+    Sleeping,
+    /// Waiting on a synchronization primitive or a channel
+    BlockedOnSync,
+    /// Blocked with `Scheduler::take_current_coroutine()`
+    Blocked,
+}
+
+impl fmt::Display for CoroutineState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CoroutineState::Running => write!(f, "running"),
+            CoroutineState::Suspended => write!(f, "suspended"),
+            CoroutineState::BlockedOnIo(Some(fd)) => write!(f, "blocked on fd {}", fd),
+            CoroutineState::BlockedOnIo(None) => write!(f, "blocked on I/O"),
+            CoroutineState::Sleeping => write!(f, "sleeping"),
+            CoroutineState::BlockedOnSync => write!(f, "blocked on a channel or lock"),
+            CoroutineState::Blocked => write!(f, "blocked"),
+        }
+    }
+}
+
+/// A live coroutine, see `Scheduler::dump_coroutines()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoroutineSnapshot {
+    pub coroutine_id: usize,
+    pub name: Option<String>,
+    /// Set with `Options::spawn_site`
+    pub spawn_site: Option<&'static str>,
+    pub state: CoroutineState,
+    /// The Processor which has resumed the coroutine last, `None` if it has never run
+    pub processor: Option<usize>,
+    /// Names of the entered spans, the innermost last
+    pub spans: Vec<&'static str>,
+}
+
+impl fmt::Display for CoroutineSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f,
+                    "#{} {} spawned at {}: {}",
+                    self.coroutine_id,
+                    self.name.as_ref().map(|n| &n[..]).unwrap_or("<unnamed>"),
+                    self.spawn_site.unwrap_or("<unknown>"),
+                    self.state));
+        if let Some(id) = self.processor {
+            try!(write!(f, " on Processor #{}", id));
+        }
+        if !self.spans.is_empty() {
+            try!(write!(f, " in {}", self.spans.join(" > ")));
+        }
+        Ok(())
+    }
+}
+
+/// Coroutines blocked forever, see `Scheduler::with_deadlock_detection()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadlockReport {
+    pub stuck: Vec<CoroutineSnapshot>,
+}
+
+impl fmt::Display for DeadlockReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "Deadlock, {} coroutines blocked forever", self.stuck.len()));
+        for coro in self.stuck.iter() {
+            try!(write!(f, "\n  {}", coro));
+        }
+        Ok(())
+    }
+}
+
+/// Snapshot of an instrumented channel, see `sync::instrumented` and
+/// `Scheduler::channel_stats()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelStats {
+    pub name: String,
+    /// Number of values sent into the channel
+    pub sent: usize,
+    /// Number of values received from the channel
+    pub received: usize,
+    /// Number of values waiting in the channel
+    pub depth: usize,
+    /// Highest number of values which have been waiting at the same time
+    pub max_depth: usize,
+    /// Time between sending and receiving the values
+    pub latency: HistogramSnapshot,
+}
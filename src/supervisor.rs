@@ -0,0 +1,287 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Restarting long-lived coroutines
+//!
+//! A `Supervisor` runs every child in its own coroutine and spawns it again once it has
+//! exited, according to its `Restart` policy. A child which keeps on failing is given up on
+//! after `max_restarts` within the restart interval, instead of burning the CPU:
+//!
+//! ```ignore
+//! let mut supervisor = Supervisor::new().with_max_restarts(5, Duration::from_secs(60));
+//! supervisor.supervise("accept loop", Restart::Always, move || accept_loop(&listener));
+//! supervisor.supervise("config watcher", Restart::OnFailure, watch_config);
+//! let children = supervisor.join();
+//! ```
+//!
+//! The children are named after their name in the supervisor, so their panics are reported
+//! with it by `Scheduler::on_coroutine_panic()`.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use options::{Options, SpawnHint};
+use scheduler::{JoinHandle, Scheduler};
+use sync::mpsc::Sender;
+
+/// When a child is spawned again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restart {
+    /// Whenever it exits, e.g. for loops which are meant to run forever
+    Always,
+    /// Only if it panicked
+    OnFailure,
+    /// Never, the child is only watched
+    Never,
+}
+
+/// What a child is doing, see `Supervisor::children()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChildState {
+    Running,
+    /// Returned and not restarted according to its `Restart` policy
+    Exited,
+    /// Panicked and not restarted according to its `Restart` policy
+    Failed,
+    /// Restarted too often within the restart interval
+    GaveUp,
+    /// Stopped by `Supervisor::shutdown()`
+    Stopped,
+}
+
+/// A child of a `Supervisor`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChildInfo {
+    pub name: String,
+    pub restarts: usize,
+    pub state: ChildState,
+}
+
+// Sends the exit notification even if the child is unwound
+struct ExitGuard(Sender<()>);
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        let _ = self.0.send(());
+    }
+}
+
+struct Child {
+    info: Mutex<ChildInfo>,
+    // The running incarnation, cancelled by `shutdown`
+    running: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// Spawns coroutines and restarts them once they exit, see the module documentation
+pub struct Supervisor {
+    max_restarts: usize,
+    interval: Duration,
+    stopping: Arc<AtomicBool>,
+    children: Vec<Arc<Child>>,
+    supervisors: Vec<JoinHandle<()>>,
+}
+
+impl Supervisor {
+    /// Create a supervisor giving up on children restarted more than 3 times in 5 seconds
+    pub fn new() -> Supervisor {
+        Supervisor {
+            max_restarts: 3,
+            interval: Duration::from_secs(5),
+            stopping: Arc::new(AtomicBool::new(false)),
+            children: Vec::new(),
+            supervisors: Vec::new(),
+        }
+    }
+
+    /// Give up on a child once it has been restarted more than `max_restarts` times within
+    /// `interval`
+    pub fn with_max_restarts(mut self, max_restarts: usize, interval: Duration) -> Supervisor {
+        self.max_restarts = max_restarts;
+        self.interval = interval;
+        self
+    }
+
+    /// Spawn `f` in a coroutine named `name`, restarting it according to `restart`.
+    ///
+    /// Must be called in a coroutine.
+    pub fn supervise<F>(&mut self, name: &str, restart: Restart, f: F)
+        where F: Fn() + Send + Sync + 'static
+    {
+        let child = Arc::new(Child {
+            info: Mutex::new(ChildInfo {
+                name: name.to_owned(),
+                restarts: 0,
+                state: ChildState::Running,
+            }),
+            running: Mutex::new(None),
+        });
+
+        let f = Arc::new(f);
+        let max_restarts = self.max_restarts;
+        let interval = self.interval;
+        let stopping = self.stopping.clone();
+        let supervised = child.clone();
+
+        let hdl = Scheduler::spawn(move || {
+            let child = supervised;
+            let mut restarted_at = VecDeque::new();
+
+            loop {
+                let (tx, rx) = ::sync::mpsc::channel();
+                {
+                    // Checked under the lock, so that `shutdown` cancels what is spawned
+                    let mut running = child.running.lock().unwrap();
+                    if stopping.load(Ordering::SeqCst) {
+                        child.info.lock().unwrap().state = ChildState::Stopped;
+                        return;
+                    }
+
+                    let f = f.clone();
+                    let name = child.info.lock().unwrap().name.clone();
+                    // Deferred, the lock must not be held while the child runs
+                    let opts = Options::new().name(Some(name)).spawn_hint(SpawnHint::Deferred);
+                    *running = Some(Scheduler::spawn_opts(move || {
+                                                              let _guard = ExitGuard(tx);
+                                                              (*f)()
+                                                          },
+                                                          opts));
+                }
+
+                let _ = rx.recv();
+                let hdl = child.running.lock().unwrap().take().unwrap();
+                let failed = hdl.join().is_err();
+
+                let state = if stopping.load(Ordering::SeqCst) {
+                    ChildState::Stopped
+                } else {
+                    match (restart, failed) {
+                        (Restart::Always, _) | (Restart::OnFailure, true) => ChildState::Running,
+                        (_, true) => ChildState::Failed,
+                        (_, false) => ChildState::Exited,
+                    }
+                };
+                if state != ChildState::Running {
+                    child.info.lock().unwrap().state = state;
+                    return;
+                }
+
+                let now = Instant::now();
+                while restarted_at.front().map_or(false, |at| now.duration_since(*at) > interval) {
+                    restarted_at.pop_front();
+                }
+                if restarted_at.len() >= max_restarts {
+                    let mut info = child.info.lock().unwrap();
+                    error!("Supervisor gave up on {} after {} restarts",
+                           info.name,
+                           info.restarts);
+                    info.state = ChildState::GaveUp;
+                    return;
+                }
+
+                restarted_at.push_back(now);
+                child.info.lock().unwrap().restarts += 1;
+            }
+        });
+
+        self.children.push(child);
+        self.supervisors.push(hdl);
+    }
+
+    /// The children in the order they have been added
+    pub fn children(&self) -> Vec<ChildInfo> {
+        self.children.iter().map(|c| c.info.lock().unwrap().clone()).collect()
+    }
+
+    /// Stop restarting the children and cancel the running ones, see `JoinHandle::cancel`
+    pub fn shutdown(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+
+        for child in self.children.iter() {
+            if let Some(ref hdl) = *child.running.lock().unwrap() {
+                hdl.cancel();
+            }
+        }
+    }
+
+    /// Wait until none of the children is restarted anymore
+    pub fn join(self) -> Vec<ChildInfo> {
+        for hdl in self.supervisors.iter() {
+            let _ = hdl.join();
+        }
+        self.children()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use scheduler::Scheduler;
+
+    #[test]
+    fn test_supervisor() {
+        Scheduler::new()
+            .on_coroutine_panic(|_, _| {})
+            .run(|| {
+                let mut supervisor = Supervisor::new()
+                                         .with_max_restarts(2, Duration::from_secs(10));
+
+                // Recovers after two failures
+                let attempts = Arc::new(AtomicUsize::new(0));
+                let counter = attempts.clone();
+                supervisor.supervise("flaky", Restart::OnFailure, move || {
+                    if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                        panic!("not yet");
+                    }
+                });
+
+                supervisor.supervise("broken", Restart::OnFailure, || panic!("always"));
+                supervisor.supervise("once", Restart::Never, || {});
+
+                // Runs until the shutdown
+                supervisor.supervise("loop", Restart::Always, || {
+                    loop {
+                        ::sleep(Duration::from_millis(10));
+                    }
+                });
+
+                ::sleep(Duration::from_millis(50));
+                supervisor.shutdown();
+                let children = supervisor.join();
+
+                assert_eq!(attempts.load(Ordering::SeqCst), 3);
+                assert_eq!(children[0].restarts, 2);
+                assert_eq!(children[0].state, ChildState::Exited);
+                assert_eq!(children[1].restarts, 2);
+                assert_eq!(children[1].state, ChildState::GaveUp);
+                assert_eq!(children[2].restarts, 0);
+                assert_eq!(children[2].state, ChildState::Exited);
+                assert_eq!(children[3].name, "loop");
+                assert_eq!(children[3].state, ChildState::Stopped);
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,281 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! Multi-producer event bus keyed by topic
+//!
+//! Every subscriber owns a bounded queue. When the queue is full, the `Overflow` policy
+//! of the subscriber decides whether the new message or the oldest one will be dropped.
+
+pub use std::sync::mpsc::{TryRecvError, RecvError};
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::{Arc, Weak, Mutex, Condvar};
+
+use coroutine::Handle;
+use runtime::Processor;
+use scheduler::Scheduler;
+use stats::WaitReason;
+
+/// What to do when a subscriber's queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Discard the message being published
+    DropNewest,
+    /// Discard the oldest queued message, so that the subscriber always sees the latest ones
+    DropOldest,
+}
+
+struct QueueState<T> {
+    items: VecDeque<T>,
+    wait_list: VecDeque<Handle>,
+    dropped: usize,
+    closed: bool,
+}
+
+struct Queue<T> {
+    state: Mutex<QueueState<T>>,
+    cond: Condvar,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+impl<T> Queue<T> {
+    fn push(&self, t: T) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.items.len() >= self.capacity {
+            state.dropped += 1;
+
+            match self.overflow {
+                Overflow::DropNewest => return,
+                Overflow::DropOldest => {
+                    state.items.pop_front();
+                }
+            }
+        }
+
+        state.items.push_back(t);
+
+        if let Some(coro) = state.wait_list.pop_front() {
+            Scheduler::ready(coro);
+        }
+        self.cond.notify_one();
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+
+        Scheduler::ready_all(state.wait_list.drain(..).collect());
+        self.cond.notify_all();
+    }
+}
+
+struct BusInner<K: Hash + Eq, T> {
+    topics: Mutex<HashMap<K, Vec<Weak<Queue<T>>>>>,
+}
+
+impl<K: Hash + Eq, T> Drop for BusInner<K, T> {
+    fn drop(&mut self) {
+        let topics = self.topics.lock().unwrap();
+
+        for queue in topics.values().flat_map(|subs| subs.iter()) {
+            if let Some(queue) = queue.upgrade() {
+                queue.close();
+            }
+        }
+    }
+}
+
+/// Publishing side of the bus, could be cloned and shared between coroutines
+pub struct Bus<T, K: Hash + Eq = String> {
+    inner: Arc<BusInner<K, T>>,
+}
+
+impl<T, K: Hash + Eq> Clone for Bus<T, K> {
+    fn clone(&self) -> Bus<T, K> {
+        Bus { inner: self.inner.clone() }
+    }
+}
+
+unsafe impl<T: Send, K: Hash + Eq + Send> Send for Bus<T, K> {}
+unsafe impl<T: Send, K: Hash + Eq + Send> Sync for Bus<T, K> {}
+
+impl<T: Clone, K: Hash + Eq> Bus<T, K> {
+    /// Create an empty bus
+    pub fn new() -> Bus<T, K> {
+        Bus { inner: Arc::new(BusInner { topics: Mutex::new(HashMap::new()) }) }
+    }
+
+    /// Subscribe to `topic` with a queue holding at most `capacity` messages
+    pub fn subscribe(&self, topic: K, capacity: usize, overflow: Overflow) -> Subscriber<T> {
+        assert!(capacity >= 1, "Subscriber queue must hold at least one message");
+
+        let queue = Arc::new(Queue {
+            state: Mutex::new(QueueState {
+                items: VecDeque::new(),
+                wait_list: VecDeque::new(),
+                dropped: 0,
+                closed: false,
+            }),
+            cond: Condvar::new(),
+            capacity: capacity,
+            overflow: overflow,
+        });
+
+        let mut topics = self.inner.topics.lock().unwrap();
+        topics.entry(topic).or_insert_with(Vec::new).push(Arc::downgrade(&queue));
+
+        Subscriber { queue: queue }
+    }
+
+    /// Publish a message to all subscribers of `topic`, returns the number of subscribers reached
+    pub fn publish(&self, topic: &K, t: T) -> usize {
+        let mut topics = self.inner.topics.lock().unwrap();
+
+        let delivered = match topics.get_mut(topic) {
+            None => 0,
+            Some(subs) => {
+                // Forget subscribers which have gone away
+                subs.retain(|q| q.upgrade().is_some());
+
+                for queue in subs.iter().filter_map(|q| q.upgrade()) {
+                    queue.push(t.clone());
+                }
+
+                subs.len()
+            }
+        };
+
+        if delivered == 0 {
+            topics.remove(topic);
+        }
+
+        delivered
+    }
+}
+
+/// Receiving side of a subscription
+pub struct Subscriber<T> {
+    queue: Arc<Queue<T>>,
+}
+
+unsafe impl<T: Send> Send for Subscriber<T> {}
+
+impl<T> Subscriber<T> {
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = self.queue.state.lock().unwrap();
+
+        match state.items.pop_front() {
+            Some(t) => Ok(t),
+            None if state.closed => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Wait for the next message, returns `Err` after all the `Bus` handles have been dropped
+    pub fn recv(&self) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(t) => return Ok(t),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            if let Some(mut processor) = Processor::current() {
+                let processor_ptr = unsafe { processor.mut_ptr() };
+
+                processor.take_current_coroutine(|coro| {
+                    coro.shared().set_wait_reason(WaitReason::Sync);
+                    let mut state = self.queue.state.lock().unwrap();
+
+                    // Ensure no one published while we are locking the wait list
+                    if state.items.is_empty() && !state.closed {
+                        state.wait_list.push_back(coro);
+                    } else {
+                        unsafe { &mut *processor_ptr }.ready(coro);
+                    }
+                });
+            } else {
+                let mut state = self.queue.state.lock().unwrap();
+                while state.items.is_empty() && !state.closed {
+                    state = self.queue.cond.wait(state).unwrap();
+                }
+            }
+        }
+    }
+
+    /// Number of messages dropped because the queue was full
+    pub fn dropped(&self) -> usize {
+        self.queue.state.lock().unwrap().dropped
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use scheduler::Scheduler;
+
+    #[test]
+    fn test_bus_topics() {
+        Scheduler::new()
+            .run(|| {
+                let bus = Bus::new();
+                let a = bus.subscribe("a".to_owned(), 16, Overflow::DropNewest);
+                let b = bus.subscribe("b".to_owned(), 16, Overflow::DropNewest);
+
+                let publisher = bus.clone();
+                let hdl = Scheduler::spawn(move || {
+                    assert_eq!(publisher.publish(&"a".to_owned(), 1), 1);
+                    assert_eq!(publisher.publish(&"b".to_owned(), 2), 1);
+                    assert_eq!(publisher.publish(&"c".to_owned(), 3), 0);
+                });
+
+                assert_eq!(a.recv(), Ok(1));
+                assert_eq!(b.recv(), Ok(2));
+                hdl.join().unwrap();
+
+                drop(bus);
+                assert_eq!(a.recv(), Err(RecvError));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_bus_overflow() {
+        let bus = Bus::<usize, &'static str>::new();
+        let newest = bus.subscribe("t", 2, Overflow::DropNewest);
+        let oldest = bus.subscribe("t", 2, Overflow::DropOldest);
+
+        for i in 0..4 {
+            bus.publish(&"t", i);
+        }
+
+        assert_eq!(newest.try_recv(), Ok(0));
+        assert_eq!(newest.try_recv(), Ok(1));
+        assert_eq!(oldest.try_recv(), Ok(2));
+        assert_eq!(oldest.try_recv(), Ok(3));
+        assert_eq!(newest.dropped(), 2);
+        assert_eq!(oldest.dropped(), 2);
+    }
+}
@@ -0,0 +1,215 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Channels recording how long values wait in them
+//!
+//! A channel created with `channel(name)` behaves like `sync::mpsc::channel()`, but
+//! additionally records the enqueue-to-dequeue latency and the queue depth. Channels
+//! created inside a Scheduler are listed by `Scheduler::channel_stats()` as long as any
+//! of their ends is alive, which makes it easy to find the slow stage of a pipeline.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use metrics::histogram::Histogram;
+use scheduler::Scheduler;
+use stats::ChannelStats;
+use sync::mpsc;
+
+pub use sync::mpsc::{SendError, TryRecvError, RecvError};
+
+/// Counters shared by both ends of an instrumented channel
+pub struct ChannelMetrics {
+    name: String,
+    sent: AtomicUsize,
+    received: AtomicUsize,
+    max_depth: AtomicUsize,
+    latency: Histogram,
+}
+
+impl ChannelMetrics {
+    fn new(name: String) -> ChannelMetrics {
+        ChannelMetrics {
+            name: name,
+            sent: AtomicUsize::new(0),
+            received: AtomicUsize::new(0),
+            max_depth: AtomicUsize::new(0),
+            latency: Histogram::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_send(&self) {
+        let depth = self.sent.fetch_add(1, Ordering::SeqCst) + 1 -
+                    self.received.load(Ordering::SeqCst);
+
+        let mut max = self.max_depth.load(Ordering::Relaxed);
+        while depth > max {
+            let prev = self.max_depth.compare_and_swap(max, depth, Ordering::Relaxed);
+            if prev == max {
+                break;
+            }
+            max = prev;
+        }
+    }
+
+    fn on_recv(&self, sent_at: Instant) {
+        self.received.fetch_add(1, Ordering::SeqCst);
+        self.latency.record(sent_at.elapsed());
+    }
+
+    /// Current values of the counters
+    pub fn snapshot(&self) -> ChannelStats {
+        let received = self.received.load(Ordering::SeqCst);
+        let sent = self.sent.load(Ordering::SeqCst);
+
+        ChannelStats {
+            name: self.name.clone(),
+            sent: sent,
+            received: received,
+            depth: sent.saturating_sub(received),
+            max_depth: self.max_depth.load(Ordering::Relaxed),
+            latency: self.latency.snapshot(),
+        }
+    }
+}
+
+pub struct Sender<T> {
+    inner: mpsc::Sender<(Instant, T)>,
+    metrics: Arc<ChannelMetrics>,
+}
+
+impl<T> Sender<T> {
+    /// Send a value, never blocks
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        // Account before sending, the receiver may take the value right away
+        self.metrics.on_send();
+
+        match self.inner.send((Instant::now(), t)) {
+            Ok(()) => Ok(()),
+            Err(SendError((_, t))) => {
+                self.metrics.sent.fetch_sub(1, Ordering::SeqCst);
+                Err(SendError(t))
+            }
+        }
+    }
+
+    pub fn metrics(&self) -> &Arc<ChannelMetrics> {
+        &self.metrics
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        Sender {
+            inner: self.inner.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    inner: mpsc::Receiver<(Instant, T)>,
+    metrics: Arc<ChannelMetrics>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.inner.try_recv().map(|(sent_at, t)| {
+            self.metrics.on_recv(sent_at);
+            t
+        })
+    }
+
+    /// Receive a value, blocks the current coroutine or thread until one is available
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.inner.recv().map(|(sent_at, t)| {
+            self.metrics.on_recv(sent_at);
+            t
+        })
+    }
+
+    pub fn metrics(&self) -> &Arc<ChannelMetrics> {
+        &self.metrics
+    }
+}
+
+/// Create an instrumented channel pair, registered in the current Scheduler if there is one
+pub fn channel<T, S: Into<String>>(name: S) -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = mpsc::channel();
+    let metrics = Arc::new(ChannelMetrics::new(name.into()));
+
+    if let Some(scheduler) = Scheduler::instance() {
+        scheduler.register_channel(&metrics);
+    }
+
+    let sender = Sender {
+        inner: tx,
+        metrics: metrics.clone(),
+    };
+
+    let receiver = Receiver {
+        inner: rx,
+        metrics: metrics,
+    };
+
+    (sender, receiver)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use scheduler::Scheduler;
+
+    #[test]
+    fn test_instrumented_channel() {
+        Scheduler::new()
+            .run(|| {
+                let (tx, rx) = channel::<u32, _>("pipeline");
+
+                tx.send(1).unwrap();
+                tx.send(2).unwrap();
+                assert_eq!(rx.metrics().snapshot().depth, 2);
+
+                assert_eq!(rx.recv(), Ok(1));
+                assert_eq!(rx.recv(), Ok(2));
+
+                let stats = Scheduler::instance().unwrap().channel_stats();
+                assert_eq!(stats.len(), 1);
+                assert_eq!(stats[0].name, "pipeline");
+                assert_eq!(stats[0].sent, 2);
+                assert_eq!(stats[0].received, 2);
+                assert_eq!(stats[0].depth, 0);
+                assert_eq!(stats[0].max_depth, 2);
+                assert_eq!(stats[0].latency.count(), 2);
+
+                drop(tx);
+                drop(rx);
+                assert!(Scheduler::instance().unwrap().channel_stats().is_empty());
+            })
+            .unwrap();
+    }
+}
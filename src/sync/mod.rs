@@ -23,6 +23,19 @@
 //! Coroutine synchronization
 
 pub use self::mutex::Mutex;
+#[cfg(unix)]
+pub use self::notify::Notify;
+pub use self::sequencer::Sequencer;
+pub use self::waitgroup::WaitGroup;
 
 pub mod mutex;
 pub mod mpsc;
+#[cfg(unix)]
+pub mod notify;
+pub mod instrumented;
+pub mod bus;
+pub mod waitgroup;
+pub mod priority;
+pub mod sequencer;
+
+mod park;
@@ -21,90 +21,516 @@
 //  DEALINGS IN THE SOFTWARE.
 
 //! Multi-producer, single-consumer FIFO queue communication primitives.
+//!
+//! Both ends of the channels could be used inside and outside of coroutines, in any
+//! combination:
+//!
+//! | Sender    | Receiver  | Blocking side waits by                |
+//! |-----------|-----------|---------------------------------------|
+//! | coroutine | coroutine | suspending the coroutine              |
+//! | thread    | coroutine | suspending the receiving coroutine    |
+//! | coroutine | thread    | parking the receiving thread          |
+//! | thread    | thread    | parking the thread                    |
+//!
+//! A thread blocked in `recv` or in `SyncSender::send` is parked on a condition variable
+//! and woken up by the other side, whether that one runs in a coroutine or not.
+//!
+//! Values are received in the order they have been sent. Coroutines blocked in
+//! `SyncSender::send` retry in the order they blocked, even if other Processors steal them
+//! and resume the later ones first: each of them gets a `sync::Sequencer` ticket when it
+//! blocks and waits for the earlier ones to retry before retrying itself.
+//!
+//! `Select` waits on several `Receiver`s at once:
+//!
+//! ```ignore
+//! let mut sel = Select::new();
+//! let a = sel.add(&rx_a);
+//! let b = sel.add(&rx_b);
+//!
+//! let ready = sel.wait();
+//! if ready == a {
+//!     let v = rx_a.try_recv();
+//! } else if ready == b {
+//!     let v = rx_b.try_recv();
+//! }
+//! ```
 
 pub use std::sync::mpsc::{TrySendError, SendError, TryRecvError, RecvError};
 
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+use std::fmt;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::park::{park, park_cancellable, park_timeout, wake_all, Wakeup};
+use super::sequencer::{Sequencer, Ticket};
+
+/// Error returned by `Receiver::recv_timeout`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    /// No value has been sent in time
+    Timeout,
+    /// All senders are gone and the channel is empty
+    Disconnected,
+}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.description().fmt(f)
+    }
+}
+
+impl Error for RecvTimeoutError {
+    fn description(&self) -> &str {
+        match *self {
+            RecvTimeoutError::Timeout => "timed out waiting on channel",
+            RecvTimeoutError::Disconnected => "channel is empty and sending half is closed",
+        }
+    }
+}
+
+/// Error returned by `SyncSender::send_timeout`, gives the value back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendTimeoutError<T> {
+    /// The channel stayed full until the timeout
+    Timeout(T),
+    /// The receiver is gone
+    Disconnected(T),
+}
+
+// Remove the receiver parked with `slot` from the wait list, returns `None` if it has been
+// woken up already
+fn cancel_recv<T>(wait_list: &Mutex<VecDeque<Waiter<T>>>,
+                  slot: *mut Option<T>)
+                  -> Option<Wakeup> {
+    let mut wait_list = wait_list.lock().unwrap();
+
+    let pos = wait_list.iter().position(|waiter| {
+        match *waiter {
+            Waiter::Recv { slot: s, .. } => s == slot,
+            _ => false,
+        }
+    });
+
+    match pos.and_then(|pos| wait_list.remove(pos)) {
+        Some(Waiter::Recv { wakeup, .. }) => Some(wakeup),
+        _ => None,
+    }
+}
+
+// Remove the parked sender from the wait list, returns `None` if it has been woken up already
+fn cancel_send(wait_list: &Mutex<VecDeque<Wakeup>>, id: usize) -> Option<Wakeup> {
+    let mut wait_list = wait_list.lock().unwrap();
+    let pos = wait_list.iter().position(|w| w.id() == id);
+    pos.and_then(|pos| wait_list.remove(pos))
+}
+
+enum Waiter<T> {
+    // A parked receiver. Senders may hand a value over directly into its `slot`,
+    // which lives on the receiver's stack, instead of going through the queue.
+    Recv {
+        wakeup: Wakeup,
+        slot: *mut Option<T>,
+    },
+    // A `Select` watching the channel, values are still queued for it
+    Select(Arc<Signal>),
+}
+
+impl<T> Waiter<T> {
+    // Give the value to the receiver and resume it. A `Select` is notified after the value
+    // has been passed to `queue`.
+    fn hand_over<E, F>(self, t: T, queue: F) -> Result<(), E>
+        where F: FnOnce(T) -> Result<(), E>
+    {
+        match self {
+            Waiter::Recv { wakeup, slot } => {
+                unsafe {
+                    *slot = Some(t);
+                }
 
-use coroutine::Handle;
-use runtime::Processor;
-use scheduler::Scheduler;
+                wakeup.wake();
+                Ok(())
+            }
+            Waiter::Select(signal) => {
+                let r = queue(t);
+                signal.fire();
+                r
+            }
+        }
+    }
+}
+
+// Resume the waiters to observe the disconnection
+fn wake_waiters<T, I>(waiters: I)
+    where I: Iterator<Item = Waiter<T>>
+{
+    wake_all(waiters.filter_map(|waiter| {
+        match waiter {
+            Waiter::Recv { wakeup, .. } => Some(wakeup),
+            Waiter::Select(signal) => {
+                signal.fire();
+                None
+            }
+        }
+    }));
+}
+
+// Wakes up a `Select` once, by whichever of its channels becomes ready first
+struct Signal {
+    wakeup: Mutex<Option<Wakeup>>,
+}
+
+impl Signal {
+    fn fire(&self) {
+        let wakeup = self.wakeup.lock().unwrap().take();
+        if let Some(wakeup) = wakeup {
+            wakeup.wake();
+        }
+    }
+}
 
-#[derive(Clone)]
 pub struct Sender<T> {
     inner: mpsc::Sender<T>,
 
-    wait_list: Arc<Mutex<VecDeque<Handle>>>,
+    wait_list: Arc<Mutex<VecDeque<Waiter<T>>>>,
+    // Number of the alive senders, the receiver observes the disconnection when it drops to 0
+    senders: Arc<AtomicUsize>,
 }
 
 unsafe impl<T: Send> Send for Sender<T> {}
 
 impl<T> Sender<T> {
+    /// Send a value, never blocks
     pub fn send(&self, t: T) -> Result<(), SendError<T>> {
-        match self.inner.send(t) {
-            Ok(..) => {
-                let mut wait_list = self.wait_list.lock().unwrap();
-                if let Some(coro) = wait_list.pop_front() {
-                    Scheduler::ready(coro);
-                }
-                Ok(())
+        // NOTE: The queue must be empty if there is a parked receiver,
+        //       so handing over the value directly preserves the ordering.
+        let mut wait_list = self.wait_list.lock().unwrap();
+
+        match wait_list.pop_front() {
+            Some(waiter) => waiter.hand_over(t, |t| self.inner.send(t)),
+            None => self.inner.send(t),
+        }
+    }
+
+    /// Create a handle which does not keep the channel connected
+    pub fn downgrade(&self) -> WeakSender<T> {
+        WeakSender {
+            inner: self.inner.clone(),
+            wait_list: self.wait_list.clone(),
+            senders: self.senders.clone(),
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.senders.fetch_add(1, Ordering::SeqCst);
+
+        Sender {
+            inner: self.inner.clone(),
+            wait_list: self.wait_list.clone(),
+            senders: self.senders.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // Wake up the parked receivers to observe the disconnection
+            let mut wait_list = self.wait_list.lock().unwrap();
+            wake_waiters(wait_list.drain(..));
+        }
+    }
+}
+
+/// A sender which does not count as alive, see `Sender::downgrade`.
+///
+/// The receiver observes the disconnection once all `Sender`s are gone,
+/// even if there are `WeakSender`s left.
+pub struct WeakSender<T> {
+    inner: mpsc::Sender<T>,
+
+    wait_list: Arc<Mutex<VecDeque<Waiter<T>>>>,
+    senders: Arc<AtomicUsize>,
+}
+
+unsafe impl<T: Send> Send for WeakSender<T> {}
+
+impl<T> WeakSender<T> {
+    /// Get a `Sender` again, unless all of them are gone already
+    pub fn upgrade(&self) -> Option<Sender<T>> {
+        let mut count = self.senders.load(Ordering::SeqCst);
+        loop {
+            if count == 0 {
+                return None;
+            }
+
+            let prev = self.senders.compare_and_swap(count, count + 1, Ordering::SeqCst);
+            if prev == count {
+                break;
             }
-            Err(err) => Err(err),
+            count = prev;
+        }
+
+        Some(Sender {
+            inner: self.inner.clone(),
+            wait_list: self.wait_list.clone(),
+            senders: self.senders.clone(),
+        })
+    }
+}
+
+impl<T> Clone for WeakSender<T> {
+    fn clone(&self) -> WeakSender<T> {
+        WeakSender {
+            inner: self.inner.clone(),
+            wait_list: self.wait_list.clone(),
+            senders: self.senders.clone(),
         }
     }
 }
 
 pub struct Receiver<T> {
     inner: mpsc::Receiver<T>,
+    // Value taken from the queue by `Select` to find out whether the channel is ready
+    buffered: RefCell<Option<T>>,
 
-    wait_list: Arc<Mutex<VecDeque<Handle>>>,
+    wait_list: Arc<Mutex<VecDeque<Waiter<T>>>>,
+    senders: Arc<AtomicUsize>,
 }
 
 unsafe impl<T: Send> Send for Receiver<T> {}
 
 impl<T> Receiver<T> {
+    /// Number of `Sender`s alive, not counting `WeakSender`s
+    pub fn sender_count(&self) -> usize {
+        self.senders.load(Ordering::SeqCst)
+    }
+
     pub fn try_recv(&self) -> Result<T, TryRecvError> {
-        self.inner.try_recv()
+        if let Some(v) = self.buffered.borrow_mut().take() {
+            return Ok(v);
+        }
+
+        match self.inner.try_recv() {
+            Err(TryRecvError::Empty) if self.senders.load(Ordering::SeqCst) == 0 => {
+                // The last Sender may have sent a value right before it has been dropped
+                match self.inner.try_recv() {
+                    Err(TryRecvError::Empty) => Err(TryRecvError::Disconnected),
+                    r => r,
+                }
+            }
+            r => r,
+        }
     }
 
+    /// Receive a value, blocks the current coroutine or thread until one is available
     pub fn recv(&self) -> Result<T, RecvError> {
-        if let Some(mut processor) = Processor::current() {
-            let processor_ptr = unsafe { processor.mut_ptr() };
-            let mut r = self.try_recv();
+        let mut r = self.try_recv();
+        let mut slot = None;
+
+        loop {
+            // 1. Try receive
+            match r {
+                Ok(v) => return Ok(v),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+            }
+
+            // 2. Block
+            let slot_ptr: *mut Option<T> = &mut slot;
+            let cancel = |_: usize| cancel_recv(&self.wait_list, slot_ptr);
+            park_cancellable(|wakeup| {
+                // 3. Lock the wait list
+                let mut wait_list = self.wait_list.lock().unwrap();
+
+                // 4. Try to receive again, to ensure no one sent items into the queue while
+                //    we are locking the wait list
+                r = self.try_recv();
 
-            loop {
-                // 1. Try receive
                 match r {
-                    Ok(v) => return Ok(v),
-                    Err(TryRecvError::Empty) => {}
-                    Err(TryRecvError::Disconnected) => return Err(RecvError),
+                    Err(TryRecvError::Empty) => {
+                        // 5.1. Push ourselves into the wait list
+                        wait_list.push_back(Waiter::Recv {
+                            wakeup: wakeup,
+                            slot: slot_ptr,
+                        });
+                    }
+                    _ => {
+                        // 5.2. Success!
+                        wakeup.wake();
+                    }
                 }
+            }, cancel);
 
-                // 2. Yield
-                processor.take_current_coroutine(|coro| {
-                    // 3. Lock the wait list
-                    let mut wait_list = self.wait_list.lock().unwrap();
+            // 6. The value may have been handed over directly by the sender
+            if let Some(v) = slot.take() {
+                return Ok(v);
+            }
+        }
+    }
 
-                    // 4. Try to receive again, to ensure no one sent items into the queue while
-                    //    we are locking the wait list
-                    r = self.try_recv();
+    /// Receive a value, blocks the current coroutine or thread until one is available or
+    /// until `timeout` has passed
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut r = self.try_recv();
+        let mut slot = None;
+
+        loop {
+            match r {
+                Ok(v) => return Ok(v),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+            }
 
-                    match r {
-                        Err(TryRecvError::Empty) => {
-                            // 5.1. Push ourselves into the wait list
-                            wait_list.push_back(coro);
-                        }
-                        _ => {
-                            // 5.2. Success!
-                            unsafe { &mut *processor_ptr }.ready(coro);
-                        }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+
+            let slot_ptr: *mut Option<T> = &mut slot;
+            park_timeout(deadline - now,
+                         |wakeup| {
+                             let mut wait_list = self.wait_list.lock().unwrap();
+                             r = self.try_recv();
+
+                             match r {
+                                 Err(TryRecvError::Empty) => {
+                                     wait_list.push_back(Waiter::Recv {
+                                         wakeup: wakeup,
+                                         slot: slot_ptr,
+                                     });
+                                 }
+                                 _ => wakeup.wake(),
+                             }
+                         },
+                         |_| cancel_recv(&self.wait_list, slot_ptr).is_some());
+
+            if let Some(v) = slot.take() {
+                return Ok(v);
+            }
+        }
+    }
+}
+
+// A channel `Select` could wait on
+trait Selectable {
+    // Whether a value or the disconnection could be received without blocking
+    fn is_ready(&self) -> bool;
+
+    // Let `signal` be fired when the channel becomes ready, returns true instead if it
+    // is ready already
+    fn watch(&self, signal: &Arc<Signal>) -> bool;
+
+    fn unwatch(&self, signal: &Arc<Signal>);
+}
+
+impl<T> Selectable for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        let mut buffered = self.buffered.borrow_mut();
+        if buffered.is_some() {
+            return true;
+        }
+
+        match self.inner.try_recv() {
+            Ok(v) => {
+                *buffered = Some(v);
+                true
+            }
+            Err(TryRecvError::Empty) => self.senders.load(Ordering::SeqCst) == 0,
+            Err(TryRecvError::Disconnected) => true,
+        }
+    }
+
+    fn watch(&self, signal: &Arc<Signal>) -> bool {
+        let mut wait_list = self.wait_list.lock().unwrap();
+        if self.is_ready() {
+            return true;
+        }
+
+        wait_list.push_back(Waiter::Select(signal.clone()));
+        false
+    }
+
+    fn unwatch(&self, signal: &Arc<Signal>) {
+        let signal = &**signal as *const Signal;
+        self.wait_list.lock().unwrap().retain(|waiter| {
+            match *waiter {
+                Waiter::Select(ref s) => &**s as *const Signal != signal,
+                _ => true,
+            }
+        });
+    }
+}
+
+/// Waits on several `Receiver`s at once, see the module documentation
+pub struct Select<'a> {
+    receivers: Vec<&'a Selectable>,
+    // Where to start looking for a ready channel, so that busy ones can't starve the others
+    next: Cell<usize>,
+}
+
+impl<'a> Select<'a> {
+    pub fn new() -> Select<'a> {
+        Select {
+            receivers: Vec::new(),
+            next: Cell::new(0),
+        }
+    }
+
+    /// Add a receiver, returns the index `wait` reports it with
+    pub fn add<T>(&mut self, receiver: &'a Receiver<T>) -> usize {
+        self.receivers.push(receiver);
+        self.receivers.len() - 1
+    }
+
+    /// Index of a receiver which is ready to receive a value, or to report the
+    /// disconnection, without blocking
+    pub fn try_wait(&self) -> Option<usize> {
+        let len = self.receivers.len();
+        let start = self.next.get();
+
+        for offset in 0..len {
+            let idx = (start + offset) % len;
+            if self.receivers[idx].is_ready() {
+                self.next.set(idx + 1);
+                return Some(idx);
+            }
+        }
+        None
+    }
+
+    /// Block the current coroutine or thread until one of the receivers is ready, returns
+    /// its index. Its `try_recv` is guaranteed not to return `Empty` afterwards.
+    pub fn wait(&self) -> usize {
+        assert!(!self.receivers.is_empty(), "Select has no receivers to wait on");
+
+        loop {
+            if let Some(idx) = self.try_wait() {
+                return idx;
+            }
+
+            let signal = Arc::new(Signal { wakeup: Mutex::new(None) });
+            park(|wakeup| {
+                *signal.wakeup.lock().unwrap() = Some(wakeup);
+
+                for receiver in self.receivers.iter() {
+                    if receiver.watch(&signal) {
+                        signal.fire();
+                        break;
                     }
-                });
+                }
+            });
+
+            for receiver in self.receivers.iter() {
+                receiver.unwatch(&signal);
             }
-        } else {
-            self.inner.recv()
         }
     }
 }
@@ -113,168 +539,412 @@ impl<T> Receiver<T> {
 pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
     let (tx, rx) = mpsc::channel();
     let wait_list = Arc::new(Mutex::new(VecDeque::new()));
+    let senders = Arc::new(AtomicUsize::new(1));
 
-    let sender = Sender {
-        inner: tx,
+    let receiver = Receiver {
+        inner: rx,
+        buffered: RefCell::new(None),
         wait_list: wait_list.clone(),
+        senders: senders.clone(),
     };
 
-    let receiver = Receiver {
-        inner: rx,
+    let sender = Sender {
+        inner: tx,
         wait_list: wait_list,
+        senders: senders,
     };
 
     (sender, receiver)
 }
 
-#[derive(Clone)]
+// Watermarks of a bounded channel, see `sync_channel_with_watermarks`
+struct FlowControl {
+    low: usize,
+    high: usize,
+    // Number of queued values, may be ahead of the queue while a value is being sent
+    len: AtomicUsize,
+    congested: AtomicBool,
+    disconnected: AtomicBool,
+    ready_wait_list: Mutex<VecDeque<Wakeup>>,
+}
+
+impl FlowControl {
+    // Count a value about to be queued
+    fn enqueue(&self) -> usize {
+        self.len.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    // The value has been queued, or not if `queued` is false
+    fn enqueued(&self, len: usize, queued: bool) {
+        if !queued {
+            self.len.fetch_sub(1, Ordering::SeqCst);
+        } else if len >= self.high {
+            self.congested.store(true, Ordering::SeqCst);
+
+            // The receiver may have caught up before it could observe the congestion
+            if self.len.load(Ordering::SeqCst) <= self.low {
+                self.relieve();
+            }
+        }
+    }
+
+    fn dequeued(&self) {
+        let len = self.len.fetch_sub(1, Ordering::SeqCst) - 1;
+        if len <= self.low && self.congested.load(Ordering::SeqCst) {
+            self.relieve();
+        }
+    }
+
+    fn relieve(&self) {
+        let mut ready_wait_list = self.ready_wait_list.lock().unwrap();
+        self.congested.store(false, Ordering::SeqCst);
+        wake_all(ready_wait_list.drain(..));
+    }
+}
+
 pub struct SyncSender<T> {
     inner: mpsc::SyncSender<T>,
 
-    send_wait_list: Arc<Mutex<VecDeque<Handle>>>,
-    recv_wait_list: Arc<Mutex<VecDeque<Handle>>>,
+    send_wait_list: Arc<Mutex<VecDeque<Wakeup>>>,
+    // Orders the retries of the senders woken up from `send_wait_list`
+    send_order: Sequencer,
+    recv_wait_list: Arc<Mutex<VecDeque<Waiter<T>>>>,
+    // Number of the alive senders, the receiver observes the disconnection when it drops to 0
+    senders: Arc<AtomicUsize>,
+    flow: Option<Arc<FlowControl>>,
 }
 
 unsafe impl<T: Send> Send for SyncSender<T> {}
 
 impl<T> SyncSender<T> {
     pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
-        match self.inner.try_send(t) {
-            Ok(..) => {
-                let mut recv_wait_list = self.recv_wait_list.lock().unwrap();
-                if let Some(coro) = recv_wait_list.pop_front() {
-                    Scheduler::ready(coro);
-                }
-                Ok(())
+        let mut recv_wait_list = self.recv_wait_list.lock().unwrap();
+
+        match recv_wait_list.pop_front() {
+            Some(waiter) => waiter.hand_over(t, |t| self.enqueue(t)),
+            None => self.enqueue(t),
+        }
+    }
+
+    fn enqueue(&self, t: T) -> Result<(), TrySendError<T>> {
+        match self.flow {
+            Some(ref flow) => {
+                let len = flow.enqueue();
+                let r = self.inner.try_send(t);
+                flow.enqueued(len, r.is_ok());
+                r
             }
-            Err(err) => Err(err),
+            None => self.inner.try_send(t),
         }
     }
 
-    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
-        if let Some(mut processor) = Processor::current() {
-            let processor_ptr = unsafe { processor.mut_ptr() };
-            let mut r = self.try_send(t);
+    /// Whether the channel has filled up to its high watermark and has not been drained down
+    /// to its low watermark since, see `sync_channel_with_watermarks`
+    pub fn is_congested(&self) -> bool {
+        self.flow.as_ref().map_or(false, |flow| flow.congested.load(Ordering::SeqCst))
+    }
 
-            loop {
-                match r {
-                    Ok(..) => return Ok(()),
-                    Err(TrySendError::Disconnected(e)) => return Err(SendError(e)),
-                    Err(TrySendError::Full(t)) => {
-                        r = processor.take_current_coroutine(move |coro| {
-                            let mut send_wait_list = self.send_wait_list.lock().unwrap();
-                            let r = self.try_send(t);
-
-                            match r {
-                                Err(TrySendError::Full(..)) => {
-                                    send_wait_list.push_back(coro);
-                                }
-                                _ => {
-                                    unsafe { &mut *processor_ptr }.ready(coro);
-                                }
-                            };
-
-                            r
-                        });
-                    }
-                }
+    /// Block the current coroutine or thread while the channel is congested, e.g. before
+    /// reading the next message from a socket. Returns an error if the receiver is gone.
+    ///
+    /// Returns right away for channels created without watermarks.
+    pub fn ready(&self) -> Result<(), SendError<()>> {
+        let flow = match self.flow {
+            Some(ref flow) => flow,
+            None => return Ok(()),
+        };
+
+        while flow.congested.load(Ordering::SeqCst) {
+            if flow.disconnected.load(Ordering::SeqCst) {
+                return Err(SendError(()));
             }
+
+            let cancel = |id: usize| cancel_send(&flow.ready_wait_list, id);
+            park_cancellable(|wakeup| {
+                let mut ready_wait_list = flow.ready_wait_list.lock().unwrap();
+                if flow.congested.load(Ordering::SeqCst) &&
+                   !flow.disconnected.load(Ordering::SeqCst) {
+                    ready_wait_list.push_back(wakeup);
+                } else {
+                    drop(ready_wait_list);
+                    wakeup.wake();
+                }
+            }, cancel);
+        }
+
+        if flow.disconnected.load(Ordering::SeqCst) {
+            Err(SendError(()))
         } else {
-            match self.inner.send(t) {
-                Ok(..) => {
-                    let mut recv_wait_list = self.recv_wait_list.lock().unwrap();
-                    if let Some(coro) = recv_wait_list.pop_front() {
-                        Scheduler::ready(coro);
+            Ok(())
+        }
+    }
+
+    /// Send a value, blocks the current coroutine or thread while the channel is full
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        let mut r = self.try_send(t);
+        // Place among the blocked senders, dropped unused if the coroutine is cancelled
+        let mut ticket = None;
+
+        loop {
+            match r {
+                Ok(..) => return Ok(()),
+                Err(TrySendError::Disconnected(e)) => return Err(SendError(e)),
+                Err(TrySendError::Full(t)) => {
+                    let turn = ticket.take().map(Ticket::wait);
+                    let next_ticket = &mut ticket;
+                    let cancel = |id: usize| cancel_send(&self.send_wait_list, id);
+                    r = park_cancellable(move |wakeup| {
+                        let mut send_wait_list = self.send_wait_list.lock().unwrap();
+                        let r = self.try_send(t);
+                        drop(turn);
+
+                        match r {
+                            Err(TrySendError::Full(..)) => {
+                                *next_ticket = Some(self.send_order.ticket());
+                                send_wait_list.push_back(wakeup);
+                            }
+                            _ => {
+                                wakeup.wake();
+                            }
+                        };
+
+                        r
+                    }, cancel);
+                }
+            }
+        }
+    }
+
+    /// Send a value, blocks the current coroutine or thread while the channel is full, but
+    /// at most for `timeout`
+    pub fn send_timeout(&self, t: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        let deadline = Instant::now() + timeout;
+        let mut r = self.try_send(t);
+        // Dropped unused when timing out, see `send`
+        let mut ticket = None;
+
+        loop {
+            match r {
+                Ok(..) => return Ok(()),
+                Err(TrySendError::Disconnected(t)) => return Err(SendTimeoutError::Disconnected(t)),
+                Err(TrySendError::Full(t)) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(SendTimeoutError::Timeout(t));
                     }
-                    Ok(())
+
+                    let turn = ticket.take().map(Ticket::wait);
+                    let next_ticket = &mut ticket;
+                    r = park_timeout(deadline - now,
+                                     move |wakeup| {
+                                         let mut send_wait_list = self.send_wait_list
+                                                                      .lock()
+                                                                      .unwrap();
+                                         let r = self.try_send(t);
+                                         drop(turn);
+
+                                         match r {
+                                             Err(TrySendError::Full(..)) => {
+                                                 *next_ticket = Some(self.send_order.ticket());
+                                                 send_wait_list.push_back(wakeup);
+                                             }
+                                             _ => wakeup.wake(),
+                                         }
+
+                                         r
+                                     },
+                                     |id| cancel_send(&self.send_wait_list, id).is_some());
                 }
-                Err(err) => Err(err),
             }
         }
     }
 }
 
+impl<T> Clone for SyncSender<T> {
+    fn clone(&self) -> SyncSender<T> {
+        self.senders.fetch_add(1, Ordering::SeqCst);
+
+        SyncSender {
+            inner: self.inner.clone(),
+            send_wait_list: self.send_wait_list.clone(),
+            send_order: self.send_order.clone(),
+            recv_wait_list: self.recv_wait_list.clone(),
+            senders: self.senders.clone(),
+            flow: self.flow.clone(),
+        }
+    }
+}
+
+impl<T> Drop for SyncSender<T> {
+    fn drop(&mut self) {
+        if self.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let mut recv_wait_list = self.recv_wait_list.lock().unwrap();
+            wake_waiters(recv_wait_list.drain(..));
+        }
+    }
+}
+
 pub struct SyncReceiver<T> {
     inner: mpsc::Receiver<T>,
 
-    send_wait_list: Arc<Mutex<VecDeque<Handle>>>,
-    recv_wait_list: Arc<Mutex<VecDeque<Handle>>>,
+    send_wait_list: Arc<Mutex<VecDeque<Wakeup>>>,
+    recv_wait_list: Arc<Mutex<VecDeque<Waiter<T>>>>,
+    senders: Arc<AtomicUsize>,
+    flow: Option<Arc<FlowControl>>,
 }
 
 unsafe impl<T: Send> Send for SyncReceiver<T> {}
 
 impl<T> SyncReceiver<T> {
+    /// Number of `SyncSender`s alive
+    pub fn sender_count(&self) -> usize {
+        self.senders.load(Ordering::SeqCst)
+    }
+
     pub fn try_recv(&self) -> Result<T, TryRecvError> {
-        match self.inner.try_recv() {
-            Ok(t) => {
-                let mut send_wait_list = self.send_wait_list.lock().unwrap();
-                if let Some(coro) = send_wait_list.pop_front() {
-                    Scheduler::ready(coro);
+        let r = self.try_recv_queue();
+        if r.is_ok() {
+            self.wake_sender();
+        }
+        r
+    }
+
+    fn try_recv_queue(&self) -> Result<T, TryRecvError> {
+        let r = match self.inner.try_recv() {
+            Err(TryRecvError::Empty) if self.senders.load(Ordering::SeqCst) == 0 => {
+                // The last SyncSender may have sent a value right before it has been dropped
+                match self.inner.try_recv() {
+                    Err(TryRecvError::Empty) => Err(TryRecvError::Disconnected),
+                    r => r,
                 }
-                Ok(t)
             }
-            Err(err) => Err(err),
+            r => r,
+        };
+
+        if r.is_ok() {
+            if let Some(ref flow) = self.flow {
+                flow.dequeued();
+            }
+        }
+        r
+    }
+
+    fn wake_sender(&self) {
+        let mut send_wait_list = self.send_wait_list.lock().unwrap();
+        if let Some(wakeup) = send_wait_list.pop_front() {
+            wakeup.wake();
         }
     }
 
+    /// Receive a value, blocks the current coroutine or thread until one is available
     pub fn recv(&self) -> Result<T, RecvError> {
-        if let Some(mut processor) = Processor::current() {
-            let processor_ptr = unsafe { processor.mut_ptr() };
-            let mut r = self.try_recv();
+        let mut r = self.try_recv();
+        let mut slot = None;
+
+        loop {
+            match r {
+                Ok(v) => return Ok(v),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+            }
 
-            loop {
-                match r {
-                    Ok(v) => return Ok(v),
-                    Err(TryRecvError::Empty) => {}
-                    Err(TryRecvError::Disconnected) => return Err(RecvError),
-                }
+            let slot_ptr: *mut Option<T> = &mut slot;
+            let cancel = |_: usize| cancel_recv(&self.recv_wait_list, slot_ptr);
+            park_cancellable(|wakeup| {
+                let mut recv_wait_list = self.recv_wait_list.lock().unwrap();
 
-                processor.take_current_coroutine(|coro| {
-                    let mut recv_wait_list = self.recv_wait_list.lock().unwrap();
+                // NOTE: Must not lock the send wait list while holding this one,
+                //       the senders lock them in the opposite order
+                r = self.try_recv_queue();
 
-                    r = self.try_recv();
+                match r {
+                    Err(TryRecvError::Empty) => {
+                        recv_wait_list.push_back(Waiter::Recv {
+                            wakeup: wakeup,
+                            slot: slot_ptr,
+                        });
+                        drop(recv_wait_list);
 
-                    match r {
-                        Err(TryRecvError::Empty) => {
-                            recv_wait_list.push_back(coro);
-                        }
-                        _ => {
-                            unsafe { &mut *processor_ptr }.ready(coro);
-                        }
+                        // A sender blocked on a zero-capacity channel could only make
+                        // progress by handing its value over to us, let it retry.
+                        self.wake_sender();
                     }
-                });
-            }
-        } else {
-            match self.inner.recv() {
-                Ok(t) => {
-                    let mut send_wait_list = self.send_wait_list.lock().unwrap();
-                    if let Some(coro) = send_wait_list.pop_front() {
-                        Scheduler::ready(coro);
+                    _ => {
+                        drop(recv_wait_list);
+                        wakeup.wake();
                     }
-                    Ok(t)
                 }
-                Err(err) => Err(err),
+            }, cancel);
+
+            if let Some(v) = slot.take() {
+                return Ok(v);
+            }
+
+            if r.is_ok() {
+                self.wake_sender();
             }
         }
     }
 }
 
+impl<T> Drop for SyncReceiver<T> {
+    fn drop(&mut self) {
+        if let Some(ref flow) = self.flow {
+            flow.disconnected.store(true, Ordering::SeqCst);
+            flow.relieve();
+        }
+    }
+}
+
 /// Create a bounded channel pair
 pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, SyncReceiver<T>) {
+    new_sync_channel(bound, None)
+}
+
+/// Create a bounded channel pair whose senders could pause producing, e.g. stop reading from
+/// a socket, before the channel is full. The channel is congested once `high` values are
+/// queued, until the receiver has drained it down to `low` values, see `SyncSender::ready()`.
+pub fn sync_channel_with_watermarks<T>(bound: usize,
+                                       low: usize,
+                                       high: usize)
+                                       -> (SyncSender<T>, SyncReceiver<T>) {
+    assert!(low < high && high <= bound,
+            "Watermarks must satisfy low < high <= bound");
+
+    let flow = Arc::new(FlowControl {
+        low: low,
+        high: high,
+        len: AtomicUsize::new(0),
+        congested: AtomicBool::new(false),
+        disconnected: AtomicBool::new(false),
+        ready_wait_list: Mutex::new(VecDeque::new()),
+    });
+    new_sync_channel(bound, Some(flow))
+}
+
+fn new_sync_channel<T>(bound: usize,
+                       flow: Option<Arc<FlowControl>>)
+                       -> (SyncSender<T>, SyncReceiver<T>) {
     let (tx, rx) = mpsc::sync_channel(bound);
     let send_wait_list = Arc::new(Mutex::new(VecDeque::new()));
     let recv_wait_list = Arc::new(Mutex::new(VecDeque::new()));
+    let senders = Arc::new(AtomicUsize::new(1));
 
-    let sender = SyncSender {
-        inner: tx,
+    let receiver = SyncReceiver {
+        inner: rx,
         send_wait_list: send_wait_list.clone(),
         recv_wait_list: recv_wait_list.clone(),
+        senders: senders.clone(),
+        flow: flow.clone(),
     };
 
-    let receiver = SyncReceiver {
-        inner: rx,
+    let sender = SyncSender {
+        inner: tx,
         send_wait_list: send_wait_list,
+        send_order: Sequencer::new(),
         recv_wait_list: recv_wait_list,
+        senders: senders,
+        flow: flow,
     };
 
     (sender, receiver)
@@ -283,6 +953,7 @@ pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, SyncReceiver<T>) {
 #[cfg(test)]
 mod test {
     use std::sync::{Arc, Barrier};
+    use std::sync::atomic::{AtomicBool, Ordering};
     use std::thread;
     use std::time::Duration;
 
@@ -422,4 +1093,292 @@ mod test {
         assert_eq!(tx1.send(1), Ok(()));
         assert_eq!(rx2.recv(), Ok(2));
     }
+
+    #[test]
+    fn test_sync_channel_send_order() {
+        Scheduler::new()
+            .with_workers(4)
+            .run(|| {
+                let (tx, rx) = sync_channel(1);
+                tx.send(0).unwrap();
+
+                // Blocked one after another
+                let handles: Vec<_> = (1..20)
+                                          .map(|i| {
+                                              let sender = tx.clone();
+                                              let hdl = Scheduler::spawn(move || {
+                                                  sender.send(i).unwrap();
+                                              });
+                                              while tx.send_wait_list.lock().unwrap().len() < i {
+                                                  Scheduler::sched();
+                                              }
+                                              hdl
+                                          })
+                                          .collect();
+
+                // Every receive wakes up the next sender, stolen ones must not overtake it
+                for i in 0..20 {
+                    assert_eq!(rx.recv(), Ok(i));
+                }
+                for hdl in handles {
+                    hdl.join().unwrap();
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_sync_channel_watermarks() {
+        Scheduler::new()
+            .run(move || {
+                let (tx, rx) = sync_channel_with_watermarks(8, 2, 6);
+                for i in 0..6 {
+                    assert_eq!(tx.send(i), Ok(()));
+                }
+                assert!(tx.is_congested());
+
+                let resumed = Arc::new(AtomicBool::new(false));
+                let producer = {
+                    let resumed = resumed.clone();
+                    Scheduler::spawn(move || {
+                        assert_eq!(tx.ready(), Ok(()));
+                        resumed.store(true, Ordering::SeqCst);
+
+                        for i in 6..12 {
+                            assert_eq!(tx.send(i), Ok(()));
+                        }
+                        tx.ready()
+                    })
+                };
+
+                // Still above the low watermark
+                for i in 0..3 {
+                    assert_eq!(rx.recv(), Ok(i));
+                }
+                Scheduler::sched();
+                assert!(!resumed.load(Ordering::SeqCst));
+
+                assert_eq!(rx.recv(), Ok(3));
+                ::sleep(Duration::from_millis(20));
+                assert!(resumed.load(Ordering::SeqCst));
+
+                // Congested again, until the receiver is gone
+                drop(rx);
+                assert_eq!(producer.join().unwrap(), Err(SendError(())));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_sync_channel_rendezvous() {
+        Scheduler::new()
+            .run(move || {
+                let (tx, rx) = sync_channel(0);
+
+                let sender = Scheduler::spawn(move || {
+                    for i in 0..100 {
+                        assert_eq!(tx.send(i), Ok(()));
+                    }
+                });
+
+                for i in 0..100 {
+                    assert_eq!(rx.recv(), Ok(i));
+                }
+                assert_eq!(rx.recv(), Err(RecvError));
+
+                sender.join().unwrap();
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_recv_disconnected() {
+        Scheduler::new()
+            .run(move || {
+                let (tx, rx) = channel::<i32>();
+                let (sync_tx, sync_rx) = sync_channel::<i32>(1);
+
+                Scheduler::spawn(move || {
+                    ::sleep_ms(10);
+                    drop(tx);
+                    drop(sync_tx);
+                });
+
+                assert_eq!(rx.recv(), Err(RecvError));
+                assert_eq!(sync_rx.recv(), Err(RecvError));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_sync_channel_thread_to_coroutine() {
+        const COUNT: usize = 1000;
+
+        for &bound in [0, 1, 16].iter() {
+            let (tx, rx) = sync_channel(bound);
+
+            let senders: Vec<_> = (0..4)
+                                      .map(|_| {
+                                          let tx = tx.clone();
+                                          thread::spawn(move || {
+                                              for i in 0..COUNT {
+                                                  assert_eq!(tx.send(i), Ok(()));
+                                              }
+                                          })
+                                      })
+                                      .collect();
+            drop(tx);
+
+            let received = Scheduler::new()
+                               .with_workers(2)
+                               .run(move || {
+                                   let mut received = 0;
+                                   while let Ok(..) = rx.recv() {
+                                       received += 1;
+                                   }
+                                   received
+                               })
+                               .unwrap();
+
+            assert_eq!(received, 4 * COUNT);
+            for sender in senders {
+                sender.join().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_sync_channel_coroutine_to_thread() {
+        const COUNT: usize = 1000;
+
+        for &bound in [0, 1, 16].iter() {
+            let (tx, rx) = sync_channel(bound);
+
+            let receiver = thread::spawn(move || {
+                let mut received = 0;
+                while let Ok(..) = rx.recv() {
+                    received += 1;
+                }
+                received
+            });
+
+            Scheduler::new()
+                .with_workers(2)
+                .run(move || {
+                    let senders: Vec<_> = (0..4)
+                                              .map(|_| {
+                                                  let tx = tx.clone();
+                                                  Scheduler::spawn(move || {
+                                                      for i in 0..COUNT {
+                                                          assert_eq!(tx.send(i), Ok(()));
+                                                      }
+                                                  })
+                                              })
+                                              .collect();
+
+                    for sender in senders {
+                        sender.join().unwrap();
+                    }
+                })
+                .unwrap();
+
+            assert_eq!(receiver.join().unwrap(), 4 * COUNT);
+        }
+    }
+
+    #[test]
+    fn test_weak_sender() {
+        let (tx, rx) = channel::<u32>();
+        let weak = tx.downgrade();
+        assert_eq!(rx.sender_count(), 1);
+
+        let tx2 = weak.upgrade().unwrap();
+        assert_eq!(rx.sender_count(), 2);
+        tx2.send(1).unwrap();
+        drop(tx2);
+        drop(tx);
+
+        assert_eq!(rx.sender_count(), 0);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(rx.recv(), Ok(1));
+        assert_eq!(rx.recv(), Err(RecvError));
+    }
+
+    #[test]
+    fn test_select() {
+        Scheduler::new()
+            .with_workers(2)
+            .run(|| {
+                let (tx1, rx1) = channel::<u32>();
+                let (tx2, rx2) = channel::<u32>();
+
+                let mut sel = Select::new();
+                let idx1 = sel.add(&rx1);
+                let idx2 = sel.add(&rx2);
+                assert_eq!(sel.try_wait(), None);
+
+                Scheduler::spawn(move || {
+                    Scheduler::sched();
+                    tx2.send(2).unwrap();
+                });
+
+                assert_eq!(sel.wait(), idx2);
+                assert_eq!(rx2.try_recv(), Ok(2));
+
+                // Disconnected channels are ready as well
+                assert_eq!(sel.wait(), idx2);
+                assert_eq!(rx2.try_recv(), Err(TryRecvError::Disconnected));
+
+                tx1.send(1).unwrap();
+                tx1.send(3).unwrap();
+                assert_eq!(sel.try_wait(), Some(idx1));
+                assert_eq!(rx1.recv(), Ok(1));
+                assert_eq!(rx1.recv(), Ok(3));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_recv_timeout() {
+        let (tx, rx) = channel::<u32>();
+        assert_eq!(rx.recv_timeout(Duration::from_millis(10)),
+                   Err(RecvTimeoutError::Timeout));
+
+        Scheduler::new()
+            .run(move || {
+                assert_eq!(rx.recv_timeout(Duration::from_millis(10)),
+                           Err(RecvTimeoutError::Timeout));
+
+                Scheduler::spawn(move || {
+                    tx.send(1).unwrap();
+                });
+                assert_eq!(rx.recv_timeout(Duration::from_secs(10)), Ok(1));
+                assert_eq!(rx.recv_timeout(Duration::from_secs(10)),
+                           Err(RecvTimeoutError::Disconnected));
+
+                // A wakeup racing with the timeout must not cut the next sleep short
+                let slept = Scheduler::instance().unwrap().sleep(Duration::from_millis(10));
+                assert_eq!(slept.unwrap(), Duration::new(0, 0));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_send_timeout() {
+        Scheduler::new()
+            .run(|| {
+                let (tx, rx) = sync_channel::<u32>(1);
+                tx.send(1).unwrap();
+
+                assert_eq!(tx.send_timeout(2, Duration::from_millis(10)),
+                           Err(SendTimeoutError::Timeout(2)));
+
+                Scheduler::spawn(move || {
+                    assert_eq!(rx.recv(), Ok(1));
+                    assert_eq!(rx.recv(), Ok(3));
+                });
+                assert_eq!(tx.send_timeout(3, Duration::from_secs(10)), Ok(()));
+            })
+            .unwrap();
+    }
 }
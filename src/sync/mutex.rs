@@ -20,15 +20,21 @@
 //  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 //  DEALINGS IN THE SOFTWARE.
 
+//! Mutual exclusion between coroutines
+//!
+//! A contended `Mutex` suspends the locking coroutine instead of blocking the thread of
+//! its Processor. Threads outside of Processors may lock it as well, they are parked
+//! until the lock is released.
+
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::cell::UnsafeCell;
+use std::collections::VecDeque;
 use std::fmt;
 use std::error::Error;
 use std::marker::Reflect;
 use std::ops::{Deref, DerefMut};
 
-use scheduler::Scheduler;
-use coroutine::Handle;
+use super::park::{park, Wakeup};
 
 pub type LockResult<G> = Result<G, PoisonError<G>>;
 pub type TryLockResult<G> = Result<G, PoisonError<G>>;
@@ -38,7 +44,7 @@ pub struct Mutex<T> {
     data: UnsafeCell<T>,
     lock: AtomicBool, // false if locked
 
-    wait_list: ::std::sync::Mutex<Vec<Handle>>,
+    wait_list: ::std::sync::Mutex<VecDeque<Wakeup>>,
 }
 
 impl<T> Mutex<T> {
@@ -49,27 +55,29 @@ impl<T> Mutex<T> {
             lock: AtomicBool::new(false),
 
             // Uses Mutex in the standard library
-            wait_list: ::std::sync::Mutex::new(Vec::new()),
+            wait_list: ::std::sync::Mutex::new(VecDeque::new()),
         }
     }
 
-    /// Acquires a mutex, blocking the current thread until it is able to do so.
+    /// Acquires a mutex, blocking the current coroutine (or the current thread outside of
+    /// Processors) until it is able to do so.
     pub fn lock<'a>(&'a self) -> LockResult<Guard<'a, T>> {
         // 1. Try to lock with the atomic boolean
         while self.lock.compare_and_swap(false, true, Ordering::SeqCst) != false {
-            // 2. Otherwise yield
-            Scheduler::take_current_coroutine(|coro| {
+            // 2. Otherwise block
+            park(|wakeup| {
                 // 3. Get the lock of wait list
                 let mut wait_list = self.wait_list.lock().unwrap();
 
                 // 4. Try again to ensure no one is releasing the lock while we
                 //    are trying to add ourselves into the wait list
                 if self.lock.compare_and_swap(false, true, Ordering::SeqCst) == false {
-                    // 4.1. Wow, got the lock!
-                    Scheduler::ready(coro);
+                    // 4.1. Wow, got the lock! Resume and leave the loop
+                    drop(wait_list);
+                    wakeup.wake();
                 } else {
                     // 4.2. Add ourselves into the wait list
-                    wait_list.push(coro);
+                    wait_list.push_back(wakeup);
                 }
             });
         }
@@ -108,14 +116,13 @@ impl<'a, T: 'a> Guard<'a, T> {
 
 impl<'a, T: 'a> Drop for Guard<'a, T> {
     fn drop(&mut self) {
-        {
-            let mut wait_list = self.mutex.wait_list.lock().unwrap();
-            while let Some(coro) = wait_list.pop() {
-                Scheduler::ready(coro);
-            }
-        }
+        self.mutex.lock.store(false, Ordering::SeqCst);
 
-        while self.mutex.lock.compare_and_swap(true, false, Ordering::SeqCst) == true {}
+        // Hand over to the longest waiting one, it has to race with newcomers for the lock
+        let wakeup = self.mutex.wait_list.lock().unwrap().pop_front();
+        if let Some(wakeup) = wakeup {
+            wakeup.wake();
+        }
     }
 }
 
@@ -178,6 +185,7 @@ impl<T: Send + Reflect> Error for PoisonError<T> {
 #[cfg(test)]
 mod test {
     use std::sync::Arc;
+    use std::thread;
 
     use scheduler::Scheduler;
 
@@ -213,4 +221,31 @@ mod test {
 
         assert_eq!(*num.lock().unwrap(), 1000);
     }
+
+    #[test]
+    fn test_mutex_outside_processor() {
+        let num = Arc::new(Mutex::new(0));
+
+        let num_cloned = num.clone();
+        Scheduler::new()
+            .run(move || {
+                let guard = num_cloned.lock().unwrap();
+
+                let num = num_cloned.clone();
+                let thread = thread::spawn(move || {
+                    // Parks the thread until the coroutine releases the lock
+                    *num.lock().unwrap() += 1;
+                });
+
+                Scheduler::instance().unwrap().sleep_ms(50).unwrap();
+                assert_eq!(*guard, 0);
+                drop(guard);
+
+                thread.join().unwrap();
+                assert_eq!(*num_cloned.lock().unwrap(), 1);
+            })
+            .unwrap();
+
+        assert_eq!(*num.lock().unwrap(), 1);
+    }
 }
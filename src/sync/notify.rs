@@ -0,0 +1,252 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! Waking a coroutine from any thread
+//!
+//! A `Notify` is backed by a pipe registered in the event loop, so `notify()` is a single
+//! non-blocking `write(2)` and could be called from any thread, e.g. a callback thread of a
+//! C library, without a channel carrying dummy values. Notifications are coalesced into one
+//! permit like `thread::park()` and `Thread::unpark()`: notifying before the coroutine waits
+//! makes the next wait return immediately, notifying several times wakes it up once.
+//!
+//! ```ignore
+//! let notify = Arc::new(Notify::new().unwrap());
+//!
+//! let n = notify.clone();
+//! thread::spawn(move || n.notify());
+//!
+//! coio::spawn(move || notify.wait().unwrap());
+//! ```
+
+use std::io::{self, ErrorKind};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use libc;
+use mio::EventSet;
+
+use net::unix::{pipe, PipeReader, PipeWriter};
+use runtime::Processor;
+use scheduler::Scheduler;
+use sys;
+
+/// A permit which could be given from any thread and waited for by one coroutine or thread
+/// at a time
+pub struct Notify {
+    reader: PipeReader,
+    writer: PipeWriter,
+    pending: AtomicBool,
+}
+
+impl Notify {
+    pub fn new() -> io::Result<Notify> {
+        let (reader, writer) = try!(pipe());
+
+        Ok(Notify {
+            reader: reader,
+            writer: writer,
+            pending: AtomicBool::new(false),
+        })
+    }
+
+    /// Give the permit, waking up the waiting coroutine or thread
+    pub fn notify(&self) {
+        if self.pending.swap(true, Ordering::SeqCst) {
+            // Not consumed yet, the pipe is readable already
+            return;
+        }
+
+        let byte = 1u8;
+        let ret = unsafe {
+            libc::write(self.writer.as_raw_fd(),
+                        &byte as *const u8 as *const libc::c_void,
+                        1)
+        };
+
+        // A full pipe is just as readable
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != ErrorKind::WouldBlock {
+                error!("Notify failed to write into the pipe: {}", err);
+            }
+        }
+    }
+
+    /// Wait until the permit is given and consume it
+    pub fn wait(&self) -> io::Result<()> {
+        self.wait_timeout_opt(None).map(|_| ())
+    }
+
+    /// Like `wait`, but gives up after `timeout`. Returns whether the permit has been consumed.
+    pub fn wait_timeout(&self, timeout: Duration) -> io::Result<bool> {
+        self.wait_timeout_opt(Some(timeout))
+    }
+
+    fn wait_timeout_opt(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        loop {
+            // Bytes of earlier notifications may be left over, they must not wake us up again
+            self.drain();
+
+            if self.pending.swap(false, Ordering::SeqCst) {
+                return Ok(true);
+            }
+
+            if !try!(self.wait_readable(timeout)) {
+                return Ok(self.pending.swap(false, Ordering::SeqCst));
+            }
+        }
+    }
+
+    // Returns false if the timeout has expired
+    fn wait_readable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        if Processor::current().is_some() {
+            let scheduler = Scheduler::instance().unwrap();
+            return match scheduler.wait_event_timeout(&*self.reader,
+                                                      EventSet::readable(),
+                                                      timeout) {
+                Ok(..) => Ok(true),
+                Err(ref err) if err.kind() == ErrorKind::TimedOut => Ok(false),
+                Err(err) => Err(err),
+            };
+        }
+
+        let timeout_ms = timeout.map_or(-1, |t| {
+            (t.as_secs() * 1_000 + (t.subsec_nanos() as u64 + 999_999) / 1_000_000) as libc::c_int
+        });
+        let mut pfd = sys::pollfd {
+            fd: self.reader.as_raw_fd(),
+            events: sys::POLLIN,
+            revents: 0,
+        };
+
+        match unsafe { sys::poll(&mut pfd, 1, timeout_ms) } {
+            n if n >= 0 => Ok(n > 0),
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.kind() == ErrorKind::Interrupted {
+                    Ok(true)
+                } else {
+                    Err(err)
+                }
+            }
+        }
+    }
+
+    fn drain(&self) {
+        let mut buf = [0u8; 64];
+        loop {
+            let ret = unsafe {
+                libc::read(self.reader.as_raw_fd(),
+                           buf.as_mut_ptr() as *mut libc::c_void,
+                           buf.len() as libc::size_t)
+            };
+
+            if ret < buf.len() as libc::ssize_t {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use scheduler::Scheduler;
+
+    #[test]
+    fn test_notify() {
+        Scheduler::new()
+            .run(|| {
+                let notify = Arc::new(Notify::new().unwrap());
+
+                // Coalesced into one permit
+                notify.notify();
+                notify.notify();
+                notify.wait().unwrap();
+                assert!(!notify.wait_timeout(Duration::from_millis(20)).unwrap());
+
+                let n = notify.clone();
+                let hdl = thread::spawn(move || {
+                    for _ in 0..3 {
+                        thread::sleep(Duration::from_millis(10));
+                        n.notify();
+                    }
+                });
+
+                for _ in 0..3 {
+                    assert!(notify.wait_timeout(Duration::from_secs(5)).unwrap());
+                }
+                hdl.join().unwrap();
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_notify_from_callback_thread() {
+        Scheduler::new()
+            .run(|| {
+                let notifies: Vec<_> = (0..2).map(|_| Arc::new(Notify::new().unwrap())).collect();
+                let woken = Arc::new(Mutex::new(Vec::new()));
+
+                let handles: Vec<_> = notifies.iter()
+                                              .enumerate()
+                                              .map(|(idx, notify)| {
+                                                  let notify = notify.clone();
+                                                  let woken = woken.clone();
+                                                  Scheduler::spawn(move || {
+                                                      for _ in 0..2 {
+                                                          notify.wait().unwrap();
+                                                          woken.lock().unwrap().push(idx);
+                                                      }
+                                                  })
+                                              })
+                                              .collect();
+
+                // Completes the work of one coroutine after the other, like a callback thread
+                // of a C library would
+                let n = notifies.clone();
+                let w = woken.clone();
+                let callbacks = thread::spawn(move || {
+                    for (count, &idx) in [1, 0, 1, 0].iter().enumerate() {
+                        n[idx].notify();
+                        while w.lock().unwrap().len() <= count {
+                            thread::sleep(Duration::from_millis(1));
+                        }
+                    }
+                });
+
+                for hdl in handles {
+                    hdl.join().unwrap();
+                }
+                callbacks.join().unwrap();
+
+                // Only the notified coroutine has been woken up each time
+                assert_eq!(*woken.lock().unwrap(), vec![1, 0, 1, 0]);
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,233 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! Blocking coroutines, or threads outside of Processors, in the synchronization primitives
+
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use coroutine::{CancelHookGuard, Coroutine, Handle, Shared};
+use runtime::Processor;
+use scheduler::Scheduler;
+use stats::WaitReason;
+
+// Parks an OS thread which is not running a Processor
+pub struct Parker {
+    notified: Mutex<bool>,
+    cvar: Condvar,
+}
+
+impl Parker {
+    pub fn new() -> Parker {
+        Parker {
+            notified: Mutex::new(false),
+            cvar: Condvar::new(),
+        }
+    }
+
+    pub fn park(&self) {
+        let mut notified = self.notified.lock().unwrap();
+        while !*notified {
+            notified = self.cvar.wait(notified).unwrap();
+        }
+        *notified = false;
+    }
+
+    pub fn park_timeout(&self, dur: Duration) {
+        let deadline = Instant::now() + dur;
+
+        let mut notified = self.notified.lock().unwrap();
+        while !*notified {
+            let now = Instant::now();
+            if now >= deadline {
+                return;
+            }
+            notified = self.cvar.wait_timeout(notified, deadline - now).unwrap().0;
+        }
+        *notified = false;
+    }
+
+    pub fn unpark(&self) {
+        *self.notified.lock().unwrap() = true;
+        self.cvar.notify_one();
+    }
+}
+
+// A blocked coroutine or thread
+pub enum Wakeup {
+    Coroutine(Handle),
+    // A coroutine in an interruptible sleep, see `park_timeout`
+    Interrupt(Arc<Shared>),
+    Thread(Arc<Parker>),
+}
+
+impl Wakeup {
+    // Identifies the blocked side, for removing it from wait lists
+    pub fn id(&self) -> usize {
+        match *self {
+            Wakeup::Coroutine(ref coro) => &**coro as *const Coroutine as usize,
+            Wakeup::Interrupt(ref shared) => &**shared as *const Shared as usize,
+            Wakeup::Thread(ref parker) => &**parker as *const Parker as usize,
+        }
+    }
+
+    // Resume it as soon as possible, on the current Processor unless it belongs to another
+    // Scheduler
+    pub fn wake(self) {
+        match self {
+            Wakeup::Coroutine(coro) => {
+                if let Some(mut processor) = Processor::current() {
+                    if processor.is_local(&coro) {
+                        return processor.ready(coro);
+                    }
+                }
+
+                Scheduler::ready(coro)
+            }
+            Wakeup::Interrupt(shared) => shared.interrupt(),
+            Wakeup::Thread(parker) => parker.unpark(),
+        }
+    }
+}
+
+// Wake up many parked coroutines and threads at once, see `Scheduler::ready_all`
+pub fn wake_all<I>(wakeups: I)
+    where I: Iterator<Item = Wakeup>
+{
+    let mut coros = Vec::new();
+    for wakeup in wakeups {
+        match wakeup {
+            Wakeup::Coroutine(coro) => coros.push(coro),
+            thread => thread.wake(),
+        }
+    }
+    Scheduler::ready_all(coros);
+}
+
+// Block the current coroutine, or the current thread if it does not run a Processor.
+//
+// `f` receives the Wakeup of the blocked side and must either store it in a wait list,
+// or wake it up right away if it does not have to wait anymore.
+pub fn park<U, F>(f: F) -> U
+    where F: FnOnce(Wakeup) -> U
+{
+    match Processor::current() {
+        Some(mut processor) => {
+            processor.take_current_coroutine(|coro| {
+                coro.shared().set_wait_reason(WaitReason::Sync);
+                f(Wakeup::Coroutine(coro))
+            })
+        }
+        None => {
+            let parker = Arc::new(Parker::new());
+            let r = f(Wakeup::Thread(parker.clone()));
+            parker.park();
+            r
+        }
+    }
+}
+
+// Borrowed `cancel` callback of `park_cancellable`. Only called by the cancel hook, which the
+// guard removes before the callback goes out of scope.
+struct CancelFn(*const (Fn(usize) -> Option<Wakeup> + 'static));
+
+unsafe impl Send for CancelFn {}
+
+impl CancelFn {
+    fn call(&self, id: usize) {
+        if let Some(wakeup) = unsafe { (*self.0)(id) } {
+            wakeup.wake();
+        }
+    }
+}
+
+// Like `park`, but a coroutine is also woken up by `Shared::cancel`, which unwinds it.
+//
+// `cancel` receives the id of the Wakeup and must take it out of the wait list, returning
+// `None` if it has been woken up already.
+pub fn park_cancellable<U, F, C>(f: F, cancel: C) -> U
+    where F: FnOnce(Wakeup) -> U,
+          C: Fn(usize) -> Option<Wakeup>
+{
+    let shared = match Processor::current().and_then(|p| p.current_shared()) {
+        Some(shared) => shared,
+        None => return park(f),
+    };
+    let guard = CancelHookGuard::new(shared.clone());
+
+    park(|wakeup| {
+        let id = wakeup.id();
+        let hook = CancelFn(unsafe { mem::transmute(&cancel as &Fn(usize) -> Option<Wakeup>) });
+
+        // Installed before the Wakeup could be woken up, so the guard always removes it
+        guard.set(Box::new(move || hook.call(id)));
+        let r = f(wakeup);
+
+        // Cancelled before the hook has been installed
+        if shared.is_aborted() {
+            if let Some(wakeup) = cancel(id) {
+                wakeup.wake();
+            }
+        }
+        r
+    })
+}
+
+// Like `park`, but gives up waiting after `dur`.
+//
+// Afterwards `cancel` receives the id of the Wakeup and must remove it from the wait list,
+// returning false if it was not there anymore because it has been woken up already.
+pub fn park_timeout<U, F, C>(dur: Duration, f: F, cancel: C) -> U
+    where F: FnOnce(Wakeup) -> U,
+          C: FnOnce(usize) -> bool
+{
+    match Processor::current().and_then(|p| p.current_shared()) {
+        Some(shared) => {
+            let wakeup = Wakeup::Interrupt(shared.clone());
+            let id = wakeup.id();
+
+            let r = f(wakeup);
+            // Ends early if the Wakeup interrupts the sleep
+            let _ = Scheduler::instance().unwrap().sleep(dur);
+
+            if !cancel(id) {
+                // Woken up right after the timeout, don't let the interruption cut the
+                // next sleep of the coroutine short
+                if shared.set_interrupt_hook(Box::new(|| {})) {
+                    shared.clear_interrupt_hook();
+                }
+            }
+            r
+        }
+        None => {
+            let parker = Arc::new(Parker::new());
+            let wakeup = Wakeup::Thread(parker.clone());
+            let id = wakeup.id();
+
+            let r = f(wakeup);
+            parker.park_timeout(dur);
+            cancel(id);
+            r
+        }
+    }
+}
@@ -0,0 +1,300 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! Multi-producer, single-consumer channel delivering the highest-priority value first
+//!
+//! Values of the same priority are received in the order they have been sent. Blocking
+//! works like in `sync::mpsc`, both inside and outside of coroutines.
+//!
+//! ```ignore
+//! let (tx, rx) = priority::channel();
+//! tx.send(bulk, 0).unwrap();
+//! tx.send(control, 10).unwrap();
+//! assert_eq!(rx.recv().unwrap(), control);
+//! ```
+
+pub use std::sync::mpsc::{SendError, TryRecvError, RecvError};
+pub use super::mpsc::RecvTimeoutError;
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::park::{park, park_timeout, Wakeup};
+
+// A queued value, ordered by priority and then by the sequence number of the send
+struct Entry<T> {
+    priority: u32,
+    seq: u64,
+    value: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Entry<T>) -> bool {
+        self.priority == other.priority && self.seq == other.seq
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Entry<T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    fn cmp(&self, other: &Entry<T>) -> Ordering {
+        // BinaryHeap pops the greatest entry: the highest priority, sent earliest
+        match self.priority.cmp(&other.priority) {
+            Ordering::Equal => other.seq.cmp(&self.seq),
+            ord => ord,
+        }
+    }
+}
+
+struct State<T> {
+    queue: BinaryHeap<Entry<T>>,
+    next_seq: u64,
+    // The parked receiver
+    waiter: Option<Wakeup>,
+    senders: usize,
+    receiver_alive: bool,
+}
+
+pub struct Sender<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+unsafe impl<T: Send> Send for Sender<T> {}
+
+impl<T> Sender<T> {
+    /// Send a value with a priority, higher priorities are received first. Never blocks.
+    pub fn send(&self, t: T, priority: u32) -> Result<(), SendError<T>> {
+        let waiter = {
+            let mut state = self.state.lock().unwrap();
+            if !state.receiver_alive {
+                return Err(SendError(t));
+            }
+
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.queue.push(Entry {
+                priority: priority,
+                seq: seq,
+                value: t,
+            });
+            state.waiter.take()
+        };
+
+        if let Some(waiter) = waiter {
+            waiter.wake();
+        }
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.state.lock().unwrap().senders += 1;
+        Sender { state: self.state.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waiter = {
+            let mut state = self.state.lock().unwrap();
+            state.senders -= 1;
+            if state.senders > 0 {
+                return;
+            }
+            state.waiter.take()
+        };
+
+        // Let the parked receiver observe the disconnection
+        if let Some(waiter) = waiter {
+            waiter.wake();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+unsafe impl<T: Send> Send for Receiver<T> {}
+
+impl<T> Receiver<T> {
+    /// Number of values waiting in the channel
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().queue.len()
+    }
+
+    /// Receive the highest-priority value without blocking
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = self.state.lock().unwrap();
+        Receiver::pop(&mut state)
+    }
+
+    fn pop(state: &mut State<T>) -> Result<T, TryRecvError> {
+        match state.queue.pop() {
+            Some(entry) => Ok(entry.value),
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Receive the highest-priority value, blocks the current coroutine or thread until
+    /// one is available
+    pub fn recv(&self) -> Result<T, RecvError> {
+        loop {
+            let r = park(|wakeup| {
+                let mut state = self.state.lock().unwrap();
+                let r = Receiver::pop(&mut state);
+
+                match r {
+                    Err(TryRecvError::Empty) => state.waiter = Some(wakeup),
+                    _ => {
+                        drop(state);
+                        wakeup.wake();
+                    }
+                }
+                r
+            });
+
+            match r {
+                Ok(v) => return Ok(v),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+            }
+        }
+    }
+
+    /// Receive the highest-priority value, blocks the current coroutine or thread until
+    /// one is available or until `timeout` has passed
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let now = Instant::now();
+            let r = if now >= deadline {
+                self.try_recv()
+            } else {
+                park_timeout(deadline - now,
+                             |wakeup| {
+                                 let mut state = self.state.lock().unwrap();
+                                 let r = Receiver::pop(&mut state);
+
+                                 match r {
+                                     Err(TryRecvError::Empty) => state.waiter = Some(wakeup),
+                                     _ => {
+                                         drop(state);
+                                         wakeup.wake();
+                                     }
+                                 }
+                                 r
+                             },
+                             |id| {
+                                 let mut state = self.state.lock().unwrap();
+                                 match state.waiter.as_ref().map(|w| w.id()) {
+                                     Some(waiter) if waiter == id => {
+                                         state.waiter = None;
+                                         true
+                                     }
+                                     _ => false,
+                                 }
+                             })
+            };
+
+            match r {
+                Ok(v) => return Ok(v),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) if now >= deadline => {
+                    return Err(RecvTimeoutError::Timeout)
+                }
+                Err(TryRecvError::Empty) => {}
+            }
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.receiver_alive = false;
+        state.queue.clear();
+    }
+}
+
+/// Create a priority channel pair
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let state = Arc::new(Mutex::new(State {
+        queue: BinaryHeap::new(),
+        next_seq: 0,
+        waiter: None,
+        senders: 1,
+        receiver_alive: true,
+    }));
+
+    (Sender { state: state.clone() }, Receiver { state: state })
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use scheduler::Scheduler;
+
+    use super::*;
+
+    #[test]
+    fn test_priority_channel() {
+        Scheduler::new()
+            .run(|| {
+                let (tx, rx) = channel();
+
+                tx.send("bulk 1", 0).unwrap();
+                tx.send("bulk 2", 0).unwrap();
+                tx.send("control", 10).unwrap();
+                assert_eq!(rx.len(), 3);
+
+                assert_eq!(rx.recv(), Ok("control"));
+                assert_eq!(rx.recv(), Ok("bulk 1"));
+                assert_eq!(rx.recv(), Ok("bulk 2"));
+
+                // Parks until a thread sends
+                let tx2 = tx.clone();
+                let t = thread::spawn(move || {
+                    tx2.send("late", 1).unwrap();
+                });
+                assert_eq!(rx.recv(), Ok("late"));
+                t.join().unwrap();
+
+                drop(tx);
+                assert_eq!(rx.recv(), Err(RecvError));
+                assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+            })
+            .unwrap();
+    }
+}
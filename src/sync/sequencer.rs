@@ -0,0 +1,283 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! Handling events in the order they happened
+//!
+//! Coroutines woken up one after another may be resumed in any order: each of them is
+//! queued on a Processor and another Processor may steal the later ones first. A `Sequencer`
+//! restores the order. The waking side numbers every event with a `Ticket`, and the woken
+//! coroutine waits for its turn before handling the event:
+//!
+//! ```ignore
+//! let seq = Sequencer::new();
+//!
+//! // Waking side, in event order
+//! let ticket = seq.ticket();
+//! send_to_handler(event, ticket);
+//!
+//! // Handler coroutine
+//! let (event, ticket) = receive();
+//! let _turn = ticket.wait();
+//! // Handlers of the earlier events are done, the later ones wait until `_turn` is dropped
+//! state_machine.apply(event);
+//! ```
+
+use std::sync::{Arc, Mutex};
+
+use super::park::{park, Wakeup};
+
+struct State {
+    // Number of the next ticket to be issued
+    issued: usize,
+    // Number of the ticket whose turn it is
+    serving: usize,
+    // Coroutines and threads waiting for their turn, with their ticket numbers
+    parked: Vec<(usize, Wakeup)>,
+    // Numbers of the tickets dropped unused before their turn, passed over when it comes
+    skipped: Vec<usize>,
+}
+
+/// Issues numbered tickets and lets their holders take turns in the order of the numbers.
+/// Clones share the same sequence.
+#[derive(Clone)]
+pub struct Sequencer {
+    state: Arc<Mutex<State>>,
+}
+
+unsafe impl Send for Sequencer {}
+unsafe impl Sync for Sequencer {}
+
+impl Sequencer {
+    pub fn new() -> Sequencer {
+        Sequencer {
+            state: Arc::new(Mutex::new(State {
+                issued: 0,
+                serving: 0,
+                parked: Vec::new(),
+                skipped: Vec::new(),
+            })),
+        }
+    }
+
+    /// Number the next event
+    pub fn ticket(&self) -> Ticket {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.issued;
+        state.issued += 1;
+
+        Ticket {
+            seq: seq,
+            state: Some(self.state.clone()),
+        }
+    }
+}
+
+impl Default for Sequencer {
+    fn default() -> Sequencer {
+        Sequencer::new()
+    }
+}
+
+// Block until it is the turn of the ticket `seq`
+fn wait_turn(state: &Mutex<State>, seq: usize) {
+    loop {
+        let served = park(|wakeup| {
+            let mut state = state.lock().unwrap();
+            if state.serving == seq {
+                drop(state);
+                wakeup.wake();
+                true
+            } else {
+                state.parked.push((seq, wakeup));
+                false
+            }
+        });
+
+        if served {
+            return;
+        }
+    }
+}
+
+// End the turn of the ticket `seq` and resume the holder of the next one
+fn advance(state: &Mutex<State>, seq: usize) {
+    let next = {
+        let mut state = state.lock().unwrap();
+        debug_assert_eq!(state.serving, seq);
+        next_turn(&mut state)
+    };
+
+    if let Some(wakeup) = next {
+        wakeup.wake();
+    }
+}
+
+// Give up the turn of the unused ticket `seq` without waiting for the earlier turns
+fn skip(state: &Mutex<State>, seq: usize) {
+    let next = {
+        let mut state = state.lock().unwrap();
+        if state.serving == seq {
+            next_turn(&mut state)
+        } else {
+            state.skipped.push(seq);
+            None
+        }
+    };
+
+    if let Some(wakeup) = next {
+        wakeup.wake();
+    }
+}
+
+// Pass the turn on, over the skipped tickets. Returns the holder of the next ticket if it is
+// waiting already.
+fn next_turn(state: &mut State) -> Option<Wakeup> {
+    state.serving += 1;
+
+    loop {
+        let serving = state.serving;
+        let idx = match state.skipped.iter().position(|&s| s == serving) {
+            Some(idx) => idx,
+            None => break,
+        };
+        state.skipped.swap_remove(idx);
+        state.serving += 1;
+    }
+
+    let serving = state.serving;
+    let idx = state.parked.iter().position(|&(s, _)| s == serving);
+    idx.map(|idx| state.parked.swap_remove(idx).1)
+}
+
+/// Place of an event in the sequence, see `Sequencer::ticket`.
+///
+/// Dropping a ticket without waiting on it gives up its turn without blocking, e.g. when the
+/// event is abandoned. The holders of the later tickets do not wait for it.
+pub struct Ticket {
+    seq: usize,
+    state: Option<Arc<Mutex<State>>>,
+}
+
+unsafe impl Send for Ticket {}
+
+impl Ticket {
+    /// Number of the ticket, counting from 0
+    pub fn seq(&self) -> usize {
+        self.seq
+    }
+
+    /// Block the current coroutine (or the current thread outside of Processors) until the
+    /// turns of all earlier tickets are over. The turn lasts until the `Turn` is dropped.
+    pub fn wait(mut self) -> Turn {
+        let state = self.state.take().unwrap();
+        wait_turn(&state, self.seq);
+
+        Turn {
+            seq: self.seq,
+            state: state,
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            skip(&state, self.seq);
+        }
+    }
+}
+
+/// The turn of a ticket, the next one is resumed when it is dropped
+#[must_use]
+pub struct Turn {
+    seq: usize,
+    state: Arc<Mutex<State>>,
+}
+
+unsafe impl Send for Turn {}
+
+impl Turn {
+    /// Number of the ticket
+    pub fn seq(&self) -> usize {
+        self.seq
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        advance(&self.state, self.seq);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use scheduler::Scheduler;
+
+    use super::*;
+
+    #[test]
+    fn test_sequencer_order() {
+        Scheduler::new()
+            .with_workers(4)
+            .run(|| {
+                let seq = Sequencer::new();
+                let log = Arc::new(Mutex::new(Vec::new()));
+
+                // Spawned in reverse, so that the later tickets tend to run first
+                let tickets: Vec<Ticket> = (0..100).map(|_| seq.ticket()).collect();
+                let handles: Vec<_> = tickets.into_iter()
+                                             .rev()
+                                             .map(|ticket| {
+                                                 let log = log.clone();
+                                                 Scheduler::spawn(move || {
+                                                     Scheduler::sched();
+                                                     let turn = ticket.wait();
+                                                     log.lock().unwrap().push(turn.seq());
+                                                 })
+                                             })
+                                             .collect();
+
+                for hdl in handles {
+                    hdl.join().unwrap();
+                }
+
+                let log = log.lock().unwrap();
+                assert_eq!(*log, (0..100).collect::<Vec<_>>());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_dropped_ticket() {
+        let seq = Sequencer::new();
+        let first = seq.ticket();
+        let second = seq.ticket();
+        let third = seq.ticket();
+
+        // Neither blocks before its turn nor holds up the later ones
+        drop(second);
+        drop(first.wait());
+        assert_eq!(third.wait().seq(), 2);
+    }
+}
@@ -0,0 +1,151 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! Waiting for a collection of coroutines to finish
+//!
+//! ```ignore
+//! let wg = WaitGroup::new();
+//! for _ in 0..10 {
+//!     wg.add(1);
+//!     let wg = wg.clone();
+//!     coio::spawn(move || {
+//!         // ...
+//!         wg.done();
+//!     });
+//! }
+//! wg.wait();
+//! ```
+
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use super::park::{park, wake_all, Wakeup};
+
+struct State {
+    count: usize,
+    waiters: Vec<Wakeup>,
+}
+
+/// A counter of pending tasks which could be waited for to drop to zero, like Go's
+/// `sync.WaitGroup`. Clones share the same counter.
+#[derive(Clone)]
+pub struct WaitGroup {
+    state: Arc<Mutex<State>>,
+}
+
+impl WaitGroup {
+    pub fn new() -> WaitGroup {
+        WaitGroup {
+            state: Arc::new(Mutex::new(State {
+                count: 0,
+                waiters: Vec::new(),
+            })),
+        }
+    }
+
+    /// Add `n` pending tasks
+    pub fn add(&self, n: usize) {
+        self.state.lock().unwrap().count += n;
+    }
+
+    /// Mark one task as finished, waking up the waiters if it was the last one.
+    ///
+    /// Panics if there are no pending tasks.
+    pub fn done(&self) {
+        let waiters = {
+            let mut state = self.state.lock().unwrap();
+            assert!(state.count > 0, "WaitGroup::done() called more often than add()");
+
+            state.count -= 1;
+            if state.count > 0 {
+                return;
+            }
+            mem::replace(&mut state.waiters, Vec::new())
+        };
+
+        wake_all(waiters.into_iter());
+    }
+
+    /// Number of pending tasks
+    pub fn count(&self) -> usize {
+        self.state.lock().unwrap().count
+    }
+
+    /// Block the current coroutine (or the current thread outside of Processors) until
+    /// there are no pending tasks anymore
+    pub fn wait(&self) {
+        park(|wakeup| {
+            let mut state = self.state.lock().unwrap();
+            if state.count == 0 {
+                drop(state);
+                wakeup.wake();
+            } else {
+                state.waiters.push(wakeup);
+            }
+        });
+    }
+}
+
+impl Default for WaitGroup {
+    fn default() -> WaitGroup {
+        WaitGroup::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use scheduler::Scheduler;
+
+    use super::*;
+
+    #[test]
+    fn test_wait_group() {
+        Scheduler::new()
+            .with_workers(4)
+            .run(|| {
+                let wg = WaitGroup::new();
+                let finished = Arc::new(AtomicUsize::new(0));
+
+                for _ in 0..100 {
+                    wg.add(1);
+                    let wg = wg.clone();
+                    let finished = finished.clone();
+                    Scheduler::spawn(move || {
+                        Scheduler::sched();
+                        finished.fetch_add(1, Ordering::SeqCst);
+                        wg.done();
+                    });
+                }
+
+                wg.wait();
+                assert_eq!(finished.load(Ordering::SeqCst), 100);
+                assert_eq!(wg.count(), 0);
+
+                // Returns right away without pending tasks
+                wg.wait();
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! System calls and types which the libc version we depend on does not provide yet
+
+#![allow(non_camel_case_types)]
+
+use std::mem;
+
+use libc::{c_int, c_short, c_void, size_t, socklen_t, ssize_t};
+#[cfg(target_os = "linux")]
+use libc::c_long;
+
+pub const FD_CLOEXEC: c_int = 1;
+
+// Same values on Linux and the BSDs
+pub const MSG_PEEK: c_int = 0x2;
+
+pub const POLLIN: c_short = 0x1;
+pub const POLLOUT: c_short = 0x4;
+pub const POLLERR: c_short = 0x8;
+pub const POLLHUP: c_short = 0x10;
+
+pub const WNOHANG: c_int = 1;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub const MSG_CTRUNC: c_int = 0x8;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub const MSG_CTRUNC: c_int = 0x20;
+
+// gettid(2) has no wrapper in the C library
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub const SYS_gettid: c_long = 186;
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "arm")))]
+pub const SYS_gettid: c_long = 224;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+pub const SYS_gettid: c_long = 178;
+
+#[repr(C)]
+pub struct linger {
+    pub l_onoff: c_int,
+    pub l_linger: c_int,
+}
+
+#[repr(C)]
+pub struct iovec {
+    pub iov_base: *mut c_void,
+    pub iov_len: size_t,
+}
+
+#[repr(C)]
+pub struct pollfd {
+    pub fd: c_int,
+    pub events: c_short,
+    pub revents: c_short,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub type nfds_t = ::libc::c_ulong;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub type nfds_t = ::libc::c_uint;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[repr(C)]
+pub struct msghdr {
+    pub msg_name: *mut c_void,
+    pub msg_namelen: socklen_t,
+    pub msg_iov: *mut iovec,
+    pub msg_iovlen: size_t,
+    pub msg_control: *mut c_void,
+    pub msg_controllen: size_t,
+    pub msg_flags: c_int,
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+#[repr(C)]
+pub struct msghdr {
+    pub msg_name: *mut c_void,
+    pub msg_namelen: socklen_t,
+    pub msg_iov: *mut iovec,
+    pub msg_iovlen: c_int,
+    pub msg_control: *mut c_void,
+    pub msg_controllen: socklen_t,
+    pub msg_flags: c_int,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[repr(C)]
+pub struct cmsghdr {
+    pub cmsg_len: size_t,
+    pub cmsg_level: c_int,
+    pub cmsg_type: c_int,
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+#[repr(C)]
+pub struct cmsghdr {
+    pub cmsg_len: socklen_t,
+    pub cmsg_level: c_int,
+    pub cmsg_type: c_int,
+}
+
+extern "C" {
+    pub fn getsockopt(fd: c_int,
+                      level: c_int,
+                      name: c_int,
+                      val: *mut c_void,
+                      len: *mut socklen_t)
+                      -> c_int;
+
+    pub fn readv(fd: c_int, iov: *const iovec, iovcnt: c_int) -> ssize_t;
+    pub fn writev(fd: c_int, iov: *const iovec, iovcnt: c_int) -> ssize_t;
+
+    pub fn sendmsg(fd: c_int, msg: *const msghdr, flags: c_int) -> ssize_t;
+    pub fn recvmsg(fd: c_int, msg: *mut msghdr, flags: c_int) -> ssize_t;
+    pub fn socketpair(domain: c_int, ty: c_int, protocol: c_int, sv: *mut c_int) -> c_int;
+
+    pub fn poll(fds: *mut pollfd, nfds: nfds_t, timeout: c_int) -> c_int;
+
+    #[cfg(target_os = "linux")]
+    pub fn syscall(num: c_long, ...) -> c_long;
+}
+
+// The CMSG_ALIGN, CMSG_LEN and CMSG_SPACE macros of the C library
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub fn cmsg_align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+pub fn cmsg_align(len: usize) -> usize {
+    let align = mem::size_of::<usize>();
+    (len + align - 1) & !(align - 1)
+}
+
+/// Value of `cmsg_len` of a control message carrying `len` bytes
+pub fn cmsg_len(len: usize) -> usize {
+    cmsg_align(mem::size_of::<cmsghdr>()) + len
+}
+
+/// Room taken by a control message carrying `len` bytes, including the padding up to the
+/// next one
+pub fn cmsg_space(len: usize) -> usize {
+    cmsg_align(mem::size_of::<cmsghdr>()) + cmsg_align(len)
+}
+
+#[cfg(test)]
+mod test {
+    use std::mem;
+
+    use super::{cmsg_align, cmsg_len, cmsg_space, cmsghdr};
+
+    #[test]
+    fn test_cmsg_len() {
+        let header = cmsg_align(mem::size_of::<cmsghdr>());
+        assert!(header >= mem::size_of::<cmsghdr>());
+
+        for len in 0..32 {
+            assert_eq!(cmsg_len(len), header + len);
+            assert!(cmsg_space(len) >= cmsg_len(len));
+            assert_eq!(cmsg_align(cmsg_space(len)), cmsg_space(len));
+        }
+    }
+
+    // The values of the C macros on x86_64 and aarch64 Linux
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    #[test]
+    fn test_cmsg_len_linux() {
+        assert_eq!(mem::size_of::<cmsghdr>(), 16);
+        assert_eq!(cmsg_len(2), 18);
+        assert_eq!(cmsg_space(2), 24);
+        assert_eq!(cmsg_len(4), 20);
+        assert_eq!(cmsg_space(4), 24);
+        assert_eq!(cmsg_space(3 * 4), 32);
+    }
+}
@@ -0,0 +1,81 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Coarse cached clock
+//!
+//! Reading the clock for every message is measurable in hot paths. The Scheduler refreshes
+//! a cached timestamp on every event loop tick and every Processor refreshes it while it
+//! is busy, so `recent()` is a single atomic load, accurate to a few milliseconds under
+//! load and to the event loop tick (100 ms) when idle.
+
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Milliseconds since the UNIX epoch, 0 if not yet initialized
+static RECENT_MS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Refresh the cached clock
+#[doc(hidden)]
+pub fn update() -> u64 {
+    let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(dur) => dur.as_secs() * 1_000 + dur.subsec_nanos() as u64 / 1_000_000,
+        Err(..) => 0,
+    };
+
+    RECENT_MS.store(now as usize, Ordering::Relaxed);
+    now
+}
+
+/// Milliseconds since the UNIX epoch as of the last refresh of the cached clock
+#[inline]
+pub fn recent_millis() -> u64 {
+    match RECENT_MS.load(Ordering::Relaxed) {
+        0 => update(),
+        ms => ms as u64,
+    }
+}
+
+/// Wall clock time as of the last refresh of the cached clock
+#[inline]
+pub fn recent() -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(recent_millis())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_recent() {
+        update();
+
+        let now = SystemTime::now();
+        let recent = recent();
+        let diff = match now.duration_since(recent) {
+            Ok(diff) => diff,
+            Err(err) => err.duration(),
+        };
+
+        assert!(diff < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,157 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Periodic ticks on top of the timers of the event loop
+
+pub use scheduler::TimerHandle;
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use scheduler::Scheduler;
+
+fn nanos(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64
+}
+
+/// Ticks once per period, like the `time.Ticker` of Go.
+///
+/// The ticks are scheduled relative to the first one instead of to the time the previous one
+/// has been received, so they do not drift. A tick which is due is delivered late to a slow
+/// receiver, the ones due while it was late are dropped.
+///
+/// Iterating over an `Interval` yields the scheduled time of each tick, until it is stopped.
+pub struct Interval {
+    timer: TimerHandle,
+    period: Duration,
+    next: Instant,
+}
+
+impl Interval {
+    /// Tick every `period`, starting one period from now. Must be called inside a Scheduler.
+    pub fn new(period: Duration) -> io::Result<Interval> {
+        Interval::new_at(Instant::now() + period, period)
+    }
+
+    /// Tick every `period`, starting at `start`. Must be called inside a Scheduler.
+    pub fn new_at(start: Instant, period: Duration) -> io::Result<Interval> {
+        assert!(nanos(period) > 0, "Period of an Interval must not be zero");
+
+        let scheduler = Scheduler::instance().expect("Interval must be created in a Scheduler");
+        Ok(Interval {
+            timer: try!(scheduler.timer_at(start)),
+            period: period,
+            next: start,
+        })
+    }
+
+    /// Time between two ticks
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Block the current coroutine until the next tick, returns the time it has been
+    /// scheduled for.
+    ///
+    /// Returns `None` once the interval has been stopped, by `stop`, by cancelling its `timer`
+    /// or by the shutdown of the scheduler.
+    pub fn tick(&mut self) -> Option<Instant> {
+        if !self.timer.wait() {
+            return None;
+        }
+
+        let tick = self.next;
+        self.next = tick + self.period;
+
+        let now = Instant::now();
+        if self.next <= now {
+            let missed = nanos(now - self.next) / nanos(self.period) + 1;
+            debug!("Interval dropped {} ticks", missed);
+            self.next = self.next + self.period * missed as u32;
+        }
+
+        self.timer.reset(self.next);
+        Some(tick)
+    }
+
+    /// Start over, the next tick is one period from now
+    pub fn reset(&mut self) {
+        self.next = Instant::now() + self.period;
+        self.timer.reset(self.next);
+    }
+
+    /// The timer of the next tick. Cancelling it stops the interval, e.g. from another
+    /// coroutine.
+    pub fn timer(&self) -> TimerHandle {
+        self.timer.clone()
+    }
+
+    /// Stop ticking, `tick` returns `None` from now on
+    pub fn stop(&self) {
+        self.timer.cancel();
+    }
+}
+
+impl Iterator for Interval {
+    type Item = Instant;
+
+    fn next(&mut self) -> Option<Instant> {
+        self.tick()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use scheduler::Scheduler;
+
+    #[test]
+    fn test_interval() {
+        Scheduler::new()
+            .run(|| {
+                let period = Duration::from_millis(20);
+                let start = Instant::now() + period;
+                let mut interval = Interval::new_at(start, period).unwrap();
+
+                assert_eq!(interval.tick(), Some(start));
+                assert!(Instant::now() >= start);
+                assert_eq!(interval.tick(), Some(start + period));
+
+                // The late tick is delivered right away, the one missed meanwhile is dropped
+                ::sleep(Duration::from_millis(50));
+                assert_eq!(interval.tick(), Some(start + period * 2));
+                let tick = interval.tick().unwrap();
+                assert!(tick == start + period * 4 || tick == start + period * 5);
+                assert!(Instant::now() >= tick);
+
+                let timer = interval.timer();
+                Scheduler::spawn(move || {
+                    timer.cancel();
+                });
+                assert_eq!(interval.tick(), None);
+                assert_eq!(interval.next(), None);
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc Developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Request-scoped spans
+//!
+//! A span covers a part of the work of a coroutine, e.g. handling one request. Entering
+//! and exiting spans is reported to the `SchedulerObserver`, together with how much of
+//! the span the coroutine spent running and how much blocked:
+//!
+//! ```ignore
+//! let _span = span!("handle_request");
+//! // ...
+//! ```
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use coroutine::Shared;
+use observer::Event;
+use runtime::Processor;
+use scheduler::Scheduler;
+
+/// Enter a span, which is exited when the returned guard is dropped
+#[macro_export]
+macro_rules! span {
+    ($name:expr) => ($crate::trace::Span::enter($name))
+}
+
+/// Guard of an entered span, see `span!`
+pub struct Span {
+    inner: Option<SpanInner>,
+}
+
+struct SpanInner {
+    name: &'static str,
+    coro: Arc<Shared>,
+    scheduler: &'static Scheduler,
+    entered: Instant,
+    running: Duration,
+    blocked: Duration,
+}
+
+impl Span {
+    /// Enter the span in the current coroutine. Outside of coroutines this does nothing.
+    pub fn enter(name: &'static str) -> Span {
+        let processor = match Processor::current() {
+            Some(processor) => processor,
+            None => return Span { inner: None },
+        };
+
+        let coro = match processor.current_shared() {
+            Some(coro) => coro,
+            None => return Span { inner: None },
+        };
+
+        let scheduler = Scheduler::instance().unwrap();
+        let now = Instant::now();
+        let (running, blocked) = coro.timing(now);
+
+        coro.push_span(name);
+        scheduler.emit(Event::SpanEntered {
+            coroutine_id: coro.id(),
+            name: name,
+        });
+
+        Span {
+            inner: Some(SpanInner {
+                name: name,
+                coro: coro,
+                scheduler: scheduler,
+                entered: now,
+                running: running,
+                blocked: blocked,
+            }),
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let inner = match self.inner.take() {
+            Some(inner) => inner,
+            None => return,
+        };
+
+        let now = Instant::now();
+        let (running, blocked) = inner.coro.timing(now);
+
+        inner.coro.pop_span();
+        inner.scheduler.emit(Event::SpanExited {
+            coroutine_id: inner.coro.id(),
+            name: inner.name,
+            elapsed: now - inner.entered,
+            running: running - inner.running,
+            blocked: blocked - inner.blocked,
+        });
+    }
+}
+
+/// Names of the spans the current coroutine has entered, the innermost last
+pub fn current_spans() -> Vec<&'static str> {
+    Processor::current()
+        .and_then(|p| p.current_shared())
+        .map(|coro| coro.spans())
+        .unwrap_or(Vec::new())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::*;
+    use observer::{Event, SchedulerObserver};
+    use scheduler::Scheduler;
+
+    struct Recorder(Arc<Mutex<Vec<Event>>>);
+
+    impl SchedulerObserver for Recorder {
+        fn on_event(&self, event: &Event) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn test_span_blocked_time() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        Scheduler::new()
+            .with_observer(Recorder(events.clone()))
+            .run(|| {
+                let _outer = span!("outer");
+                {
+                    let _inner = span!("inner");
+                    assert_eq!(current_spans(), vec!["outer", "inner"]);
+                    ::sleep_ms(100);
+                }
+                assert_eq!(current_spans(), vec!["outer"]);
+            })
+            .unwrap();
+
+        let events = events.lock().unwrap();
+        let exited = events.iter()
+                           .filter_map(|e| {
+                               match *e {
+                                   Event::SpanExited { name, elapsed, blocked, .. } => {
+                                       Some((name, elapsed, blocked))
+                                   }
+                                   _ => None,
+                               }
+                           })
+                           .collect::<Vec<_>>();
+
+        assert_eq!(exited.len(), 2);
+        assert_eq!(exited[0].0, "inner");
+        assert!(exited[0].2 >= Duration::from_millis(90));
+        assert!(exited[0].1 >= exited[0].2);
+        assert_eq!(exited[1].0, "outer");
+    }
+}
@@ -0,0 +1,73 @@
+// Everything in `coio::prelude` is covered by the semver guarantees of the module, so every
+// item is used here through the glob import alone. Do not import anything else from coio.
+
+extern crate coio;
+
+use std::time::Duration;
+
+use coio::prelude::*;
+
+// Generic over the traits of the prelude, fails to compile if one of them goes missing
+fn echo_once<S: Read + Write>(stream: &mut S) {
+    let mut buf = [0u8; 16];
+    let len = stream.read(&mut buf).unwrap();
+    stream.write_all(&buf[..len]).unwrap();
+}
+
+fn shutdown_boxed(stream: Box<CoStream>) {
+    stream.shutdown(Shutdown::Write).unwrap();
+}
+
+#[allow(dead_code)]
+fn deadline_io<S: DeadlineIo>(_: &S) {}
+
+#[allow(dead_code)]
+fn udp_ext<S: UdpSocketExt>(_: &S) {}
+
+#[cfg(unix)]
+#[allow(dead_code)]
+fn unix_types(_: Option<UnixListener>, _: Option<UnixStream>, _: Option<CoIo<UnixStream>>) {}
+
+#[test]
+fn test_prelude() {
+    Scheduler::new()
+        .run(|| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let server: JoinHandle<()> = spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                echo_once(&mut stream);
+            });
+
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"ping").unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"ping");
+            shutdown_boxed(Box::new(stream));
+            server.join().unwrap();
+
+            let (tx, rx): (Sender<u32>, Receiver<u32>) = channel();
+            let (stx, srx): (SyncSender<u32>, SyncReceiver<u32>) = sync_channel(1);
+            let opts = Options::new().priority(Priority::High).spawn_hint(SpawnHint::Deferred);
+            spawn_opts(move || {
+                           tx.send(1).unwrap();
+                           stx.send(2).unwrap();
+                       },
+                       opts);
+            sched();
+            assert_eq!(rx.recv().unwrap() + srx.recv().unwrap(), 3);
+
+            let timer: TimerHandle = Scheduler::instance()
+                                         .unwrap()
+                                         .timer(Duration::from_millis(1))
+                                         .unwrap();
+            assert!(timer.wait());
+            assert_eq!(sleep(Duration::from_millis(1)), Duration::from_millis(0));
+            assert_eq!(run_blocking(|| 1).unwrap(), 1);
+
+            let _: Option<UdpSocket> = None;
+        })
+        .unwrap();
+}